@@ -60,6 +60,7 @@ class Command(BaseModel):
     class Subscribe(BaseModel):
         extractor_id: ExtractorIdentity
         include_state: bool
+        finalized_only: bool = False
 
     class Unsubscribe(BaseModel):
         subscription_id: UUID
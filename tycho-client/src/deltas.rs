@@ -102,11 +102,12 @@ pub enum DeltasError {
 #[derive(Clone, Debug)]
 pub struct SubscriptionOptions {
     include_state: bool,
+    finalized_only: bool,
 }
 
 impl Default for SubscriptionOptions {
     fn default() -> Self {
-        Self { include_state: true }
+        Self { include_state: true, finalized_only: false }
     }
 }
 
@@ -118,6 +119,12 @@ impl SubscriptionOptions {
         self.include_state = val;
         self
     }
+    /// Only receive blocks once they are finalized. Such a subscription never receives reverts,
+    /// at the cost of lagging behind the chain head by the finality depth.
+    pub fn with_finalized_only(mut self, val: bool) -> Self {
+        self.finalized_only = val;
+        self
+    }
 }
 
 #[cfg_attr(test, automock)]
@@ -562,7 +569,11 @@ impl DeltasClient for WsDeltasClient {
                 .expect("ws not connected");
             trace!("Sending subscribe command");
             inner.new_subscription(&extractor_id, ready_tx)?;
-            let cmd = Command::Subscribe { extractor_id, include_state: options.include_state };
+            let cmd = Command::Subscribe {
+                extractor_id,
+                include_state: options.include_state,
+                finalized_only: options.finalized_only,
+            };
             inner
                 .ws_send(tungstenite::protocol::Message::Text(
                     serde_json::to_string(&cmd).expect("serialize cmd encode error"),
@@ -818,7 +829,8 @@ mod tests {
                         "chain":"ethereum",
                         "name":"vm:ambient"
                     },
-                    "include_state": true
+                    "include_state": true,
+                    "finalized_only": false
                 }"#.to_owned().replace(|c: char| c.is_whitespace(), "")
             )),
             ExpectedComm::Send(tungstenite::protocol::Message::Text(r#"
@@ -953,7 +965,8 @@ mod tests {
                         "chain": "ethereum",
                         "name": "vm:ambient"
                     },
-                    "include_state": true
+                    "include_state": true,
+                    "finalized_only": false
                 }"#
                     .to_owned()
                     .replace(|c: char| c.is_whitespace(), ""),
@@ -1048,7 +1061,8 @@ mod tests {
                         "chain":"ethereum",
                         "name":"vm:ambient"
                     },
-                    "include_state": true
+                    "include_state": true,
+                    "finalized_only": false
                 }"#
                     .to_owned()
                     .replace(|c: char| c.is_whitespace(), ""),
@@ -1121,7 +1135,8 @@ mod tests {
                         "chain":"ethereum",
                         "name":"vm:ambient"
                     },
-                    "include_state": true
+                    "include_state": true,
+                    "finalized_only": false
                 }"#.to_owned().replace(|c: char| c.is_whitespace(), "")
             )),
             ExpectedComm::Send(tungstenite::protocol::Message::Text(r#"
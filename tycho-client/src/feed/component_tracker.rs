@@ -268,6 +268,7 @@ mod test {
                 Ok(ProtocolComponentRequestResponse {
                     protocol_components: vec![component.clone()],
                     pagination: PaginationResponse { page: 0, page_size: 20, total: 1 },
+                    block: None,
                 })
             });
 
@@ -300,6 +301,7 @@ mod test {
                 Ok(ProtocolComponentRequestResponse {
                     protocol_components: vec![component.clone()],
                     pagination: PaginationResponse { page: 0, page_size: 20, total: 1 },
+                    block: None,
                 })
             });
 
@@ -18,8 +18,8 @@ use tokio::{
 use tracing::{debug, error, info, instrument, trace, warn};
 use tycho_core::{
     dto::{
-        BlockChanges, BlockParam, ExtractorIdentity, ProtocolComponent, ResponseAccount,
-        ResponseProtocolState, VersionParam,
+        BlockChanges, BlockParam, ExtractorIdentity, ProtocolComponent, ResolvedBlock,
+        ResponseAccount, ResponseProtocolState, VersionParam,
     },
     Bytes,
 };
@@ -35,6 +35,24 @@ use crate::{
 
 pub type SyncResult<T> = anyhow::Result<T>;
 
+/// Verifies that a snapshot was taken at the block it was requested for.
+///
+/// Older servers that do not report the resolved block are trusted.
+fn check_snapshot_block(header: &Header, block: Option<&ResolvedBlock>) -> SyncResult<()> {
+    match block {
+        Some(block) if block.number != header.number || block.hash != header.hash => {
+            Err(anyhow::anyhow!(
+                "Snapshot resolved at block {} ({}) but was requested at block {} ({})",
+                block.number,
+                block.hash,
+                header.number,
+                header.hash
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
 #[derive(Clone)]
 pub struct ProtocolStateSynchronizer<R: RPCClient, D: DeltasClient> {
     extractor_id: ExtractorIdentity,
@@ -216,7 +234,7 @@ where
             return Ok(StateSyncMessage { header, ..Default::default() });
         }
 
-        let mut protocol_states = self
+        let protocol_states_response = self
             .rpc_client
             .get_protocol_states_paginated(
                 self.extractor_id.chain,
@@ -227,7 +245,9 @@ where
                 100,
                 4,
             )
-            .await?
+            .await?;
+        check_snapshot_block(&header, protocol_states_response.block.as_ref())?;
+        let mut protocol_states = protocol_states_response
             .states
             .into_iter()
             .map(|state| (state.component_id.clone(), state))
@@ -260,7 +280,7 @@ where
                 .clone()
                 .into_iter()
                 .collect();
-            let contract_states = self
+            let contract_states_response = self
                 .rpc_client
                 .get_contract_state_paginated(
                     self.extractor_id.chain,
@@ -270,7 +290,9 @@ where
                     100,
                     4,
                 )
-                .await?
+                .await?;
+            check_snapshot_block(&header, contract_states_response.block.as_ref())?;
+            let contract_states = contract_states_response
                 .accounts
                 .into_iter()
                 .map(|acc| (acc.address.clone(), acc))
@@ -529,7 +551,7 @@ where
 mod test {
     use test_log::test;
     use tycho_core::dto::{
-        Block, Chain, PaginationResponse, ProtocolComponentRequestResponse,
+        Block, Chain, FinalityStatus, PaginationResponse, ProtocolComponentRequestResponse,
         ProtocolComponentsRequestBody, ProtocolStateRequestBody, ProtocolStateRequestResponse,
         ProtocolSystemsRequestBody, ProtocolSystemsRequestResponse, StateRequestBody,
         StateRequestResponse, TokensRequestBody, TokensRequestResponse,
@@ -663,6 +685,7 @@ mod test {
                 ..Default::default()
             }],
             pagination: PaginationResponse { page: 0, page_size: 20, total: 1 },
+            block: None,
         }
     }
 
@@ -711,6 +734,42 @@ mod test {
         assert_eq!(snap, exp);
     }
 
+    #[test(tokio::test)]
+    async fn test_get_snapshots_block_mismatch() {
+        let header = Header { number: 1, hash: Bytes::from("0x01"), ..Default::default() };
+        let mut rpc = MockRPCClient::new();
+        rpc.expect_get_protocol_states()
+            .returning(|_| {
+                Ok(ProtocolStateRequestResponse {
+                    block: Some(ResolvedBlock {
+                        number: 2,
+                        hash: Bytes::from("0x02"),
+                        ts: Default::default(),
+                        finality: FinalityStatus::Unfinalized,
+                    }),
+                    ..state_snapshot_native()
+                })
+            });
+        let state_sync = with_mocked_clients(true, Some(rpc), None);
+        let mut tracker = ComponentTracker::new(
+            Chain::Ethereum,
+            "uniswap-v2",
+            ComponentFilter::with_tvl_range(0.0, 0.0),
+            state_sync.rpc_client.clone(),
+        );
+        let component = ProtocolComponent { id: "Component1".to_string(), ..Default::default() };
+        tracker
+            .components
+            .insert("Component1".to_string(), component);
+        let components_arg = ["Component1".to_string()];
+
+        let res = state_sync
+            .get_snapshots(header, &tracker, Some(&components_arg))
+            .await;
+
+        assert!(res.is_err());
+    }
+
     fn state_snapshot_vm() -> StateRequestResponse {
         StateRequestResponse {
             accounts: vec![
@@ -718,6 +777,7 @@ mod test {
                 ResponseAccount { address: Bytes::from("0xbabe42"), ..Default::default() },
             ],
             pagination: PaginationResponse { page: 0, page_size: 20, total: 1 },
+            block: None,
         }
     }
 
@@ -801,6 +861,7 @@ mod test {
                         ProtocolComponent { id: "Component3".to_string(), ..Default::default() },
                     ],
                     pagination: PaginationResponse { page: 0, page_size: 20, total: 1 },
+                    block: None,
                 })
             });
         rpc_client
@@ -821,6 +882,7 @@ mod test {
                         ..Default::default()
                     }],
                     pagination: PaginationResponse { page: 0, page_size: 20, total: 1 },
+                    block: None,
                 })
             });
 
@@ -838,6 +900,7 @@ mod test {
                         // a third component will have a tvl update above threshold
                     ],
                     pagination: PaginationResponse { page: 0, page_size: 20, total: 1 },
+                    block: None,
                 })
             });
         rpc_client
@@ -856,6 +919,7 @@ mod test {
                         },
                     ],
                     pagination: PaginationResponse { page: 0, page_size: 20, total: 1 },
+                    block: None,
                 })
             });
         // Mock deltas client and messages
@@ -1095,6 +1159,7 @@ mod test {
                         ..Default::default()
                     }],
                     pagination: PaginationResponse { page: 0, page_size: 20, total: 1 },
+                    block: None,
                 })
             });
 
@@ -1115,6 +1180,7 @@ mod test {
                         ..Default::default()
                     }],
                     pagination: PaginationResponse { page: 0, page_size: 20, total: 1 },
+                    block: None,
                 })
             });
 
@@ -1128,6 +1194,7 @@ mod test {
                         ProtocolComponent { id: "Component2".to_string(), ..Default::default() },
                     ],
                     pagination: PaginationResponse { page: 0, page_size: 20, total: 1 },
+                    block: None,
                 })
             });
 
@@ -1146,6 +1213,7 @@ mod test {
                         },
                     ],
                     pagination: PaginationResponse { page: 0, page_size: 20, total: 1 },
+                    block: None,
                 })
             });
 
@@ -17,7 +17,7 @@ use tycho_core::{
     dto::{
         Chain, PaginationParams, PaginationResponse, ProtocolComponentRequestResponse,
         ProtocolComponentsRequestBody, ProtocolStateRequestBody, ProtocolStateRequestResponse,
-        ProtocolSystemsRequestBody, ProtocolSystemsRequestResponse, ResponseToken,
+        ProtocolSystemsRequestBody, ProtocolSystemsRequestResponse, ResolvedBlock, ResponseToken,
         StateRequestBody, StateRequestResponse, TokensRequestBody, TokensRequestResponse,
         VersionParam,
     },
@@ -44,6 +44,37 @@ pub enum RPCError {
     Fatal(String),
 }
 
+/// Returns the block shared by all pages of a paginated response.
+///
+/// Pages are requested independently, so the server may resolve them at different blocks if a new
+/// block arrives in between. Stitching such pages together would produce a response that never
+/// existed on chain, so this fails instead. Finality is ignored as it may advance between pages
+/// without changing the state.
+fn common_block<'a>(
+    blocks: impl IntoIterator<Item = Option<&'a ResolvedBlock>>,
+) -> Result<Option<ResolvedBlock>, RPCError> {
+    let mut blocks = blocks.into_iter();
+    let first = match blocks.next() {
+        Some(block) => block,
+        None => return Ok(None),
+    };
+    for block in blocks {
+        let same = match (first, block) {
+            (Some(a), Some(b)) => a.number == b.number && a.hash == b.hash,
+            (None, None) => true,
+            _ => false,
+        };
+        if !same {
+            return Err(RPCError::Fatal(format!(
+                "Paginated response pages were resolved at different blocks: {:?} and {:?}",
+                first.map(|b| b.number),
+                block.map(|b| b.number)
+            )));
+        }
+    }
+    Ok(first.cloned())
+}
+
 #[cfg_attr(test, automock)]
 #[async_trait]
 pub trait RPCClient: Send + Sync {
@@ -99,10 +130,16 @@ pub trait RPCClient: Send + Sync {
             .iter()
             .map(|r| r.pagination.total)
             .sum();
+        let block = common_block(
+            responses
+                .iter()
+                .map(|r| r.block.as_ref()),
+        )?;
 
         Ok(StateRequestResponse {
             accounts,
             pagination: PaginationResponse { page: 0, page_size: chunk_size as i64, total },
+            block,
         })
     }
 
@@ -151,19 +188,25 @@ pub trait RPCClient: Send + Sync {
                     });
                 }
 
-                try_join_all(tasks)
-                    .await
-                    .map(|responses| ProtocolComponentRequestResponse {
-                        protocol_components: responses
-                            .into_iter()
-                            .flat_map(|r| r.protocol_components.into_iter())
-                            .collect(),
-                        pagination: PaginationResponse {
-                            page: 0,
-                            page_size: chunk_size as i64,
-                            total: ids.len() as i64,
-                        },
-                    })
+                let responses = try_join_all(tasks).await?;
+                let block = common_block(
+                    responses
+                        .iter()
+                        .map(|r| r.block.as_ref()),
+                )?;
+
+                Ok(ProtocolComponentRequestResponse {
+                    block,
+                    protocol_components: responses
+                        .into_iter()
+                        .flat_map(|r| r.protocol_components.into_iter())
+                        .collect(),
+                    pagination: PaginationResponse {
+                        page: 0,
+                        page_size: chunk_size as i64,
+                        total: ids.len() as i64,
+                    },
+                })
             }
             _ => {
                 // If no component ids are specified, we need to make requests based on the total
@@ -192,6 +235,7 @@ pub trait RPCClient: Send + Sync {
                         page_size: chunk_size as i64,
                         total: total_items,
                     },
+                    block: first_response.block,
                 };
 
                 let mut page = 1;
@@ -225,32 +269,22 @@ pub trait RPCClient: Send + Sync {
                         })
                         .collect();
 
-                    let responses = try_join_all(tasks)
-                        .await
-                        .map(|responses| {
-                            let total = responses[0].pagination.total;
-                            ProtocolComponentRequestResponse {
-                                protocol_components: responses
-                                    .into_iter()
-                                    .flat_map(|r| r.protocol_components.into_iter())
-                                    .collect(),
-                                pagination: PaginationResponse {
-                                    page,
-                                    page_size: chunk_size as i64,
-                                    total,
-                                },
-                            }
-                        });
-
-                    // Update the accumulated response or set the initial response
-                    match responses {
-                        Ok(mut resp) => {
-                            accumulated_response
-                                .protocol_components
-                                .append(&mut resp.protocol_components);
-                        }
-                        Err(e) => return Err(e),
-                    }
+                    let responses = try_join_all(tasks).await?;
+                    common_block(
+                        std::iter::once(accumulated_response.block.as_ref()).chain(
+                            responses
+                                .iter()
+                                .map(|r| r.block.as_ref()),
+                        ),
+                    )?;
+
+                    accumulated_response
+                        .protocol_components
+                        .extend(
+                            responses
+                                .into_iter()
+                                .flat_map(|r| r.protocol_components.into_iter()),
+                        );
 
                     page += concurrency as i64;
                 }
@@ -307,23 +341,26 @@ pub trait RPCClient: Send + Sync {
             });
         }
 
-        try_join_all(tasks)
-            .await
-            .map(|responses| {
-                let states = responses
-                    .clone()
-                    .into_iter()
-                    .flat_map(|r| r.states)
-                    .collect();
-                let total = responses
-                    .iter()
-                    .map(|r| r.pagination.total)
-                    .sum();
-                ProtocolStateRequestResponse {
-                    states,
-                    pagination: PaginationResponse { page: 0, page_size: chunk_size as i64, total },
-                }
-            })
+        let responses = try_join_all(tasks).await?;
+        let block = common_block(
+            responses
+                .iter()
+                .map(|r| r.block.as_ref()),
+        )?;
+        let total = responses
+            .iter()
+            .map(|r| r.pagination.total)
+            .sum();
+        let states = responses
+            .into_iter()
+            .flat_map(|r| r.states)
+            .collect();
+
+        Ok(ProtocolStateRequestResponse {
+            states,
+            pagination: PaginationResponse { page: 0, page_size: chunk_size as i64, total },
+            block,
+        })
     }
 
     /// This function returns only one chunk of tokens. To get all tokens please call
@@ -464,6 +501,7 @@ impl RPCClient for HttpRPCClient {
                     page_size: request.pagination.page,
                     total: 0,
                 },
+                block: None,
             });
         }
 
@@ -563,6 +601,7 @@ impl RPCClient for HttpRPCClient {
                     page_size: request.pagination.page_size,
                     total: 0,
                 },
+                block: None,
             });
         }
 
@@ -790,6 +829,79 @@ mod tests {
         );
     }
 
+    #[rstest]
+    #[case::same_block("0x01", true)]
+    #[case::different_block("0x02", false)]
+    #[tokio::test]
+    async fn test_get_contract_state_paginated_block_consistency(
+        #[case] second_hash: &str,
+        #[case] is_ok: bool,
+    ) {
+        let mut server = Server::new_async().await;
+        let page_resp = |hash: &str, finality: &str| {
+            format!(
+                r#"{{
+                    "accounts": [],
+                    "pagination": {{ "page": 0, "page_size": 1, "total": 0 }},
+                    "block": {{
+                        "number": 1,
+                        "hash": "{hash}",
+                        "ts": "2022-01-01T00:00:00",
+                        "finality": "{finality}"
+                    }}
+                }}"#
+            )
+        };
+        let first_mock = server
+            .mock("POST", "/v1/contract_state")
+            .match_body(mockito::Matcher::Regex(
+                "0x0000000000000000000000000000000000000001".into(),
+            ))
+            .expect(1)
+            .with_body(page_resp("0x01", "Unfinalized"))
+            .create_async()
+            .await;
+        let second_mock = server
+            .mock("POST", "/v1/contract_state")
+            .match_body(mockito::Matcher::Regex(
+                "0x0000000000000000000000000000000000000002".into(),
+            ))
+            .expect(1)
+            .with_body(page_resp(second_hash, "Finalized"))
+            .create_async()
+            .await;
+        let client = HttpRPCClient::new(server.url().as_str(), None).expect("create client");
+        let ids = [
+            Bytes::from_str("0x0000000000000000000000000000000000000001").unwrap(),
+            Bytes::from_str("0x0000000000000000000000000000000000000002").unwrap(),
+        ];
+
+        let res = client
+            .get_contract_state_paginated(
+                Chain::Ethereum,
+                &ids,
+                "uniswap_v2",
+                &VersionParam::default(),
+                1,
+                2,
+            )
+            .await;
+
+        first_mock.assert();
+        second_mock.assert();
+        match res {
+            Ok(response) => {
+                assert!(is_ok);
+                let block = response.block.expect("block");
+                assert_eq!(block.hash, Bytes::from_str("0x01").unwrap());
+            }
+            Err(err) => {
+                assert!(!is_ok);
+                assert!(matches!(err, RPCError::Fatal(_)));
+            }
+        }
+    }
+
     #[tokio::test]
     async fn test_get_protocol_components() {
         let mut server = Server::new_async().await;
@@ -976,6 +1088,7 @@ mod tests {
                 tax: 0,
                 gas: vec![Some(29962)],
                 quality: 100,
+                flags: vec![],
             },
             ResponseToken {
                 chain: Chain::Ethereum,
@@ -985,6 +1098,7 @@ mod tests {
                 tax: 0,
                 gas: vec![Some(40652)],
                 quality: 100,
+                flags: vec![],
             },
         ];
 
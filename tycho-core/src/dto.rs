@@ -137,8 +137,16 @@ impl fmt::Display for ExtractorIdentity {
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
 #[serde(tag = "method", rename_all = "lowercase")]
 pub enum Command {
-    Subscribe { extractor_id: ExtractorIdentity, include_state: bool },
-    Unsubscribe { subscription_id: Uuid },
+    Subscribe {
+        extractor_id: ExtractorIdentity,
+        include_state: bool,
+        /// Only deliver blocks once they are finalized, so the subscription never sees reverts.
+        #[serde(default)]
+        finalized_only: bool,
+    },
+    Unsubscribe {
+        subscription_id: Uuid,
+    },
 }
 
 /// A response sent from the server to the client
@@ -191,6 +199,35 @@ impl From<&Block> for BlockParam {
     }
 }
 
+/// Finality of the block a response reflects.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize, Serialize, ToSchema)]
+pub enum FinalityStatus {
+    /// The block can't be reverted anymore.
+    Finalized,
+    /// The block may still be reverted. The response includes state from pending deltas.
+    Unfinalized,
+}
+
+/// The block a state or component response was resolved at.
+///
+/// Requests for the latest state or for a timestamp are resolved to a concrete block, which allows
+/// aligning the response with delta messages received via the websocket.
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize, ToSchema)]
+pub struct ResolvedBlock {
+    pub number: u64,
+    #[schema(value_type=String)]
+    #[serde(with = "hex_bytes")]
+    pub hash: Bytes,
+    pub ts: NaiveDateTime,
+    pub finality: FinalityStatus,
+}
+
+impl ResolvedBlock {
+    pub fn new(block: &models::blockchain::Block, finality: FinalityStatus) -> Self {
+        Self { number: block.number, hash: block.hash.clone(), ts: block.ts, finality }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Default)]
 pub struct TokenBalances(#[serde(with = "hex_hashmap_key")] pub HashMap<Bytes, ComponentBalance>);
 
@@ -589,11 +626,14 @@ impl StateRequestBody {
 pub struct StateRequestResponse {
     pub accounts: Vec<ResponseAccount>,
     pub pagination: PaginationResponse,
+    /// The block the returned state reflects.
+    #[serde(default)]
+    pub block: Option<ResolvedBlock>,
 }
 
 impl StateRequestResponse {
     pub fn new(accounts: Vec<ResponseAccount>, pagination: PaginationResponse) -> Self {
-        Self { accounts, pagination }
+        Self { accounts, pagination, block: None }
     }
 }
 
@@ -885,6 +925,10 @@ pub struct ResponseToken {
     pub tax: u64,
     pub gas: Vec<Option<u64>>,
     pub quality: u32,
+    /// Transfer behaviours detected by the token analysis, e.g. `rebasing` or
+    /// `fee_on_transfer`.
+    #[serde(default)]
+    pub flags: Vec<String>,
 }
 
 impl From<models::token::CurrencyToken> for ResponseToken {
@@ -897,10 +941,74 @@ impl From<models::token::CurrencyToken> for ResponseToken {
             tax: value.tax,
             gas: value.gas,
             quality: value.quality,
+            flags: value
+                .flags
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
         }
     }
 }
 
+/// Query parameters for a token analysis history request.
+#[derive(Serialize, Deserialize, Debug, Default, IntoParams, Clone, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct TokenAnalysisParams {
+    #[serde(default)]
+    pub chain: Chain,
+    /// Maximum number of analyses to return, newest first. Max supported is 100.
+    #[param(default = 20)]
+    pub limit: Option<i64>,
+}
+
+/// A single analysis run of a token.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema, Eq, Hash)]
+#[serde(rename = "TokenAnalysis")]
+pub struct ResponseTokenAnalysis {
+    pub analyzed_at: NaiveDateTime,
+    /// The block the analysis ran at, null if it ran at the latest block.
+    pub block_number: Option<u64>,
+    /// Either `good`, `bad` or `failed`.
+    #[schema(example = "bad")]
+    pub outcome: String,
+    /// Why the token was found bad or why the analysis failed.
+    pub reason: Option<String>,
+    /// The token quality that resulted from this analysis.
+    pub quality: u32,
+    pub gas: Option<u64>,
+    pub tax: Option<u64>,
+}
+
+impl From<models::token::TokenAnalysis> for ResponseTokenAnalysis {
+    fn from(value: models::token::TokenAnalysis) -> Self {
+        Self {
+            analyzed_at: value.analyzed_at,
+            block_number: value.block_number,
+            outcome: value.outcome.to_string(),
+            reason: value.reason,
+            quality: value.quality,
+            gas: value.gas,
+            tax: value.tax,
+        }
+    }
+}
+
+/// Response from Tycho server for a token analysis history request.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, ToSchema, Eq, Hash)]
+pub struct TokenAnalysisResponse {
+    pub chain: Chain,
+    #[schema(value_type=String, example="0xc9f2e6ea1637E499406986ac50ddC92401ce1f58")]
+    #[serde(with = "hex_bytes")]
+    pub token: Bytes,
+    pub analyses: Vec<ResponseTokenAnalysis>,
+}
+
+impl TokenAnalysisResponse {
+    pub fn new(chain: Chain, token: Bytes, analyses: Vec<ResponseTokenAnalysis>) -> Self {
+        Self { chain, token, analyses }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, ToSchema, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct ProtocolComponentsRequestBody {
@@ -1018,6 +1126,9 @@ impl ProtocolComponentRequestParameters {
 pub struct ProtocolComponentRequestResponse {
     pub protocol_components: Vec<ProtocolComponent>,
     pub pagination: PaginationResponse,
+    /// The block the returned components reflect.
+    #[serde(default)]
+    pub block: Option<ResolvedBlock>,
 }
 
 impl ProtocolComponentRequestResponse {
@@ -1025,7 +1136,7 @@ impl ProtocolComponentRequestResponse {
         protocol_components: Vec<ProtocolComponent>,
         pagination: PaginationResponse,
     ) -> Self {
-        Self { protocol_components, pagination }
+        Self { protocol_components, pagination, block: None }
     }
 }
 
@@ -1220,11 +1331,14 @@ impl<'de> Deserialize<'de> for ProtocolStateRequestBody {
 pub struct ProtocolStateRequestResponse {
     pub states: Vec<ResponseProtocolState>,
     pub pagination: PaginationResponse,
+    /// The block the returned state reflects.
+    #[serde(default)]
+    pub block: Option<ResolvedBlock>,
 }
 
 impl ProtocolStateRequestResponse {
     pub fn new(states: Vec<ResponseProtocolState>, pagination: PaginationResponse) -> Self {
-        Self { states, pagination }
+        Self { states, pagination, block: None }
     }
 }
 
@@ -1235,7 +1349,7 @@ pub struct ProtocolComponentId {
     pub id: String,
 }
 
-#[derive(Debug, Serialize, ToSchema)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
 #[serde(tag = "status", content = "message")]
 #[schema(example = json!({"status": "NotReady", "message": "No db connection"}))]
 pub enum Health {
@@ -1244,6 +1358,76 @@ pub enum Health {
     NotReady(String),
 }
 
+/// Detailed status of the service and each of its extractors.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ServiceStatus {
+    /// The overall readiness, as reported by the health endpoint.
+    pub health: Health,
+    /// Whether the database could be reached.
+    pub database: bool,
+    /// Whether the task buffering unfinalized extractor messages is running. `None` if the
+    /// service runs without extractors.
+    pub pending_deltas: Option<bool>,
+    pub extractors: Vec<ExtractorStatus>,
+}
+
+/// Lifecycle state of an extractor.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "state", content = "error")]
+pub enum ExtractorState {
+    /// The extractor has not processed any block yet.
+    Starting,
+    Running,
+    /// The extractor was paused through the admin API.
+    Paused,
+    /// The extractor stopped with the given error.
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ExtractorStatus {
+    pub chain: Chain,
+    pub name: String,
+    #[serde(flatten)]
+    pub state: ExtractorState,
+    /// Number of the last block the extractor processed.
+    pub last_block: Option<u64>,
+    /// Timestamp of the last block the extractor processed.
+    pub last_block_ts: Option<NaiveDateTime>,
+    /// When the extractor last processed a block.
+    pub last_processed_at: Option<NaiveDateTime>,
+    /// Estimated block number of the chain head.
+    pub chain_head: Option<u64>,
+    /// Number of blocks the extractor is behind the estimated chain head.
+    pub lag: Option<u64>,
+}
+
+/// An extractor as listed by the admin API.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtractorInfo {
+    pub chain: Chain,
+    pub name: String,
+    #[serde(flatten)]
+    pub state: ExtractorState,
+    /// Substreams cursor of the last processed block.
+    pub cursor: Option<String>,
+    /// Number of the last block the extractor processed.
+    pub last_block: Option<u64>,
+    /// Number of subscribers receiving the extractor's messages.
+    pub subscribers: usize,
+}
+
+/// Restarts an extractor from the given cursor or block. If neither is set, the extractor restarts
+/// after the last block committed to the database.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RestartExtractorRequest {
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub block: Option<i64>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq, ToSchema, Eq, Hash, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct ProtocolSystemsRequestBody {
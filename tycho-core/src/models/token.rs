@@ -1,6 +1,8 @@
 use std::collections::HashMap;
 
+use chrono::{NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
 
 use super::{Address, Balance};
 use crate::{models::Chain, traits::TokenOwnerFinding, Bytes};
@@ -27,6 +29,13 @@ pub struct CurrencyToken {
     ///  - 9-5: Token analysis failed on cronjob (after creation).
     ///  - 0: Failed to extract decimals onchain
     pub quality: u32,
+    /// Fallbacks that were needed to extract the token's metadata onchain. Empty for tokens
+    /// that follow the ERC-20 standard.
+    #[serde(default)]
+    pub metadata_fallbacks: Vec<MetadataFallback>,
+    /// Transfer behaviours detected by the token analysis, e.g. rebasing or fee-on-transfer.
+    #[serde(default)]
+    pub flags: Vec<TokenFlag>,
 }
 
 impl CurrencyToken {
@@ -47,6 +56,105 @@ impl CurrencyToken {
             gas: gas.to_owned(),
             chain,
             quality,
+            metadata_fallbacks: Vec::new(),
+            flags: Vec::new(),
+        }
+    }
+}
+
+/// Describes how non-standard token metadata was recovered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, EnumString, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum MetadataFallback {
+    /// `symbol()` returned `bytes32` instead of `string`.
+    Bytes32Symbol,
+    /// `symbol()` could not be read, `name()` is used as the symbol.
+    NameAsSymbol,
+    /// Neither `symbol()` nor `name()` could be read, the address is used as the symbol.
+    AddressAsSymbol,
+    /// `decimals()` could not be read, 18 decimals are assumed.
+    DefaultDecimals,
+    /// Metadata was read from the implementation behind an EIP-1967 proxy.
+    Eip1967Implementation,
+    /// Metadata was read from the implementation behind an EIP-1167 minimal proxy.
+    Eip1167Implementation,
+}
+
+/// Transfer behaviour of a token that deviates from a plain ERC-20 transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, EnumString, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum TokenFlag {
+    /// Balances change without transfers, e.g. share-based or elastic supply tokens.
+    Rebasing,
+    /// Transfers deliver less than the sent amount.
+    FeeOnTransfer,
+    /// The fee taken on transfer depends on the transferred amount.
+    AmountDependentTax,
+    /// Addresses can be blacklisted from transferring the token.
+    Blacklistable,
+    /// Transfers of the token can be paused.
+    Pausable,
+}
+
+/// Outcome of a token analysis run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, EnumString, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum TokenAnalysisOutcome {
+    /// The token passed the analysis.
+    Good,
+    /// The token failed the analysis, see the analysis reason.
+    Bad,
+    /// The analysis itself could not be executed, e.g. because of an RPC error.
+    Failed,
+}
+
+/// A single, persisted token analysis run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenAnalysis {
+    pub token: Address,
+    pub chain: Chain,
+    pub analyzed_at: NaiveDateTime,
+    /// The block the analysis ran at. `None` if it ran at the latest block.
+    pub block_number: Option<u64>,
+    pub outcome: TokenAnalysisOutcome,
+    /// Why the token was found bad or why the analysis failed.
+    pub reason: Option<String>,
+    /// The token quality that resulted from this analysis.
+    pub quality: u32,
+    pub gas: Option<TransferCost>,
+    pub tax: Option<TransferTax>,
+}
+
+impl TokenAnalysis {
+    /// Creates an analysis record timestamped with the current time from the result of
+    /// [`TokenAnalyzer::analyze`](crate::traits::TokenAnalyzer::analyze).
+    pub fn new(
+        token: &Address,
+        chain: Chain,
+        block_number: Option<u64>,
+        result: &Result<(TokenQuality, Option<TransferCost>, Option<TransferTax>), String>,
+        quality: u32,
+    ) -> Self {
+        let (outcome, reason, gas, tax) = match result {
+            Ok((TokenQuality::Good, gas, tax)) => (TokenAnalysisOutcome::Good, None, *gas, *tax),
+            Ok((TokenQuality::Bad { reason }, gas, tax)) => {
+                (TokenAnalysisOutcome::Bad, Some(reason.clone()), *gas, *tax)
+            }
+            Err(error) => (TokenAnalysisOutcome::Failed, Some(error.clone()), None, None),
+        };
+        Self {
+            token: token.clone(),
+            chain,
+            analyzed_at: Utc::now().naive_utc(),
+            block_number,
+            outcome,
+            reason,
+            quality,
+            gas,
+            tax,
         }
     }
 }
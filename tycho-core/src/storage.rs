@@ -14,13 +14,16 @@ use crate::{
             ComponentBalance, ProtocolComponent, ProtocolComponentState,
             ProtocolComponentStateDelta, QualityRange,
         },
-        token::CurrencyToken,
+        token::{CurrencyToken, TokenAnalysis},
         Address, BlockHash, Chain, ComponentId, ContractId, ExtractionState, PaginationParams,
-        ProtocolType, TxHash,
+        ProtocolType, StoreKey, StoreVal, TxHash,
     },
     Bytes,
 };
 
+#[cfg(feature = "conformance")]
+pub mod conformance;
+
 /// Identifies a block in storage.
 #[derive(Debug, Clone, PartialEq, Hash, Eq)]
 pub enum BlockIdentifier {
@@ -106,6 +109,19 @@ pub trait ChainGateway {
     /// # Returns
     /// - An Ok result containing the block. Might fail if the block does not exist yet.
     async fn get_block(&self, id: &BlockIdentifier) -> Result<Block, StorageError>;
+    /// Retrieves the most recent block at or before a timestamp.
+    ///
+    /// # Parameters
+    /// - `chain`: The chain to retrieve the block for.
+    /// - `ts`: The timestamp the block must not be newer than.
+    ///
+    /// # Returns
+    /// - An Ok result containing the block. Fails if no stored block is old enough.
+    async fn get_block_at_timestamp(
+        &self,
+        chain: &Chain,
+        ts: &NaiveDateTime,
+    ) -> Result<Block, StorageError>;
     /// Upserts a transaction to storage.
     ///
     /// Ignores any existing tx, if the new entry has different attributes
@@ -259,6 +275,19 @@ pub struct WithTotal<T> {
     pub total: Option<i64>,
 }
 
+/// Number of rows affected by removing a protocol system from storage.
+///
+/// Versioned entities count every stored version, not just the latest one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProtocolSystemRemoval {
+    pub protocol_components: i64,
+    pub protocol_states: i64,
+    pub component_balances: i64,
+    pub contracts: i64,
+    pub tokens: i64,
+    pub extraction_states: i64,
+}
+
 /// Store and retrieve protocol related structs.
 ///
 /// This trait defines how to retrieve protocol components, state as well as
@@ -283,6 +312,24 @@ pub trait ProtocolGateway {
         pagination_params: Option<&PaginationParams>,
     ) -> Result<WithTotal<Vec<ProtocolComponent>>, StorageError>;
 
+    /// Retrieve the ProtocolComponents of a system that existed at a version
+    ///
+    /// Components created after `version` are excluded, components deleted after `version` are
+    /// included.
+    ///
+    /// # Parameters
+    /// - `chain` The chain of the components
+    /// - `system` The protocol system of the components.
+    /// - `version` The version at which the components have to exist.
+    /// - `pagination_params` Optional pagination, components are returned in a stable order.
+    async fn get_protocol_components_at(
+        &self,
+        chain: &Chain,
+        system: &str,
+        version: &Version,
+        pagination_params: Option<&PaginationParams>,
+    ) -> Result<WithTotal<Vec<ProtocolComponent>>, StorageError>;
+
     /// Retrieves owners of tokens
     ///
     /// Queries for owners (protocol components) of tokens that have a certain minimum
@@ -322,6 +369,18 @@ pub trait ProtocolGateway {
         new_protocol_types: &[ProtocolType],
     ) -> Result<(), StorageError>;
 
+    /// Retrieves stored ProtocolTypes.
+    ///
+    /// # Parameters
+    /// - `names` If set, only the protocol types with these names are returned.
+    ///
+    /// # Returns
+    /// The matching protocol types, unknown names are ignored.
+    async fn get_protocol_types(
+        &self,
+        names: Option<&[&str]>,
+    ) -> Result<Vec<ProtocolType>, StorageError>;
+
     /// Retrieve protocol component states
     ///
     /// This resource is versioned, the version can be specified by either block
@@ -398,6 +457,16 @@ pub trait ProtocolGateway {
     /// insert.
     async fn add_tokens(&self, tokens: &[CurrencyToken]) -> Result<(), StorageError>;
 
+    /// Appends analyses to the analysis history of their tokens.
+    ///
+    /// Analyses are written together with the tokens added alongside them, so they can be
+    /// recorded for tokens that are inserted by the same writes. Will warn if the token of an
+    /// analysis does not exist.
+    ///
+    /// # Parameters
+    /// - `analyses` The analyses to record.
+    async fn add_token_analyses(&self, analyses: &[TokenAnalysis]) -> Result<(), StorageError>;
+
     /// Updates multiple tokens in storage.
     ///
     /// Updates token in storage. Will warn if one of the tokens does not exist in the
@@ -406,11 +475,36 @@ pub trait ProtocolGateway {
     ///
     /// # Parameters
     /// - `token` The tokens to update.
+    /// - `analyses` The analyses that determined the updated values, appended to the analysis
+    ///   history of their tokens.
     ///
     /// # Return
     /// Ok if all tokens could be inserted, Err if at least one token failed to
     /// insert.
-    async fn update_tokens(&self, tokens: &[CurrencyToken]) -> Result<(), StorageError>;
+    async fn update_tokens(
+        &self,
+        tokens: &[CurrencyToken],
+        analyses: &[TokenAnalysis],
+    ) -> Result<(), StorageError>;
+
+    /// Retrieves the analysis history of a token.
+    ///
+    /// Analyses are recorded through [`Self::add_token_analyses`] and [`Self::update_tokens`].
+    ///
+    /// # Parameters
+    /// - `chain` The chain of the token.
+    /// - `token` The address of the token.
+    /// - `limit` The maximum number of analyses to return.
+    ///
+    /// # Return
+    /// The most recent analyses, newest first. Errors with `NotFound` if the token does not
+    /// exist.
+    async fn get_token_analyses(
+        &self,
+        chain: &Chain,
+        token: &Address,
+        limit: i64,
+    ) -> Result<Vec<TokenAnalysis>, StorageError>;
 
     /// Retrieve protocol state changes
     ///
@@ -463,6 +557,20 @@ pub trait ProtocolGateway {
         tvl_values: &HashMap<String, f64>,
     ) -> Result<(), StorageError>;
 
+    /// Retrieves the current tvl of protocol components.
+    ///
+    /// # Parameters
+    /// - `chain` The chain of the components.
+    /// - `ids` If set, only the tvl of these components is returned.
+    ///
+    /// # Returns
+    /// The tvl by component id, components without a tvl are omitted.
+    async fn get_component_tvls(
+        &self,
+        chain: &Chain,
+        ids: Option<&[&str]>,
+    ) -> Result<HashMap<String, f64>, StorageError>;
+
     /// Retrieve a list of actively supported protocol systems
     ///
     /// Fetches the list of protocol systems supported by the Tycho indexing service.
@@ -478,6 +586,27 @@ pub trait ProtocolGateway {
         chain: &Chain,
         pagination_params: Option<&PaginationParams>,
     ) -> Result<WithTotal<Vec<String>>, StorageError>;
+
+    /// Removes a protocol system and all data linked to it.
+    ///
+    /// Deletes the system's components together with their states, balances and tvl, the
+    /// contracts and tokens that are exclusively used by the system's components and the
+    /// extraction state of the system's extractor. Contracts and tokens shared with other
+    /// protocol systems, as well as blocks and transactions, are kept. All deletions happen
+    /// atomically.
+    ///
+    /// # Parameters
+    /// - `system` The name of the protocol system to remove.
+    /// - `dry_run` If set, nothing is deleted and the returned counts describe what would be
+    ///   removed.
+    ///
+    /// # Return
+    /// The number of removed rows per entity.
+    async fn remove_protocol_system(
+        &self,
+        system: &str,
+        dry_run: bool,
+    ) -> Result<ProtocolSystemRemoval, StorageError>;
 }
 
 /// Manage contracts and their state in storage.
@@ -530,6 +659,29 @@ pub trait ContractStateGateway {
         pagination_params: Option<&PaginationParams>,
     ) -> Result<WithTotal<Vec<Account>>, StorageError>;
 
+    /// Get a random sample of a contract's storage slots.
+    ///
+    /// The sample is drawn by the storage backend, so callers that only need a few slots, e.g. to
+    /// spot check them against a node, don't have to load the full contract storage.
+    ///
+    /// # Parameters
+    /// - `chain`: The blockchain where the contract resides.
+    /// - `address`: The address of the contract.
+    /// - `version`: Version at which to retrieve the slots. If set to `None`, it retrieves the
+    ///   latest state.
+    /// - `limit`: The maximum number of slots to return.
+    ///
+    /// # Returns
+    /// At most `limit` slots of the contract with their values. Deleted slots are returned with an
+    /// empty value.
+    async fn sample_contract_slots(
+        &self,
+        chain: &Chain,
+        address: &Address,
+        version: Option<&Version>,
+        limit: usize,
+    ) -> Result<HashMap<StoreKey, StoreVal>, StorageError>;
+
     /// Inserts a new contract into the database.
     ///
     /// If it the creation transaction is known, the contract will have slots, balance and code
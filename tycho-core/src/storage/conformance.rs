@@ -0,0 +1,1038 @@
+//! # Gateway conformance suite
+//!
+//! Backend agnostic test cases for implementations of the [`Gateway`] trait. Each case writes a
+//! small, self-contained fixture through the gateway traits and then asserts on the read side, so
+//! any backend can be checked against the semantics of the reference postgres implementation:
+//! versioning by `valid_from`/`valid_to` and transaction index, `VersionKind::Last`, forward and
+//! backward deltas and reverts.
+//!
+//! Every case expects an empty storage that knows about [`CHAIN`] and [`PROTOCOL_SYSTEM`], with
+//! the native token of the chain already present. Backends that buffer writes can hook into
+//! [`ConformanceBackend`] to flush them at the end of each block.
+//!
+//! The [`conformance_tests`](crate::conformance_tests) macro generates one test per case:
+//!
+//! ```ignore
+//! async fn run<F, Fut>(case: F)
+//! where
+//!     F: FnOnce(MyGateway) -> Fut,
+//!     Fut: std::future::Future<Output = ()>,
+//! {
+//!     case(MyGateway::new()).await
+//! }
+//!
+//! tycho_core::conformance_tests!(run);
+//! ```
+use std::collections::{HashMap, HashSet};
+
+use async_trait::async_trait;
+use chrono::DateTime;
+
+use super::{
+    BlockIdentifier, BlockOrTimestamp, Gateway, ProtocolSystemRemoval, StorageError, Version,
+};
+use crate::{
+    models::{
+        blockchain::{Block, Transaction},
+        contract::{Account, AccountDelta},
+        protocol::{
+            ComponentBalance, ProtocolComponent, ProtocolComponentStateDelta, QualityRange,
+        },
+        token::{CurrencyToken, TokenAnalysis, TokenAnalysisOutcome},
+        Address, Chain, ChangeType, ContractId, ExtractionState, FinancialType, ImplementationType,
+        PaginationParams, ProtocolType, TxHash,
+    },
+    Bytes,
+};
+
+/// The chain all fixtures are written to.
+pub const CHAIN: Chain = Chain::Ethereum;
+
+/// The protocol system all fixture components belong to.
+pub const PROTOCOL_SYSTEM: &str = "conformance";
+
+const PROTOCOL_TYPE: &str = "conformance_pool";
+
+/// Hooks for backends that do not apply writes immediately.
+#[async_trait]
+pub trait ConformanceBackend: Gateway {
+    /// Called before any writes belonging to `block` are issued.
+    async fn start_block(&self, _block: &Block) {}
+
+    /// Called once all writes of the current block were issued. Afterwards, all writes must be
+    /// visible to reads.
+    async fn commit_block(&self) {}
+}
+
+/// Generates a `#[tokio::test]` for each conformance case.
+///
+/// `$runner` must be an async function that creates a fresh gateway, passes it to the case and
+/// cleans up afterwards if necessary.
+#[macro_export]
+macro_rules! conformance_tests {
+    ($runner:path) => {
+        $crate::conformance_tests!(
+            $runner;
+            blocks_and_transactions,
+            extraction_state,
+            contract_versioning,
+            contract_pagination,
+            contract_slot_sampling,
+            contract_deltas,
+            contract_deletion,
+            protocol_components,
+            protocol_components_at_version,
+            protocol_state_versioning,
+            protocol_state_deltas,
+            component_balances,
+            tokens,
+            token_analysis_history,
+            revert,
+            protocol_system_removal,
+        );
+    };
+    ($runner:path; $($case:ident),+ $(,)?) => {
+        $(
+            #[tokio::test]
+            async fn $case() {
+                $runner(|gw| async move {
+                    $crate::storage::conformance::$case(&gw).await
+                })
+                .await;
+            }
+        )+
+    };
+}
+
+/// Block `number` of the fixture chain, blocks are 12 seconds apart.
+pub fn block(number: u64) -> Block {
+    Block::new(
+        number,
+        CHAIN,
+        Bytes::from(number).lpad(32, 0),
+        Bytes::from(number - 1).lpad(32, 0),
+        DateTime::from_timestamp(1_700_000_000 + number as i64 * 12, 0)
+            .map(|d| d.naive_utc())
+            .unwrap(),
+    )
+}
+
+/// Hash of the transaction at `index` within block `number`.
+pub fn tx_hash(number: u64, index: u64) -> TxHash {
+    Bytes::from(number * 1_000 + index).lpad(32, 0)
+}
+
+fn transaction(number: u64, index: u64) -> Transaction {
+    Transaction::new(
+        tx_hash(number, index),
+        block(number).hash,
+        Bytes::from("0x0000000000000000000000000000000000000001"),
+        Some(Bytes::from("0x0000000000000000000000000000000000000002")),
+        index,
+    )
+}
+
+fn address(id: u64) -> Address {
+    Bytes::from(id).lpad(20, 0)
+}
+
+fn slot(value: u64) -> Bytes {
+    Bytes::from(value).lpad(32, 0)
+}
+
+fn slots(data: &[(u64, u64)]) -> HashMap<Bytes, Bytes> {
+    data.iter()
+        .map(|(k, v)| (slot(*k), slot(*v)))
+        .collect()
+}
+
+fn at_block(number: u64) -> BlockOrTimestamp {
+    BlockOrTimestamp::Block(BlockIdentifier::Number((CHAIN, number as i64)))
+}
+
+fn version(number: u64) -> Version {
+    Version::from_block_number(CHAIN, number as i64)
+}
+
+/// Writes blocks `1..=n`, each with `txs_per_block` transactions.
+async fn insert_blocks<G: ConformanceBackend>(gw: &G, n: u64, txs_per_block: u64) {
+    for number in 1..=n {
+        let block = block(number);
+        gw.start_block(&block).await;
+        gw.upsert_block(&[block])
+            .await
+            .expect("block inserted");
+        let txs: Vec<_> = (0..txs_per_block)
+            .map(|index| transaction(number, index))
+            .collect();
+        gw.upsert_tx(&txs)
+            .await
+            .expect("transactions inserted");
+        gw.commit_block().await;
+    }
+}
+
+fn contract(id: u64, creation_block: u64, balance: u64, slots: HashMap<Bytes, Bytes>) -> Account {
+    let tx = tx_hash(creation_block, 0);
+    Account::new(
+        CHAIN,
+        address(id),
+        format!("contract_{id}"),
+        slots,
+        Bytes::from(balance).lpad(32, 0),
+        HashMap::new(),
+        Bytes::from("0x6080"),
+        Bytes::from(crate::keccak256(Bytes::from("0x6080"))),
+        tx.clone(),
+        tx.clone(),
+        Some(tx),
+    )
+}
+
+fn slot_update(id: u64, data: &[(u64, u64)], balance: Option<u64>) -> AccountDelta {
+    AccountDelta::new(
+        CHAIN,
+        address(id),
+        data.iter()
+            .map(|(k, v)| (slot(*k), Some(slot(*v))))
+            .collect(),
+        balance.map(|b| Bytes::from(b).lpad(32, 0)),
+        None,
+        ChangeType::Update,
+    )
+}
+
+/// Inserts the protocol type, two tokens and a component created in block 1.
+async fn insert_component_fixture<G: ConformanceBackend>(gw: &G) -> Vec<Address> {
+    let tokens = vec![address(0x100), address(0x200)];
+    gw.start_block(&block(1)).await;
+    gw.add_protocol_types(&[ProtocolType::new(
+        PROTOCOL_TYPE.to_string(),
+        FinancialType::Swap,
+        None,
+        ImplementationType::Custom,
+    )])
+    .await
+    .expect("protocol type inserted");
+    gw.add_tokens(&[
+        CurrencyToken::new(&tokens[0], "TKA", 18, 0, &[], CHAIN, 100),
+        CurrencyToken::new(&tokens[1], "TKB", 6, 0, &[], CHAIN, 10),
+    ])
+    .await
+    .expect("tokens inserted");
+    gw.add_protocol_components(&[component("pc_0", 1, &tokens)])
+        .await
+        .expect("component inserted");
+    gw.commit_block().await;
+    tokens
+}
+
+fn component(id: &str, creation_block: u64, tokens: &[Address]) -> ProtocolComponent {
+    ProtocolComponent::new(
+        id,
+        PROTOCOL_SYSTEM,
+        PROTOCOL_TYPE,
+        CHAIN,
+        tokens.to_vec(),
+        vec![],
+        HashMap::new(),
+        ChangeType::Creation,
+        tx_hash(creation_block, 0),
+        block(creation_block).ts,
+    )
+}
+
+fn state_delta(id: &str, updated: &[(&str, u64)], deleted: &[&str]) -> ProtocolComponentStateDelta {
+    ProtocolComponentStateDelta::new(
+        id,
+        updated
+            .iter()
+            .map(|(attr, value)| (attr.to_string(), Bytes::from(*value)))
+            .collect(),
+        deleted
+            .iter()
+            .map(|attr| attr.to_string())
+            .collect(),
+    )
+}
+
+fn balance(component_id: &str, token: &Address, value: u64, number: u64) -> ComponentBalance {
+    ComponentBalance::new(
+        token.clone(),
+        Bytes::from(value),
+        value as f64,
+        tx_hash(number, 0),
+        component_id,
+    )
+}
+
+fn sorted<T: Ord>(mut items: Vec<T>) -> Vec<T> {
+    items.sort();
+    items
+}
+
+/// Blocks and transactions can be retrieved by number, hash, timestamp and as latest block.
+pub async fn blocks_and_transactions<G: ConformanceBackend>(gw: &G) {
+    insert_blocks(gw, 3, 2).await;
+
+    let by_number = gw
+        .get_block(&BlockIdentifier::Number((CHAIN, 2)))
+        .await
+        .expect("block by number");
+    let by_hash = gw
+        .get_block(&BlockIdentifier::Hash(block(2).hash))
+        .await
+        .expect("block by hash");
+    let latest = gw
+        .get_block(&BlockIdentifier::Latest(CHAIN))
+        .await
+        .expect("latest block");
+    let tx = gw
+        .get_tx(&tx_hash(2, 1))
+        .await
+        .expect("transaction");
+    let missing = gw
+        .get_block(&BlockIdentifier::Number((CHAIN, 4)))
+        .await;
+    let at_timestamp = gw
+        .get_block_at_timestamp(&CHAIN, &(block(2).ts + chrono::Duration::seconds(1)))
+        .await
+        .expect("block at timestamp");
+    let exactly_at_timestamp = gw
+        .get_block_at_timestamp(&CHAIN, &block(3).ts)
+        .await
+        .expect("block exactly at timestamp");
+    let before_first = gw
+        .get_block_at_timestamp(&CHAIN, &(block(1).ts - chrono::Duration::seconds(1)))
+        .await;
+
+    assert_eq!(by_number, block(2));
+    assert_eq!(by_hash, block(2));
+    assert_eq!(latest, block(3));
+    assert_eq!(tx, transaction(2, 1));
+    assert!(matches!(missing, Err(StorageError::NotFound(..))));
+    assert_eq!(at_timestamp, block(2));
+    assert_eq!(exactly_at_timestamp, block(3));
+    assert!(matches!(before_first, Err(StorageError::NotFound(..))));
+}
+
+/// Extraction states are upserted and retrieved by name and chain.
+pub async fn extraction_state<G: ConformanceBackend>(gw: &G) {
+    insert_blocks(gw, 2, 1).await;
+    let first = ExtractionState::new(
+        "extractor".to_string(),
+        CHAIN,
+        Some(serde_json::json!({"foo": "bar"})),
+        b"cursor1",
+        block(1).hash,
+    );
+    let second =
+        ExtractionState { cursor: b"cursor2".to_vec(), block_hash: block(2).hash, ..first.clone() };
+
+    gw.start_block(&block(1)).await;
+    gw.save_state(&first)
+        .await
+        .expect("state saved");
+    gw.commit_block().await;
+    let saved = gw
+        .get_state("extractor", &CHAIN)
+        .await
+        .expect("state retrieved");
+    gw.start_block(&block(2)).await;
+    gw.save_state(&second)
+        .await
+        .expect("state updated");
+    gw.commit_block().await;
+    let updated = gw
+        .get_state("extractor", &CHAIN)
+        .await
+        .expect("state retrieved");
+    let missing = gw.get_state("unknown", &CHAIN).await;
+
+    assert_eq!(saved, first);
+    assert_eq!(updated, second);
+    assert!(matches!(missing, Err(StorageError::NotFound(..))));
+}
+
+/// Writes the contract fixture used by the contract cases:
+///
+/// * block 1: contract 1 is created with slot 1 = 1 and balance 100
+/// * block 2: slot 1 = 2, slot 2 = 5 and balance 200
+/// * block 3: slot 1 = 3
+async fn insert_contract_fixture<G: ConformanceBackend>(gw: &G) {
+    insert_blocks(gw, 3, 1).await;
+    gw.start_block(&block(1)).await;
+    gw.upsert_contract(&contract(1, 1, 100, slots(&[(1, 1)])))
+        .await
+        .expect("contract inserted");
+    gw.commit_block().await;
+    gw.start_block(&block(2)).await;
+    gw.update_contracts(&[(tx_hash(2, 0), slot_update(1, &[(1, 2), (2, 5)], Some(200)))])
+        .await
+        .expect("contract updated");
+    gw.commit_block().await;
+    gw.start_block(&block(3)).await;
+    gw.update_contracts(&[(tx_hash(3, 0), slot_update(1, &[(1, 3)], None))])
+        .await
+        .expect("contract updated");
+    gw.commit_block().await;
+}
+
+/// Contract storage and balances are versioned, retrieval respects the requested version.
+pub async fn contract_versioning<G: ConformanceBackend>(gw: &G) {
+    insert_contract_fixture(gw).await;
+    let id = ContractId::new(CHAIN, address(1));
+
+    let at_1 = gw
+        .get_contract(&id, Some(&version(1)), true)
+        .await
+        .expect("contract at block 1");
+    let at_2 = gw
+        .get_contract(&id, Some(&version(2)), true)
+        .await
+        .expect("contract at block 2");
+    let latest = gw
+        .get_contract(&id, None, true)
+        .await
+        .expect("latest contract");
+    let without_slots = gw
+        .get_contract(&id, None, false)
+        .await
+        .expect("latest contract");
+    let missing = gw
+        .get_contract(&ContractId::new(CHAIN, address(2)), None, true)
+        .await;
+
+    assert_eq!(at_1.slots, slots(&[(1, 1)]));
+    assert_eq!(at_1.native_balance, Bytes::from(100u64).lpad(32, 0));
+    assert_eq!(at_2.slots, slots(&[(1, 2), (2, 5)]));
+    assert_eq!(at_2.native_balance, Bytes::from(200u64).lpad(32, 0));
+    assert_eq!(latest.slots, slots(&[(1, 3), (2, 5)]));
+    assert_eq!(latest.native_balance, Bytes::from(200u64).lpad(32, 0));
+    assert_eq!(latest.code, Bytes::from("0x6080"));
+    assert!(without_slots.slots.is_empty());
+    assert!(matches!(missing, Err(StorageError::NotFound(..))));
+}
+
+/// Contracts are filtered by creation version and paginated.
+pub async fn contract_pagination<G: ConformanceBackend>(gw: &G) {
+    insert_blocks(gw, 2, 1).await;
+    gw.start_block(&block(1)).await;
+    gw.upsert_contract(&contract(1, 1, 100, slots(&[(1, 1)])))
+        .await
+        .expect("contract inserted");
+    gw.commit_block().await;
+    gw.start_block(&block(2)).await;
+    gw.upsert_contract(&contract(2, 2, 100, slots(&[(1, 1)])))
+        .await
+        .expect("contract inserted");
+    gw.commit_block().await;
+
+    let at_1 = gw
+        .get_contracts(&CHAIN, None, Some(&version(1)), false, None)
+        .await
+        .expect("contracts at block 1");
+    let page_0 = gw
+        .get_contracts(&CHAIN, None, None, false, Some(&PaginationParams::new(0, 1)))
+        .await
+        .expect("first page");
+    let page_1 = gw
+        .get_contracts(&CHAIN, None, None, false, Some(&PaginationParams::new(1, 1)))
+        .await
+        .expect("second page");
+    let filtered = gw
+        .get_contracts(&CHAIN, Some(&[address(2)]), None, false, None)
+        .await
+        .expect("filtered contracts");
+
+    let addresses = |accounts: &[Account]| {
+        accounts
+            .iter()
+            .map(|a| a.address.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(addresses(&at_1.entity), vec![address(1)]);
+    assert_eq!(page_0.total, Some(2));
+    assert_eq!(page_1.total, Some(2));
+    assert_eq!(
+        sorted([addresses(&page_0.entity), addresses(&page_1.entity)].concat()),
+        vec![address(1), address(2)]
+    );
+    assert_eq!(addresses(&filtered.entity), vec![address(2)]);
+}
+
+/// Sampled slots are a subset of the contract storage at the requested version.
+pub async fn contract_slot_sampling<G: ConformanceBackend>(gw: &G) {
+    insert_contract_fixture(gw).await;
+
+    let at_2 = gw
+        .sample_contract_slots(&CHAIN, &address(1), Some(&version(2)), 10)
+        .await
+        .expect("slots at block 2");
+    let latest = gw
+        .sample_contract_slots(&CHAIN, &address(1), None, 10)
+        .await
+        .expect("latest slots");
+    let sample = gw
+        .sample_contract_slots(&CHAIN, &address(1), None, 1)
+        .await
+        .expect("sampled slot");
+    let missing = gw
+        .sample_contract_slots(&CHAIN, &address(2), None, 10)
+        .await
+        .expect("slots of unknown contract");
+
+    assert_eq!(at_2, slots(&[(1, 2), (2, 5)]));
+    assert_eq!(latest, slots(&[(1, 3), (2, 5)]));
+    assert_eq!(sample.len(), 1);
+    assert!(sample
+        .iter()
+        .all(|(k, v)| latest.get(k) == Some(v)));
+    assert!(missing.is_empty());
+}
+
+/// Account deltas contain the latest changes going forward and previous values going backward.
+pub async fn contract_deltas<G: ConformanceBackend>(gw: &G) {
+    insert_contract_fixture(gw).await;
+
+    let forward = gw
+        .get_accounts_delta(&CHAIN, Some(&at_block(1)), &at_block(3))
+        .await
+        .expect("forward delta");
+    let backward = gw
+        .get_accounts_delta(&CHAIN, Some(&at_block(3)), &at_block(1))
+        .await
+        .expect("backward delta");
+
+    assert_eq!(forward.len(), 1);
+    assert_eq!(
+        forward[0].slots,
+        HashMap::from([(slot(1), Some(slot(3))), (slot(2), Some(slot(5)))])
+    );
+    assert_eq!(forward[0].balance, Some(Bytes::from(200u64).lpad(32, 0)));
+    assert_eq!(backward.len(), 1);
+    assert_eq!(backward[0].slots, HashMap::from([(slot(1), Some(slot(1))), (slot(2), None)]));
+    assert_eq!(backward[0].balance, Some(Bytes::from(100u64).lpad(32, 0)));
+}
+
+/// Deleted contracts can still be retrieved at versions before their deletion.
+pub async fn contract_deletion<G: ConformanceBackend>(gw: &G) {
+    insert_contract_fixture(gw).await;
+    let id = ContractId::new(CHAIN, address(1));
+
+    gw.start_block(&block(3)).await;
+    gw.delete_contract(&id, &tx_hash(3, 0))
+        .await
+        .expect("contract deleted");
+    gw.commit_block().await;
+
+    let at_2 = gw
+        .get_contract(&id, Some(&version(2)), true)
+        .await
+        .expect("contract at block 2");
+    let latest = gw.get_contract(&id, None, true).await;
+
+    assert_eq!(at_2.slots, slots(&[(1, 2), (2, 5)]));
+    assert!(matches!(latest, Err(StorageError::NotFound(..))));
+}
+
+/// Protocol components are filtered by system, ids and tvl and paginated in id order, tvl and
+/// protocol types can be read back.
+pub async fn protocol_components<G: ConformanceBackend>(gw: &G) {
+    insert_blocks(gw, 2, 1).await;
+    let tokens = insert_component_fixture(gw).await;
+    gw.start_block(&block(2)).await;
+    gw.add_protocol_components(&[component("pc_1", 2, &tokens), component("pc_2", 2, &tokens)])
+        .await
+        .expect("components inserted");
+    gw.upsert_component_tvl(
+        &CHAIN,
+        &HashMap::from([("pc_0".to_string(), 10.0), ("pc_1".to_string(), 100.0)]),
+    )
+    .await
+    .expect("tvl upserted");
+    gw.commit_block().await;
+
+    let all = gw
+        .get_protocol_components(&CHAIN, Some(PROTOCOL_SYSTEM.to_string()), None, None, None)
+        .await
+        .expect("all components");
+    let page = gw
+        .get_protocol_components(&CHAIN, None, None, None, Some(&PaginationParams::new(1, 2)))
+        .await
+        .expect("second page");
+    let by_id = gw
+        .get_protocol_components(&CHAIN, None, Some(&["pc_2"]), None, None)
+        .await
+        .expect("component by id");
+    let by_tvl = gw
+        .get_protocol_components(&CHAIN, None, None, Some(50.0), None)
+        .await
+        .expect("components by tvl");
+    let tvls = gw
+        .get_component_tvls(&CHAIN, Some(&["pc_1", "pc_2"]))
+        .await
+        .expect("component tvls");
+    let protocol_types = gw
+        .get_protocol_types(Some(&[PROTOCOL_TYPE, "unknown"]))
+        .await
+        .expect("protocol types");
+    let other_system = gw
+        .get_protocol_components(&CHAIN, Some("unknown".to_string()), None, None, None)
+        .await
+        .expect("components of other system");
+    let missing_type = gw
+        .add_protocol_components(&[ProtocolComponent {
+            protocol_type_name: "unknown".to_string(),
+            ..component("pc_3", 2, &tokens)
+        }])
+        .await;
+
+    let ids = |components: &[ProtocolComponent]| {
+        components
+            .iter()
+            .map(|c| c.id.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(ids(&all.entity), vec!["pc_0", "pc_1", "pc_2"]);
+    assert_eq!(all.total, Some(3));
+    assert_eq!(ids(&page.entity), vec!["pc_2"]);
+    assert_eq!(page.total, Some(3));
+    assert_eq!(ids(&by_id.entity), vec!["pc_2"]);
+    assert_eq!(by_id.entity[0].tokens, tokens);
+    assert_eq!(ids(&by_tvl.entity), vec!["pc_1"]);
+    assert_eq!(tvls, HashMap::from([("pc_1".to_string(), 100.0)]));
+    assert_eq!(
+        protocol_types
+            .iter()
+            .map(|t| t.name.as_str())
+            .collect::<Vec<_>>(),
+        vec![PROTOCOL_TYPE]
+    );
+    assert!(other_system.entity.is_empty());
+    assert!(matches!(missing_type, Err(StorageError::NotFound(..))));
+}
+
+/// Components are returned if they existed at the requested version, even if deleted later.
+pub async fn protocol_components_at_version<G: ConformanceBackend>(gw: &G) {
+    insert_blocks(gw, 3, 1).await;
+    let tokens = insert_component_fixture(gw).await;
+    gw.start_block(&block(2)).await;
+    gw.add_protocol_components(&[component("pc_1", 2, &tokens), component("pc_2", 2, &tokens)])
+        .await
+        .expect("components inserted");
+    gw.commit_block().await;
+    gw.start_block(&block(3)).await;
+    gw.delete_protocol_components(&[component("pc_0", 1, &tokens)], block(3).ts)
+        .await
+        .expect("component deleted");
+    gw.commit_block().await;
+
+    let at_1 = gw
+        .get_protocol_components_at(&CHAIN, PROTOCOL_SYSTEM, &version(1), None)
+        .await
+        .expect("components at block 1");
+    let at_2 = gw
+        .get_protocol_components_at(&CHAIN, PROTOCOL_SYSTEM, &version(2), None)
+        .await
+        .expect("components at block 2");
+    let page = gw
+        .get_protocol_components_at(
+            &CHAIN,
+            PROTOCOL_SYSTEM,
+            &version(3),
+            Some(&PaginationParams::new(1, 1)),
+        )
+        .await
+        .expect("second page at block 3");
+
+    let ids = |components: &[ProtocolComponent]| {
+        components
+            .iter()
+            .map(|c| c.id.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(ids(&at_1.entity), vec!["pc_0"]);
+    assert_eq!(ids(&at_2.entity), vec!["pc_0", "pc_1", "pc_2"]);
+    assert_eq!(at_2.total, Some(3));
+    assert_eq!(ids(&page.entity), vec!["pc_2"]);
+    assert_eq!(page.total, Some(2));
+}
+
+/// Writes the protocol state fixture used by the protocol state cases:
+///
+/// * block 1: reserve = 1, fee = 5
+/// * block 2: reserve = 2, tick = 3, fee is deleted
+/// * block 3: reserve = 4 by tx 1 then reserve = 5 by tx 2, passed in reverse order
+async fn insert_state_fixture<G: ConformanceBackend>(gw: &G) {
+    insert_blocks(gw, 3, 3).await;
+    insert_component_fixture(gw).await;
+    gw.start_block(&block(1)).await;
+    gw.update_protocol_states(&[(
+        tx_hash(1, 0),
+        state_delta("pc_0", &[("reserve", 1), ("fee", 5)], &[]),
+    )])
+    .await
+    .expect("state inserted");
+    gw.commit_block().await;
+    gw.start_block(&block(2)).await;
+    gw.update_protocol_states(&[(
+        tx_hash(2, 0),
+        state_delta("pc_0", &[("reserve", 2), ("tick", 3)], &["fee"]),
+    )])
+    .await
+    .expect("state updated");
+    gw.commit_block().await;
+    gw.start_block(&block(3)).await;
+    gw.update_protocol_states(&[
+        (tx_hash(3, 2), state_delta("pc_0", &[("reserve", 5)], &[])),
+        (tx_hash(3, 1), state_delta("pc_0", &[("reserve", 4)], &[])),
+    ])
+    .await
+    .expect("state updated");
+    gw.commit_block().await;
+}
+
+/// Protocol states are versioned, deletions end an attribute's validity and writes within a block
+/// are ordered by transaction index.
+pub async fn protocol_state_versioning<G: ConformanceBackend>(gw: &G) {
+    insert_state_fixture(gw).await;
+
+    let states_at = |number: Option<u64>| async move {
+        let mut states = gw
+            .get_protocol_states(&CHAIN, number.map(version), None, Some(&["pc_0"]), false, None)
+            .await
+            .expect("protocol states")
+            .entity;
+        assert_eq!(states.len(), 1);
+        states.remove(0).attributes
+    };
+    let attributes = |data: &[(&str, u64)]| {
+        data.iter()
+            .map(|(attr, value)| (attr.to_string(), Bytes::from(*value)))
+            .collect::<HashMap<_, _>>()
+    };
+
+    assert_eq!(states_at(Some(1)).await, attributes(&[("reserve", 1), ("fee", 5)]));
+    assert_eq!(states_at(Some(2)).await, attributes(&[("reserve", 2), ("tick", 3)]));
+    assert_eq!(states_at(Some(3)).await, attributes(&[("reserve", 5), ("tick", 3)]));
+    assert_eq!(states_at(None).await, attributes(&[("reserve", 5), ("tick", 3)]));
+}
+
+/// State deltas contain the latest values going forward and previous values going backward.
+pub async fn protocol_state_deltas<G: ConformanceBackend>(gw: &G) {
+    insert_state_fixture(gw).await;
+
+    let forward = gw
+        .get_protocol_states_delta(&CHAIN, Some(&at_block(1)), &at_block(3))
+        .await
+        .expect("forward delta");
+    let backward = gw
+        .get_protocol_states_delta(&CHAIN, Some(&at_block(3)), &at_block(1))
+        .await
+        .expect("backward delta");
+
+    assert_eq!(forward, vec![state_delta("pc_0", &[("reserve", 5), ("tick", 3)], &["fee"])]);
+    assert_eq!(backward, vec![state_delta("pc_0", &[("reserve", 1), ("fee", 5)], &["tick"])]);
+}
+
+/// Component balances are versioned and balance deltas work in both directions.
+pub async fn component_balances<G: ConformanceBackend>(gw: &G) {
+    insert_blocks(gw, 3, 1).await;
+    let tokens = insert_component_fixture(gw).await;
+    for (number, values) in [(1, [10, 20]), (2, [11, 20]), (3, [12, 0])] {
+        gw.start_block(&block(number)).await;
+        gw.add_component_balances(&[
+            balance("pc_0", &tokens[0], values[0], number),
+            balance("pc_0", &tokens[1], values[1], number),
+        ])
+        .await
+        .expect("balances inserted");
+        gw.commit_block().await;
+    }
+
+    let at_2 = gw
+        .get_component_balances(&CHAIN, None, Some(&version(2)))
+        .await
+        .expect("balances at block 2");
+    let forward = gw
+        .get_balance_deltas(&CHAIN, Some(&at_block(1)), &at_block(3))
+        .await
+        .expect("forward balance delta");
+    let backward = gw
+        .get_balance_deltas(&CHAIN, Some(&at_block(3)), &at_block(2))
+        .await
+        .expect("backward balance delta");
+
+    let values = |balances: &[ComponentBalance]| {
+        sorted(
+            balances
+                .iter()
+                .map(|b| (b.component_id.clone(), b.token.clone(), b.balance.clone()))
+                .collect(),
+        )
+    };
+    let pc_0 = &at_2["pc_0"];
+    assert_eq!(pc_0[&tokens[0]].balance, Bytes::from(11u64));
+    assert_eq!(pc_0[&tokens[1]].balance, Bytes::from(20u64));
+    assert_eq!(
+        values(&forward),
+        vec![
+            ("pc_0".to_string(), tokens[0].clone(), Bytes::from(12u64)),
+            ("pc_0".to_string(), tokens[1].clone(), Bytes::from(0u64)),
+        ]
+    );
+    assert_eq!(
+        values(&backward),
+        vec![
+            ("pc_0".to_string(), tokens[0].clone(), Bytes::from(10u64)),
+            ("pc_0".to_string(), tokens[1].clone(), Bytes::from(20u64)),
+        ]
+    );
+}
+
+/// Tokens are filtered by address and quality, updated in place and paginated.
+pub async fn tokens<G: ConformanceBackend>(gw: &G) {
+    insert_blocks(gw, 1, 1).await;
+    let tokens = insert_component_fixture(gw).await;
+    let native = CHAIN.native_token().address;
+
+    gw.start_block(&block(1)).await;
+    gw.update_tokens(&[CurrencyToken::new(&tokens[1], "TKB", 6, 0, &[], CHAIN, 50)], &[])
+        .await
+        .expect("token updated");
+    gw.commit_block().await;
+
+    let all = gw
+        .get_tokens(CHAIN, None, QualityRange::None(), None, None)
+        .await
+        .expect("all tokens");
+    let by_address = gw
+        .get_tokens(CHAIN, Some(&[&tokens[1]]), QualityRange::None(), None, None)
+        .await
+        .expect("token by address");
+    let low_quality = gw
+        .get_tokens(CHAIN, None, QualityRange::new(0, 60), None, None)
+        .await
+        .expect("low quality tokens");
+    let page = gw
+        .get_tokens(CHAIN, None, QualityRange::None(), None, Some(&PaginationParams::new(0, 2)))
+        .await
+        .expect("first page");
+
+    let addresses = |tokens: &[CurrencyToken]| {
+        sorted(
+            tokens
+                .iter()
+                .map(|t| t.address.clone())
+                .collect(),
+        )
+    };
+    assert_eq!(addresses(&all.entity), sorted(vec![native, tokens[0].clone(), tokens[1].clone()]));
+    assert_eq!(all.total, Some(3));
+    assert_eq!(by_address.entity[0].quality, 50);
+    assert_eq!(addresses(&low_quality.entity), vec![tokens[1].clone()]);
+    assert_eq!(page.entity.len(), 2);
+    assert_eq!(page.total, Some(3));
+}
+
+/// Analyses recorded for inserted or updated tokens are returned newest first.
+pub async fn token_analysis_history<G: ConformanceBackend>(gw: &G) {
+    insert_blocks(gw, 1, 1).await;
+    let token = address(0x300);
+    let analysis = |seconds: i64, outcome: TokenAnalysisOutcome, quality: u32| TokenAnalysis {
+        token: token.clone(),
+        chain: CHAIN,
+        analyzed_at: block(1).ts + chrono::Duration::seconds(seconds),
+        block_number: Some(1),
+        outcome,
+        reason: (outcome != TokenAnalysisOutcome::Good).then(|| "no owner".to_string()),
+        quality,
+        gas: None,
+        tax: Some(0),
+    };
+    let first = analysis(0, TokenAnalysisOutcome::Bad, 10);
+    let second = analysis(60, TokenAnalysisOutcome::Good, 100);
+
+    let new_token = CurrencyToken::new(&token, "TKC", 18, 0, &[], CHAIN, 10);
+    gw.start_block(&block(1)).await;
+    gw.add_tokens(&[new_token])
+        .await
+        .expect("token inserted");
+    gw.add_token_analyses(std::slice::from_ref(&first))
+        .await
+        .expect("analysis inserted");
+    gw.commit_block().await;
+    let updated_token = CurrencyToken::new(&token, "TKC", 18, 0, &[], CHAIN, 100);
+    gw.update_tokens(&[updated_token], std::slice::from_ref(&second))
+        .await
+        .expect("token updated");
+
+    let history = gw
+        .get_token_analyses(&CHAIN, &token, 10)
+        .await
+        .expect("analysis history");
+    let latest = gw
+        .get_token_analyses(&CHAIN, &token, 1)
+        .await
+        .expect("latest analysis");
+    let stored = gw
+        .get_tokens(CHAIN, Some(&[&token]), QualityRange::None(), None, None)
+        .await
+        .expect("token");
+    let unknown = gw
+        .get_token_analyses(&CHAIN, &address(0x999), 10)
+        .await;
+
+    assert_eq!(history, vec![second.clone(), first]);
+    assert_eq!(latest, vec![second]);
+    assert_eq!(stored.entity[0].quality, 100);
+    assert!(matches!(unknown, Err(StorageError::NotFound(..))));
+}
+
+/// Reverting removes later blocks, transactions and any entity created or modified by them.
+pub async fn revert<G: ConformanceBackend>(gw: &G) {
+    insert_state_fixture(gw).await;
+    gw.start_block(&block(3)).await;
+    gw.add_protocol_components(&[component("pc_3", 3, &[])])
+        .await
+        .expect("component inserted");
+    gw.upsert_contract(&contract(1, 3, 100, slots(&[(1, 1)])))
+        .await
+        .expect("contract inserted");
+    gw.commit_block().await;
+
+    gw.revert_state(&BlockIdentifier::Number((CHAIN, 2)))
+        .await
+        .expect("state reverted");
+
+    let latest = gw
+        .get_block(&BlockIdentifier::Latest(CHAIN))
+        .await
+        .expect("latest block");
+    let reverted_tx = gw.get_tx(&tx_hash(3, 0)).await;
+    let components = gw
+        .get_protocol_components(&CHAIN, None, None, None, None)
+        .await
+        .expect("components");
+    let states = gw
+        .get_protocol_states(&CHAIN, None, None, Some(&["pc_0"]), false, None)
+        .await
+        .expect("protocol states");
+    let contract = gw
+        .get_contract(&ContractId::new(CHAIN, address(1)), None, true)
+        .await;
+
+    assert_eq!(latest, block(2));
+    assert!(matches!(reverted_tx, Err(StorageError::NotFound(..))));
+    assert_eq!(
+        components
+            .entity
+            .iter()
+            .map(|c| c.id.as_str())
+            .collect::<HashSet<_>>(),
+        HashSet::from(["pc_0"])
+    );
+    assert_eq!(
+        states.entity[0].attributes,
+        HashMap::from([
+            ("reserve".to_string(), Bytes::from(2u64)),
+            ("tick".to_string(), Bytes::from(3u64))
+        ])
+    );
+    assert!(matches!(contract, Err(StorageError::NotFound(..))));
+}
+
+/// Removing a protocol system deletes its components with their states and balances, the
+/// contracts and tokens they use and the extraction state. A dry run only reports counts.
+pub async fn protocol_system_removal<G: ConformanceBackend>(gw: &G) {
+    insert_blocks(gw, 2, 1).await;
+    gw.start_block(&block(1)).await;
+    gw.upsert_contract(&contract(1, 1, 100, slots(&[(1, 1)])))
+        .await
+        .expect("contract inserted");
+    gw.commit_block().await;
+    let tokens = insert_component_fixture(gw).await;
+    gw.start_block(&block(1)).await;
+    gw.update_protocol_states(&[(tx_hash(1, 0), state_delta("pc_0", &[("reserve", 1)], &[]))])
+        .await
+        .expect("state inserted");
+    gw.add_component_balances(&[balance("pc_0", &tokens[0], 10, 1)])
+        .await
+        .expect("balances inserted");
+    gw.commit_block().await;
+    gw.start_block(&block(2)).await;
+    gw.add_protocol_components(&[ProtocolComponent {
+        contract_addresses: vec![address(1)],
+        ..component("pc_1", 2, &tokens)
+    }])
+    .await
+    .expect("component inserted");
+    gw.update_protocol_states(&[(tx_hash(2, 0), state_delta("pc_0", &[("reserve", 2)], &[]))])
+        .await
+        .expect("state updated");
+    gw.save_state(&ExtractionState::new(
+        PROTOCOL_SYSTEM.to_string(),
+        CHAIN,
+        None,
+        b"cursor",
+        block(2).hash,
+    ))
+    .await
+    .expect("state saved");
+    gw.commit_block().await;
+
+    let expected = ProtocolSystemRemoval {
+        protocol_components: 2,
+        protocol_states: 2,
+        component_balances: 1,
+        contracts: 1,
+        tokens: 2,
+        extraction_states: 1,
+    };
+    let dry_run = gw
+        .remove_protocol_system(PROTOCOL_SYSTEM, true)
+        .await
+        .expect("dry run");
+    let kept = gw
+        .get_protocol_components(&CHAIN, Some(PROTOCOL_SYSTEM.to_string()), None, None, None)
+        .await
+        .expect("components");
+    assert_eq!(dry_run, expected);
+    assert_eq!(kept.entity.len(), 2);
+
+    let removal = gw
+        .remove_protocol_system(PROTOCOL_SYSTEM, false)
+        .await
+        .expect("protocol system removed");
+    let components = gw
+        .get_protocol_components(&CHAIN, Some(PROTOCOL_SYSTEM.to_string()), None, None, None)
+        .await
+        .expect("components");
+    let states = gw
+        .get_protocol_states(&CHAIN, None, None, Some(&["pc_0"]), false, None)
+        .await
+        .expect("protocol states");
+    let contract = gw
+        .get_contract(&ContractId::new(CHAIN, address(1)), None, true)
+        .await;
+    let remaining_tokens = gw
+        .get_tokens(CHAIN, Some(&[&tokens[0], &tokens[1]]), QualityRange::None(), None, None)
+        .await
+        .expect("tokens");
+    let extraction_state = gw
+        .get_state(PROTOCOL_SYSTEM, &CHAIN)
+        .await;
+    let latest = gw
+        .get_block(&BlockIdentifier::Latest(CHAIN))
+        .await
+        .expect("latest block");
+
+    assert_eq!(removal, expected);
+    assert!(components.entity.is_empty());
+    assert!(states.entity.is_empty());
+    assert!(matches!(contract, Err(StorageError::NotFound(..))));
+    assert!(remaining_tokens.entity.is_empty());
+    assert!(matches!(extraction_state, Err(StorageError::NotFound(..))));
+    assert_eq!(latest, block(2));
+}
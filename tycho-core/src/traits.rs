@@ -7,8 +7,8 @@ use crate::{
     models::{
         blockchain::{Block, BlockTag},
         contract::AccountDelta,
-        token::{CurrencyToken, TokenQuality, TransferCost, TransferTax},
-        Address, Balance,
+        token::{CurrencyToken, TokenAnalysis, TokenQuality, TransferCost, TransferTax},
+        Address, Balance, Code, StoreKey, StoreVal,
     },
     Bytes,
 };
@@ -24,6 +24,36 @@ pub trait AccountExtractor {
     ) -> Result<HashMap<Bytes, AccountDelta>, Self::Error>; //TODO: do not return `AccountUpdate` but `Account`
 }
 
+/// Trait for reading single values of an account's state from a node, e.g. to verify indexed
+/// data against the chain.
+#[async_trait]
+pub trait AccountStateReader: Send + Sync {
+    type Error;
+
+    /// Returns the value of a storage slot of a contract at the given block.
+    async fn get_storage_at(
+        &self,
+        address: &Address,
+        slot: &StoreKey,
+        block: BlockTag,
+    ) -> Result<StoreVal, Self::Error>;
+
+    /// Returns the code deployed at an address at the given block.
+    async fn get_code(&self, address: &Address, block: BlockTag) -> Result<Code, Self::Error>;
+
+    /// Returns the native balance of an address at the given block.
+    async fn get_balance(&self, address: &Address, block: BlockTag)
+        -> Result<Balance, Self::Error>;
+
+    /// Returns the balance of `owner` as reported by the ERC-20 `balanceOf` of `token`.
+    async fn get_token_balance(
+        &self,
+        token: &Address,
+        owner: &Address,
+        block: BlockTag,
+    ) -> Result<Balance, Self::Error>;
+}
+
 /// Trait for analyzing a token, including its quality, transfer cost, and transfer tax.
 #[async_trait]
 pub trait TokenAnalyzer: Send + Sync {
@@ -85,11 +115,11 @@ pub trait TokenPreProcessor: Send + Sync {
     ///
     /// # Returns
     /// A vector of `CurrencyToken` objects, each containing the processed information for the
-    /// token.
+    /// token, paired with the `TokenAnalysis` that determined the token's quality.
     async fn get_tokens(
         &self,
         addresses: Vec<Bytes>,
         token_finder: Arc<dyn TokenOwnerFinding>,
         block: BlockTag,
-    ) -> Vec<CurrencyToken>;
+    ) -> Vec<(CurrencyToken, TokenAnalysis)>;
 }
@@ -32,11 +32,7 @@ async fn main() -> Result<(), ()> {
         ),
     )]));
 
-    let trace_call = TraceCallDetector {
-        web3: w3,
-        finder: Arc::new(tf),
-        settlement_contract: H160::from_str("0xc9f2e6ea1637E499406986ac50ddC92401ce1f58").unwrap(),
-    };
+    let trace_call = TraceCallDetector::with_web3(w3, Arc::new(tf));
 
     let quality = trace_call
         .analyze(
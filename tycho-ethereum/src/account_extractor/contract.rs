@@ -1,28 +1,74 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use chrono::NaiveDateTime;
 use ethers::{
     middleware::Middleware,
-    prelude::{BlockId, Http, Provider, H160, H256, U256},
+    prelude::{
+        BlockId, BlockNumber, Http, JsonRpcClient, Provider, ProviderError, H160, H256, U256,
+    },
 };
+use futures03::{future, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use tracing::trace;
+use tracing::{debug, trace, warn};
 use tycho_core::{
-    models::{blockchain::Block, contract::AccountDelta, Address, Chain, ChangeType},
+    models::{blockchain::Block, contract::AccountDelta, Address, Chain, ChangeType, StoreKey},
     traits::AccountExtractor,
     Bytes,
 };
 
 use crate::{BytesCodec, RPCError};
 
-pub struct EVMAccountExtractor {
-    provider: Provider<Http>,
+/// Default number of accounts, or slots when reading given slots, requested concurrently.
+pub const DEFAULT_CONCURRENCY: usize = 8;
+/// Default number of slots requested per `debug_storageRangeAt` call.
+pub const DEFAULT_STORAGE_PAGE_SIZE: usize = 10_000;
+/// Number of times a failed storage page is requested again before giving up.
+const MAX_PAGE_RETRIES: u32 = 5;
+/// Delay before the first retry of a failed storage page, doubled for every further retry.
+const PAGE_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+/// JSON-RPC error code returned for methods the node doesn't support.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// Extracts the code, balance and storage of contracts from an archive node.
+///
+/// The storage is enumerated with `debug_storageRangeAt`. Nodes without the `debug` namespace
+/// can only serve the slots given with [`EVMAccountExtractor::with_slots`], which are then read
+/// with `eth_getStorageAt`.
+///
+/// If extracting some accounts fails, the work done for the others is kept, so retrying the
+/// same block only requests what is still missing.
+pub struct EVMAccountExtractor<P = Http> {
+    provider: Provider<P>,
     chain: Chain,
+    concurrency: usize,
+    storage_page_size: usize,
+    /// Slots to read per contract if the node doesn't support `debug_storageRangeAt`.
+    slots: HashMap<Address, Vec<StoreKey>>,
+    /// Set once the node rejected `debug_storageRangeAt`, so it isn't requested again.
+    storage_range_unsupported: AtomicBool,
+    progress: Mutex<Progress>,
+}
+
+/// Work of a failed `get_accounts` call, kept so a retry at the same block can resume it.
+#[derive(Default)]
+struct Progress {
+    block: Bytes,
+    /// Accounts extracted before other accounts of the call failed.
+    accounts: HashMap<Address, AccountDelta>,
+    /// Storage enumerated so far of accounts whose storage failed, with the key to continue from.
+    storage: HashMap<Address, (HashMap<U256, U256>, H256)>,
 }
 
 #[async_trait]
-impl AccountExtractor for EVMAccountExtractor {
+impl<P: JsonRpcClient> AccountExtractor for EVMAccountExtractor<P> {
     type Error = RPCError;
 
     async fn get_accounts(
@@ -30,80 +76,304 @@ impl AccountExtractor for EVMAccountExtractor {
         block: tycho_core::models::blockchain::Block,
         account_addresses: Vec<Address>,
     ) -> Result<HashMap<Bytes, AccountDelta>, RPCError> {
-        let mut updates = HashMap::new();
-
-        for address in account_addresses {
-            let address = H160::from_bytes(&address);
-            trace!(contract=?address, block_number=?block.number, block_hash=?block.hash, "Extracting contract code and storage" );
-            let block_id = Some(BlockId::from(block.number));
+        {
+            let mut progress = self.progress();
+            if progress.block != block.hash {
+                *progress = Progress { block: block.hash.clone(), ..Default::default() };
+            }
+        }
+        let storage_position = if self
+            .storage_range_unsupported
+            .load(Ordering::Relaxed)
+        {
+            None
+        } else {
+            Some(self.storage_position(&block).await?)
+        };
 
-            let balance = Some(
-                self.provider
-                    .get_balance(address, block_id)
-                    .await?,
-            );
+        let results = futures03::stream::iter(account_addresses)
+            .map(|address| async {
+                let account = self
+                    .get_account(&block, storage_position, address.clone())
+                    .await;
+                (address, account)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect::<Vec<_>>()
+            .await;
 
-            let code = self
-                .provider
-                .get_code(address, block_id)
-                .await?;
-
-            let code: Option<Bytes> = Some(Bytes::from(code.to_vec()));
-
-            let slots = self
-                .get_storage_range(address, H256::from_bytes(&block.hash))
-                .await?
-                .into_iter()
-                .map(|(k, v)| (k.to_bytes(), Some(v.to_bytes())))
-                .collect();
-
-            updates.insert(
-                Bytes::from(address.to_fixed_bytes()),
-                AccountDelta {
-                    address: address.to_bytes(),
-                    chain: self.chain,
-                    slots,
-                    balance: balance.map(BytesCodec::to_bytes),
-                    code,
-                    change: ChangeType::Creation,
-                },
-            );
+        let mut accounts = HashMap::new();
+        let mut error = None;
+        for (address, account) in results {
+            match account {
+                Ok(account) => {
+                    accounts.insert(address, account);
+                }
+                Err(err) => {
+                    warn!(%address, %err, block_number = block.number, "Failed to extract account");
+                    error = Some(err);
+                }
+            }
         }
-        return Ok(updates);
+        if let Some(err) = error {
+            self.progress()
+                .accounts
+                .extend(accounts);
+            return Err(err);
+        }
+        Ok(accounts)
     }
 }
 
-impl EVMAccountExtractor {
+impl EVMAccountExtractor<Http> {
     pub async fn new(node_url: &str, chain: Chain) -> Result<Self, RPCError>
     where
         Self: Sized,
     {
         let provider = Provider::<Http>::try_from(node_url);
         match provider {
-            Ok(p) => Ok(Self { provider: p, chain }),
+            Ok(p) => Ok(Self::from_provider(p, chain)),
             Err(e) => Err(RPCError::SetupError(e.to_string())),
         }
     }
+}
+
+impl<P: JsonRpcClient> EVMAccountExtractor<P> {
+    pub fn from_provider(provider: Provider<P>, chain: Chain) -> Self {
+        Self {
+            provider,
+            chain,
+            concurrency: DEFAULT_CONCURRENCY,
+            storage_page_size: DEFAULT_STORAGE_PAGE_SIZE,
+            slots: HashMap::new(),
+            storage_range_unsupported: AtomicBool::new(false),
+            progress: Mutex::new(Progress::default()),
+        }
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn with_storage_page_size(mut self, storage_page_size: usize) -> Self {
+        self.storage_page_size = storage_page_size.max(1);
+        self
+    }
+
+    /// Sets the slots to read per contract if the node doesn't support `debug_storageRangeAt`.
+    ///
+    /// Extracting a contract without given slots fails on such nodes.
+    pub fn with_slots(mut self, slots: HashMap<Address, Vec<StoreKey>>) -> Self {
+        self.slots = slots;
+        self
+    }
+
+    /// Whether the node can enumerate contract storage with `debug_storageRangeAt`.
+    ///
+    /// Without it, only the slots given with [`EVMAccountExtractor::with_slots`] can be read.
+    pub async fn supports_storage_range(&self) -> Result<bool, RPCError> {
+        let block = self
+            .provider
+            .get_block(BlockNumber::Latest)
+            .await?
+            .and_then(|block| block.hash)
+            .ok_or_else(|| RPCError::UnexpectedResponse("Latest block not found".to_string()))?;
+        let params =
+            serde_json::json!([format!("0x{:x}", block), 0, H160::zero(), H256::zero(), 1]);
+        match self
+            .provider
+            .request::<_, serde_json::Value>("debug_storageRangeAt", params)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(err) if is_method_unsupported(&err) => {
+                self.storage_range_unsupported
+                    .store(true, Ordering::Relaxed);
+                Ok(false)
+            }
+            // Any other error response, e.g. for the probed account not existing, means the
+            // method is served.
+            Err(ProviderError::JsonRpcClientError(err)) if err.as_error_response().is_some() => {
+                Ok(true)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn progress(&self) -> std::sync::MutexGuard<'_, Progress> {
+        self.progress
+            .lock()
+            .expect("progress lock poisoned")
+    }
+
+    /// Block hash and transaction index at which the storage after `block` is enumerated.
+    ///
+    /// `debug_storageRangeAt` returns the state before the transaction at the given index. The
+    /// state after `block`, which balance, code and `eth_getStorageAt` are requested at, is the
+    /// state before the index following its last transaction. Reading it from `block` itself
+    /// instead of the start of the following block also works at the chain head.
+    async fn storage_position(&self, block: &Block) -> Result<(H256, usize), RPCError> {
+        let node_block = self
+            .provider
+            .get_block(block.number)
+            .await?
+            .ok_or_else(|| {
+                RPCError::UnexpectedResponse(format!(
+                    "Block {} is not available to read its storage",
+                    block.number
+                ))
+            })?;
+        let hash = node_block.hash.ok_or_else(|| {
+            RPCError::UnexpectedResponse(format!("Block {} has no hash", block.number))
+        })?;
+        if hash.to_bytes() != block.hash {
+            return Err(RPCError::UnexpectedResponse(format!(
+                "Block {} was reorged, its hash doesn't match the node's",
+                block.number
+            )));
+        }
+        Ok((hash, node_block.transactions.len()))
+    }
+
+    async fn get_account(
+        &self,
+        block: &Block,
+        storage_position: Option<(H256, usize)>,
+        address: Address,
+    ) -> Result<AccountDelta, RPCError> {
+        if let Some(account) = self
+            .progress()
+            .accounts
+            .remove(&address)
+        {
+            return Ok(account);
+        }
+        let contract = H160::from_bytes(&address);
+        trace!(
+            ?contract,
+            block_number = ?block.number,
+            block_hash = ?block.hash,
+            "Extracting contract code and storage"
+        );
+        let block_id = Some(BlockId::from(block.number));
+
+        let balance = self
+            .provider
+            .get_balance(contract, block_id)
+            .await?;
+
+        let code = self
+            .provider
+            .get_code(contract, block_id)
+            .await?;
+
+        let slots = self
+            .get_storage(contract, block, storage_position)
+            .await?
+            .into_iter()
+            .map(|(k, v)| (k.to_bytes(), Some(v.to_bytes())))
+            .collect();
+
+        Ok(AccountDelta {
+            address,
+            chain: self.chain,
+            slots,
+            balance: Some(balance.to_bytes()),
+            code: Some(Bytes::from(code.to_vec())),
+            change: ChangeType::Creation,
+        })
+    }
 
+    async fn get_storage(
+        &self,
+        address: H160,
+        block: &Block,
+        storage_position: Option<(H256, usize)>,
+    ) -> Result<HashMap<U256, U256>, RPCError> {
+        if let Some((storage_block, tx_index)) = storage_position.filter(|_| {
+            !self
+                .storage_range_unsupported
+                .load(Ordering::Relaxed)
+        }) {
+            match self
+                .get_storage_range(address, storage_block, tx_index)
+                .await
+            {
+                Err(RPCError::RequestError(err)) if is_method_unsupported(&err) => {
+                    warn!(
+                        %err,
+                        "Node doesn't support debug_storageRangeAt, reading given slots instead"
+                    );
+                    self.storage_range_unsupported
+                        .store(true, Ordering::Relaxed);
+                }
+                res => return res,
+            }
+        }
+        self.get_given_slots(address, block)
+            .await
+    }
+
+    /// Enumerates the storage of a contract with `debug_storageRangeAt` before the transaction at
+    /// `tx_index` of `block`.
+    ///
+    /// The storage is requested in pages of `storage_page_size` slots. If a page fails, e.g.
+    /// because the node timed out, it is requested again from the same key with half the size, so
+    /// the slots retrieved so far are kept. The size is doubled again after every page that
+    /// succeeds. If a page keeps failing, the slots retrieved so far are kept for the next attempt
+    /// at the same block.
     async fn get_storage_range(
         &self,
         address: H160,
         block: H256,
+        tx_index: usize,
     ) -> Result<HashMap<U256, U256>, RPCError> {
-        let mut all_slots = HashMap::new();
-        let mut start_key = H256::zero();
+        let (mut all_slots, mut start_key) = self
+            .progress()
+            .storage
+            .remove(&address.to_bytes())
+            .unwrap_or_else(|| (HashMap::new(), H256::zero()));
+        if !all_slots.is_empty() {
+            debug!(?address, ?start_key, n_slots = all_slots.len(), "Resuming storage range");
+        }
+        let mut page_size = self.storage_page_size;
+        let mut retries = 0;
         let block = format!("0x{:x}", block);
         loop {
-            let params = serde_json::json!([
-                block, 0, // transaction index, 0 for the state at the end of the block
-                address, start_key, 2147483647 // limit
-            ]);
+            let params = serde_json::json!([block, tx_index, address, start_key, page_size]);
 
             trace!("Requesting storage range for {:?}, block: {:?}", address, block);
-            let result: StorageRange = self
+            let result: StorageRange = match self
                 .provider
                 .request("debug_storageRangeAt", params)
-                .await?;
+                .await
+            {
+                Ok(result) => result,
+                Err(err) if retries < MAX_PAGE_RETRIES && !is_method_unsupported(&err) => {
+                    page_size = (page_size / 2).max(1);
+                    warn!(
+                        ?address,
+                        ?start_key,
+                        page_size,
+                        %err,
+                        "Storage range request failed, retrying"
+                    );
+                    tokio::time::sleep(PAGE_RETRY_BACKOFF * 2u32.pow(retries)).await;
+                    retries += 1;
+                    continue;
+                }
+                Err(err) => {
+                    if !is_method_unsupported(&err) {
+                        self.progress()
+                            .storage
+                            .insert(address.to_bytes(), (all_slots, start_key));
+                    }
+                    return Err(err.into());
+                }
+            };
+            retries = 0;
+            page_size = (page_size * 2).min(self.storage_page_size);
 
             for (_, entry) in result.storage {
                 all_slots
@@ -117,9 +387,46 @@ impl EVMAccountExtractor {
             }
         }
 
+        debug!(?address, n_slots = all_slots.len(), "Extracted contract storage");
         Ok(all_slots)
     }
 
+    /// Reads the slots given for a contract with `eth_getStorageAt`.
+    ///
+    /// Slots that are not set are skipped, as `debug_storageRangeAt` would.
+    async fn get_given_slots(
+        &self,
+        address: H160,
+        block: &Block,
+    ) -> Result<HashMap<U256, U256>, RPCError> {
+        let slots = self
+            .slots
+            .get(&address.to_bytes())
+            .filter(|slots| !slots.is_empty())
+            .cloned()
+            .ok_or_else(|| {
+                RPCError::SetupError(format!(
+                    "No slots given for contract {:?} and the node doesn't support \
+                     debug_storageRangeAt",
+                    address
+                ))
+            })?;
+        let block_id = Some(BlockId::from(block.number));
+        futures03::stream::iter(slots)
+            .map(|slot| async move {
+                let key = H256::from_bytes(&slot.lpad(32, 0));
+                let value = self
+                    .provider
+                    .get_storage_at(address, key, block_id)
+                    .await?;
+                Ok::<_, RPCError>((U256::from(key.as_bytes()), U256::from(value.as_bytes())))
+            })
+            .buffer_unordered(self.concurrency)
+            .try_filter(|(_, value)| future::ready(!value.is_zero()))
+            .try_collect()
+            .await
+    }
+
     pub async fn get_block_data(&self, block_id: i64) -> Result<Block, RPCError> {
         let block = self
             .provider
@@ -138,6 +445,22 @@ impl EVMAccountExtractor {
     }
 }
 
+/// Whether the node rejected a request because it doesn't support the method.
+fn is_method_unsupported(err: &ProviderError) -> bool {
+    match err {
+        ProviderError::JsonRpcClientError(err) => err
+            .as_error_response()
+            .is_some_and(|response| {
+                let message = response.message.to_lowercase();
+                response.code == METHOD_NOT_FOUND ||
+                    message.contains("method not found") ||
+                    message.contains("not supported") ||
+                    message.contains("unsupported method")
+            }),
+        _ => false,
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct StorageEntry {
     key: H256,
@@ -155,8 +478,270 @@ struct StorageRange {
 mod tests {
     use std::str::FromStr;
 
+    use ethers::providers::{JsonRpcError, MockResponse};
+
     use super::*;
 
+    fn storage_range(slots: &[(u64, u64)], next_key: Option<H256>) -> serde_json::Value {
+        let storage = slots
+            .iter()
+            .map(|&(key, value)| {
+                let key = H256::from_low_u64_be(key);
+                let entry = StorageEntry { key, value: H256::from_low_u64_be(value) };
+                (format!("{:?}", key), serde_json::to_value(entry).unwrap())
+            })
+            .collect::<serde_json::Map<_, _>>();
+        serde_json::json!({ "storage": storage, "nextKey": next_key })
+    }
+
+    fn slot(value: u64) -> Bytes {
+        U256::from(value).to_bytes()
+    }
+
+    fn block(number: u64) -> Block {
+        Block::new(
+            number,
+            Chain::Ethereum,
+            Bytes::from(number).lpad(32, 0),
+            Bytes::from(number - 1).lpad(32, 0),
+            Default::default(),
+        )
+    }
+
+    /// The node's response for `block`, containing `n_txs` transactions.
+    fn node_block(block: &Block, n_txs: usize) -> ethers::types::Block<H256> {
+        ethers::types::Block {
+            hash: Some(H256::from_bytes(&block.hash)),
+            parent_hash: H256::from_bytes(&block.parent_hash),
+            transactions: vec![H256::zero(); n_txs],
+            ..Default::default()
+        }
+    }
+
+    fn error(code: i64, message: &str) -> MockResponse {
+        MockResponse::Error(JsonRpcError { code, message: message.to_string(), data: None })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_get_storage_range_resumes_failed_page() {
+        let (provider, mock) = Provider::mocked();
+        let extractor =
+            EVMAccountExtractor::from_provider(provider, Chain::Ethereum).with_storage_page_size(2);
+        let next_key = H256::from_low_u64_be(3);
+        let last_key = H256::from_low_u64_be(4);
+        // The mock returns responses in reverse order.
+        mock.push::<serde_json::Value, _>(storage_range(&[(4, 40)], None))
+            .unwrap();
+        mock.push::<serde_json::Value, _>(storage_range(&[(3, 30)], Some(last_key)))
+            .unwrap();
+        mock.push_response(error(-32000, "request timed out"));
+        mock.push::<serde_json::Value, _>(storage_range(&[(1, 10), (2, 20)], Some(next_key)))
+            .unwrap();
+
+        let slots = extractor
+            .get_storage_range(H160::zero(), H256::zero(), 0)
+            .await
+            .expect("storage range failed");
+
+        assert_eq!(
+            slots,
+            HashMap::from([
+                (U256::from(1), U256::from(10)),
+                (U256::from(2), U256::from(20)),
+                (U256::from(3), U256::from(30)),
+                (U256::from(4), U256::from(40)),
+            ])
+        );
+        let block = format!("0x{:x}", H256::zero());
+        // The page size is restored after the smaller page succeeded.
+        for (start_key, page_size) in
+            [(H256::zero(), 2), (next_key, 2), (next_key, 1), (last_key, 2)]
+        {
+            mock.assert_request(
+                "debug_storageRangeAt",
+                serde_json::json!([block, 0, H160::zero(), start_key, page_size]),
+            )
+            .unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_get_accounts_resumes_failed_accounts() {
+        let (provider, mock) = Provider::mocked();
+        let extractor = EVMAccountExtractor::from_provider(provider, Chain::Ethereum)
+            .with_concurrency(1)
+            .with_storage_page_size(1);
+        let extracted = Bytes::from([0xaa; 20]);
+        let address = Bytes::from([0xbb; 20]);
+        let next_key = H256::from_low_u64_be(2);
+        let block = block(1);
+        // The mock returns responses in reverse order.
+        for _ in 0..=MAX_PAGE_RETRIES {
+            mock.push_response(error(-32000, "request timed out"));
+        }
+        mock.push::<serde_json::Value, _>(storage_range(&[(1, 10)], Some(next_key)))
+            .unwrap();
+        mock.push::<ethers::types::Bytes, _>(ethers::types::Bytes::from(vec![0x60, 0x80]))
+            .unwrap();
+        mock.push(U256::from(100)).unwrap();
+        mock.push::<serde_json::Value, _>(storage_range(&[(5, 50)], None))
+            .unwrap();
+        mock.push::<ethers::types::Bytes, _>(ethers::types::Bytes::from(vec![0x60, 0x80]))
+            .unwrap();
+        mock.push(U256::from(100)).unwrap();
+        mock.push(node_block(&block, 3))
+            .unwrap();
+
+        let failed = extractor
+            .get_accounts(block.clone(), vec![extracted.clone(), address.clone()])
+            .await;
+        assert!(failed.is_err());
+
+        mock.push::<serde_json::Value, _>(storage_range(&[(2, 20)], None))
+            .unwrap();
+        mock.push::<ethers::types::Bytes, _>(ethers::types::Bytes::from(vec![0x60, 0x80]))
+            .unwrap();
+        mock.push(U256::from(100)).unwrap();
+        mock.push(node_block(&block, 3))
+            .unwrap();
+        let accounts = extractor
+            .get_accounts(block.clone(), vec![extracted.clone(), address.clone()])
+            .await
+            .expect("account extraction failed");
+
+        // The account extracted by the first attempt is not requested again.
+        assert_eq!(accounts[&extracted].slots, HashMap::from([(slot(5), Some(slot(50)))]));
+        // Only the first attempt enumerated slot 1, so the second continued from the failed page.
+        assert_eq!(
+            accounts[&address].slots,
+            HashMap::from([(slot(1), Some(slot(10))), (slot(2), Some(slot(20)))])
+        );
+        // Balance, code and storage are read at the end of block 1, the storage after its last
+        // transaction.
+        let contract = H160::from_bytes(&extracted);
+        mock.assert_request("eth_getBlockByNumber", serde_json::json!(["0x1", false]))
+            .unwrap();
+        mock.assert_request("eth_getBalance", serde_json::json!([contract, "0x1"]))
+            .unwrap();
+        mock.assert_request("eth_getCode", serde_json::json!([contract, "0x1"]))
+            .unwrap();
+        mock.assert_request(
+            "debug_storageRangeAt",
+            serde_json::json!([
+                format!("0x{:x}", H256::from_bytes(&block.hash)),
+                3,
+                contract,
+                H256::zero(),
+                1
+            ]),
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_accounts_without_slots() {
+        let (provider, mock) = Provider::mocked();
+        let extractor = EVMAccountExtractor::from_provider(provider, Chain::Ethereum);
+        extractor
+            .storage_range_unsupported
+            .store(true, Ordering::Relaxed);
+        // The mock returns responses in reverse order.
+        mock.push::<ethers::types::Bytes, _>(ethers::types::Bytes::from(vec![0x60, 0x80]))
+            .unwrap();
+        mock.push(U256::from(100)).unwrap();
+
+        let res = extractor
+            .get_accounts(block(1), vec![Bytes::from([0xaa; 20])])
+            .await;
+
+        assert!(matches!(res, Err(RPCError::SetupError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_get_accounts_without_debug_namespace() {
+        let (provider, mock) = Provider::mocked();
+        let address = Bytes::from([0xaa; 20]);
+        let extractor = EVMAccountExtractor::from_provider(provider, Chain::Ethereum)
+            .with_concurrency(1)
+            .with_slots(HashMap::from([(
+                address.clone(),
+                vec![Bytes::from(1u8), Bytes::from(2u8)],
+            )]));
+        // The mock returns responses in reverse order.
+        mock.push(H256::zero()).unwrap();
+        mock.push(H256::from_low_u64_be(10))
+            .unwrap();
+        mock.push_response(error(
+            METHOD_NOT_FOUND,
+            "the method debug_storageRangeAt does not exist/is not available",
+        ));
+        mock.push::<ethers::types::Bytes, _>(ethers::types::Bytes::from(vec![0x60, 0x80]))
+            .unwrap();
+        mock.push(U256::from(100)).unwrap();
+        let block = block(1);
+        mock.push(node_block(&block, 0))
+            .unwrap();
+
+        let accounts = extractor
+            .get_accounts(block, vec![address.clone()])
+            .await
+            .expect("account extraction failed");
+
+        // Slot 2 is not set and therefore skipped.
+        let expected = AccountDelta::new(
+            Chain::Ethereum,
+            address.clone(),
+            HashMap::from([(slot(1), Some(slot(10)))]),
+            Some(U256::from(100).to_bytes()),
+            Some(Bytes::from(vec![0x60, 0x80])),
+            ChangeType::Creation,
+        );
+        assert_eq!(accounts, HashMap::from([(address, expected)]));
+        assert!(extractor
+            .storage_range_unsupported
+            .load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_supports_storage_range() {
+        // A storage range or an error other than the method missing means it is supported.
+        for (error, expected) in
+            [(None, true), (Some(-32000), true), (Some(METHOD_NOT_FOUND), false)]
+        {
+            let (provider, mock) = Provider::mocked();
+            let extractor = EVMAccountExtractor::from_provider(provider, Chain::Ethereum);
+            // The mock returns responses in reverse order.
+            match error {
+                Some(code) => mock.push_response(MockResponse::Error(JsonRpcError {
+                    code,
+                    message: "request failed".to_string(),
+                    data: None,
+                })),
+                None => mock
+                    .push::<serde_json::Value, _>(storage_range(&[], None))
+                    .unwrap(),
+            }
+            mock.push(ethers::types::Block::<H256> {
+                hash: Some(H256::from_low_u64_be(1)),
+                ..Default::default()
+            })
+            .unwrap();
+
+            let supported = extractor
+                .supports_storage_range()
+                .await
+                .expect("probing storage range support failed");
+
+            assert_eq!(supported, expected);
+            assert_eq!(
+                extractor
+                    .storage_range_unsupported
+                    .load(Ordering::Relaxed),
+                !expected
+            );
+        }
+    }
+
     #[tokio::test]
     #[ignore = "require RPC connection"]
     async fn test_contract_extractor() -> Result<(), Box<dyn std::error::Error>> {
@@ -0,0 +1,93 @@
+use async_trait::async_trait;
+use ethers::{
+    middleware::Middleware,
+    prelude::{BlockId, Http, Provider, TransactionRequest, H160, H256},
+};
+use tracing::trace;
+use tycho_core::{
+    models::{blockchain::BlockTag, Address, Balance, Code, StoreKey, StoreVal},
+    traits::AccountStateReader,
+};
+
+use crate::{BlockTagWrapper, BytesCodec, RPCError};
+
+/// Selector of the ERC-20 `balanceOf(address)` function.
+const BALANCE_OF_SELECTOR: [u8; 4] = [0x70, 0xa0, 0x82, 0x31];
+
+/// Reads account state from an EVM node over JSON-RPC.
+pub struct EVMStateReader {
+    provider: Provider<Http>,
+}
+
+impl EVMStateReader {
+    pub fn new(node_url: &str) -> Result<Self, RPCError> {
+        let provider = Provider::<Http>::try_from(node_url)
+            .map_err(|e| RPCError::SetupError(e.to_string()))?;
+        Ok(Self { provider })
+    }
+}
+
+#[async_trait]
+impl AccountStateReader for EVMStateReader {
+    type Error = RPCError;
+
+    async fn get_storage_at(
+        &self,
+        address: &Address,
+        slot: &StoreKey,
+        block: BlockTag,
+    ) -> Result<StoreVal, RPCError> {
+        trace!(%address, %slot, ?block, "Requesting storage slot");
+        let slot = H256::from_bytes(&slot.lpad(32, 0));
+        let value = self
+            .provider
+            .get_storage_at(
+                H160::from_bytes(address),
+                slot,
+                Some(BlockId::from(BlockTagWrapper(block))),
+            )
+            .await?;
+        Ok(value.to_bytes())
+    }
+
+    async fn get_code(&self, address: &Address, block: BlockTag) -> Result<Code, RPCError> {
+        let code = self
+            .provider
+            .get_code(H160::from_bytes(address), Some(BlockId::from(BlockTagWrapper(block))))
+            .await?;
+        Ok(Code::from(code.to_vec()))
+    }
+
+    async fn get_balance(&self, address: &Address, block: BlockTag) -> Result<Balance, RPCError> {
+        let balance = self
+            .provider
+            .get_balance(H160::from_bytes(address), Some(BlockId::from(BlockTagWrapper(block))))
+            .await?;
+        Ok(balance.to_bytes())
+    }
+
+    async fn get_token_balance(
+        &self,
+        token: &Address,
+        owner: &Address,
+        block: BlockTag,
+    ) -> Result<Balance, RPCError> {
+        let mut data = BALANCE_OF_SELECTOR.to_vec();
+        data.extend_from_slice(H256::from(H160::from_bytes(owner)).as_bytes());
+        let tx = TransactionRequest::new()
+            .to(H160::from_bytes(token))
+            .data(data);
+        let output = self
+            .provider
+            .call(&tx.into(), Some(BlockId::from(BlockTagWrapper(block))))
+            .await?;
+        if output.len() != 32 {
+            return Err(RPCError::UnexpectedResponse(format!(
+                "balanceOf of token {} returned {} bytes",
+                token,
+                output.len()
+            )));
+        }
+        Ok(Balance::from(output.to_vec()))
+    }
+}
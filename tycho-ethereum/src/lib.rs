@@ -19,6 +19,8 @@ pub enum RPCError {
     SetupError(String),
     #[error("RPC error: {0}")]
     RequestError(#[from] ProviderError),
+    #[error("Unexpected RPC response: {0}")]
+    UnexpectedResponse(String),
 }
 
 pub struct BlockTagWrapper(BlockTag);
@@ -36,6 +38,25 @@ impl From<BlockTagWrapper> for BlockNumber {
     }
 }
 
+impl From<BlockTagWrapper> for ethers::types::BlockNumber {
+    fn from(value: BlockTagWrapper) -> Self {
+        match value.0 {
+            BlockTag::Finalized => Self::Finalized,
+            BlockTag::Safe => Self::Safe,
+            BlockTag::Latest => Self::Latest,
+            BlockTag::Earliest => Self::Earliest,
+            BlockTag::Pending => Self::Pending,
+            BlockTag::Number(n) => Self::Number(n.into()),
+        }
+    }
+}
+
+impl From<BlockTagWrapper> for ethers::types::BlockId {
+    fn from(value: BlockTagWrapper) -> Self {
+        Self::Number(ethers::types::BlockNumber::from(value))
+    }
+}
+
 /// A trait for converting types to and from `Bytes`.
 ///
 /// This trait provides methods to convert a type into a `Bytes` object,
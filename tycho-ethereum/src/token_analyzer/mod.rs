@@ -1,5 +1,6 @@
 pub mod ethrpc;
 pub mod http_client;
 pub mod rpc_client;
+pub mod simulation;
 pub mod trace_call;
 pub mod trace_many;
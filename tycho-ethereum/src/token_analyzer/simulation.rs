@@ -0,0 +1,544 @@
+//! Token analysis by simulating transfers in a local EVM.
+//!
+//! Unlike [`TraceCallDetector`], this does not require the node to support `trace_callMany`.
+//! The simulated transfers are the same, but they are executed with revm against state that is
+//! fetched lazily over `eth_getBalance`, `eth_getTransactionCount`, `eth_getCode` and
+//! `eth_getStorageAt`. Any archive node can serve these, as can a node that keeps recent state
+//! when analyzing at a recent block.
+
+use std::{
+    cmp,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Context, Result};
+use ethers::{
+    abi::{self, Token},
+    providers::Middleware,
+    types::{BlockId, BlockNumber, H160, H256, U256},
+};
+use revm::{
+    db::{CacheDB, DatabaseRef},
+    primitives::{
+        AccountInfo, Address, BlockEnv, Bytecode, Bytes as EvmBytes, ExecutionResult, TransactTo,
+        B256, U256 as EvmU256,
+    },
+    Database, DatabaseCommit, Evm,
+};
+use tokio::runtime::Handle;
+use tycho_core::{
+    models::{
+        blockchain::BlockTag,
+        token::{TokenQuality, TransferCost, TransferTax},
+    },
+    traits::{TokenAnalyzer, TokenOwnerFinding},
+    Bytes,
+};
+
+use crate::{
+    token_analyzer::trace_call::{SimulatedCall, TraceCallDetector, MIN_AMOUNT},
+    BlockTagWrapper, BytesCodec,
+};
+
+/// Gas limit of each simulated call.
+const SIMULATION_GAS_LIMIT: u64 = 30_000_000;
+
+/// Gas charged for every transaction before execution. Subtracted from the gas used by
+/// simulated calls so it matches the gas reported by traces.
+const TX_BASE_GAS: u64 = 21_000;
+
+/// Detects bad tokens like [`TraceCallDetector`], but simulates the transfers in a local EVM
+/// instead of relying on `trace_callMany`.
+pub struct EvmSimulationDetector<M> {
+    pub client: Arc<M>,
+    pub finder: Arc<dyn TokenOwnerFinding>,
+    pub settlement_contract: H160,
+    /// Number of RPC requests sent so far, shared with the state databases of the simulations.
+    rpc_requests: Arc<AtomicU64>,
+}
+
+impl<M: Middleware + 'static> EvmSimulationDetector<M> {
+    pub fn new(client: Arc<M>, finder: Arc<dyn TokenOwnerFinding>) -> Self {
+        Self {
+            client,
+            finder,
+            // middle contract used to check for fees, set to cowswap settlement
+            settlement_contract: "0xc9f2e6ea1637E499406986ac50ddC92401ce1f58"
+                .parse()
+                .unwrap(),
+            rpc_requests: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Returns the number of RPC requests this detector has sent.
+    pub fn rpc_requests(&self) -> u64 {
+        self.rpc_requests
+            .load(Ordering::Relaxed)
+    }
+
+    pub async fn detect_impl(
+        &self,
+        token: H160,
+        block: BlockId,
+    ) -> Result<(TokenQuality, Option<U256>, Option<U256>)> {
+        let (take_from, amount) = match self
+            .finder
+            .find_owner(token.to_bytes(), MIN_AMOUNT.into())
+            .await
+            .map_err(|e| anyhow!(e.to_string()))?
+        {
+            // Only transfer a portion of the balance, see `TraceCallDetector::detect_impl`.
+            Some((address, balance)) => (
+                H160::from_bytes(&address),
+                cmp::max(U256::from_bytes(&balance) / 2, MIN_AMOUNT.into()),
+            ),
+            None => {
+                return Ok((
+                    TokenQuality::bad(format!(
+                        "Could not find on chain source of the token with at least {MIN_AMOUNT} \
+                     balance.",
+                    )),
+                    None,
+                    None,
+                ))
+            }
+        };
+
+        self.rpc_requests
+            .fetch_add(1, Ordering::Relaxed);
+        let header = self
+            .client
+            .get_block(block)
+            .await
+            .map_err(|e| anyhow!("Failed to get block {block:?}: {e}"))?
+            .with_context(|| format!("Block {block:?} not found"))?;
+        let number = header
+            .number
+            .context("Block is pending")?
+            .as_u64();
+        let block_env = BlockEnv {
+            number: EvmU256::from(number),
+            timestamp: to_evm_u256(header.timestamp),
+            coinbase: to_address(header.author.unwrap_or_default()),
+            gas_limit: to_evm_u256(header.gas_limit),
+            prevrandao: Some(B256::from(header.mix_hash.unwrap_or_default().0)),
+            ..Default::default()
+        };
+
+        // revm's database interface is synchronous, so the simulation runs on a blocking thread
+        // that waits on the state requests.
+        let db = RpcStateDb::new(self.client.clone(), Handle::current(), number)
+            .with_request_counter(self.rpc_requests.clone());
+        let settlement = self.settlement_contract;
+        tokio::task::spawn_blocking(move || {
+            simulate_transfers(CacheDB::new(db), block_env, token, amount, take_from, settlement)
+        })
+        .await?
+    }
+}
+
+#[async_trait::async_trait]
+impl<M: Middleware + 'static> TokenAnalyzer for EvmSimulationDetector<M> {
+    type Error = String;
+
+    async fn analyze(
+        &self,
+        token: Bytes,
+        block: BlockTag,
+    ) -> std::result::Result<(TokenQuality, Option<TransferCost>, Option<TransferTax>), String>
+    {
+        let (quality, transfer_cost, tax) = self
+            .detect_impl(H160::from_bytes(&token), BlockTagWrapper(block).into())
+            .await
+            .map_err(|e| e.to_string())?;
+        tracing::debug!(?token, ?quality, "determined token quality");
+        Ok((
+            quality,
+            transfer_cost.map(|cost| cost.try_into().unwrap_or(8_000_000)),
+            tax.map(|cost| cost.try_into().unwrap_or(10_000)),
+        ))
+    }
+}
+
+/// Simulates transferring `amount` of `token` from `take_from` into `settlement` and on to an
+/// arbitrary recipient, then evaluates the results like [`TraceCallDetector`].
+pub fn simulate_transfers<DB>(
+    db: DB,
+    block: BlockEnv,
+    token: H160,
+    amount: U256,
+    take_from: H160,
+    settlement: H160,
+) -> Result<(TokenQuality, Option<U256>, Option<U256>)>
+where
+    DB: Database + DatabaseCommit,
+    DB::Error: std::fmt::Debug,
+{
+    let recipient = TraceCallDetector::arbitrary_recipient();
+    let mut evm = Evm::builder()
+        .with_db(db)
+        .modify_block_env(|env| *env = block)
+        // The transfers are sent from the token holder and the settlement contract, which both
+        // usually have code.
+        .modify_cfg_env(|cfg| cfg.disable_eip3607 = true)
+        .build();
+    let mut call = |from: Option<H160>, data: Vec<u8>| -> Result<CallOutcome> {
+        let tx = evm.tx_mut();
+        tx.caller = from.map(to_address).unwrap_or_default();
+        tx.transact_to = TransactTo::Call(to_address(token));
+        tx.data = EvmBytes::from(data);
+        tx.gas_limit = SIMULATION_GAS_LIMIT;
+        tx.gas_price = EvmU256::ZERO;
+        tx.nonce = None;
+        let result = evm
+            .transact_commit()
+            .map_err(|e| anyhow!("Simulation failed: {e:?}"))?;
+        Ok(CallOutcome::from_result(result, evm.tx().data.as_ref()))
+    };
+
+    // The calls match `TraceCallDetector::create_trace_request`, as they are evaluated the same.
+    let mut calls = vec![
+        // 0 Get balance of settlement contract before
+        call(None, balance_of(settlement))?,
+        // 1 Transfer from take_from to settlement contract
+        call(Some(take_from), transfer(settlement, amount))?,
+        // 2 Get balance of settlement contract after
+        call(None, balance_of(settlement))?,
+        // 3 Get balance of arbitrary recipient before
+        call(None, balance_of(recipient))?,
+    ];
+    let middle_amount =
+        match calls[2].output_u256() {
+            Some(balance) => balance,
+            None => return Ok((
+                TokenQuality::bad(
+                    "Failed to decode the token's balanceOf response because it did not return \
+                     32 bytes.",
+                ),
+                None,
+                None,
+            )),
+        };
+    calls.extend([
+        // 4 Transfer from settlement contract to arbitrary recipient
+        call(Some(settlement), transfer(recipient, middle_amount))?,
+        // 5 Get balance of settlement contract after
+        call(None, balance_of(settlement))?,
+        // 6 Get balance of arbitrary recipient after
+        call(None, balance_of(recipient))?,
+        // 7 Approve max with settlement contract
+        call(Some(settlement), approve(recipient, U256::MAX))?,
+    ]);
+
+    TraceCallDetector::handle_response(&calls, amount, middle_amount, take_from)
+}
+
+/// The result of a simulated call.
+#[derive(Debug)]
+struct CallOutcome {
+    /// Gas used excluding the transaction's intrinsic gas, or the reason the call failed.
+    gas: std::result::Result<U256, String>,
+    output: Vec<u8>,
+}
+
+impl CallOutcome {
+    fn from_result(result: ExecutionResult, calldata: &[u8]) -> Self {
+        let intrinsic = intrinsic_gas(calldata);
+        match result {
+            ExecutionResult::Success { gas_used, output, .. } => Self {
+                gas: Ok(gas_used
+                    .saturating_sub(intrinsic)
+                    .into()),
+                output: output.into_data().to_vec(),
+            },
+            ExecutionResult::Revert { output, .. } => {
+                Self { gas: Err(format!("transaction failed: Reverted {output}")), output: vec![] }
+            }
+            ExecutionResult::Halt { reason, .. } => {
+                Self { gas: Err(format!("transaction failed: {reason:?}")), output: vec![] }
+            }
+        }
+    }
+}
+
+impl SimulatedCall for CallOutcome {
+    fn gas_used(&self) -> Result<std::result::Result<U256, String>> {
+        Ok(self.gas.clone())
+    }
+
+    fn output_u256(&self) -> Option<U256> {
+        (self.output.len() == 32).then(|| U256::from_big_endian(&self.output))
+    }
+}
+
+/// Intrinsic gas of a call transaction with `calldata` since EIP-2028.
+fn intrinsic_gas(calldata: &[u8]) -> u64 {
+    calldata
+        .iter()
+        .fold(TX_BASE_GAS, |gas, byte| gas + if *byte == 0 { 4 } else { 16 })
+}
+
+fn balance_of(owner: H160) -> Vec<u8> {
+    encode_call("balanceOf(address)", &[Token::Address(owner)])
+}
+
+fn transfer(to: H160, amount: U256) -> Vec<u8> {
+    encode_call("transfer(address,uint256)", &[Token::Address(to), Token::Uint(amount)])
+}
+
+fn approve(spender: H160, amount: U256) -> Vec<u8> {
+    encode_call("approve(address,uint256)", &[Token::Address(spender), Token::Uint(amount)])
+}
+
+fn encode_call(signature: &str, args: &[Token]) -> Vec<u8> {
+    [&ethers::utils::id(signature)[..], &abi::encode(args)].concat()
+}
+
+fn to_address(address: H160) -> Address {
+    Address::from(address.0)
+}
+
+fn to_evm_u256(value: U256) -> EvmU256 {
+    EvmU256::from_limbs(value.0)
+}
+
+/// Reads account state at a fixed block from a node. Every read is a separate request, callers
+/// should cache them, e.g. by wrapping this in a [`CacheDB`].
+///
+/// Reads block on `runtime`, so this must not be used from within an async context.
+pub struct RpcStateDb<M> {
+    client: Arc<M>,
+    runtime: Handle,
+    block: BlockId,
+    /// Number of RPC requests sent so far.
+    rpc_requests: Arc<AtomicU64>,
+}
+
+impl<M: Middleware> RpcStateDb<M> {
+    pub fn new(client: Arc<M>, runtime: Handle, block_number: u64) -> Self {
+        Self {
+            client,
+            runtime,
+            block: BlockId::Number(BlockNumber::Number(block_number.into())),
+            rpc_requests: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Counts the RPC requests sent by this database in `counter`.
+    pub fn with_request_counter(mut self, counter: Arc<AtomicU64>) -> Self {
+        self.rpc_requests = counter;
+        self
+    }
+
+    fn count_requests(&self, requests: u64) {
+        self.rpc_requests
+            .fetch_add(requests, Ordering::Relaxed);
+    }
+}
+
+impl<M: Middleware> DatabaseRef for RpcStateDb<M> {
+    type Error = String;
+
+    fn basic_ref(&self, address: Address) -> std::result::Result<Option<AccountInfo>, String> {
+        let address = H160::from(address.into_array());
+        let block = Some(self.block);
+        self.count_requests(3);
+        let (balance, nonce, code) = self
+            .runtime
+            .block_on(async {
+                futures03::try_join!(
+                    self.client.get_balance(address, block),
+                    self.client
+                        .get_transaction_count(address, block),
+                    self.client.get_code(address, block),
+                )
+            })
+            .map_err(|e| format!("Failed to get account {address:?}: {e}"))?;
+        // The node reports accounts that don't exist as empty, revm expects them to be missing.
+        if balance.is_zero() && nonce.is_zero() && code.is_empty() {
+            return Ok(None);
+        }
+        let code = Bytecode::new_raw(EvmBytes::from(code.to_vec()));
+        Ok(Some(AccountInfo::new(to_evm_u256(balance), nonce.as_u64(), code.hash_slow(), code)))
+    }
+
+    fn code_by_hash_ref(&self, code_hash: B256) -> std::result::Result<Bytecode, String> {
+        // Code is always returned together with the account, so it is never looked up by hash.
+        Err(format!("Unexpected lookup of code by hash {code_hash}"))
+    }
+
+    fn storage_ref(
+        &self,
+        address: Address,
+        index: EvmU256,
+    ) -> std::result::Result<EvmU256, String> {
+        let address = H160::from(address.into_array());
+        let slot = H256::from(index.to_be_bytes::<32>());
+        self.count_requests(1);
+        let value = self
+            .runtime
+            .block_on(
+                self.client
+                    .get_storage_at(address, slot, Some(self.block)),
+            )
+            .map_err(|e| format!("Failed to get storage {slot:?} of {address:?}: {e}"))?;
+        Ok(EvmU256::from_be_bytes(value.0))
+    }
+
+    fn block_hash_ref(&self, number: EvmU256) -> std::result::Result<B256, String> {
+        let number = number.to::<u64>();
+        self.count_requests(1);
+        let block = self
+            .runtime
+            .block_on(self.client.get_block(number))
+            .map_err(|e| format!("Failed to get block {number}: {e}"))?
+            .ok_or_else(|| format!("Block {number} not found"))?;
+        Ok(B256::from(block.hash.unwrap_or_default().0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use revm::db::EmptyDB;
+
+    use super::*;
+
+    /// A minimal token without any checks besides balances. `balanceOf(a)` reads slot `a`,
+    /// `transfer` moves balances between slots and `approve` always succeeds. Unknown functions
+    /// revert.
+    ///
+    /// ```text
+    /// selector = calldataload(0) >> 224
+    /// if selector == balanceOf: return sload(calldataload(4))
+    /// if selector == transfer:
+    ///     if calldataload(36) > sload(caller): revert
+    ///     sstore(caller, sload(caller) - calldataload(36))
+    ///     sstore(calldataload(4), sload(calldataload(4)) + calldataload(36))
+    ///     return true
+    /// if selector == approve: return true
+    /// revert
+    /// ```
+    const TOKEN_CODE: &str = "60003560e01c806370a08231146029578063a9059cbb146036578063095ea7b3146050575b600080fd5b6004355460005260206000f35b335460243581811160245780910333556004355401600435555b600160005260206000f3";
+
+    /// Like `TOKEN_CODE` but the recipient receives 1% less than the transferred amount.
+    const FEE_TOKEN_CODE: &str = "60003560e01c806370a08231146029578063a9059cbb146036578063095ea7b3146056575b600080fd5b6004355460005260206000f35b335460243581811160245780910333556064810490036004355401600435555b600160005260206000f3";
+
+    fn token_fixture(code: &str, holder: H160, balance: u64) -> CacheDB<EmptyDB> {
+        let token = to_address(token());
+        let code = Bytecode::new_raw(EvmBytes::from(ethers::utils::hex::decode(code).unwrap()));
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(token, AccountInfo::new(EvmU256::ZERO, 1, code.hash_slow(), code));
+        db.insert_account_storage(
+            token,
+            EvmU256::from_be_bytes(H256::from(holder).0),
+            EvmU256::from(balance),
+        )
+        .unwrap();
+        db
+    }
+
+    fn token() -> H160 {
+        H160::from_low_u64_be(0x70c3)
+    }
+
+    fn settlement() -> H160 {
+        H160::from_low_u64_be(0x5e77)
+    }
+
+    fn holder() -> H160 {
+        H160::from_low_u64_be(0x401d)
+    }
+
+    #[test]
+    fn test_simulate_transfers_good_token() {
+        let db = token_fixture(TOKEN_CODE, holder(), 1_000_000);
+
+        let (quality, gas, tax) = simulate_transfers(
+            db,
+            BlockEnv::default(),
+            token(),
+            500_000.into(),
+            holder(),
+            settlement(),
+        )
+        .unwrap();
+
+        assert_eq!(quality, TokenQuality::Good);
+        assert!(gas.is_some_and(|gas| !gas.is_zero()));
+        assert_eq!(tax, Some(U256::zero()));
+    }
+
+    #[test]
+    fn test_simulate_transfers_from_contracts() {
+        // Holders are usually pools and the settlement is a contract, EIP-3607 would reject
+        // transactions sent from them.
+        let mut db = token_fixture(TOKEN_CODE, holder(), 1_000_000);
+        let code = Bytecode::new_raw(EvmBytes::from(vec![0x00]));
+        for contract in [holder(), settlement()] {
+            db.insert_account_info(
+                to_address(contract),
+                AccountInfo::new(EvmU256::ZERO, 1, code.hash_slow(), code.clone()),
+            );
+        }
+
+        let (quality, gas, tax) = simulate_transfers(
+            db,
+            BlockEnv::default(),
+            token(),
+            500_000.into(),
+            holder(),
+            settlement(),
+        )
+        .unwrap();
+
+        assert_eq!(quality, TokenQuality::Good);
+        assert!(gas.is_some_and(|gas| !gas.is_zero()));
+        assert_eq!(tax, Some(U256::zero()));
+    }
+
+    #[test]
+    fn test_simulate_transfers_fee_token() {
+        let db = token_fixture(FEE_TOKEN_CODE, holder(), 1_000_000);
+
+        let (quality, _, tax) = simulate_transfers(
+            db,
+            BlockEnv::default(),
+            token(),
+            500_000.into(),
+            holder(),
+            settlement(),
+        )
+        .unwrap();
+
+        assert!(matches!(quality, TokenQuality::Bad { .. }));
+        assert_eq!(tax, Some(U256::from(100)));
+    }
+
+    #[test]
+    fn test_simulate_transfers_insufficient_balance() {
+        let db = token_fixture(TOKEN_CODE, holder(), 1_000);
+
+        let (quality, gas, tax) = simulate_transfers(
+            db,
+            BlockEnv::default(),
+            token(),
+            500_000.into(),
+            holder(),
+            settlement(),
+        )
+        .unwrap();
+
+        assert!(matches!(quality, TokenQuality::Bad { .. }));
+        assert_eq!(gas, None);
+        assert_eq!(tax, None);
+    }
+
+    #[test]
+    fn test_intrinsic_gas() {
+        assert_eq!(intrinsic_gas(&[]), 21_000);
+        assert_eq!(intrinsic_gas(&[0, 1, 0]), 21_024);
+    }
+}
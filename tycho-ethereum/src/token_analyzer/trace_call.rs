@@ -1,6 +1,13 @@
-use std::{cmp, str::FromStr, sync::Arc};
+use std::{
+    cmp,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
-use anyhow::{bail, ensure, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use contracts::ERC20;
 use ethcontract::{dyns::DynTransport, transaction::TransactionBuilder, PrivateKey};
 use ethers::types::{H160, U256};
@@ -9,7 +16,7 @@ use reqwest::Client;
 use tycho_core::{
     models::{
         blockchain::BlockTag,
-        token::{TokenQuality, TransferCost, TransferTax},
+        token::{TokenFlag, TokenQuality, TransferCost, TransferTax},
     },
     traits::{TokenAnalyzer, TokenOwnerFinding},
     Bytes,
@@ -17,7 +24,7 @@ use tycho_core::{
 use url::Url;
 use web3::{
     signing::keccak256,
-    types::{BlockNumber, BlockTrace, CallRequest, Res},
+    types::{BlockId, BlockNumber, BlockTrace, CallRequest, FilterBuilder, Log, Res, H256},
 };
 
 use crate::{token_analyzer::trace_many, BlockTagWrapper, BytesCodec};
@@ -33,6 +40,8 @@ pub struct TraceCallDetector {
     pub web3: Web3,
     pub finder: Arc<dyn TokenOwnerFinding>,
     pub settlement_contract: H160,
+    /// Number of RPC requests sent so far.
+    rpc_requests: AtomicU64,
 }
 
 #[async_trait::async_trait]
@@ -58,6 +67,26 @@ impl TokenAnalyzer for TraceCallDetector {
     }
 }
 
+// Arbitrary amount that is large enough that small relative fees should be
+// visible.
+pub(crate) const MIN_AMOUNT: u64 = 100_000;
+
+/// Number of blocks over which a holder's balance change is compared to its transfers to
+/// detect rebasing tokens.
+const REBASE_LOOKBACK_BLOCKS: u64 = 300;
+
+/// View functions whose presence indicates a token behaviour. Functions taking an address are
+/// called with the settlement contract.
+const FLAG_PROBES: [(&str, TokenFlag); 5] = [
+    ("paused()", TokenFlag::Pausable),
+    ("isBlacklisted(address)", TokenFlag::Blacklistable),
+    ("isBlackListed(address)", TokenFlag::Blacklistable),
+    // Share based rebasing tokens, e.g. stETH.
+    ("sharesOf(address)", TokenFlag::Rebasing),
+    // Scaled balance rebasing tokens, e.g. Aave aTokens and AMPL.
+    ("scaledBalanceOf(address)", TokenFlag::Rebasing),
+];
+
 enum TraceRequestType {
     SimpleTransfer,
     DoubleTransfer(U256),
@@ -65,26 +94,52 @@ enum TraceRequestType {
 
 impl TraceCallDetector {
     pub fn new(url: &str, finder: Arc<dyn TokenOwnerFinding>) -> Self {
-        Self {
-            web3: Web3::new(Web3Transport::new(HttpTransport::new(
+        Self::with_web3(
+            Web3::new(Web3Transport::new(HttpTransport::new(
                 Client::new(),
                 Url::from_str(url).unwrap(),
                 "transport".to_owned(),
             ))),
             finder,
+        )
+    }
+
+    pub fn with_web3(web3: Web3, finder: Arc<dyn TokenOwnerFinding>) -> Self {
+        Self {
+            web3,
+            finder,
             // middle contract used to check for fees, set to cowswap settlement
             settlement_contract: H160::from_str("0xc9f2e6ea1637E499406986ac50ddC92401ce1f58")
                 .unwrap(),
+            rpc_requests: AtomicU64::new(0),
         }
     }
+
+    /// Returns the number of RPC requests this detector has sent.
+    pub fn rpc_requests(&self) -> u64 {
+        self.rpc_requests
+            .load(Ordering::Relaxed)
+    }
+
+    fn count_request(&self) {
+        self.rpc_requests
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    async fn trace_many(
+        &self,
+        requests: Vec<CallRequest>,
+        block: BlockNumber,
+    ) -> Result<Vec<BlockTrace>> {
+        self.count_request();
+        trace_many::trace_many(requests, &self.web3, block).await
+    }
+
     pub async fn detect_impl(
         &self,
         token: H160,
         block: BlockNumber,
     ) -> Result<(TokenQuality, Option<U256>, Option<U256>), String> {
-        // Arbitrary amount that is large enough that small relative fees should be
-        // visible.
-        const MIN_AMOUNT: u64 = 100_000;
         let (take_from, amount) = match self
             .finder
             .find_owner(token.to_bytes(), MIN_AMOUNT.into())
@@ -124,7 +179,8 @@ impl TraceCallDetector {
         // yet (implicitly 0) causes an allocation.
         let request =
             self.create_trace_request(token, amount, take_from, TraceRequestType::SimpleTransfer);
-        let traces = trace_many::trace_many(request, &self.web3, block)
+        let traces = self
+            .trace_many(request, block)
             .await
             .map_err(|e| e.to_string())?;
 
@@ -147,16 +203,205 @@ impl TraceCallDetector {
             take_from,
             TraceRequestType::DoubleTransfer(middle_balance),
         );
-        let traces = trace_many::trace_many(request, &self.web3, block)
+        let traces = self
+            .trace_many(request, block)
             .await
             .map_err(|e| e.to_string())?;
         Self::handle_response(&traces, amount, middle_balance, take_from).map_err(|e| e.to_string())
     }
 
+    /// Detects transfer behaviours that don't make a token bad, but that consumers need to be
+    /// aware of. See [`TokenFlag`].
+    pub async fn analyze_flags(
+        &self,
+        token: Bytes,
+        block: BlockTag,
+    ) -> std::result::Result<Vec<TokenFlag>, String> {
+        let flags = self
+            .detect_flags(H160::from_bytes(&token), BlockTagWrapper(block).into())
+            .await
+            .map_err(|e| e.to_string())?;
+        tracing::debug!(?token, ?flags, "determined token flags");
+        Ok(flags)
+    }
+
+    pub async fn detect_flags(&self, token: H160, block: BlockNumber) -> Result<Vec<TokenFlag>> {
+        let mut flags = self.probe_flags(token, block).await?;
+
+        let (holder, balance) = match self
+            .finder
+            .find_owner(token.to_bytes(), MIN_AMOUNT.into())
+            .await
+            .map_err(|e| anyhow!(e.to_string()))?
+        {
+            Some((holder, balance)) => (H160::from_bytes(&holder), U256::from_bytes(&balance)),
+            None => return Ok(flags),
+        };
+
+        // Compare the fees of two transfers whose amounts differ by two orders of magnitude.
+        let amount = cmp::max(balance / 2, MIN_AMOUNT.into());
+        let small_amount = cmp::max(amount / 100, MIN_AMOUNT.into());
+        let fee = self
+            .transfer_fee(token, holder, amount, block)
+            .await?;
+        let small_fee = self
+            .transfer_fee(token, holder, small_amount, block)
+            .await?;
+        if fee
+            .iter()
+            .chain(small_fee.iter())
+            .any(|fee| !fee.is_zero())
+        {
+            flags.push(TokenFlag::FeeOnTransfer);
+        }
+        if let (Some(fee), Some(small_fee)) = (fee, small_fee) {
+            if is_amount_dependent(fee, small_fee) {
+                flags.push(TokenFlag::AmountDependentTax);
+            }
+        }
+
+        if !flags.contains(&TokenFlag::Rebasing) {
+            match self
+                .has_untracked_balance_change(token, holder, block)
+                .await
+            {
+                Ok(true) => flags.push(TokenFlag::Rebasing),
+                Ok(false) => {}
+                Err(error) => tracing::debug!(?token, ?error, "rebase detection failed"),
+            }
+        }
+        Ok(flags)
+    }
+
+    /// Calls the view functions in `FLAG_PROBES` and returns the flags of the ones that exist.
+    async fn probe_flags(&self, token: H160, block: BlockNumber) -> Result<Vec<TokenFlag>> {
+        let requests = FLAG_PROBES
+            .iter()
+            .map(|(signature, _)| {
+                let mut data = keccak256(signature.as_bytes())[..4].to_vec();
+                if signature.ends_with("(address)") {
+                    data.extend_from_slice(&[0u8; 12]);
+                    data.extend_from_slice(self.settlement_contract.as_bytes());
+                }
+                CallRequest { to: Some(token), data: Some(data.into()), ..Default::default() }
+            })
+            .collect();
+        let traces = self.trace_many(requests, block).await?;
+        ensure!(traces.len() == FLAG_PROBES.len(), "unexpected number of traces");
+
+        let mut flags = Vec::new();
+        for ((_, flag), trace) in FLAG_PROBES.iter().zip(&traces) {
+            let exists = matches!(ensure_transaction_ok_and_get_gas(trace)?, Ok(_)) &&
+                trace.output.0.len() == 32;
+            if exists && !flags.contains(flag) {
+                flags.push(*flag);
+            }
+        }
+        Ok(flags)
+    }
+
+    /// Simulates a transfer of `amount` from `take_from` into the settlement contract and
+    /// returns the fee in basis points. `None` if the transfer fails or the balances can't be
+    /// decoded.
+    async fn transfer_fee(
+        &self,
+        token: H160,
+        take_from: H160,
+        amount: U256,
+        block: BlockNumber,
+    ) -> Result<Option<U256>> {
+        let request =
+            self.create_trace_request(token, amount, take_from, TraceRequestType::SimpleTransfer);
+        let traces = self.trace_many(request, block).await?;
+        ensure!(traces.len() == 4, "unexpected number of traces");
+        if ensure_transaction_ok_and_get_gas(&traces[1])?.is_err() {
+            return Ok(None);
+        }
+        Ok(decode_u256(&traces[0])
+            .zip(decode_u256(&traces[2]))
+            .and_then(|(before, after)| transfer_fee_bps(amount, before, after)))
+    }
+
+    /// Compares the change of `holder`'s balance over the last `REBASE_LOOKBACK_BLOCKS` blocks
+    /// to the amounts transferred from and to it.
+    async fn has_untracked_balance_change(
+        &self,
+        token: H160,
+        holder: H160,
+        block: BlockNumber,
+    ) -> Result<bool> {
+        let to_block = match block {
+            BlockNumber::Number(number) => number.as_u64(),
+            _ => {
+                self.count_request();
+                self.web3
+                    .eth()
+                    .block_number()
+                    .await?
+                    .as_u64()
+            }
+        };
+        let from_block = to_block.saturating_sub(REBASE_LOOKBACK_BLOCKS);
+        let balance_before = self
+            .balance_at(token, holder, from_block)
+            .await?;
+        let balance_after = self
+            .balance_at(token, holder, to_block)
+            .await?;
+
+        let transfer_topic = H256(keccak256(b"Transfer(address,address,uint256)"));
+        let holder_topic = H256::from(holder);
+        let filter = |from: Option<Vec<H256>>, to: Option<Vec<H256>>| {
+            FilterBuilder::default()
+                .address(vec![token])
+                .from_block(BlockNumber::Number((from_block + 1).into()))
+                .to_block(BlockNumber::Number(to_block.into()))
+                .topics(Some(vec![transfer_topic]), from, to, None)
+                .build()
+        };
+        self.count_request();
+        let sent = self
+            .web3
+            .eth()
+            .logs(filter(Some(vec![holder_topic]), None))
+            .await?;
+        self.count_request();
+        let received = self
+            .web3
+            .eth()
+            .logs(filter(None, Some(vec![holder_topic])))
+            .await?;
+
+        Ok(is_rebasing(
+            balance_before,
+            balance_after,
+            sum_transfers(&received),
+            sum_transfers(&sent),
+        ))
+    }
+
+    async fn balance_at(&self, token: H160, holder: H160, block: u64) -> Result<U256> {
+        let tx = ERC20::at(&self.web3, token)
+            .balance_of(holder)
+            .m
+            .tx;
+        self.count_request();
+        let output = self
+            .web3
+            .eth()
+            .call(
+                call_request(None, token, tx),
+                Some(BlockId::Number(BlockNumber::Number(block.into()))),
+            )
+            .await?;
+        ensure!(output.0.len() == 32, "balanceOf did not return 32 bytes");
+        Ok(U256::from_big_endian(&output.0))
+    }
+
     // For the out transfer we use an arbitrary address without balance to detect
     // tokens that usually apply fees but not if the the sender or receiver is
     // specifically exempt like their own uniswap pools.
-    fn arbitrary_recipient() -> H160 {
+    pub(crate) fn arbitrary_recipient() -> H160 {
         PrivateKey::from_raw(keccak256(b"propeller"))
             .unwrap()
             .public_address()
@@ -224,15 +469,17 @@ impl TraceCallDetector {
         }
     }
 
-    fn handle_response(
-        traces: &[BlockTrace],
+    /// Evaluates the results of the calls of a double transfer request, see
+    /// `create_trace_request` for their order.
+    pub(crate) fn handle_response<T: SimulatedCall>(
+        traces: &[T],
         amount: U256,
         middle_amount: U256,
         take_from: H160,
     ) -> Result<(TokenQuality, Option<U256>, Option<U256>)> {
         ensure!(traces.len() == 8, "unexpected number of traces");
 
-        let gas_in = match ensure_transaction_ok_and_get_gas(&traces[1])? {
+        let gas_in = match traces[1].gas_used()? {
             Ok(gas) => gas,
             Err(reason) => {
                 return Ok((
@@ -246,7 +493,7 @@ impl TraceCallDetector {
             }
         };
         let arbitrary = Self::arbitrary_recipient();
-        let gas_out = match ensure_transaction_ok_and_get_gas(&traces[4])? {
+        let gas_out = match traces[4].gas_used()? {
             Ok(gas) => gas,
             Err(reason) => {
                 return Ok((
@@ -270,23 +517,23 @@ impl TraceCallDetector {
             information.\
         ";
         let bad = TokenQuality::Bad { reason: message.to_string() };
-        let balance_before_in = match decode_u256(&traces[0]) {
+        let balance_before_in = match traces[0].output_u256() {
             Some(balance) => balance,
             None => return Ok((bad, Some(gas_per_transfer), None)),
         };
-        let balance_after_in = match decode_u256(&traces[2]) {
+        let balance_after_in = match traces[2].output_u256() {
             Some(balance) => balance,
             None => return Ok((bad, Some(gas_per_transfer), None)),
         };
-        let balance_after_out = match decode_u256(&traces[5]) {
+        let balance_after_out = match traces[5].output_u256() {
             Some(balance) => balance,
             None => return Ok((bad, Some(gas_per_transfer), None)),
         };
-        let balance_recipient_before = match decode_u256(&traces[3]) {
+        let balance_recipient_before = match traces[3].output_u256() {
             Some(balance) => balance,
             None => return Ok((bad, Some(gas_per_transfer), None)),
         };
-        let balance_recipient_after = match decode_u256(&traces[6]) {
+        let balance_recipient_after = match traces[6].output_u256() {
             Some(balance) => balance,
             None => return Ok((bad, Some(gas_per_transfer), None)),
         };
@@ -380,7 +627,7 @@ impl TraceCallDetector {
             ));
         }
 
-        if let Err(err) = ensure_transaction_ok_and_get_gas(&traces[7])? {
+        if let Err(err) = traces[7].gas_used()? {
             return Ok((
                 TokenQuality::bad(format!("Approval of U256::MAX failed: {err}")),
                 Some(gas_per_transfer),
@@ -471,6 +718,35 @@ fn error_mul(a: U256, b: U256) -> Result<U256, anyhow::Error> {
         .ok_or_else(|| anyhow::format_err!("overflow"))
 }
 
+/// Fee of a transfer of `amount` in basis points, given the recipient's balance before and
+/// after. `None` if the recipient received more than `amount`.
+fn transfer_fee_bps(amount: U256, balance_before: U256, balance_after: U256) -> Option<U256> {
+    let received = balance_after.checked_sub(balance_before)?;
+    let fee = amount.checked_sub(received)?;
+    fee.checked_mul(U256::from(10_000))?
+        .checked_div(amount)
+}
+
+/// Whether two transfer fees in basis points differ by more than the rounding error of 1 bps.
+fn is_amount_dependent(fee: U256, other_fee: U256) -> bool {
+    cmp::max(fee, other_fee) - cmp::min(fee, other_fee) > U256::one()
+}
+
+/// Whether a balance changed by a different amount than the net amount transferred.
+fn is_rebasing(balance_before: U256, balance_after: U256, received: U256, sent: U256) -> bool {
+    balance_before
+        .checked_add(received)
+        .and_then(|balance| balance.checked_sub(sent)) !=
+        Some(balance_after)
+}
+
+/// Sums the amounts of `Transfer` logs.
+fn sum_transfers(logs: &[Log]) -> U256 {
+    logs.iter()
+        .filter(|log| log.data.0.len() == 32)
+        .fold(U256::zero(), |sum, log| sum.saturating_add(U256::from_big_endian(&log.data.0)))
+}
+
 fn call_request(
     from: Option<H160>,
     to: H160,
@@ -480,6 +756,26 @@ fn call_request(
     CallRequest { from, to: Some(to), data: Some(calldata), ..Default::default() }
 }
 
+/// The result of a single call of a transfer simulation.
+pub(crate) trait SimulatedCall {
+    /// The outer result signals a failure to obtain the result. The inner result is the gas used
+    /// or the reason the call failed.
+    fn gas_used(&self) -> Result<Result<U256, String>>;
+
+    /// Returns none if the call output is not 32 bytes long.
+    fn output_u256(&self) -> Option<U256>;
+}
+
+impl SimulatedCall for BlockTrace {
+    fn gas_used(&self) -> Result<Result<U256, String>> {
+        ensure_transaction_ok_and_get_gas(self)
+    }
+
+    fn output_u256(&self) -> Option<U256> {
+        decode_u256(self)
+    }
+}
+
 /// Returns none if the length of the bytes in the trace output is not 32.
 fn decode_u256(trace: &BlockTrace) -> Option<U256> {
     let bytes = trace.output.0.as_slice();
@@ -508,3 +804,28 @@ fn ensure_transaction_ok_and_get_gas(trace: &BlockTrace) -> Result<Result<U256,
     };
     Ok(Ok(call_result.gas_used))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfer_fee_bps() {
+        assert_eq!(transfer_fee_bps(100_000.into(), 10.into(), 95_010.into()), Some(500.into()));
+        assert_eq!(transfer_fee_bps(100_000.into(), 0.into(), 100_000.into()), Some(0.into()));
+        assert_eq!(transfer_fee_bps(100_000.into(), 0.into(), 100_001.into()), None);
+    }
+
+    #[test]
+    fn test_is_amount_dependent() {
+        assert!(!is_amount_dependent(500.into(), 499.into()));
+        assert!(is_amount_dependent(100.into(), 500.into()));
+    }
+
+    #[test]
+    fn test_is_rebasing() {
+        assert!(!is_rebasing(100.into(), 150.into(), 70.into(), 20.into()));
+        assert!(is_rebasing(100.into(), 151.into(), 70.into(), 20.into()));
+        assert!(is_rebasing(100.into(), 0.into(), 0.into(), 200.into()));
+    }
+}
@@ -0,0 +1,503 @@
+//! Fetching and decoding of ERC-20 token metadata.
+//!
+//! Metadata is fetched in Multicall3 batches. Decoding is tolerant towards tokens that deviate
+//! from the standard: `bytes32` symbols (e.g. MKR), reverting or missing functions and proxies
+//! whose metadata can only be read by running the implementation's code directly on the proxy.
+//! Every deviation is recorded as a [`MetadataFallback`] on the resulting [`TokenMetadata`].
+
+use ethers::{
+    abi::{self, Abi, ParamType, Token},
+    providers::Middleware,
+    types::{spoof, BlockId, H160, H256, U256},
+};
+use futures03::future::join_all;
+use tracing::{debug, warn};
+use tycho_core::models::{token::MetadataFallback, Chain};
+
+use super::multicall::{self, Call};
+
+/// The ERC-20 view functions queried for each token, in the order they are batched.
+const METADATA_FUNCTIONS: [&str; 4] = ["symbol", "decimals", "name", "totalSupply"];
+
+/// Default number of tokens whose metadata is fetched in a single Multicall3 batch.
+pub const DEFAULT_MULTICALL_BATCH_SIZE: usize = 100;
+
+/// Decimals assumed for tokens that don't implement `decimals()`.
+const DEFAULT_DECIMALS: u8 = 18;
+
+/// Storage slot of the implementation address of EIP-1967 proxies:
+/// `bytes32(uint256(keccak256('eip1967.proxy.implementation')) - 1)`.
+const EIP1967_IMPLEMENTATION_SLOT: H256 = H256([
+    0x36, 0x08, 0x94, 0xa1, 0x3b, 0xa1, 0xa3, 0x21, 0x06, 0x67, 0xc8, 0x28, 0x49, 0x2d, 0xb9, 0x8d,
+    0xca, 0x3e, 0x20, 0x76, 0xcc, 0x37, 0x35, 0xa9, 0x33, 0xe2, 0xcc, 0x2e, 0xa3, 0xd1, 0xee, 0x1d,
+]);
+
+/// Runtime bytecode of EIP-1167 minimal proxies, split around the implementation address.
+const EIP1167_PREFIX: [u8; 10] = [0x36, 0x3d, 0x3d, 0x37, 0x3d, 0x3d, 0x3d, 0x36, 0x3d, 0x73];
+const EIP1167_SUFFIX: [u8; 15] =
+    [0x5a, 0xf4, 0x3d, 0x82, 0x80, 0x3e, 0x90, 0x3d, 0x91, 0x60, 0x2b, 0x57, 0xfd, 0x5b, 0xf3];
+
+/// Metadata of an ERC-20 token, with fallbacks applied for values that could not be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenMetadata {
+    pub symbol: String,
+    pub decimals: u8,
+    pub name: Option<String>,
+    pub total_supply: Option<U256>,
+    pub fallbacks: Vec<MetadataFallback>,
+}
+
+impl TokenMetadata {
+    /// Builds the metadata of `token` from the raw return data of its metadata calls, in
+    /// `METADATA_FUNCTIONS` order. `None` marks a call that reverted.
+    pub fn from_call_results(token: H160, outputs: &[Option<Vec<u8>>]) -> Self {
+        RawMetadata::decode(outputs).resolve(token)
+    }
+}
+
+/// Decoded metadata before any fallback value is applied.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct RawMetadata {
+    symbol: Option<String>,
+    decimals: Option<u8>,
+    name: Option<String>,
+    total_supply: Option<U256>,
+    fallbacks: Vec<MetadataFallback>,
+}
+
+impl RawMetadata {
+    fn decode(outputs: &[Option<Vec<u8>>]) -> Self {
+        let output = |idx: usize| {
+            outputs
+                .get(idx)
+                .and_then(Option::as_deref)
+        };
+        let mut metadata = Self {
+            symbol: None,
+            decimals: output(1).and_then(decode_decimals),
+            name: output(2)
+                .and_then(decode_string)
+                .map(|(name, _)| name),
+            total_supply: output(3).and_then(decode_uint),
+            fallbacks: Vec::new(),
+        };
+        if let Some((symbol, is_bytes32)) = output(0).and_then(decode_string) {
+            if is_bytes32 {
+                metadata
+                    .fallbacks
+                    .push(MetadataFallback::Bytes32Symbol);
+            }
+            metadata.symbol = Some(symbol);
+        }
+        metadata
+    }
+
+    /// Whether the values required to index the token could be read.
+    fn is_complete(&self) -> bool {
+        self.symbol.is_some() && self.decimals.is_some()
+    }
+
+    /// Fills the missing values with the ones read through a proxy's implementation.
+    fn merge_implementation(&mut self, implementation: RawMetadata, fallback: MetadataFallback) {
+        if (self.symbol.is_none() && implementation.symbol.is_some()) ||
+            (self.decimals.is_none() && implementation.decimals.is_some())
+        {
+            self.fallbacks.push(fallback);
+        }
+        if self.symbol.is_none() && implementation.symbol.is_some() {
+            self.symbol = implementation.symbol;
+            self.fallbacks
+                .extend(implementation.fallbacks);
+        }
+        self.decimals = self
+            .decimals
+            .or(implementation.decimals);
+        self.name = self.name.take().or(implementation.name);
+        self.total_supply = self
+            .total_supply
+            .or(implementation.total_supply);
+    }
+
+    fn resolve(self, token: H160) -> TokenMetadata {
+        let mut fallbacks = self.fallbacks;
+        let symbol = match (self.symbol, &self.name) {
+            (Some(symbol), _) => symbol,
+            (None, Some(name)) => {
+                fallbacks.push(MetadataFallback::NameAsSymbol);
+                name.clone()
+            }
+            (None, None) => {
+                fallbacks.push(MetadataFallback::AddressAsSymbol);
+                format!("{token:#x}")
+            }
+        };
+        let decimals = self.decimals.unwrap_or_else(|| {
+            fallbacks.push(MetadataFallback::DefaultDecimals);
+            DEFAULT_DECIMALS
+        });
+        TokenMetadata {
+            symbol,
+            decimals,
+            name: self.name,
+            total_supply: self.total_supply,
+            fallbacks,
+        }
+    }
+}
+
+/// Decodes a `string` or `bytes32` return value. Returns the decoded value and whether it was
+/// encoded as `bytes32`. Empty values are treated as missing.
+fn decode_string(data: &[u8]) -> Option<(String, bool)> {
+    let (value, is_bytes32) = match abi::decode(&[ParamType::String], data)
+        .ok()
+        .and_then(|mut tokens| tokens.pop())
+    {
+        Some(Token::String(value)) => (value, false),
+        _ if data.len() >= 32 => {
+            let bytes = &data[..32];
+            let end = bytes
+                .iter()
+                .rposition(|b| *b != 0)
+                .map_or(0, |pos| pos + 1);
+            (String::from_utf8(bytes[..end].to_vec()).ok()?, true)
+        }
+        _ => return None,
+    };
+    let value = value.replace('\0', "");
+    let value = value.trim();
+    (!value.is_empty()).then(|| (value.to_string(), is_bytes32))
+}
+
+fn decode_uint(data: &[u8]) -> Option<U256> {
+    match abi::decode(&[ParamType::Uint(256)], data)
+        .ok()?
+        .pop()?
+    {
+        Token::Uint(value) => Some(value),
+        _ => None,
+    }
+}
+
+fn decode_decimals(data: &[u8]) -> Option<u8> {
+    decode_uint(data).and_then(|decimals| u8::try_from(decimals).ok())
+}
+
+/// Extracts the implementation address from the value of the EIP-1967 implementation slot.
+fn eip1967_implementation(slot_value: H256) -> Option<H160> {
+    let implementation = H160::from_slice(&slot_value[12..]);
+    (!implementation.is_zero()).then_some(implementation)
+}
+
+/// Extracts the implementation address from the runtime bytecode of an EIP-1167 minimal proxy.
+fn eip1167_implementation(code: &[u8]) -> Option<H160> {
+    let address = code
+        .strip_prefix(EIP1167_PREFIX.as_slice())?
+        .strip_suffix(EIP1167_SUFFIX.as_slice())?;
+    (address.len() == 20).then(|| H160::from_slice(address))
+}
+
+fn metadata_calls(abi: &Abi, token: H160) -> Vec<Call> {
+    METADATA_FUNCTIONS
+        .iter()
+        .map(|name| {
+            let data = abi
+                .function(name)
+                .and_then(|f| f.encode_input(&[]))
+                .expect("ERC20 ABI is missing metadata functions");
+            Call::new(token, data)
+        })
+        .collect()
+}
+
+/// Fetches the metadata of `tokens` on `chain` at `block`, returned in the same order as
+/// `tokens`.
+///
+/// Tokens are queried in Multicall3 batches of `batch_size` tokens. If a batch fails as a
+/// whole, e.g. because Multicall3 is not deployed on `chain`, or if a token's `symbol` or
+/// `decimals` call fails within a batch (e.g. because another call in the batch consumed all
+/// the gas), the affected tokens are queried again with individual calls. If that still fails and
+/// the token is a proxy, the missing values are read by executing the implementation's code against
+/// the proxy's storage.
+pub async fn fetch_metadata<M: Middleware>(
+    client: &M,
+    chain: Chain,
+    abi: &Abi,
+    tokens: &[H160],
+    block: Option<BlockId>,
+    batch_size: usize,
+) -> Vec<TokenMetadata> {
+    let mut metadata = Vec::with_capacity(tokens.len());
+    for chunk in tokens.chunks(batch_size.max(1)) {
+        let calls: Vec<Call> = chunk
+            .iter()
+            .flat_map(|token| metadata_calls(abi, *token))
+            .collect();
+        let outputs = multicall::aggregate(client, chain, &calls, block)
+            .await
+            .unwrap_or_else(|e| {
+                warn!(error=?e, n_tokens=chunk.len(), "MulticallFailed");
+                vec![None; calls.len()]
+            });
+
+        for (token, outputs) in chunk
+            .iter()
+            .zip(outputs.chunks(METADATA_FUNCTIONS.len()))
+        {
+            let mut token_metadata = RawMetadata::decode(outputs);
+            if !token_metadata.is_complete() {
+                debug!(?token, "Fetching token metadata with individual calls");
+                token_metadata = fetch_single(client, abi, *token, block).await;
+            }
+            if !token_metadata.is_complete() {
+                read_from_implementation(client, abi, *token, block, &mut token_metadata).await;
+            }
+            metadata.push(token_metadata.resolve(*token));
+        }
+    }
+    metadata
+}
+
+/// Fetches the metadata of a single token without going through Multicall3.
+async fn fetch_single<M: Middleware>(
+    client: &M,
+    abi: &Abi,
+    token: H160,
+    block: Option<BlockId>,
+) -> RawMetadata {
+    let calls = metadata_calls(abi, token);
+    let outputs = join_all(
+        calls
+            .iter()
+            .map(|call| multicall::call(client, call, block)),
+    )
+    .await;
+    RawMetadata::decode(&outputs)
+}
+
+/// Detects whether `token` is an EIP-1967 or EIP-1167 proxy and, if so, fills the missing
+/// metadata with the values returned when the implementation's code runs on the proxy.
+///
+/// The metadata calls are sent to the proxy with its code overridden by the implementation's
+/// code, so the values are read from the proxy's storage, where they live, while bypassing
+/// the proxy's own dispatch logic (e.g. admin restrictions of transparent proxies).
+async fn read_from_implementation<M: Middleware>(
+    client: &M,
+    abi: &Abi,
+    token: H160,
+    block: Option<BlockId>,
+    metadata: &mut RawMetadata,
+) {
+    let eip1967 = client
+        .get_storage_at(token, EIP1967_IMPLEMENTATION_SLOT, block)
+        .await
+        .ok()
+        .and_then(eip1967_implementation)
+        .map(|implementation| (implementation, MetadataFallback::Eip1967Implementation));
+    let proxy = match eip1967 {
+        Some(proxy) => Some(proxy),
+        None => client
+            .get_code(token, block)
+            .await
+            .ok()
+            .and_then(|code| eip1167_implementation(&code))
+            .map(|implementation| (implementation, MetadataFallback::Eip1167Implementation)),
+    };
+    let Some((implementation, fallback)) = proxy else {
+        return;
+    };
+    let code = match client
+        .get_code(implementation, block)
+        .await
+    {
+        Ok(code) if !code.is_empty() => code,
+        Ok(_) => {
+            debug!(?token, ?implementation, "Proxy implementation has no code");
+            return;
+        }
+        Err(e) => {
+            warn!(?token, ?implementation, error=?e, "Failed to fetch proxy implementation code");
+            return;
+        }
+    };
+    debug!(?token, ?implementation, %fallback, "Reading token metadata through implementation");
+    let state = spoof::code(token, code);
+    let calls = metadata_calls(abi, token);
+    let outputs = join_all(
+        calls
+            .iter()
+            .map(|call| multicall::call_with_state(client, call, block, &state)),
+    )
+    .await;
+    metadata.merge_implementation(RawMetadata::decode(&outputs), fallback);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ethers::{providers::Provider, types::Bytes, utils::hex};
+
+    use super::*;
+
+    const MKR_SYMBOL: &str = "0x4d4b520000000000000000000000000000000000000000000000000000000000";
+
+    fn token() -> H160 {
+        H160::from_str("0x9f8F72aA9304c8B593d555F12eF6589cC3A579A2").unwrap()
+    }
+
+    fn encode_string(value: &str) -> Option<Vec<u8>> {
+        Some(abi::encode(&[Token::String(value.to_string())]))
+    }
+
+    fn encode_uint(value: u64) -> Option<Vec<u8>> {
+        Some(abi::encode(&[Token::Uint(value.into())]))
+    }
+
+    #[test]
+    fn test_standard_token() {
+        let outputs = vec![
+            encode_string("USDC"),
+            encode_uint(6),
+            encode_string("USD Coin"),
+            encode_uint(1000),
+        ];
+
+        let res = TokenMetadata::from_call_results(token(), &outputs);
+
+        assert_eq!(
+            res,
+            TokenMetadata {
+                symbol: "USDC".to_string(),
+                decimals: 6,
+                name: Some("USD Coin".to_string()),
+                total_supply: Some(1000.into()),
+                fallbacks: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_bytes32_symbol() {
+        let outputs = vec![
+            Some(hex::decode(&MKR_SYMBOL[2..]).unwrap()),
+            encode_uint(18),
+            Some(hex::decode(&MKR_SYMBOL[2..]).unwrap()),
+            None,
+        ];
+
+        let res = TokenMetadata::from_call_results(token(), &outputs);
+
+        assert_eq!(res.symbol, "MKR");
+        assert_eq!(res.decimals, 18);
+        assert_eq!(res.name, Some("MKR".to_string()));
+        assert_eq!(res.fallbacks, vec![MetadataFallback::Bytes32Symbol]);
+    }
+
+    #[test]
+    fn test_name_as_symbol() {
+        let outputs = vec![None, encode_uint(8), encode_string("Wrapped BTC"), None];
+
+        let res = TokenMetadata::from_call_results(token(), &outputs);
+
+        assert_eq!(res.symbol, "Wrapped BTC");
+        assert_eq!(res.fallbacks, vec![MetadataFallback::NameAsSymbol]);
+    }
+
+    #[test]
+    fn test_reverting_metadata() {
+        let outputs = vec![None, Some(vec![]), None, None];
+
+        let res = TokenMetadata::from_call_results(token(), &outputs);
+
+        assert_eq!(res.symbol, "0x9f8f72aa9304c8b593d555f12ef6589cc3a579a2");
+        assert_eq!(res.decimals, 18);
+        assert_eq!(
+            res.fallbacks,
+            vec![MetadataFallback::AddressAsSymbol, MetadataFallback::DefaultDecimals]
+        );
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        // Decimals out of range, empty symbol and a bytes32 name that is not valid UTF-8.
+        let outputs = vec![encode_string("\0"), encode_uint(256), Some(vec![0xff; 32]), None];
+
+        let res = TokenMetadata::from_call_results(token(), &outputs);
+
+        assert_eq!(res.name, None);
+        assert_eq!(
+            res.fallbacks,
+            vec![MetadataFallback::AddressAsSymbol, MetadataFallback::DefaultDecimals]
+        );
+    }
+
+    #[test]
+    fn test_merge_implementation() {
+        let mut proxy = RawMetadata::decode(&[None, None, None, encode_uint(5)]);
+        let implementation =
+            RawMetadata::decode(&[encode_string("ABC"), encode_uint(6), None, encode_uint(0)]);
+
+        proxy.merge_implementation(implementation, MetadataFallback::Eip1167Implementation);
+        let res = proxy.resolve(token());
+
+        assert_eq!(res.symbol, "ABC");
+        assert_eq!(res.decimals, 6);
+        assert_eq!(res.total_supply, Some(5.into()));
+        assert_eq!(res.fallbacks, vec![MetadataFallback::Eip1167Implementation]);
+    }
+
+    #[test]
+    fn test_eip1967_implementation() {
+        let implementation = H160::from_low_u64_be(0xbeef);
+
+        assert_eq!(eip1967_implementation(implementation.into()), Some(implementation));
+        assert_eq!(eip1967_implementation(H256::zero()), None);
+    }
+
+    #[test]
+    fn test_eip1167_implementation() {
+        let implementation = H160::from_low_u64_be(0xbeef);
+        let code =
+            [EIP1167_PREFIX.as_slice(), implementation.as_bytes(), EIP1167_SUFFIX.as_slice()]
+                .concat();
+
+        assert_eq!(eip1167_implementation(&code), Some(implementation));
+        assert_eq!(eip1167_implementation(&code[1..]), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_from_eip1967_implementation() {
+        let (provider, mock) = Provider::mocked();
+        let implementation = H160::from_low_u64_be(0xbeef);
+        // Responses are served in reverse order: storage slot, implementation code, then the
+        // symbol, decimals, name and totalSupply calls.
+        for output in [None::<u64>, None, Some(6)] {
+            let data = output
+                .map(|value| abi::encode(&[Token::Uint(value.into())]))
+                .unwrap_or_default();
+            mock.push::<Bytes, _>(Bytes::from(data))
+                .unwrap();
+        }
+        mock.push::<Bytes, _>(Bytes::from(abi::encode(&[Token::String("ABC".to_string())])))
+            .unwrap();
+        mock.push::<Bytes, _>(Bytes::from(vec![0x60, 0x00]))
+            .unwrap();
+        mock.push::<H256, _>(H256::from(implementation))
+            .unwrap();
+        let abi = abi::parse_abi(&[
+            "function symbol() view returns (string)",
+            "function decimals() view returns (uint8)",
+            "function name() view returns (string)",
+            "function totalSupply() view returns (uint256)",
+        ])
+        .unwrap();
+        let mut metadata = RawMetadata::decode(&[None, None, None, None]);
+
+        read_from_implementation(&provider, &abi, token(), None, &mut metadata).await;
+        let res = metadata.resolve(token());
+
+        assert_eq!(res.symbol, "ABC");
+        assert_eq!(res.decimals, 6);
+        assert_eq!(res.fallbacks, vec![MetadataFallback::Eip1967Implementation]);
+    }
+}
@@ -1,15 +1,23 @@
+pub mod metadata;
+pub mod multicall;
+
 use std::{str::FromStr, sync::Arc};
 
 use async_trait::async_trait;
-use ethers::{abi::Abi, contract::Contract, prelude::Provider, providers::Http, types::H160};
+use ethers::{abi::Abi, prelude::Provider, providers::Http, types::H160};
 use ethrpc::{http::HttpTransport, Web3, Web3Transport};
+use futures03::{stream, StreamExt};
+use metadata::{fetch_metadata, DEFAULT_MULTICALL_BATCH_SIZE};
 use reqwest::Client;
 use serde_json::from_str;
-use tracing::{instrument, warn};
+use tracing::{debug, instrument, warn};
 use tycho_core::{
     models::{
         blockchain::BlockTag,
-        token::{CurrencyToken, TokenQuality},
+        token::{
+            CurrencyToken, MetadataFallback, TokenAnalysis, TokenFlag, TokenQuality, TransferCost,
+            TransferTax,
+        },
         Chain,
     },
     traits::{TokenAnalyzer, TokenOwnerFinding, TokenPreProcessor},
@@ -18,7 +26,104 @@ use tycho_core::{
 use unicode_segmentation::UnicodeSegmentation;
 use url::Url;
 
-use crate::{token_analyzer::trace_call::TraceCallDetector, BytesCodec};
+use crate::{
+    token_analyzer::{simulation::EvmSimulationDetector, trace_call::TraceCallDetector},
+    BlockTagWrapper, BytesCodec,
+};
+
+/// Default number of token analyses that are run concurrently.
+pub const DEFAULT_ANALYSIS_CONCURRENCY: usize = 10;
+
+/// How the transfers of new tokens are simulated to analyze them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum AnalysisMethod {
+    /// Simulate the transfers with `trace_callMany`. Requires a node supporting the trace API.
+    #[default]
+    TraceCall,
+    /// Simulate the transfers in a local EVM against state fetched from the node. Works with
+    /// any node that serves the state of the analyzed block.
+    Simulation,
+}
+
+/// Analyzes tokens with the detector selected by an [`AnalysisMethod`].
+///
+/// Token flags are always detected with the [`TraceCallDetector`], the simulation does not
+/// detect them.
+pub struct MethodAnalyzer {
+    method: AnalysisMethod,
+    trace_call: TraceCallDetector,
+    simulation: EvmSimulationDetector<Provider<Http>>,
+}
+
+impl MethodAnalyzer {
+    pub fn new(
+        method: AnalysisMethod,
+        ethers_client: Arc<Provider<Http>>,
+        web3_client: Web3,
+        finder: Arc<dyn TokenOwnerFinding>,
+    ) -> Self {
+        Self {
+            method,
+            trace_call: TraceCallDetector::with_web3(web3_client, finder.clone()),
+            simulation: EvmSimulationDetector::new(ethers_client, finder),
+        }
+    }
+
+    pub fn new_from_url(
+        method: AnalysisMethod,
+        rpc_url: &str,
+        finder: Arc<dyn TokenOwnerFinding>,
+    ) -> Self {
+        let ethers_client: Provider<Http> =
+            Provider::<Http>::try_from(rpc_url).expect("Error creating HTTP provider");
+        let web3_client = Web3::new(Web3Transport::new(HttpTransport::new(
+            Client::new(),
+            Url::from_str(rpc_url).unwrap(),
+            "transport".to_owned(),
+        )));
+        Self::new(method, Arc::new(ethers_client), web3_client, finder)
+    }
+
+    /// Detects the flags of a token, see [`TraceCallDetector::analyze_flags`].
+    pub async fn analyze_flags(
+        &self,
+        token: Bytes,
+        block: BlockTag,
+    ) -> Result<Vec<TokenFlag>, String> {
+        self.trace_call
+            .analyze_flags(token, block)
+            .await
+    }
+
+    /// Returns the number of RPC requests this analyzer has sent.
+    pub fn rpc_requests(&self) -> u64 {
+        self.trace_call.rpc_requests() + self.simulation.rpc_requests()
+    }
+}
+
+#[async_trait]
+impl TokenAnalyzer for MethodAnalyzer {
+    type Error = String;
+
+    async fn analyze(
+        &self,
+        token: Bytes,
+        block: BlockTag,
+    ) -> Result<(TokenQuality, Option<TransferCost>, Option<TransferTax>), String> {
+        match self.method {
+            AnalysisMethod::TraceCall => {
+                self.trace_call
+                    .analyze(token, block)
+                    .await
+            }
+            AnalysisMethod::Simulation => {
+                self.simulation
+                    .analyze(token, block)
+                    .await
+            }
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct EthereumTokenPreProcessor {
@@ -26,6 +131,10 @@ pub struct EthereumTokenPreProcessor {
     erc20_abi: Abi,
     web3_client: Web3,
     chain: Chain,
+    multicall_batch_size: usize,
+    analysis_concurrency: usize,
+    analysis_method: AnalysisMethod,
+    detect_flags: bool,
 }
 
 const ABI_STR: &str = include_str!("./abi/erc20.json");
@@ -38,6 +147,10 @@ impl EthereumTokenPreProcessor {
             erc20_abi: abi,
             web3_client,
             chain,
+            multicall_batch_size: DEFAULT_MULTICALL_BATCH_SIZE,
+            analysis_concurrency: DEFAULT_ANALYSIS_CONCURRENCY,
+            analysis_method: AnalysisMethod::default(),
+            detect_flags: false,
         }
     }
 
@@ -57,8 +170,40 @@ impl EthereumTokenPreProcessor {
             erc20_abi: abi,
             web3_client,
             chain,
+            multicall_batch_size: DEFAULT_MULTICALL_BATCH_SIZE,
+            analysis_concurrency: DEFAULT_ANALYSIS_CONCURRENCY,
+            analysis_method: AnalysisMethod::default(),
+            detect_flags: false,
         }
     }
+
+    /// Sets the number of tokens whose metadata is fetched in a single Multicall3 batch.
+    pub fn with_multicall_batch_size(mut self, batch_size: usize) -> Self {
+        self.multicall_batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Sets the maximum number of token analyses that run concurrently.
+    pub fn with_analysis_concurrency(mut self, concurrency: usize) -> Self {
+        self.analysis_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Sets how new tokens are analyzed.
+    pub fn with_analysis_method(mut self, method: AnalysisMethod) -> Self {
+        self.analysis_method = method;
+        self
+    }
+
+    /// Sets whether token flags are detected when tokens are added.
+    ///
+    /// Flag detection makes several RPC requests per token on the extraction path, so it is
+    /// disabled by default. New tokens then have no flags until they are analyzed again, e.g.
+    /// by the token analysis scheduler.
+    pub fn with_flag_detection(mut self, detect_flags: bool) -> Self {
+        self.detect_flags = detect_flags;
+        self
+    }
 }
 
 #[async_trait]
@@ -69,50 +214,87 @@ impl TokenPreProcessor for EthereumTokenPreProcessor {
         addresses: Vec<Bytes>,
         token_finder: Arc<dyn TokenOwnerFinding>,
         block: BlockTag,
-    ) -> Vec<CurrencyToken> {
-        let mut tokens_info = Vec::new();
-
-        for address in addresses {
-            let contract = Contract::new(
-                H160::from_bytes(&address),
-                self.erc20_abi.clone(),
-                self.ethers_client.clone(),
-            );
-
-            let symbol = contract
-                .method("symbol", ())
-                .expect("Error preparing request")
-                .call()
-                .await;
-
-            let decimals: Result<u8, _> = contract
-                .method("decimals", ())
-                .expect("Error preparing request")
-                .call()
-                .await;
-
-            let trace_call = TraceCallDetector {
-                web3: self.web3_client.clone(),
-                finder: token_finder.clone(),
-                settlement_contract: H160::from_str("0xc9f2e6ea1637E499406986ac50ddC92401ce1f58") // middle contract used to check for fees, set to cowswap settlement
-                    .unwrap(),
-            };
+    ) -> Vec<(CurrencyToken, TokenAnalysis)> {
+        let token_addresses: Vec<H160> = addresses
+            .iter()
+            .map(H160::from_bytes)
+            .collect();
+        let metadata = fetch_metadata(
+            self.ethers_client.as_ref(),
+            self.chain,
+            &self.erc20_abi,
+            &token_addresses,
+            Some(BlockTagWrapper(block).into()),
+            self.multicall_batch_size,
+        )
+        .await;
 
-            let (token_quality, gas, tax) = trace_call
-                .analyze(address.clone(), block)
-                .await
-                .unwrap_or_else(|e| {
-                    warn!(error=?e, "TokenDetectionFailure");
-                    (TokenQuality::bad("Detection failed"), None, None)
-                });
-
-            let (symbol, decimals, mut quality) = match (symbol, decimals) {
-                (Ok(symbol), Ok(decimals)) => (symbol, decimals, 100),
-                (Ok(symbol), Err(_)) => (symbol, 18, 0),
-                (Err(_), Ok(decimals)) => (address.to_string(), decimals, 0),
-                (Err(_), Err(_)) => (address.to_string(), 18, 0),
+        let analyzer = MethodAnalyzer::new(
+            self.analysis_method,
+            self.ethers_client.clone(),
+            self.web3_client.clone(),
+            token_finder,
+        );
+        let analyses: Vec<_> = stream::iter(addresses.iter().cloned())
+            .map(|address| {
+                let analyzer = &analyzer;
+                async move {
+                    let analysis = analyzer
+                        .analyze(address.clone(), block)
+                        .await;
+                    if let Err(e) = &analysis {
+                        warn!(error=?e, "TokenDetectionFailure");
+                    }
+                    let flags = if self.detect_flags {
+                        analyzer
+                            .analyze_flags(address, block)
+                            .await
+                            .unwrap_or_else(|e| {
+                                warn!(error=?e, "TokenFlagDetectionFailure");
+                                Vec::new()
+                            })
+                    } else {
+                        Vec::new()
+                    };
+                    (analysis, flags)
+                }
+            })
+            .buffered(self.analysis_concurrency)
+            .collect()
+            .await;
+
+        let mut tokens_info = Vec::with_capacity(addresses.len());
+        let block_number = match block {
+            BlockTag::Number(number) => Some(number),
+            _ => None,
+        };
+        for ((address, metadata), (analysis, flags)) in addresses
+            .into_iter()
+            .zip(metadata)
+            .zip(analyses)
+        {
+            // Tokens whose symbol or decimals could not be read onchain are flagged with
+            // quality 0.
+            let mut quality = if metadata
+                .fallbacks
+                .iter()
+                .any(|fallback| {
+                    matches!(
+                        fallback,
+                        MetadataFallback::AddressAsSymbol | MetadataFallback::DefaultDecimals
+                    )
+                }) {
+                0
+            } else {
+                100
             };
+            if !metadata.fallbacks.is_empty() {
+                debug!(?address, fallbacks=?metadata.fallbacks, "NonStandardTokenMetadata");
+            }
 
+            let (token_quality, gas, tax) = analysis
+                .clone()
+                .unwrap_or_else(|_| (TokenQuality::bad("Detection failed"), None, None));
             if let TokenQuality::Bad { reason } = token_quality {
                 warn!(address=?address, ?reason, "BadToken");
                 // Flag this token as bad using quality, an external script is responsible for
@@ -125,21 +307,32 @@ impl TokenPreProcessor for EthereumTokenPreProcessor {
                 quality = 50;
             }
 
-            tokens_info.push(CurrencyToken {
+            // If quality is 100 but it's a rebase token, set quality to 75
+            if quality == 100 && flags.contains(&TokenFlag::Rebasing) {
+                quality = 75;
+            }
+
+            let analysis =
+                TokenAnalysis::new(&address, self.chain, block_number, &analysis, quality);
+            let token = CurrencyToken {
                 address,
-                symbol: symbol
+                symbol: metadata
+                    .symbol
                     .replace('\0', "")
                     .graphemes(true)
                     .take(255)
                     .collect::<String>(),
-                decimals: decimals.into(),
+                decimals: metadata.decimals.into(),
                 tax: tax.unwrap_or(0),
                 gas: gas
                     .map(|g| vec![Some(g)])
                     .unwrap_or_else(Vec::new),
                 chain: self.chain,
                 quality,
-            });
+                metadata_fallbacks: metadata.fallbacks,
+                flags,
+            };
+            tokens_info.push((token, analysis));
         }
 
         tokens_info
@@ -188,7 +381,7 @@ mod tests {
         assert_eq!(results.len(), 3);
         let relevant_attrs: Vec<(String, u32, u32)> = results
             .iter()
-            .map(|t| (t.symbol.clone(), t.decimals, t.quality))
+            .map(|(t, _)| (t.symbol.clone(), t.decimals, t.quality))
             .collect();
         assert_eq!(
             relevant_attrs,
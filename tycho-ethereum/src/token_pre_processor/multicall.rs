@@ -0,0 +1,220 @@
+//! Batching of read-only contract calls through the Multicall3 contract.
+//!
+//! Calls are sent with `allowFailure = true`, so a single reverting call does not fail the
+//! whole batch. Results are returned as raw return data to leave decoding to the caller.
+
+use anyhow::{anyhow, bail, ensure, Result};
+use ethers::{
+    abi::{self, ParamType, Token},
+    providers::{call_raw::RawCall, Middleware},
+    types::{spoof, transaction::eip2718::TypedTransaction, BlockId, TransactionRequest, H160},
+};
+use tycho_core::models::Chain;
+
+/// Canonical address of the Multicall3 contract, used by chains that support the deterministic
+/// deployment proxy.
+pub const MULTICALL3_ADDRESS: H160 = H160([
+    0xca, 0x11, 0xbd, 0xe0, 0x59, 0x77, 0xb3, 0x63, 0x11, 0x67, 0x02, 0x88, 0x62, 0xbe, 0x2a, 0x17,
+    0x39, 0x76, 0xca, 0x11,
+]);
+
+/// Address of the Multicall3 contract on zkSync Era, which derives contract addresses
+/// differently and therefore can't use the canonical deployment.
+pub const ZKSYNC_MULTICALL3_ADDRESS: H160 = H160([
+    0xf9, 0xcd, 0xa6, 0x24, 0xfb, 0xc7, 0xe0, 0x59, 0x35, 0x5c, 0xe9, 0x8a, 0x31, 0x69, 0x3d, 0x29,
+    0x9f, 0xac, 0xd9, 0x63,
+]);
+
+/// Returns the address of the Multicall3 contract on `chain`, or `None` if it has no known
+/// deployment there.
+pub fn multicall3_address(chain: Chain) -> Option<H160> {
+    match chain {
+        Chain::Ethereum | Chain::Arbitrum | Chain::Base => Some(MULTICALL3_ADDRESS),
+        Chain::ZkSync => Some(ZKSYNC_MULTICALL3_ADDRESS),
+        Chain::Starknet => None,
+    }
+}
+
+/// A single read-only call to be batched.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub target: H160,
+    pub data: Vec<u8>,
+}
+
+impl Call {
+    pub fn new(target: H160, data: Vec<u8>) -> Self {
+        Self { target, data }
+    }
+}
+
+fn call_params() -> ParamType {
+    ParamType::Array(Box::new(ParamType::Tuple(vec![
+        ParamType::Address,
+        ParamType::Bool,
+        ParamType::Bytes,
+    ])))
+}
+
+fn result_params() -> ParamType {
+    ParamType::Array(Box::new(ParamType::Tuple(vec![ParamType::Bool, ParamType::Bytes])))
+}
+
+/// Encodes the calldata of `aggregate3((address,bool,bytes)[])`.
+pub fn encode_aggregate3(calls: &[Call]) -> Vec<u8> {
+    let selector = abi::short_signature("aggregate3", &[call_params()]);
+    let calls = calls
+        .iter()
+        .map(|call| {
+            Token::Tuple(vec![
+                Token::Address(call.target),
+                Token::Bool(true),
+                Token::Bytes(call.data.clone()),
+            ])
+        })
+        .collect();
+    [selector.as_slice(), &abi::encode(&[Token::Array(calls)])].concat()
+}
+
+/// Decodes the output of `aggregate3`. Returns the return data of each call, or `None` if the
+/// call failed.
+pub fn decode_aggregate3(data: &[u8], n_calls: usize) -> Result<Vec<Option<Vec<u8>>>> {
+    let results = match abi::decode(&[result_params()], data)?.pop() {
+        Some(Token::Array(results)) => results,
+        other => bail!("Unexpected aggregate3 output: {other:?}"),
+    };
+    ensure!(
+        results.len() == n_calls,
+        "aggregate3 returned {} results for {} calls",
+        results.len(),
+        n_calls
+    );
+    results
+        .into_iter()
+        .map(|result| match result {
+            Token::Tuple(values) => match values.as_slice() {
+                [Token::Bool(true), Token::Bytes(data)] => Ok(Some(data.clone())),
+                [Token::Bool(false), Token::Bytes(_)] => Ok(None),
+                other => Err(anyhow!("Unexpected aggregate3 result: {other:?}")),
+            },
+            other => Err(anyhow!("Unexpected aggregate3 result: {other:?}")),
+        })
+        .collect()
+}
+
+/// Executes all calls in a single `eth_call` to the Multicall3 contract of `chain`.
+///
+/// Fails only if the batch as a whole fails, e.g. if Multicall3 is not deployed on `chain` or at
+/// `block` or the node rejects the request. Failures of individual calls are returned as `None`.
+pub async fn aggregate<M: Middleware>(
+    client: &M,
+    chain: Chain,
+    calls: &[Call],
+    block: Option<BlockId>,
+) -> Result<Vec<Option<Vec<u8>>>> {
+    if calls.is_empty() {
+        return Ok(Vec::new());
+    }
+    let multicall = multicall3_address(chain)
+        .ok_or_else(|| anyhow!("Multicall3 is not deployed on {chain}"))?;
+    let tx: TypedTransaction = TransactionRequest::new()
+        .to(multicall)
+        .data(encode_aggregate3(calls))
+        .into();
+    let output = client
+        .call(&tx, block)
+        .await
+        .map_err(|e| anyhow!("Multicall failed: {e}"))?;
+    decode_aggregate3(&output, calls.len())
+}
+
+/// Executes a single call without going through Multicall3. Returns `None` if the call fails.
+pub async fn call<M: Middleware>(
+    client: &M,
+    call: &Call,
+    block: Option<BlockId>,
+) -> Option<Vec<u8>> {
+    let tx: TypedTransaction = TransactionRequest::new()
+        .to(call.target)
+        .data(call.data.clone())
+        .into();
+    client
+        .call(&tx, block)
+        .await
+        .ok()
+        .map(|output| output.to_vec())
+}
+
+/// Executes a single call with the given state overrides applied. Returns `None` if the call
+/// fails or the node does not support state overrides.
+pub async fn call_with_state<M: Middleware>(
+    client: &M,
+    call: &Call,
+    block: Option<BlockId>,
+    state: &spoof::State,
+) -> Option<Vec<u8>> {
+    let tx: TypedTransaction = TransactionRequest::new()
+        .to(call.target)
+        .data(call.data.clone())
+        .into();
+    let mut raw_call = client
+        .provider()
+        .call_raw(&tx)
+        .state(state);
+    if let Some(block) = block {
+        raw_call = raw_call.block(block);
+    }
+    raw_call
+        .await
+        .ok()
+        .map(|output| output.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_multicall3_address() {
+        let canonical = H160::from_str("0xcA11bde05977b3631167028862bE2a173976CA11").unwrap();
+        let zksync = H160::from_str("0xF9cda624FBC7e059355ce98a31693d299FACd963").unwrap();
+
+        assert_eq!(multicall3_address(Chain::Ethereum), Some(canonical));
+        assert_eq!(multicall3_address(Chain::Arbitrum), Some(canonical));
+        assert_eq!(multicall3_address(Chain::Base), Some(canonical));
+        assert_eq!(multicall3_address(Chain::ZkSync), Some(zksync));
+        assert_eq!(multicall3_address(Chain::Starknet), None);
+    }
+
+    #[test]
+    fn test_encode_aggregate3() {
+        let target = H160::from_low_u64_be(1);
+        let encoded = encode_aggregate3(&[Call::new(target, vec![0x95, 0xd8, 0x9b, 0x41])]);
+
+        assert_eq!(encoded[..4], [0x82, 0xad, 0x56, 0xcb]);
+        let decoded = abi::decode(&[call_params()], &encoded[4..]).unwrap();
+        assert_eq!(
+            decoded,
+            vec![Token::Array(vec![Token::Tuple(vec![
+                Token::Address(target),
+                Token::Bool(true),
+                Token::Bytes(vec![0x95, 0xd8, 0x9b, 0x41]),
+            ])])]
+        );
+    }
+
+    #[test]
+    fn test_decode_aggregate3() {
+        let output = abi::encode(&[Token::Array(vec![
+            Token::Tuple(vec![Token::Bool(true), Token::Bytes(vec![1, 2, 3])]),
+            Token::Tuple(vec![Token::Bool(false), Token::Bytes(vec![])]),
+        ])]);
+
+        let res = decode_aggregate3(&output, 2).unwrap();
+
+        assert_eq!(res, vec![Some(vec![1, 2, 3]), None]);
+        assert!(decode_aggregate3(&output, 3).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use clap::{Args, Parser, Subcommand};
 use tycho_core::{models::Chain, Bytes};
+use tycho_ethereum::token_pre_processor::AnalysisMethod;
 
 /// Tycho Indexer using Substreams
 ///
@@ -34,6 +35,20 @@ pub enum Command {
     AnalyzeTokens(AnalyzeTokenArgs),
     /// Starts Tycho RPC only. No extractors.
     Rpc,
+    /// Exports the state of protocol systems at a block into a snapshot file.
+    Export(ExportArgs),
+    /// Imports a snapshot file into the database.
+    Import(ImportArgs),
+    /// Removes a protocol system and all its data from the database.
+    RemoveProtocolSystem(RemoveProtocolSystemArgs),
+    /// Checks the extractors configuration file and reports all problems found.
+    ValidateConfig(ValidateConfigArgs),
+    /// Compares the changes two substreams packages emit over the same block range.
+    DiffSpkg(DiffSpkgArgs),
+    /// Serves recorded substreams responses as a local Substreams endpoint.
+    MockSubstreams(MockSubstreamsArgs),
+    /// Compares a sample of the indexed state against the state reported by a node.
+    CheckConsistency(CheckConsistencyArgs),
 }
 
 #[derive(Parser, Debug, Clone, PartialEq, Eq)]
@@ -68,6 +83,67 @@ pub struct GlobalArgs {
     /// The server version prefix
     #[clap(long, default_value = "v1")]
     pub server_version_prefix: String,
+
+    /// Blocks an extractor may lag behind the chain head before the readiness endpoint reports the
+    /// service as not ready
+    #[clap(long, default_value = "50")]
+    pub max_extractor_lag: u64,
+
+    /// Bearer token for the admin API. The admin endpoints are disabled if not set.
+    #[clap(long, env = "TYCHO_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
+    /// The admin server IP
+    #[clap(long, default_value = "127.0.0.1")]
+    pub admin_server_ip: String,
+
+    /// The admin server port
+    #[clap(long, default_value = "4243")]
+    pub admin_server_port: u16,
+
+    #[clap(flatten)]
+    pub rpc_cache: RpcCacheArgs,
+}
+
+#[derive(Args, Debug, Clone, PartialEq, Eq)]
+pub struct RpcCacheArgs {
+    /// Maximum number of cached token responses
+    #[clap(long, default_value = "50")]
+    pub token_cache_capacity: u64,
+
+    /// Seconds token responses are cached for
+    #[clap(long, default_value = "420")]
+    pub token_cache_ttl: u64,
+
+    /// Maximum number of cached contract state responses
+    #[clap(long, default_value = "50")]
+    pub contract_state_cache_capacity: u64,
+
+    /// Seconds contract state responses at finalized versions are cached for
+    #[clap(long, default_value = "420")]
+    pub contract_state_cache_ttl: u64,
+
+    /// Maximum number of cached protocol state responses
+    #[clap(long, default_value = "50")]
+    pub protocol_state_cache_capacity: u64,
+
+    /// Seconds protocol state responses at finalized versions are cached for
+    #[clap(long, default_value = "420")]
+    pub protocol_state_cache_ttl: u64,
+
+    /// Maximum number of cached protocol component responses
+    #[clap(long, default_value = "500")]
+    pub component_cache_capacity: u64,
+
+    /// Seconds protocol component responses are cached for
+    #[clap(long, default_value = "420")]
+    pub component_cache_ttl: u64,
+
+    /// Seconds state responses at unfinalized versions are cached for at most
+    ///
+    /// These responses are dropped earlier as soon as a new block arrives or a revert happens.
+    #[clap(long, default_value = "60")]
+    pub unfinalized_cache_ttl: u64,
 }
 
 #[derive(Args, Debug, Clone, PartialEq)]
@@ -90,6 +166,11 @@ pub struct IndexArgs {
     #[clap(long, env, default_value = "./extractors.yaml")]
     pub extractors_config: String,
 
+    /// Interval in seconds in which the extractors configuration file is checked for changes.
+    /// Independently of it, the configuration is reloaded on SIGHUP.
+    #[clap(long)]
+    pub extractors_config_poll_interval: Option<u64>,
+
     /// A comma separated list of blockchains to index on
     #[clap(long, default_value = "ethereum", value_delimiter = ',')]
     pub chains: Vec<String>,
@@ -99,6 +180,15 @@ pub struct IndexArgs {
     /// Any data before this date is not kept in storage.
     #[clap(long, env, default_value = "2024-01-01T00:00:00")]
     pub retention_horizon: String,
+
+    /// Directory to export finalized state changes to as Parquet files
+    ///
+    /// Optional. If not provided, no analytics files are written.
+    #[clap(long, env)]
+    pub analytics_dir: Option<String>,
+
+    #[clap(flatten)]
+    pub token_analysis: TokenAnalysisSchedulerArgs,
 }
 
 #[derive(Args, Debug, Clone, PartialEq)]
@@ -141,6 +231,16 @@ pub struct RunSpkgArgs {
     /// Block number to initialize the accounts at
     #[clap(long, default_value = "0")]
     pub initialization_block: i64,
+
+    /// Name of the post processor to apply to the changes
+    #[clap(long)]
+    pub post_processor: Option<String>,
+
+    /// Write the changes of each final block as JSON lines to this file instead of indexing them.
+    ///
+    /// No database is needed. Requires a stop block.
+    #[clap(long)]
+    pub dry_run_output: Option<String>,
 }
 
 impl RunSpkgArgs {
@@ -182,6 +282,187 @@ pub struct AnalyzeTokenArgs {
     pub fetch_batch_size: usize,
 }
 
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct TokenAnalysisSchedulerArgs {
+    /// Continuously re-analyze stored tokens while indexing
+    ///
+    /// Failed tokens are retried with exponential backoff and good tokens are checked again
+    /// periodically.
+    #[clap(long = "analyze-tokens", env = "ANALYZE_TOKENS")]
+    pub enabled: bool,
+    /// Seconds between token analysis rounds.
+    #[clap(long = "token-analysis-interval", default_value = "60")]
+    pub interval_secs: u64,
+    /// Maximum number of RPC requests per minute token analysis may use.
+    #[clap(
+        long = "token-analysis-rpc-budget",
+        env = "TOKEN_ANALYSIS_RPC_BUDGET",
+        default_value = "300"
+    )]
+    pub rpc_budget: u32,
+    /// How many tokens to analyze concurrently.
+    #[clap(long = "token-analysis-concurrency", default_value = "5")]
+    pub concurrency: usize,
+    /// Seconds to wait before retrying a failed token. Doubles with every failed attempt.
+    #[clap(long = "token-analysis-retry-backoff", default_value = "600")]
+    pub retry_backoff_secs: u64,
+    /// Maximum seconds to wait before retrying a failed token.
+    #[clap(long = "token-analysis-max-retry-backoff", default_value = "86400")]
+    pub max_retry_backoff_secs: u64,
+    /// Hours after which good tokens are analyzed again, as their tax may change.
+    #[clap(long = "token-analysis-recheck-hours", default_value = "24")]
+    pub recheck_hours: u64,
+    /// Tokens of components with at least this TVL are analyzed first. Denoted in the chain's
+    /// native token.
+    #[clap(long = "token-analysis-priority-tvl", default_value = "100")]
+    pub priority_tvl: f64,
+    /// Detect token flags already when tokens are added during extraction
+    ///
+    /// This makes several RPC requests per new token on the extraction path. If not set, the
+    /// flags of new tokens are detected when they are analyzed by the scheduler.
+    #[clap(long = "detect-token-flags-on-creation", env = "DETECT_TOKEN_FLAGS_ON_CREATION")]
+    pub detect_flags_on_creation: bool,
+    /// How tokens are analyzed, both when they are added during extraction and when the
+    /// scheduler analyzes them again
+    #[clap(
+        long = "token-analysis-method",
+        env = "TOKEN_ANALYSIS_METHOD",
+        value_enum,
+        default_value = "trace-call"
+    )]
+    pub method: AnalysisMethod,
+}
+
+#[derive(Args, Debug, Clone, PartialEq, Eq)]
+pub struct ExportArgs {
+    /// Blockchain to export the state for.
+    #[clap(long, default_value = "ethereum")]
+    pub chain: Chain,
+    /// A comma separated list of protocol systems to export.
+    #[clap(long, value_delimiter = ',')]
+    pub protocol_systems: Vec<String>,
+    /// Block number to export the state at.
+    ///
+    /// Optional. Defaults to the latest stored block.
+    #[clap(long)]
+    pub block: Option<i64>,
+    /// Path of the snapshot file to write.
+    #[clap(long)]
+    pub output: String,
+}
+
+#[derive(Args, Debug, Clone, PartialEq, Eq)]
+pub struct ImportArgs {
+    /// Path of the snapshot file to import.
+    #[clap(long)]
+    pub input: String,
+}
+
+#[derive(Args, Debug, Clone, PartialEq, Eq)]
+pub struct RemoveProtocolSystemArgs {
+    /// Name of the protocol system to remove.
+    #[clap(long)]
+    pub protocol_system: String,
+    /// Only report the number of rows that would be removed, without deleting anything.
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Args, Debug, Clone, PartialEq, Eq)]
+pub struct ValidateConfigArgs {
+    /// Extractors configuration file
+    #[clap(long, env, default_value = "./extractors.yaml")]
+    pub extractors_config: String,
+}
+
+#[derive(Args, Debug, Clone, PartialEq, Eq)]
+pub struct DiffSpkgArgs {
+    /// The blockchain the packages index
+    #[clap(long, default_value = "ethereum")]
+    pub chain: String,
+
+    /// Substreams API token
+    #[clap(long, env, hide_env_values = true, alias = "api_token")]
+    pub substreams_api_token: String,
+
+    /// Substreams Package file currently in use
+    #[clap(long)]
+    pub spkg: String,
+
+    /// Substreams Package file to compare against
+    #[clap(long)]
+    pub new_spkg: String,
+
+    /// Substreams Module name
+    #[clap(long)]
+    pub module: String,
+
+    /// Substreams Module name in the new package
+    ///
+    /// Defaults to the module name of the current package.
+    #[clap(long)]
+    pub new_module: Option<String>,
+
+    // The names of the protocol_types to index
+    #[clap(long, value_delimiter = ',')]
+    pub protocol_type_names: Vec<String>,
+
+    /// Name of the post processor to apply to the changes of both packages
+    #[clap(long)]
+    pub post_processor: Option<String>,
+
+    /// First block to compare
+    #[clap(long)]
+    pub start_block: i64,
+
+    /// Block to stop comparing at
+    #[clap(long)]
+    pub stop_block: i64,
+
+    /// Write the differences as JSON lines to this file instead of stdout
+    #[clap(long)]
+    pub output: Option<String>,
+}
+
+#[derive(Args, Debug, Clone, PartialEq, Eq)]
+pub struct MockSubstreamsArgs {
+    /// File of length delimited `sf.substreams.rpc.v2.Response` messages to replay
+    #[clap(long)]
+    pub recording: String,
+
+    /// Address to serve the Substreams endpoint on
+    #[clap(long, default_value = "127.0.0.1:9000")]
+    pub addr: String,
+}
+
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct CheckConsistencyArgs {
+    /// Node rpc url
+    #[clap(env, long)]
+    pub rpc_url: String,
+    /// Blockchain the protocol systems are indexed on.
+    #[clap(long, default_value = "ethereum")]
+    pub chain: Chain,
+    /// A comma separated list of protocol systems to check.
+    #[clap(long, value_delimiter = ',', required = true)]
+    pub protocol_systems: Vec<String>,
+    /// How many components to sample per protocol system.
+    #[clap(long, default_value = "20")]
+    pub sample_size: usize,
+    /// How many storage slots to compare per sampled contract.
+    #[clap(long, default_value = "20")]
+    pub slots_per_contract: usize,
+    /// Path of the JSON report to write.
+    #[clap(long, default_value = "consistency_report.json")]
+    pub report: String,
+    /// Repeat the check every this many seconds and expose the results as metrics.
+    ///
+    /// Optional. Without it the check runs once and exits with an error if mismatches are
+    /// found.
+    #[clap(long)]
+    pub interval: Option<u64>,
+}
+
 #[cfg(test)]
 mod cli_tests {
     use super::*;
@@ -218,6 +499,21 @@ mod cli_tests {
                 server_ip: "0.0.0.0".to_string(),
                 server_port: 4242,
                 server_version_prefix: "v1".to_string(),
+                max_extractor_lag: 50,
+                admin_token: None,
+                admin_server_ip: "127.0.0.1".to_string(),
+                admin_server_port: 4243,
+                rpc_cache: RpcCacheArgs {
+                    token_cache_capacity: 50,
+                    token_cache_ttl: 420,
+                    contract_state_cache_capacity: 50,
+                    contract_state_cache_ttl: 420,
+                    protocol_state_cache_capacity: 50,
+                    protocol_state_cache_ttl: 420,
+                    component_cache_capacity: 500,
+                    component_cache_ttl: 420,
+                    unfinalized_cache_ttl: 60,
+                },
             },
             command: Command::Run(RunSpkgArgs {
                 chain: "ethereum".to_string(),
@@ -232,6 +528,8 @@ mod cli_tests {
                 },
                 initialized_accounts: vec![],
                 initialization_block: 0,
+                post_processor: None,
+                dry_run_output: None,
             }),
         };
 
@@ -246,6 +544,8 @@ mod cli_tests {
             "http://example.com",
             "--database-url",
             "my_db",
+            "--unfinalized-cache-ttl",
+            "12",
             "index",
             "--rpc-url",
             "http://example.com",
@@ -253,6 +553,9 @@ mod cli_tests {
             "/opt/extractors.yaml",
             "--api_token",
             "your_api_token",
+            "--analyze-tokens",
+            "--token-analysis-rpc-budget",
+            "120",
         ])
         .expect("parse errored");
 
@@ -264,6 +567,21 @@ mod cli_tests {
                 server_ip: "0.0.0.0".to_string(),
                 server_port: 4242,
                 server_version_prefix: "v1".to_string(),
+                max_extractor_lag: 50,
+                admin_token: None,
+                admin_server_ip: "127.0.0.1".to_string(),
+                admin_server_port: 4243,
+                rpc_cache: RpcCacheArgs {
+                    token_cache_capacity: 50,
+                    token_cache_ttl: 420,
+                    contract_state_cache_capacity: 50,
+                    contract_state_cache_ttl: 420,
+                    protocol_state_cache_capacity: 50,
+                    protocol_state_cache_ttl: 420,
+                    component_cache_capacity: 500,
+                    component_cache_ttl: 420,
+                    unfinalized_cache_ttl: 12,
+                },
             },
             command: Command::Index(IndexArgs {
                 substreams_args: SubstreamsArgs {
@@ -272,13 +590,177 @@ mod cli_tests {
                 },
                 chains: vec!["ethereum".to_string()],
                 extractors_config: "/opt/extractors.yaml".to_string(),
+                extractors_config_poll_interval: None,
                 retention_horizon: "2024-01-01T00:00:00".to_string(),
+                analytics_dir: None,
+                token_analysis: TokenAnalysisSchedulerArgs {
+                    enabled: true,
+                    interval_secs: 60,
+                    rpc_budget: 120,
+                    concurrency: 5,
+                    retry_backoff_secs: 600,
+                    max_retry_backoff_secs: 86400,
+                    recheck_hours: 24,
+                    priority_tvl: 100.0,
+                    detect_flags_on_creation: false,
+                    method: AnalysisMethod::TraceCall,
+                },
             }),
         };
 
         assert_eq!(cli, expected_args);
     }
 
+    #[test]
+    fn test_arg_parsing_export_cmd() {
+        let cli = Cli::try_parse_from(vec![
+            "tycho-indexer",
+            "export",
+            "--protocol-systems",
+            "uniswap_v2,uniswap_v3",
+            "--block",
+            "20000000",
+            "--output",
+            "snapshot.jsonl.gz",
+        ])
+        .expect("parse errored");
+
+        assert_eq!(
+            cli.command(),
+            Command::Export(ExportArgs {
+                chain: Chain::Ethereum,
+                protocol_systems: vec!["uniswap_v2".to_string(), "uniswap_v3".to_string()],
+                block: Some(20000000),
+                output: "snapshot.jsonl.gz".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_arg_parsing_remove_protocol_system_cmd() {
+        let cli = Cli::try_parse_from(vec![
+            "tycho-indexer",
+            "remove-protocol-system",
+            "--protocol-system",
+            "uniswap_v2",
+            "--dry-run",
+        ])
+        .expect("parse errored");
+
+        assert_eq!(
+            cli.command(),
+            Command::RemoveProtocolSystem(RemoveProtocolSystemArgs {
+                protocol_system: "uniswap_v2".to_string(),
+                dry_run: true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_arg_parsing_validate_config_cmd() {
+        let cli = Cli::try_parse_from(vec![
+            "tycho-indexer",
+            "validate-config",
+            "--extractors-config",
+            "/opt/extractors.yaml",
+        ])
+        .expect("parse errored");
+
+        assert_eq!(
+            cli.command(),
+            Command::ValidateConfig(ValidateConfigArgs {
+                extractors_config: "/opt/extractors.yaml".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_arg_parsing_diff_spkg_cmd() {
+        let cli = Cli::try_parse_from(vec![
+            "tycho-indexer",
+            "diff-spkg",
+            "--api_token",
+            "your_api_token",
+            "--spkg",
+            "ethereum-uniswap-v3-v0.1.0.spkg",
+            "--new-spkg",
+            "ethereum-uniswap-v3-v0.2.0.spkg",
+            "--module",
+            "map_pool_events",
+            "--protocol-type-names",
+            "uniswap_v3_pool",
+            "--start-block",
+            "12369621",
+            "--stop-block",
+            "12369721",
+        ])
+        .expect("parse errored");
+
+        assert_eq!(
+            cli.command(),
+            Command::DiffSpkg(DiffSpkgArgs {
+                chain: "ethereum".to_string(),
+                substreams_api_token: "your_api_token".to_string(),
+                spkg: "ethereum-uniswap-v3-v0.1.0.spkg".to_string(),
+                new_spkg: "ethereum-uniswap-v3-v0.2.0.spkg".to_string(),
+                module: "map_pool_events".to_string(),
+                new_module: None,
+                protocol_type_names: vec!["uniswap_v3_pool".to_string()],
+                post_processor: None,
+                start_block: 12369621,
+                stop_block: 12369721,
+                output: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_arg_parsing_mock_substreams_cmd() {
+        let cli = Cli::try_parse_from(vec![
+            "tycho-indexer",
+            "mock-substreams",
+            "--recording",
+            "uniswap_v2.bin",
+        ])
+        .expect("parse errored");
+
+        assert_eq!(
+            cli.command(),
+            Command::MockSubstreams(MockSubstreamsArgs {
+                recording: "uniswap_v2.bin".to_string(),
+                addr: "127.0.0.1:9000".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_arg_parsing_check_consistency_cmd() {
+        let cli = Cli::try_parse_from(vec![
+            "tycho-indexer",
+            "check-consistency",
+            "--rpc-url",
+            "http://example.com",
+            "--protocol-systems",
+            "uniswap_v2,vm:balancer",
+            "--interval",
+            "600",
+        ])
+        .expect("parse errored");
+
+        assert_eq!(
+            cli.command(),
+            Command::CheckConsistency(CheckConsistencyArgs {
+                rpc_url: "http://example.com".to_string(),
+                chain: Chain::Ethereum,
+                protocol_systems: vec!["uniswap_v2".to_string(), "vm:balancer".to_string()],
+                sample_size: 20,
+                slots_per_contract: 20,
+                report: "consistency_report.json".to_string(),
+                interval: Some(600),
+            })
+        );
+    }
+
     #[test]
     fn test_arg_parsing_missing_val() {
         let args = Cli::try_parse_from(vec![
@@ -1,21 +1,61 @@
-use chrono::NaiveDateTime;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-// hacky workaround to estimate current state
-#[derive(Default, Clone, Copy)]
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+use tycho_core::models::Chain;
+use tycho_ethereum::token_analyzer::rpc_client::EthereumRpcClient;
+
+/// The block number of a chain's head, shared by all extractors of the chain.
+///
+/// The head is only updated while [ChainState::track] runs, otherwise it stays at the block it
+/// was created with.
+#[derive(Default, Clone, Debug)]
 pub struct ChainState {
-    start: NaiveDateTime,
-    block_number_at_start: u64,
-    block_time: i64,
+    head: Arc<AtomicU64>,
 }
 
 impl ChainState {
-    pub fn new(start: NaiveDateTime, block_number_at_start: u64, block_time: i64) -> Self {
-        Self { start, block_number_at_start, block_time }
+    pub fn new(block_number: u64) -> Self {
+        Self { head: Arc::new(AtomicU64::new(block_number)) }
     }
+
     pub async fn current_block(&self) -> u64 {
-        let now = chrono::Local::now().naive_utc();
-        let diff = now.signed_duration_since(self.start);
-        let blocks_passed = (diff.num_seconds() / self.block_time) as u64;
-        self.block_number_at_start + blocks_passed
+        self.head.load(Ordering::Relaxed)
+    }
+
+    /// Polls the node for its latest block number every `interval` and updates the head.
+    ///
+    /// The head never moves backwards, so a node behind a load balancer lagging for a request
+    /// doesn't make the extractors appear ahead of the chain. Failed requests are retried on the
+    /// next tick.
+    pub fn track(
+        &self,
+        chain: Chain,
+        rpc_client: EthereumRpcClient,
+        interval: Duration,
+    ) -> JoinHandle<()> {
+        let head = self.head.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                match rpc_client.get_block_number().await {
+                    Ok(block_number) => {
+                        let previous = head.fetch_max(block_number, Ordering::Relaxed);
+                        debug!(%chain, block_number, previous, "Updated chain head");
+                    }
+                    Err(err) => {
+                        warn!(%chain, error = %err, "Failed to fetch the chain head");
+                    }
+                }
+            }
+        })
     }
 }
@@ -0,0 +1,676 @@
+//! Verification of the indexed state against the chain.
+//!
+//! Components of each protocol system are sampled and their stored state is compared with the
+//! node at the block the system's extractor last committed. For the components' contracts this
+//! covers storage slots, code hash and native balance. For native components, which hold their
+//! tokens at the address given by their id, it covers the component balances against the
+//! tokens' ERC-20 `balanceOf`.
+
+use std::{collections::HashMap, fmt::Display, str::FromStr, sync::Arc};
+
+use chrono::{NaiveDateTime, Utc};
+use metrics::{counter, gauge};
+use rand::seq::index;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use tycho_core::{
+    keccak256,
+    models::{
+        blockchain::BlockTag, contract::Account, protocol::ProtocolComponent, Address, Chain,
+        PaginationParams,
+    },
+    storage::{BlockIdentifier, BlockOrTimestamp, Gateway, StorageError, Version, VersionKind},
+    traits::AccountStateReader,
+    Bytes,
+};
+
+/// Default number of components sampled per protocol system.
+pub const DEFAULT_SAMPLE_SIZE: usize = 20;
+/// Default number of storage slots compared per contract.
+pub const DEFAULT_SLOTS_PER_CONTRACT: usize = 20;
+
+/// The kind of value that differs between the stored state and the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MismatchKind {
+    Slot,
+    CodeHash,
+    NativeBalance,
+    ComponentBalance,
+}
+
+impl MismatchKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MismatchKind::Slot => "slot",
+            MismatchKind::CodeHash => "code_hash",
+            MismatchKind::NativeBalance => "native_balance",
+            MismatchKind::ComponentBalance => "component_balance",
+        }
+    }
+}
+
+/// A stored value that differs from the value reported by the node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mismatch {
+    pub kind: MismatchKind,
+    pub component_id: String,
+    /// The contract for account values, the token for component balances.
+    pub address: Address,
+    /// The storage slot of slot mismatches.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slot: Option<Bytes>,
+    pub stored: Bytes,
+    pub onchain: Bytes,
+}
+
+/// The outcome of checking a single protocol system.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SystemReport {
+    pub protocol_system: String,
+    /// The block the stored state was compared at.
+    pub block: u64,
+    pub components: usize,
+    pub contracts: usize,
+    /// Number of values compared with the node.
+    pub values: usize,
+    /// Number of values that could not be fetched from the node.
+    pub rpc_errors: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl SystemReport {
+    fn new(protocol_system: &str, block: u64) -> Self {
+        Self {
+            protocol_system: protocol_system.to_string(),
+            block,
+            components: 0,
+            contracts: 0,
+            values: 0,
+            rpc_errors: 0,
+            mismatches: Vec::new(),
+        }
+    }
+
+    /// Records the comparison of a stored value with the value fetched from the node.
+    fn compare<E: Display>(
+        &mut self,
+        kind: MismatchKind,
+        component_id: &str,
+        address: &Address,
+        slot: Option<&Bytes>,
+        stored: &Bytes,
+        onchain: Result<Bytes, E>,
+    ) {
+        match onchain {
+            Ok(onchain) => {
+                self.values += 1;
+                if !same_value(stored, &onchain) {
+                    self.mismatches.push(Mismatch {
+                        kind,
+                        component_id: component_id.to_string(),
+                        address: address.clone(),
+                        slot: slot.cloned(),
+                        stored: stored.clone(),
+                        onchain,
+                    });
+                }
+            }
+            Err(err) => {
+                warn!(error = %err, kind = kind.as_str(), component_id, %address, "ConsistencyCheckRpcError");
+                self.rpc_errors += 1;
+            }
+        }
+    }
+}
+
+/// The outcome of checking all requested protocol systems.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConsistencyReport {
+    pub chain: Chain,
+    pub checked_at: NaiveDateTime,
+    pub systems: Vec<SystemReport>,
+}
+
+impl ConsistencyReport {
+    pub fn mismatches(&self) -> usize {
+        self.systems
+            .iter()
+            .map(|system| system.mismatches.len())
+            .sum()
+    }
+}
+
+/// Compares two big-endian values ignoring leading zero bytes, since the stored values and
+/// the values returned by the node are not padded the same way.
+fn same_value(a: &Bytes, b: &Bytes) -> bool {
+    fn trimmed(value: &[u8]) -> &[u8] {
+        let start = value
+            .iter()
+            .position(|b| *b != 0)
+            .unwrap_or(value.len());
+        &value[start..]
+    }
+    trimmed(a) == trimmed(b)
+}
+
+pub struct ConsistencyChecker<R> {
+    chain: Chain,
+    gateway: Arc<dyn Gateway>,
+    reader: R,
+    sample_size: usize,
+    slots_per_contract: usize,
+}
+
+impl<R> ConsistencyChecker<R>
+where
+    R: AccountStateReader,
+    R::Error: Display,
+{
+    pub fn new(chain: Chain, gateway: Arc<dyn Gateway>, reader: R) -> Self {
+        Self {
+            chain,
+            gateway,
+            reader,
+            sample_size: DEFAULT_SAMPLE_SIZE,
+            slots_per_contract: DEFAULT_SLOTS_PER_CONTRACT,
+        }
+    }
+
+    /// Sets the number of components sampled per protocol system.
+    pub fn with_sample_size(mut self, sample_size: usize) -> Self {
+        self.sample_size = sample_size;
+        self
+    }
+
+    /// Sets the number of storage slots compared per contract.
+    pub fn with_slots_per_contract(mut self, slots_per_contract: usize) -> Self {
+        self.slots_per_contract = slots_per_contract;
+        self
+    }
+
+    /// Checks a sample of components of each protocol system and reports the mismatches as
+    /// metrics.
+    pub async fn check(
+        &self,
+        protocol_systems: &[String],
+    ) -> Result<ConsistencyReport, StorageError> {
+        let mut systems = Vec::with_capacity(protocol_systems.len());
+        for protocol_system in protocol_systems {
+            let report = self
+                .check_system(protocol_system)
+                .await?;
+            self.report_metrics(&report);
+            systems.push(report);
+        }
+        Ok(ConsistencyReport { chain: self.chain, checked_at: Utc::now().naive_utc(), systems })
+    }
+
+    async fn check_system(&self, protocol_system: &str) -> Result<SystemReport, StorageError> {
+        // Compare at the block the extractor last committed, so components of systems that are
+        // behind are not reported for changes they have not processed yet.
+        let state = self
+            .gateway
+            .get_state(protocol_system, &self.chain)
+            .await?;
+        let block = self
+            .gateway
+            .get_block(&BlockIdentifier::Hash(state.block_hash))
+            .await?;
+        let version = Version(
+            BlockOrTimestamp::Block(BlockIdentifier::Hash(block.hash.clone())),
+            VersionKind::Last,
+        );
+        let tag = BlockTag::Number(block.number);
+        let mut report = SystemReport::new(protocol_system, block.number);
+
+        let sample = self
+            .sample_components(protocol_system)
+            .await?;
+        report.components = sample.len();
+
+        let mut contract_components: HashMap<Address, String> = HashMap::new();
+        for component in sample.iter() {
+            for address in component.contract_addresses.iter() {
+                contract_components
+                    .entry(address.clone())
+                    .or_insert_with(|| component.id.clone());
+            }
+        }
+        if !contract_components.is_empty() {
+            let addresses = contract_components
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>();
+            let accounts = self
+                .gateway
+                .get_contracts(&self.chain, Some(&addresses), Some(&version), false, None)
+                .await?
+                .entity;
+            report.contracts = accounts.len();
+            for account in accounts.iter() {
+                let component_id = contract_components
+                    .get(&account.address)
+                    .map(String::as_str)
+                    .unwrap_or_default();
+                self.check_account(&mut report, component_id, account, &version, tag)
+                    .await?;
+            }
+        }
+
+        // Components holding their tokens in a shared contract can't be compared with the
+        // token balance of that contract, so only components without contracts whose id is an
+        // address are checked.
+        let owners: HashMap<&str, Address> = sample
+            .iter()
+            .filter(|component| component.contract_addresses.is_empty())
+            .filter_map(|component| {
+                Address::from_str(&component.id)
+                    .ok()
+                    .filter(|address| address.len() == 20)
+                    .map(|address| (component.id.as_str(), address))
+            })
+            .collect();
+        if !owners.is_empty() {
+            let ids = owners
+                .keys()
+                .copied()
+                .collect::<Vec<_>>();
+            let balances = self
+                .gateway
+                .get_component_balances(&self.chain, Some(&ids), Some(&version))
+                .await?;
+            for (component_id, balances) in balances.iter() {
+                let Some(owner) = owners.get(component_id.as_str()) else {
+                    continue;
+                };
+                for (token, balance) in balances.iter() {
+                    let onchain = self
+                        .reader
+                        .get_token_balance(token, owner, tag)
+                        .await;
+                    report.compare(
+                        MismatchKind::ComponentBalance,
+                        component_id,
+                        token,
+                        None,
+                        &balance.balance,
+                        onchain,
+                    );
+                }
+            }
+        }
+
+        info!(
+            protocol_system,
+            block = report.block,
+            components = report.components,
+            values = report.values,
+            mismatches = report.mismatches.len(),
+            rpc_errors = report.rpc_errors,
+            "ConsistencyChecked"
+        );
+        Ok(report)
+    }
+
+    /// Draws a random sample of the system's components.
+    ///
+    /// Components are fetched one page at a time at randomly chosen offsets, so the check
+    /// doesn't load all components of large systems.
+    async fn sample_components(
+        &self,
+        protocol_system: &str,
+    ) -> Result<Vec<ProtocolComponent>, StorageError> {
+        let total = self
+            .gateway
+            .get_protocol_components(
+                &self.chain,
+                Some(protocol_system.to_string()),
+                None,
+                None,
+                Some(&PaginationParams::new(0, 1)),
+            )
+            .await?
+            .total
+            .unwrap_or_default()
+            .max(0) as usize;
+        let offsets = index::sample(&mut rand::thread_rng(), total, self.sample_size.min(total));
+
+        let mut sample = Vec::with_capacity(offsets.len());
+        for offset in offsets.iter() {
+            // Components removed since counting them are skipped.
+            let page = self
+                .gateway
+                .get_protocol_components(
+                    &self.chain,
+                    Some(protocol_system.to_string()),
+                    None,
+                    None,
+                    Some(&PaginationParams::new(offset as i64, 1)),
+                )
+                .await?;
+            sample.extend(page.entity);
+        }
+        Ok(sample)
+    }
+
+    async fn check_account(
+        &self,
+        report: &mut SystemReport,
+        component_id: &str,
+        account: &Account,
+        version: &Version,
+        tag: BlockTag,
+    ) -> Result<(), StorageError> {
+        let address = &account.address;
+        let code_hash = self
+            .reader
+            .get_code(address, tag)
+            .await
+            .map(|code| Bytes::from(keccak256(code)));
+        report.compare(
+            MismatchKind::CodeHash,
+            component_id,
+            address,
+            None,
+            &account.code_hash,
+            code_hash,
+        );
+
+        let balance = self
+            .reader
+            .get_balance(address, tag)
+            .await;
+        report.compare(
+            MismatchKind::NativeBalance,
+            component_id,
+            address,
+            None,
+            &account.native_balance,
+            balance,
+        );
+
+        let slots = self
+            .gateway
+            .sample_contract_slots(&self.chain, address, Some(version), self.slots_per_contract)
+            .await?;
+        for (slot, value) in slots.iter() {
+            let onchain = self
+                .reader
+                .get_storage_at(address, slot, tag)
+                .await;
+            report.compare(MismatchKind::Slot, component_id, address, Some(slot), value, onchain);
+        }
+        Ok(())
+    }
+
+    fn report_metrics(&self, report: &SystemReport) {
+        let chain = self.chain.to_string();
+        for mismatch in report.mismatches.iter() {
+            counter!(
+                "consistency_check_mismatches",
+                "chain" => chain.clone(),
+                "protocol_system" => report.protocol_system.clone(),
+                "kind" => mismatch.kind.as_str()
+            )
+            .increment(1);
+        }
+        counter!(
+            "consistency_check_values",
+            "chain" => chain.clone(),
+            "protocol_system" => report.protocol_system.clone()
+        )
+        .increment(report.values as u64);
+        counter!(
+            "consistency_check_rpc_errors",
+            "chain" => chain.clone(),
+            "protocol_system" => report.protocol_system.clone()
+        )
+        .increment(report.rpc_errors as u64);
+        gauge!(
+            "consistency_check_block",
+            "chain" => chain,
+            "protocol_system" => report.protocol_system.clone()
+        )
+        .set(report.block as f64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDateTime;
+    use mockall::mock;
+    use tycho_core::{
+        models::{
+            blockchain::Block, protocol::ComponentBalance, ChangeType, Code, ExtractionState,
+            StoreKey, StoreVal,
+        },
+        storage::WithTotal,
+    };
+
+    use super::*;
+    use crate::testing::MockGateway;
+
+    mock! {
+        pub StateReader {}
+
+        #[async_trait::async_trait]
+        impl AccountStateReader for StateReader {
+            type Error = String;
+
+            async fn get_storage_at(
+                &self,
+                address: &Address,
+                slot: &StoreKey,
+                block: BlockTag,
+            ) -> Result<StoreVal, String>;
+
+            async fn get_code(&self, address: &Address, block: BlockTag) -> Result<Code, String>;
+
+            async fn get_balance(&self, address: &Address, block: BlockTag) -> Result<Bytes, String>;
+
+            async fn get_token_balance(
+                &self,
+                token: &Address,
+                owner: &Address,
+                block: BlockTag,
+            ) -> Result<Bytes, String>;
+        }
+    }
+
+    const POOL: &str = "0x7ec8e94a9b379f6b90ee5af7b9a78624280b50ea";
+    const TOKEN: &str = "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2";
+    const CONTRACT: &str = "0x0000000000000000000000000000000000000001";
+
+    fn component(id: &str, contracts: Vec<Bytes>) -> ProtocolComponent {
+        ProtocolComponent::new(
+            id,
+            "test_protocol",
+            "pool",
+            Chain::Ethereum,
+            vec![Bytes::from(TOKEN)],
+            contracts,
+            HashMap::new(),
+            ChangeType::Creation,
+            Bytes::from("0x00"),
+            NaiveDateTime::default(),
+        )
+    }
+
+    fn gateway() -> MockGateway {
+        let mut gw = MockGateway::new();
+        gw.expect_get_state()
+            .returning(|name, chain| {
+                Ok(ExtractionState::new(name.to_string(), *chain, None, &[], Bytes::from("0x0a")))
+            });
+        gw.expect_get_block().returning(|_| {
+            Ok(Block::new(
+                10,
+                Chain::Ethereum,
+                Bytes::from("0x0a"),
+                Bytes::from("0x09"),
+                NaiveDateTime::default(),
+            ))
+        });
+        gw.expect_get_protocol_components()
+            .returning(|_, _, _, _, pagination| {
+                let mut components = vec![
+                    component(POOL, vec![]),
+                    component("vm_pool", vec![Bytes::from(CONTRACT)]),
+                ];
+                let pagination = pagination.expect("components are paginated");
+                let entity = components
+                    .drain(..)
+                    .skip(pagination.offset() as usize)
+                    .take(pagination.page_size as usize)
+                    .collect();
+                Box::pin(async move { Ok(WithTotal { entity, total: Some(2) }) })
+            });
+        gw.expect_get_contracts()
+            .returning(|_, _, _, _, _| {
+                Box::pin(async move {
+                    Ok(WithTotal {
+                        entity: vec![Account::new(
+                            Chain::Ethereum,
+                            Bytes::from(CONTRACT),
+                            "contract".to_string(),
+                            HashMap::new(),
+                            Bytes::from("0x64"),
+                            HashMap::new(),
+                            Bytes::from("0xc0de"),
+                            Bytes::from(keccak256([0xc0, 0xde])),
+                            Bytes::from("0x00"),
+                            Bytes::from("0x00"),
+                            None,
+                        )],
+                        total: Some(1),
+                    })
+                })
+            });
+        gw.expect_sample_contract_slots()
+            .returning(|_, _, _, limit| {
+                let slots = [
+                    (Bytes::from("0x01"), Bytes::from("0x0a")),
+                    (Bytes::from("0x02"), Bytes::from("0x0b")),
+                ];
+                let sample = slots.into_iter().take(limit).collect();
+                Box::pin(async move { Ok(sample) })
+            });
+        gw.expect_get_component_balances()
+            .returning(|_, _, _| {
+                Box::pin(async move {
+                    Ok(HashMap::from([(
+                        POOL.to_string(),
+                        HashMap::from([(
+                            Bytes::from(TOKEN),
+                            ComponentBalance {
+                                token: Bytes::from(TOKEN),
+                                balance: Bytes::from("0x03e8"),
+                                balance_float: 1000.0,
+                                modify_tx: Bytes::from("0x00"),
+                                component_id: POOL.to_string(),
+                            },
+                        )]),
+                    )]))
+                })
+            });
+        gw
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let mut reader = MockStateReader::new();
+        reader
+            .expect_get_code()
+            .returning(|_, _| Ok(Bytes::from("0xc0de")));
+        // Same balance, padded differently than the stored value.
+        reader
+            .expect_get_balance()
+            .returning(|_, _| Ok(Bytes::from(100u64).lpad(32, 0)));
+        reader
+            .expect_get_storage_at()
+            .returning(|_, slot, _| {
+                if slot == &Bytes::from("0x01") {
+                    Ok(Bytes::from("0x0a").lpad(32, 0))
+                } else {
+                    Ok(Bytes::from("0x0c").lpad(32, 0))
+                }
+            });
+        reader
+            .expect_get_token_balance()
+            .returning(|_, owner, block| {
+                assert_eq!(owner, &Bytes::from(POOL));
+                assert_eq!(block, BlockTag::Number(10));
+                Err("execution reverted".to_string())
+            });
+        let checker = ConsistencyChecker::new(Chain::Ethereum, Arc::new(gateway()), reader);
+
+        let report = checker
+            .check(&["test_protocol".to_string()])
+            .await
+            .expect("check failed");
+
+        assert_eq!(report.mismatches(), 1);
+        let system = &report.systems[0];
+        assert_eq!(system.block, 10);
+        assert_eq!(system.components, 2);
+        assert_eq!(system.contracts, 1);
+        assert_eq!(system.values, 4);
+        assert_eq!(system.rpc_errors, 1);
+        assert_eq!(
+            system.mismatches,
+            vec![Mismatch {
+                kind: MismatchKind::Slot,
+                component_id: "vm_pool".to_string(),
+                address: Bytes::from(CONTRACT),
+                slot: Some(Bytes::from("0x02")),
+                stored: Bytes::from("0x0b"),
+                onchain: Bytes::from("0x0c").lpad(32, 0),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_component_balance_mismatch() {
+        let mut reader = MockStateReader::new();
+        reader
+            .expect_get_code()
+            .returning(|_, _| Ok(Bytes::from("0xc0de")));
+        reader
+            .expect_get_balance()
+            .returning(|_, _| Ok(Bytes::from("0x64")));
+        reader
+            .expect_get_storage_at()
+            .returning(|_, slot, _| {
+                Ok(if slot == &Bytes::from("0x01") {
+                    Bytes::from("0x0a")
+                } else {
+                    Bytes::from("0x0b")
+                })
+            });
+        reader
+            .expect_get_token_balance()
+            .returning(|_, _, _| Ok(Bytes::from(999u64).lpad(32, 0)));
+        let checker = ConsistencyChecker::new(Chain::Ethereum, Arc::new(gateway()), reader)
+            .with_slots_per_contract(1);
+
+        let report = checker
+            .check(&["test_protocol".to_string()])
+            .await
+            .expect("check failed");
+
+        let system = &report.systems[0];
+        assert_eq!(system.values, 4);
+        assert_eq!(
+            system.mismatches,
+            vec![Mismatch {
+                kind: MismatchKind::ComponentBalance,
+                component_id: POOL.to_string(),
+                address: Bytes::from(TOKEN),
+                slot: None,
+                stored: Bytes::from("0x03e8"),
+                onchain: Bytes::from(999u64).lpad(32, 0),
+            }]
+        );
+    }
+}
@@ -0,0 +1,205 @@
+//! Runs a substreams package without storage and writes the changes Tycho would ingest to a file.
+use std::{collections::HashMap, io::Write};
+
+use anyhow::Error;
+use futures03::{Stream, StreamExt};
+use tracing::{debug, info};
+use tycho_core::models::{blockchain::BlockAggregatedChanges, Chain, ProtocolType};
+
+use crate::{
+    extractor::{
+        post_processors::PostProcessorFn, protocol_extractor::decode_block_changes,
+        runner::ExtractorConfig, ExtractionError,
+    },
+    pb::sf::substreams::rpc::v2::BlockScopedData,
+    substreams::stream::BlockResponse,
+};
+
+/// Decodes final blocks of a substreams package the same way the extractor does.
+///
+/// Nothing is read from the database. Therefore no token metadata is fetched and no TVL is
+/// computed: `new_tokens` and `component_tvl` are always empty.
+pub struct BlockDecoder {
+    name: String,
+    chain: Chain,
+    protocol_types: HashMap<String, ProtocolType>,
+    post_processor: Option<PostProcessorFn>,
+}
+
+impl BlockDecoder {
+    pub fn new(config: &ExtractorConfig) -> Result<Self, ExtractionError> {
+        Ok(Self {
+            name: config.name().to_string(),
+            chain: config.chain(),
+            protocol_types: config.protocol_type_map(),
+            post_processor: config.resolve_post_processor()?,
+        })
+    }
+
+    /// Decodes and aggregates the changes of a block. Returns `None` for messages without a
+    /// block, which the extractor skips as well.
+    pub fn decode(
+        &self,
+        data: BlockScopedData,
+    ) -> Result<Option<BlockAggregatedChanges>, ExtractionError> {
+        let output = data
+            .output
+            .as_ref()
+            .and_then(|output| output.map_output.as_ref())
+            .ok_or_else(|| ExtractionError::DecodeError("Block without map output".to_string()))?;
+        let changes = match decode_block_changes(
+            output,
+            &self.name,
+            self.chain,
+            &self.name,
+            &self.protocol_types,
+            data.final_block_height,
+        ) {
+            Ok(changes) => changes,
+            Err(ExtractionError::Empty) => {
+                debug!(cursor = data.cursor, "Skipping message without block");
+                return Ok(None);
+            }
+            Err(err) => return Err(err),
+        };
+        let changes = match self.post_processor {
+            Some(post_process_f) => post_process_f(changes),
+            None => changes,
+        };
+        Ok(Some(changes.aggregate_updates()?))
+    }
+
+    /// Returns the changes of the next block in the stream, or `None` once the stream ended.
+    ///
+    /// The stream is expected to only contain final blocks, an undo signal is an error.
+    pub async fn next_block<S>(
+        &self,
+        stream: &mut S,
+    ) -> Result<Option<BlockAggregatedChanges>, ExtractionError>
+    where
+        S: Stream<Item = Result<BlockResponse, Error>> + Unpin,
+    {
+        while let Some(response) = stream.next().await {
+            match response.map_err(|err| ExtractionError::SubstreamsError(err.to_string()))? {
+                BlockResponse::New(data) => {
+                    if let Some(changes) = self.decode(data)? {
+                        return Ok(Some(changes));
+                    }
+                }
+                BlockResponse::Undo(undo) => {
+                    return Err(ExtractionError::SubstreamsError(format!(
+                        "Unexpected undo signal in final blocks stream: {:?}",
+                        undo.last_valid_block
+                    )))
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Writes the changes of each block decoded by a [`BlockDecoder`] as one JSON line.
+pub struct DryRun<W> {
+    decoder: BlockDecoder,
+    output: W,
+}
+
+impl<W: Write> DryRun<W> {
+    pub fn new(config: &ExtractorConfig, output: W) -> Result<Self, ExtractionError> {
+        Ok(Self { decoder: BlockDecoder::new(config)?, output })
+    }
+
+    /// Consumes the stream until it ends and returns the number of blocks written.
+    pub async fn run<S>(mut self, mut stream: S) -> Result<usize, ExtractionError>
+    where
+        S: Stream<Item = Result<BlockResponse, Error>> + Unpin,
+    {
+        let mut blocks_written = 0;
+        while let Some(changes) = self
+            .decoder
+            .next_block(&mut stream)
+            .await?
+        {
+            let line = serde_json::to_string(&changes).map_err(|err| {
+                ExtractionError::Unknown(format!("Failed to serialize block: {}", err))
+            })?;
+            writeln!(self.output, "{}", line).map_err(|err| {
+                ExtractionError::Unknown(format!("Failed to write block: {}", err))
+            })?;
+            blocks_written += 1;
+        }
+        self.output
+            .flush()
+            .map_err(|err| ExtractionError::Unknown(format!("Failed to flush output: {}", err)))?;
+        info!(blocks = blocks_written, "Dry run finished");
+        Ok(blocks_written)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures03::stream;
+    use tycho_substreams::pb::tycho::evm::v1 as tycho_substreams;
+
+    use super::*;
+    use crate::testing::fixtures as pb_fixtures;
+
+    fn config() -> ExtractorConfig {
+        serde_yaml::from_str(
+            r#"
+name: "native:test"
+chain: "ethereum"
+implementation_type: "Custom"
+sync_batch_size: 1000
+start_block: 1
+protocol_types:
+  - name: "WeightedPool"
+    financial_type: "Swap"
+spkg: "test.spkg"
+module_name: "map_changes"
+"#,
+        )
+        .expect("invalid config")
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let mut output = Vec::new();
+        let dry_run = DryRun::new(&config(), &mut output).unwrap();
+        let empty = tycho_substreams::BlockChanges { block: None, changes: vec![] };
+        let blocks = stream::iter(vec![
+            Ok(BlockResponse::New(pb_fixtures::pb_block_scoped_data(
+                pb_fixtures::pb_native_block_changes(0),
+                None,
+                None,
+            ))),
+            Ok(BlockResponse::New(pb_fixtures::pb_block_scoped_data(empty, None, None))),
+        ]);
+
+        let written = dry_run.run(blocks).await.unwrap();
+
+        let lines: Vec<BlockAggregatedChanges> = std::str::from_utf8(&output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(written, 1);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].extractor, "native:test");
+        assert_eq!(lines[0].block.number, 1);
+        assert!(lines[0]
+            .state_deltas
+            .contains_key("State1"));
+        assert!(lines[0].new_tokens.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_rejects_undo() {
+        let dry_run = DryRun::new(&config(), Vec::new()).unwrap();
+        let blocks = stream::iter(vec![Ok(BlockResponse::Undo(Default::default()))]);
+
+        let res = dry_run.run(blocks).await;
+
+        assert!(matches!(res, Err(ExtractionError::SubstreamsError(_))));
+    }
+}
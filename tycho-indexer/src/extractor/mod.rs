@@ -24,15 +24,23 @@ use crate::{
 };
 
 pub mod chain_state;
+pub mod consistency_check;
+pub mod dry_run;
 pub mod models;
 pub mod post_processors;
 pub mod protobuf_deserialisation;
 pub mod protocol_cache;
 pub mod protocol_extractor;
+pub mod reload;
 pub mod reorg_buffer;
+#[cfg(test)]
+mod reorg_fuzz;
 pub mod runner;
+pub mod spkg_diff;
 pub mod token_analysis_cron;
+pub mod token_analysis_scheduler;
 mod u256_num;
+pub mod validation;
 
 #[derive(Error, Debug, PartialEq)]
 pub enum ExtractionError {
@@ -56,6 +64,8 @@ pub enum ExtractionError {
     MergeError(String),
     #[error("Reorg buffer error: {0}")]
     ReorgBufferError(String),
+    #[error("Account extraction failed: {0}")]
+    AccountExtractionError(String),
 }
 
 #[derive(Error, Debug)]
@@ -90,6 +100,10 @@ pub trait Extractor: Send + Sync {
     ) -> Result<Option<ExtractorMsg>, ExtractionError>;
 
     async fn handle_progress(&self, inp: ModulesProgress) -> Result<(), ExtractionError>;
+
+    /// Writes out data the extractor buffers outside of the database, called before the
+    /// extractor is stopped or replaced.
+    async fn flush(&self) -> Result<(), ExtractionError>;
 }
 
 /// Wrapper to carry a cursor along with another struct.
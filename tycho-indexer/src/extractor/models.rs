@@ -6,7 +6,7 @@ use tycho_core::{
         blockchain::{Block, BlockAggregatedChanges, BlockScoped, TxWithChanges},
         contract::{AccountBalance, AccountChangesWithTx},
         protocol::{ComponentBalance, ProtocolChangesWithTx, ProtocolComponent},
-        token::CurrencyToken,
+        token::{CurrencyToken, TokenAnalysis},
         Address, AttrStoreKey, Chain, ComponentId,
     },
     Bytes,
@@ -142,6 +142,8 @@ pub struct BlockChanges {
     /// Required here, so it is part of the revert buffer and thus inserted into storage once
     /// finalized.
     pub new_tokens: HashMap<Address, CurrencyToken>,
+    /// Analyses of the new tokens, recorded together with them once finalized.
+    pub new_token_analyses: Vec<TokenAnalysis>,
     /// Vec of updates at this block, aggregated by tx and sorted by tx index in ascending order
     pub txs_with_update: Vec<TxWithChanges>,
 }
@@ -162,6 +164,7 @@ impl BlockChanges {
             finalized_block_height,
             revert,
             new_tokens: HashMap::new(),
+            new_token_analyses: Vec::new(),
             txs_with_update,
         }
     }
@@ -339,6 +342,7 @@ impl From<BlockContractChanges> for BlockChanges {
             finalized_block_height: value.finalized_block_height,
             revert: value.revert,
             new_tokens: value.new_tokens,
+            new_token_analyses: Vec::new(),
             txs_with_update: value
                 .tx_updates
                 .into_iter()
@@ -357,6 +361,7 @@ impl From<BlockEntityChanges> for BlockChanges {
             finalized_block_height: value.finalized_block_height,
             revert: value.revert,
             new_tokens: value.new_tokens,
+            new_token_analyses: Vec::new(),
             txs_with_update: value
                 .txs_with_update
                 .into_iter()
@@ -402,6 +407,7 @@ pub mod fixtures {
                 finalized_block_height,
                 revert,
                 new_tokens,
+                new_token_analyses: Vec::new(),
                 txs_with_update,
             }
         }
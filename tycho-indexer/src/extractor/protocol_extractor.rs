@@ -9,7 +9,9 @@ use chrono::{Duration, NaiveDateTime};
 use metrics::{counter, gauge};
 use mockall::automock;
 use prost::Message;
+use serde::Deserialize;
 use tokio::sync::Mutex;
+use tokio_retry::{strategy::ExponentialBackoff, Retry};
 use tracing::{debug, error, info, instrument, trace, warn};
 use tycho_core::{
     models::{
@@ -19,7 +21,7 @@ use tycho_core::{
             ComponentBalance, ProtocolComponent, ProtocolComponentState,
             ProtocolComponentStateDelta,
         },
-        token::{CurrencyToken, TokenOwnerStore},
+        token::{CurrencyToken, TokenAnalysis, TokenOwnerStore},
         Address, Balance, BlockHash, Chain, ChangeType, ExtractionState, ExtractorIdentity,
         ProtocolType, TxHash,
     },
@@ -27,10 +29,11 @@ use tycho_core::{
         BlockIdentifier, ChainGateway, ContractStateGateway, ExtractionStateGateway,
         ProtocolGateway, StorageError,
     },
-    traits::TokenPreProcessor,
+    traits::{AccountExtractor, TokenPreProcessor},
     Bytes,
 };
-use tycho_storage::postgres::cache::CachedGateway;
+use tycho_ethereum::RPCError;
+use tycho_storage::{analytics::ParquetSink, postgres::cache::CachedGateway};
 use tycho_substreams::pb::tycho::evm::v1 as tycho_substreams;
 
 #[allow(deprecated)]
@@ -46,6 +49,67 @@ use crate::{
     pb::sf::substreams::rpc::v2::{BlockScopedData, BlockUndoSignal, ModulesProgress},
 };
 
+/// Suffixes of the substreams output types `handle_tick_scoped_data` can decode.
+pub const SUPPORTED_OUTPUT_TYPES: [&str; 3] =
+    ["BlockChanges", "BlockContractChanges", "BlockEntityChanges"];
+
+/// Decodes the output of a substreams module into `BlockChanges`.
+///
+/// Backwards Compatibility:
+/// Check if message_type ends with BlockAccountChanges or BlockEntityChanges. If it does,
+/// then we need to decode as the corresponding message type, then convert it to BlockChanges
+#[allow(deprecated)]
+pub fn decode_block_changes(
+    data: &prost_types::Any,
+    extractor: &str,
+    chain: Chain,
+    protocol_system: &str,
+    protocol_types: &HashMap<String, ProtocolType>,
+    final_block_height: u64,
+) -> Result<BlockChanges, ExtractionError> {
+    match data.type_url.as_str() {
+        url if url.ends_with("BlockChanges") => {
+            let raw_msg = tycho_substreams::BlockChanges::decode(data.value.as_slice())?;
+            trace!(?raw_msg, "Received BlockChanges message");
+            BlockChanges::try_from_message((
+                raw_msg,
+                extractor,
+                chain,
+                protocol_system,
+                protocol_types,
+                final_block_height,
+            ))
+        }
+        url if url.ends_with("BlockContractChanges") => {
+            let raw_msg = tycho_substreams::BlockContractChanges::decode(data.value.as_slice())?;
+            trace!(?raw_msg, "Received BlockContractChanges message");
+            BlockContractChanges::try_from_message((
+                raw_msg,
+                extractor,
+                chain,
+                protocol_system.to_string(),
+                protocol_types,
+                final_block_height,
+            ))
+            .map(Into::into)
+        }
+        url if url.ends_with("BlockEntityChanges") => {
+            let raw_msg = tycho_substreams::BlockEntityChanges::decode(data.value.as_slice())?;
+            trace!(?raw_msg, "Received BlockEntityChanges message");
+            BlockEntityChanges::try_from_message((
+                raw_msg,
+                extractor,
+                chain,
+                protocol_system,
+                protocol_types,
+                final_block_height,
+            ))
+            .map(Into::into)
+        }
+        _ => Err(ExtractionError::DecodeError("Unknown message type".into())),
+    }
+}
+
 pub struct Inner {
     cursor: Vec<u8>,
     last_processed_block: Option<Block>,
@@ -55,6 +119,28 @@ pub struct Inner {
     first_message_processed: bool,
 }
 
+/// Number of times fetching contracts referenced by new components is retried.
+const ACCOUNT_INIT_RETRIES: usize = 3;
+
+/// What to do with a block if the contracts referenced by its new components can't be fetched.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountInitFailurePolicy {
+    /// Fail the block, stopping the extractor until it is restarted. No contract state is lost.
+    #[default]
+    Fail,
+    /// Index the block without the contracts. Their state stays missing until they are
+    /// initialized otherwise, e.g. through `initialized_accounts`.
+    Skip,
+}
+
+/// The analytics sink together with the finalized balances needed to calculate tvl.
+struct AnalyticsExport {
+    sink: ParquetSink,
+    /// Balances at the last exported block of all components changed by exported blocks.
+    balances: HashMap<String, HashMap<Bytes, ComponentBalance>>,
+}
+
 pub struct ProtocolExtractor<G, T> {
     gateway: G,
     name: String,
@@ -68,6 +154,12 @@ pub struct ProtocolExtractor<G, T> {
     /// Allows to attach some custom logic, e.g. to fix encoding bugs without resync.
     post_processor: Option<fn(BlockChanges) -> BlockChanges>,
     reorg_buffer: Mutex<ReorgBuffer<BlockUpdateWithCursor<BlockChanges>>>,
+    /// Optional sink receiving the changes of every finalized block.
+    analytics_sink: Option<Mutex<AnalyticsExport>>,
+    /// If set, contracts referenced by new components that are not indexed yet are initialized
+    /// with their state fetched from a node.
+    account_extractor: Option<Arc<dyn AccountExtractor<Error = RPCError> + Send + Sync>>,
+    account_init_failure_policy: AccountInitFailurePolicy,
 }
 
 impl<G, T> ProtocolExtractor<G, T>
@@ -109,6 +201,9 @@ where
                     protocol_types,
                     post_processor,
                     reorg_buffer: Mutex::new(ReorgBuffer::new()),
+                    analytics_sink: None,
+                    account_extractor: None,
+                    account_init_failure_policy: AccountInitFailurePolicy::default(),
                 }
             }
             Ok((cursor, block_hash)) => {
@@ -144,6 +239,9 @@ where
                     protocol_types,
                     post_processor,
                     reorg_buffer: Mutex::new(ReorgBuffer::new()),
+                    analytics_sink: None,
+                    account_extractor: None,
+                    account_init_failure_policy: AccountInitFailurePolicy::default(),
                 }
             }
             Err(err) => return Err(ExtractionError::Setup(err.to_string())),
@@ -153,6 +251,25 @@ where
         Ok(res)
     }
 
+    /// Exports the changes of finalized blocks to the given sink.
+    pub fn with_analytics_sink(mut self, sink: ParquetSink) -> Self {
+        self.analytics_sink = Some(Mutex::new(AnalyticsExport { sink, balances: HashMap::new() }));
+        self
+    }
+
+    /// Initializes contracts referenced by new components using the given account extractor.
+    ///
+    /// `failure_policy` decides what happens to a block whose contracts can't be fetched.
+    pub fn with_account_extractor(
+        mut self,
+        account_extractor: Arc<dyn AccountExtractor<Error = RPCError> + Send + Sync>,
+        failure_policy: AccountInitFailurePolicy,
+    ) -> Self {
+        self.account_extractor = Some(account_extractor);
+        self.account_init_failure_policy = failure_policy;
+        self
+    }
+
     async fn update_cursor(&self, cursor: String) {
         let mut state = self.inner.lock().await;
         state.cursor = cursor.into();
@@ -181,7 +298,8 @@ where
             .num_seconds();
         if time_passed >= 60 {
             let current_block = self.chain_state.current_block().await;
-            let distance_to_current = current_block - block.number;
+            // the head is polled, so it may lag behind the blocks received from the substreams
+            let distance_to_current = current_block.saturating_sub(block.number);
             let blocks_processed = block.number - state.last_report_block_number;
             let blocks_per_minute = blocks_processed as f64 * 60.0 / time_passed as f64;
 
@@ -274,11 +392,21 @@ where
             balances
         };
 
+        msg.component_tvl = self.calculate_tvl(&balances).await?;
+
+        Ok(())
+    }
+
+    /// Calculates the tvl of components from their balances of all tokens.
+    async fn calculate_tvl(
+        &self,
+        balances: &HashMap<String, HashMap<Bytes, ComponentBalance>>,
+    ) -> Result<HashMap<String, f64>, ExtractionError> {
         // collect token decimals and prices to calculate tvl in the next step
         // most of this data should be in the cache.
         let addresses = balances
             .values()
-            .flat_map(|b| b.clone().into_keys())
+            .flat_map(|b| b.keys().cloned())
             .collect::<Vec<_>>();
 
         let prices = self
@@ -313,9 +441,97 @@ where
             })
             .collect::<HashMap<_, _>>();
 
-        msg.component_tvl = tvl_updates;
+        Ok(tvl_updates)
+    }
 
-        Ok(())
+    /// Passes finalized blocks to the analytics sink, if one is configured, and flushes it.
+    ///
+    /// Must be called before the blocks are committed, so a crash can duplicate exported rows
+    /// but never lose them. The tvl of a block is calculated from the balances at that block:
+    /// the balances of all previously exported blocks merged with the block's own balance
+    /// changes. Failing to export is logged but does not interrupt the extraction.
+    async fn export_finalized_blocks(&self, finalized: &[BlockUpdateWithCursor<BlockChanges>]) {
+        let Some(export) = &self.analytics_sink else {
+            return;
+        };
+        if finalized.is_empty() {
+            return;
+        }
+        let mut export = export.lock().await;
+        for block_update in finalized {
+            let block_number = block_update.block_update().block.number;
+            let mut changes = match block_update
+                .block_update()
+                .clone()
+                .aggregate_updates()
+            {
+                Ok(changes) => changes,
+                Err(err) => {
+                    error!(?err, block_number, "Failed to aggregate finalized block");
+                    continue;
+                }
+            };
+            match self
+                .finalized_tvl(&mut export.balances, &changes)
+                .await
+            {
+                Ok(tvl) => changes.component_tvl = tvl,
+                Err(err) => {
+                    error!(?err, block_number, "Failed to calculate tvl of finalized block");
+                }
+            }
+            if let Err(err) = export
+                .sink
+                .append(&self.protocol_system, &changes)
+            {
+                error!(?err, block_number, "Failed to export analytics data");
+            }
+        }
+        if let Err(err) = export.sink.flush() {
+            error!(?err, "Failed to flush analytics data");
+        }
+    }
+
+    /// Calculates the tvl of the components whose balances changed in a finalized block.
+    ///
+    /// `balances` holds the finalized balances of all components changed by previously
+    /// exported blocks, components missing from it have not changed since the extractor
+    /// started and their balances are loaded from storage.
+    async fn finalized_tvl(
+        &self,
+        balances: &mut HashMap<String, HashMap<Bytes, ComponentBalance>>,
+        changes: &BlockAggregatedChanges,
+    ) -> Result<HashMap<String, f64>, ExtractionError> {
+        if changes.component_balances.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let missing = changes
+            .component_balances
+            .keys()
+            .filter(|id| !balances.contains_key(*id))
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            let mut stored = self
+                .gateway
+                .get_components_balances(&missing)
+                .await?;
+            for id in missing {
+                balances.insert(id.to_string(), stored.remove(id).unwrap_or_default());
+            }
+        }
+        for (id, delta) in changes.component_balances.iter() {
+            balances
+                .entry(id.clone())
+                .or_default()
+                .extend(delta.clone());
+        }
+        let changed = changes
+            .component_balances
+            .keys()
+            .filter_map(|id| Some((id.clone(), balances.get(id)?.clone())))
+            .collect::<HashMap<_, _>>();
+        self.calculate_tvl(&changed).await
     }
 
     /// Returns component balances at the tip of the reorg buffer.
@@ -475,10 +691,146 @@ where
         Ok(combined_balances)
     }
 
+    /// Adds the state of contracts referenced by new components that are not indexed yet.
+    ///
+    /// The substreams module may not track every contract a component depends on, e.g. if it was
+    /// deployed before the module's start block. Such contracts are fetched at this block and
+    /// added as creations to the first transaction referencing them, so they are committed or
+    /// reverted together with the component.
+    ///
+    /// Fetching is retried a few times, afterwards the block is failed or indexed without the
+    /// contracts according to the configured [`AccountInitFailurePolicy`].
+    async fn initialize_new_accounts(&self, msg: &mut BlockChanges) -> Result<(), ExtractionError> {
+        let Some(account_extractor) = &self.account_extractor else {
+            return Ok(());
+        };
+
+        let mut missing = msg
+            .protocol_components()
+            .into_iter()
+            .flat_map(|pc| pc.contract_addresses)
+            .collect::<HashSet<_>>();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        // Skip contracts created by this block or by a block in the reorg buffer.
+        {
+            let reorg_buffer = self.reorg_buffer.lock().await;
+            let buffered = reorg_buffer
+                .get_block_range(None, None)
+                .map_err(ExtractionError::Storage)?
+                .map(|entry| entry.block_update());
+            for changes in buffered.chain(std::iter::once(&*msg)) {
+                for tx in changes.txs_with_update.iter() {
+                    for (address, delta) in tx.account_deltas.iter() {
+                        if delta.is_creation() {
+                            missing.remove(address);
+                        }
+                    }
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        // Skip contracts that were already committed.
+        let addresses = missing.into_iter().collect::<Vec<_>>();
+        let stored = self
+            .gateway
+            .get_stored_contract_addresses(&addresses)
+            .await?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let missing = addresses
+            .into_iter()
+            .filter(|address| !stored.contains(address))
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        info!(
+            n_accounts = missing.len(),
+            block_number = msg.block.number,
+            "Initializing accounts referenced by new components"
+        );
+        // The node may be briefly unavailable or lag behind the substreams, so failed requests
+        // are retried before applying the failure policy.
+        let backoff = ExponentialBackoff::from_millis(2)
+            .factor(250)
+            .take(ACCOUNT_INIT_RETRIES);
+        let block = msg.block.clone();
+        let accounts = match Retry::spawn(backoff, || {
+            account_extractor.get_accounts(block.clone(), missing.clone())
+        })
+        .await
+        {
+            Ok(accounts) => accounts,
+            Err(err) => {
+                counter!(
+                    "extractor_account_initialization_failures",
+                    "chain" => self.chain.to_string(),
+                    "extractor" => self.name.clone()
+                )
+                .increment(1);
+                match self.account_init_failure_policy {
+                    AccountInitFailurePolicy::Fail => {
+                        return Err(ExtractionError::AccountExtractionError(err.to_string()))
+                    }
+                    AccountInitFailurePolicy::Skip => {
+                        warn!(
+                            %err,
+                            addresses = ?missing,
+                            block_number = msg.block.number,
+                            "Failed to initialize accounts, indexing block without them"
+                        );
+                        return Ok(());
+                    }
+                }
+            }
+        };
+        counter!(
+            "extractor_initialized_accounts",
+            "chain" => self.chain.to_string(),
+            "extractor" => self.name.clone()
+        )
+        .increment(accounts.len() as u64);
+
+        for (address, mut account) in accounts {
+            // Place the creation before any update of the contract emitted by the module, so the
+            // updates are applied on top of it when the block is aggregated.
+            let Some(tx) = msg
+                .txs_with_update
+                .iter_mut()
+                .find(|tx| {
+                    tx.account_deltas.contains_key(&address) ||
+                        tx.protocol_components
+                            .values()
+                            .any(|pc| pc.contract_addresses.contains(&address))
+                })
+            else {
+                continue;
+            };
+            debug!(%address, tx_hash = %tx.tx.hash, "NewContract");
+            if let Some(update) = tx.account_deltas.remove(&address) {
+                account
+                    .merge(update)
+                    .map_err(ExtractionError::MergeError)?;
+            }
+            tx.account_deltas
+                .insert(address, account);
+        }
+        Ok(())
+    }
+
+    /// Returns the tokens of the block's components, together with the analyses of the tokens
+    /// that are not known yet.
     async fn construct_currency_tokens(
         &self,
         msg: &BlockChanges,
-    ) -> Result<HashMap<Address, CurrencyToken>, StorageError> {
+    ) -> Result<(HashMap<Address, CurrencyToken>, Vec<TokenAnalysis>), StorageError> {
         let new_token_addresses = msg
             .protocol_components()
             .into_iter()
@@ -558,15 +910,18 @@ where
             .into_iter()
             .flatten()
             .map(|t| (t.address.clone(), t));
-        let new_tokens: HashMap<Address, CurrencyToken> = self
+        let (new_tokens, analyses): (Vec<_>, Vec<_>) = self
             .token_pre_processor
             .get_tokens(unknown_tokens, Arc::new(tf), BlockTag::Number(msg.block.number))
             .await
+            .into_iter()
+            .unzip();
+        let new_tokens: HashMap<Address, CurrencyToken> = new_tokens
             .into_iter()
             .map(|t| (t.address.clone(), t))
             .chain(existing_tokens)
             .collect();
-        Ok(new_tokens)
+        Ok((new_tokens, analyses))
     }
 }
 
@@ -604,7 +959,6 @@ where
             .clone()
     }
 
-    #[allow(deprecated)]
     #[instrument(skip_all, fields(block_number))]
     async fn handle_tick_scoped_data(
         &self,
@@ -618,51 +972,14 @@ where
             .as_ref()
             .unwrap();
 
-        // Backwards Compatibility:
-        // Check if message_type ends with BlockAccountChanges or BlockEntityChanges. If it does,
-        // then we need to decode as the corresponding message type, then convert it to BlockChanges
-        let msg = match data.type_url.as_str() {
-            url if url.ends_with("BlockChanges") => {
-                let raw_msg = tycho_substreams::BlockChanges::decode(data.value.as_slice())?;
-                trace!(?raw_msg, "Received BlockChanges message");
-                BlockChanges::try_from_message((
-                    raw_msg,
-                    &self.name,
-                    self.chain,
-                    &self.protocol_system,
-                    &self.protocol_types,
-                    inp.final_block_height,
-                ))
-            }
-            url if url.ends_with("BlockContractChanges") => {
-                let raw_msg =
-                    tycho_substreams::BlockContractChanges::decode(data.value.as_slice())?;
-                trace!(?raw_msg, "Received BlockContractChanges message");
-                BlockContractChanges::try_from_message((
-                    raw_msg,
-                    &self.name,
-                    self.chain,
-                    self.protocol_system.clone(),
-                    &self.protocol_types,
-                    inp.final_block_height,
-                ))
-                .map(Into::into)
-            }
-            url if url.ends_with("BlockEntityChanges") => {
-                let raw_msg = tycho_substreams::BlockEntityChanges::decode(data.value.as_slice())?;
-                trace!(?raw_msg, "Received BlockEntityChanges message");
-                BlockEntityChanges::try_from_message((
-                    raw_msg,
-                    &self.name,
-                    self.chain,
-                    &self.protocol_system,
-                    &self.protocol_types,
-                    inp.final_block_height,
-                ))
-                .map(Into::into)
-            }
-            _ => return Err(ExtractionError::DecodeError("Unknown message type".into())),
-        };
+        let msg = decode_block_changes(
+            data,
+            &self.name,
+            self.chain,
+            &self.protocol_system,
+            &self.protocol_types,
+            inp.final_block_height,
+        );
 
         let msg = match msg {
             Ok(changes) => {
@@ -695,7 +1012,10 @@ where
             }
         }
 
-        msg.new_tokens = self
+        self.initialize_new_accounts(&mut msg)
+            .await?;
+
+        (msg.new_tokens, msg.new_token_analyses) = self
             .construct_currency_tokens(&msg)
             .await?;
         self.protocol_cache
@@ -717,11 +1037,12 @@ where
                 .insert_block(BlockUpdateWithCursor::new(msg.clone(), inp.cursor.clone()))
                 .map_err(ExtractionError::Storage)?;
 
-            let mut msgs = reorg_buffer
+            let finalized = reorg_buffer
                 .drain_new_finalized_blocks(inp.final_block_height)
-                .map_err(ExtractionError::Storage)?
-                .into_iter()
-                .peekable();
+                .map_err(ExtractionError::Storage)?;
+            self.export_finalized_blocks(&finalized)
+                .await;
+            let mut msgs = finalized.into_iter().peekable();
 
             while let Some(msg) = msgs.next() {
                 // Force a database commit if we're not syncing and this is the last block to be
@@ -1181,6 +1502,13 @@ where
     async fn handle_progress(&self, _inp: ModulesProgress) -> Result<(), ExtractionError> {
         todo!()
     }
+
+    async fn flush(&self) -> Result<(), ExtractionError> {
+        if let Some(export) = &self.analytics_sink {
+            export.lock().await.sink.flush()?;
+        }
+        Ok(())
+    }
 }
 pub struct ExtractorPgGateway {
     name: String,
@@ -1210,6 +1538,12 @@ pub trait ExtractorGateway: Send + Sync {
 
     async fn get_contracts(&self, addresses: &[Address]) -> Result<Vec<Account>, StorageError>;
 
+    /// Returns which of the given contracts are stored, without loading their state.
+    async fn get_stored_contract_addresses(
+        &self,
+        addresses: &[Address],
+    ) -> Result<Vec<Address>, StorageError>;
+
     async fn get_components_balances<'a>(
         &self,
         component_ids: &[&'a str],
@@ -1303,6 +1637,11 @@ impl ExtractorGateway for ExtractorPgGateway {
                 .add_tokens(&new_tokens)
                 .await?;
         }
+        if !changes.new_token_analyses.is_empty() {
+            self.state_gateway
+                .add_token_analyses(&changes.new_token_analyses)
+                .await?;
+        }
         self.state_gateway
             .upsert_block(&[changes.block.clone()])
             .await?;
@@ -1443,6 +1782,22 @@ impl ExtractorGateway for ExtractorPgGateway {
             .map(|contract_data| contract_data.entity)
     }
 
+    async fn get_stored_contract_addresses(
+        &self,
+        addresses: &[Address],
+    ) -> Result<Vec<Address>, StorageError> {
+        self.state_gateway
+            .get_contracts(&self.chain, Some(addresses), None, false, None)
+            .await
+            .map(|contract_data| {
+                contract_data
+                    .entity
+                    .into_iter()
+                    .map(|account| account.address)
+                    .collect()
+            })
+    }
+
     async fn get_components_balances<'a>(
         &self,
         component_ids: &[&'a str],
@@ -1467,12 +1822,15 @@ mod test {
     use float_eq::assert_float_eq;
     use mockall::mock;
     use tycho_core::{
-        models::blockchain::{Transaction, TxWithChanges},
+        models::{
+            blockchain::{Transaction, TxWithChanges},
+            token::TokenQuality,
+        },
         traits::TokenOwnerFinding,
     };
 
     use super::*;
-    use crate::testing::{fixtures as pb_fixtures, MockGateway};
+    use crate::testing::{self, fixtures as pb_fixtures, MockGateway};
 
     mock! {
         pub TokenPreProcessor {}
@@ -1484,7 +1842,7 @@ mod test {
                 addresses: Vec<Bytes>,
                 token_finder: Arc<dyn TokenOwnerFinding>,
                 block: BlockTag,
-            ) -> Vec<CurrencyToken>;
+            ) -> Vec<(CurrencyToken, TokenAnalysis)>;
         }
     }
 
@@ -1818,6 +2176,229 @@ mod test {
         );
     }
 
+    mock! {
+        pub AccountExtractor {}
+
+        #[async_trait::async_trait]
+        impl AccountExtractor for AccountExtractor {
+            type Error = RPCError;
+
+            async fn get_accounts(
+                &self,
+                block: Block,
+                account_addresses: Vec<Address>,
+            ) -> Result<HashMap<Bytes, AccountDelta>, RPCError>;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_initialize_new_accounts() {
+        let stored = Bytes::from([0xaa; 20]);
+        let buffered = Bytes::from([0xbb; 20]);
+        let created = Bytes::from([0xcc; 20]);
+        let missing = Bytes::from([0xdd; 20]);
+        let creation = |address: &Bytes| {
+            AccountDelta::new(
+                Chain::Ethereum,
+                address.clone(),
+                HashMap::from([
+                    (Bytes::from(1u8).lpad(32, 0), Some(Bytes::from(1u8).lpad(32, 0))),
+                    (Bytes::from(2u8).lpad(32, 0), Some(Bytes::from(1u8).lpad(32, 0))),
+                ]),
+                Some(Bytes::from(100u8).lpad(32, 0)),
+                Some(Bytes::from("0x6080")),
+                ChangeType::Creation,
+            )
+        };
+        let mut gw = MockExtractorGateway::new();
+        gw.expect_ensure_protocol_types()
+            .times(1)
+            .returning(|_| ());
+        gw.expect_get_cursor()
+            .times(1)
+            .returning(|| Ok(("cursor".into(), Bytes::default())));
+        gw.expect_get_block()
+            .times(1)
+            .returning(|_| Ok(Block::default()));
+        let (stored_c, missing_c) = (stored.clone(), missing.clone());
+        gw.expect_get_stored_contract_addresses()
+            .withf(move |addresses| {
+                addresses.len() == 2 &&
+                    addresses.contains(&stored_c) &&
+                    addresses.contains(&missing_c)
+            })
+            .times(1)
+            .returning({
+                let stored = stored.clone();
+                move |_| Ok(vec![stored.clone()])
+            });
+        let mut account_extractor = MockAccountExtractor::new();
+        let missing_c = missing.clone();
+        account_extractor
+            .expect_get_accounts()
+            .withf(move |block, addresses| {
+                block.number == 2 && addresses == &vec![missing_c.clone()]
+            })
+            .times(1)
+            .returning(move |_, addresses| {
+                Ok(addresses
+                    .iter()
+                    .map(|address| (address.clone(), creation(address)))
+                    .collect())
+            });
+        let extractor = create_extractor(gw)
+            .await
+            .with_account_extractor(Arc::new(account_extractor), AccountInitFailurePolicy::Fail);
+
+        let tx = |block: u64, index: u64| {
+            Transaction::new(
+                Bytes::from(block * 10 + index).lpad(32, 0),
+                Bytes::from(block).lpad(32, 0),
+                Bytes::zero(20),
+                None,
+                index,
+            )
+        };
+        extractor
+            .reorg_buffer
+            .lock()
+            .await
+            .insert_block(BlockUpdateWithCursor::new(
+                BlockChanges::new(
+                    EXTRACTOR_NAME.to_string(),
+                    Chain::Ethereum,
+                    testing::block(1),
+                    0,
+                    false,
+                    vec![TxWithChanges {
+                        account_deltas: HashMap::from([(buffered.clone(), creation(&buffered))]),
+                        tx: tx(1, 0),
+                        ..Default::default()
+                    }],
+                ),
+                "cursor@1".to_string(),
+            ))
+            .unwrap();
+        let update = AccountDelta::new(
+            Chain::Ethereum,
+            missing.clone(),
+            HashMap::from([(Bytes::from(2u8).lpad(32, 0), Some(Bytes::from(2u8).lpad(32, 0)))]),
+            None,
+            None,
+            ChangeType::Update,
+        );
+        let component = ProtocolComponent {
+            id: "pc_0".to_string(),
+            contract_addresses: vec![
+                stored.clone(),
+                buffered.clone(),
+                created.clone(),
+                missing.clone(),
+            ],
+            ..Default::default()
+        };
+        let mut msg = BlockChanges::new(
+            EXTRACTOR_NAME.to_string(),
+            Chain::Ethereum,
+            testing::block(2),
+            0,
+            false,
+            vec![
+                TxWithChanges {
+                    account_deltas: HashMap::from([(missing.clone(), update)]),
+                    tx: tx(2, 0),
+                    ..Default::default()
+                },
+                TxWithChanges {
+                    protocol_components: HashMap::from([("pc_0".to_string(), component)]),
+                    account_deltas: HashMap::from([(created.clone(), creation(&created))]),
+                    tx: tx(2, 1),
+                    ..Default::default()
+                },
+            ],
+        );
+
+        extractor
+            .initialize_new_accounts(&mut msg)
+            .await
+            .expect("Failed to initialize accounts");
+
+        // The creation is merged with the update the module emitted before the component.
+        let mut expected = creation(&missing);
+        expected
+            .slots
+            .insert(Bytes::from(2u8).lpad(32, 0), Some(Bytes::from(2u8).lpad(32, 0)));
+        assert_eq!(
+            msg.txs_with_update[0].account_deltas,
+            HashMap::from([(missing.clone(), expected)])
+        );
+        assert_eq!(
+            msg.txs_with_update[1].account_deltas,
+            HashMap::from([(created.clone(), creation(&created))])
+        );
+    }
+
+    #[rstest::rstest]
+    #[case::fail(AccountInitFailurePolicy::Fail)]
+    #[case::skip(AccountInitFailurePolicy::Skip)]
+    #[tokio::test(start_paused = true)]
+    async fn test_initialize_new_accounts_failure(#[case] policy: AccountInitFailurePolicy) {
+        let missing = Bytes::from([0xdd; 20]);
+        let mut gw = MockExtractorGateway::new();
+        gw.expect_ensure_protocol_types()
+            .times(1)
+            .returning(|_| ());
+        gw.expect_get_cursor()
+            .times(1)
+            .returning(|| Ok(("cursor".into(), Bytes::default())));
+        gw.expect_get_block()
+            .times(1)
+            .returning(|_| Ok(Block::default()));
+        gw.expect_get_stored_contract_addresses()
+            .times(1)
+            .returning(|_| Ok(vec![]));
+        let mut account_extractor = MockAccountExtractor::new();
+        account_extractor
+            .expect_get_accounts()
+            .times(ACCOUNT_INIT_RETRIES + 1)
+            .returning(|_, _| Err(RPCError::UnexpectedResponse("header not found".to_string())));
+        let extractor = create_extractor(gw)
+            .await
+            .with_account_extractor(Arc::new(account_extractor), policy);
+        let component = ProtocolComponent {
+            id: "pc_0".to_string(),
+            contract_addresses: vec![missing.clone()],
+            ..Default::default()
+        };
+        let mut msg = BlockChanges::new(
+            EXTRACTOR_NAME.to_string(),
+            Chain::Ethereum,
+            testing::block(2),
+            0,
+            false,
+            vec![TxWithChanges {
+                protocol_components: HashMap::from([("pc_0".to_string(), component)]),
+                ..Default::default()
+            }],
+        );
+
+        let res = extractor
+            .initialize_new_accounts(&mut msg)
+            .await;
+
+        match policy {
+            AccountInitFailurePolicy::Fail => {
+                assert!(matches!(res, Err(ExtractionError::AccountExtractionError(_))))
+            }
+            AccountInitFailurePolicy::Skip => {
+                res.expect("block should be indexed without the accounts");
+                assert!(msg.txs_with_update[0]
+                    .account_deltas
+                    .is_empty());
+            }
+        }
+    }
+
     fn token_prices() -> HashMap<Bytes, f64> {
         HashMap::from([
             (
@@ -1927,7 +2508,14 @@ mod test {
             Chain::Ethereum,
             100,
         );
-        let ret = vec![t3.clone()];
+        let t3_analysis = TokenAnalysis::new(
+            &t3.address,
+            Chain::Ethereum,
+            None,
+            &Ok((TokenQuality::Good, None, None)),
+            100,
+        );
+        let ret = vec![(t3.clone(), t3_analysis.clone())];
         preprocessor
             .expect_get_tokens()
             .return_once(|_, balance_owner_store, _| {
@@ -1999,12 +2587,13 @@ mod test {
         .expect("Extractor init failed");
         let exp = HashMap::from([(t1.address.clone(), t1), (t3.address.clone(), t3)]);
 
-        let res = extractor
+        let (res, analyses) = extractor
             .construct_currency_tokens(&msg)
             .await
             .expect("construct_currency_tokens failed");
 
         assert_eq!(res, exp);
+        assert_eq!(analyses, vec![t3_analysis]);
     }
 
     #[test_log::test(tokio::test)]
@@ -2140,6 +2729,86 @@ mod test {
         assert_eq!(msg.component_tvl.len(), 1);
         assert_float_eq!(*res, exp_tvl, rmax <= 0.000_001);
     }
+
+    #[tokio::test]
+    async fn test_finalized_tvl() {
+        let token_1 = Bytes::from("0x0000000000000000000000000000000000000001");
+        let token_2 = Bytes::from("0x0000000000000000000000000000000000000002");
+        let prices = token_prices();
+        let balance = |token: &Bytes, units: f64| {
+            ComponentBalance::new(
+                token.clone(),
+                Bytes::from("0x01"),
+                units * prices[token],
+                Bytes::zero(32),
+                "comp1",
+            )
+        };
+        let block_changes = |balances: Vec<ComponentBalance>| BlockAggregatedChanges {
+            component_balances: HashMap::from([(
+                "comp1".to_string(),
+                balances
+                    .into_iter()
+                    .map(|b| (b.token.clone(), b))
+                    .collect(),
+            )]),
+            ..Default::default()
+        };
+
+        let mut protocol_gw = MockGateway::new();
+        protocol_gw
+            .expect_get_token_prices()
+            .returning(|_| Box::pin(async { Ok(token_prices()) }));
+        let protocol_cache = ProtocolMemoryCache::new(
+            Chain::Ethereum,
+            chrono::Duration::seconds(900),
+            Arc::new(protocol_gw),
+        );
+        let mut gw = MockExtractorGateway::new();
+        gw.expect_ensure_protocol_types()
+            .returning(|_| ());
+        gw.expect_get_cursor()
+            .returning(|| Ok(("cursor".into(), Bytes::default())));
+        gw.expect_get_block()
+            .returning(|_| Ok(Block::default()));
+        let stored = HashMap::from([(
+            "comp1".to_string(),
+            HashMap::from([
+                (token_1.clone(), balance(&token_1, 1.0)),
+                (token_2.clone(), balance(&token_2, 10.0)),
+            ]),
+        )]);
+        // stored balances are only loaded for the first block touching the component
+        gw.expect_get_components_balances()
+            .times(1)
+            .return_once(move |_| Ok(stored));
+        let extractor = ProtocolExtractor::new(
+            gw,
+            EXTRACTOR_NAME,
+            Chain::Ethereum,
+            ChainState::default(),
+            TEST_PROTOCOL.to_string(),
+            protocol_cache,
+            HashMap::new(),
+            MockTokenPreProcessor::new(),
+            None,
+        )
+        .await
+        .expect("extractor init failed");
+        let mut balances = HashMap::new();
+
+        let first = extractor
+            .finalized_tvl(&mut balances, &block_changes(vec![balance(&token_1, 2.0)]))
+            .await
+            .expect("first tvl failed");
+        let second = extractor
+            .finalized_tvl(&mut balances, &block_changes(vec![balance(&token_2, 3.0)]))
+            .await
+            .expect("second tvl failed");
+
+        assert_float_eq!(first["comp1"], 12.0, rmax <= 0.000_001);
+        assert_float_eq!(second["comp1"], 5.0, rmax <= 0.000_001);
+    }
 }
 
 /// It is notoriously hard to mock postgres here, we would need to have traits and abstractions
@@ -2160,8 +2829,8 @@ mod test_serial_db {
     use mockall::mock;
     use tycho_core::{
         models::{
-            blockchain::TxWithChanges, protocol::QualityRange, ContractId, FinancialType,
-            ImplementationType,
+            blockchain::TxWithChanges, protocol::QualityRange, token::TokenQuality, ContractId,
+            FinancialType, ImplementationType,
         },
         storage::BlockOrTimestamp,
         traits::TokenOwnerFinding,
@@ -2187,7 +2856,7 @@ mod test_serial_db {
                 addresses: Vec<Bytes>,
                 token_finder: Arc<dyn TokenOwnerFinding>,
                 block: BlockTag,
-            ) -> Vec<CurrencyToken>;
+            ) -> Vec<(CurrencyToken, TokenAnalysis)>;
         }
     }
 
@@ -2254,7 +2923,21 @@ mod test_serial_db {
         ];
         mock_processor
             .expect_get_tokens()
-            .returning(move |_, _, _| new_tokens.clone());
+            .returning(move |_, _, _| {
+                new_tokens
+                    .iter()
+                    .map(|t| {
+                        let analysis = TokenAnalysis::new(
+                            &t.address,
+                            t.chain,
+                            None,
+                            &Ok((TokenQuality::Good, None, None)),
+                            t.quality,
+                        );
+                        (t.clone(), analysis)
+                    })
+                    .collect()
+            });
 
         mock_processor
     }
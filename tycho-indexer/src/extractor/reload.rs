@@ -0,0 +1,537 @@
+//! Applies changes of the extractors configuration at runtime.
+//!
+//! The [ExtractorSupervisor] owns the tasks of all running extractors. On reload it compares the
+//! configuration file with the running extractors, stops removed extractors, starts added ones and
+//! restarts changed ones. Unchanged extractors are left untouched.
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use futures03::{future::select_all, FutureExt};
+use serde::Deserialize;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+    task::{JoinError, JoinHandle},
+};
+use tracing::{error, info, warn};
+use tycho_core::models::ExtractorIdentity;
+
+use crate::extractor::{
+    runner::{ExtractorConfig, ExtractorHandle, HandleResult},
+    ExtractionError,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct ExtractorConfigs {
+    pub extractors: HashMap<String, ExtractorConfig>,
+}
+
+impl ExtractorConfigs {
+    pub fn new(extractors: HashMap<String, ExtractorConfig>) -> Self {
+        Self { extractors }
+    }
+
+    pub fn from_yaml(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut file = File::open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let config: ExtractorConfigs = serde_yaml::from_str(&contents)?;
+        Ok(config)
+    }
+
+    /// Compares this configuration with `new`. Extractors are matched by their key.
+    pub fn diff(&self, new: &ExtractorConfigs) -> ConfigDiff {
+        let mut diff = ConfigDiff::default();
+        for (key, config) in new.extractors.iter() {
+            match self.extractors.get(key) {
+                None => diff.added.push(key.clone()),
+                Some(current) if current != config => diff.changed.push(key.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.removed = self
+            .extractors
+            .keys()
+            .filter(|key| !new.extractors.contains_key(*key))
+            .cloned()
+            .collect();
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+        diff
+    }
+}
+
+/// Extractors to start and stop to apply a new configuration, identified by their key in the
+/// configuration file.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Extractors whose configuration changed. They are restarted.
+    pub changed: Vec<String>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// A change to the set of running extractors, applied to the running services.
+pub enum ExtractorUpdate {
+    /// The extractor was started and can be subscribed to.
+    Added(ExtractorHandle),
+    /// The extractor is being stopped.
+    Removed(ExtractorIdentity),
+}
+
+/// Starts an extractor from its configuration.
+#[async_trait]
+pub trait ExtractorLauncher: Send + Sync {
+    async fn launch(&self, config: &ExtractorConfig) -> Result<HandleResult, ExtractionError>;
+}
+
+enum SupervisorCommand {
+    Reload,
+    Stop,
+}
+
+/// Handle to request a reload or the shutdown of an [ExtractorSupervisor].
+#[derive(Clone)]
+pub struct SupervisorHandle {
+    tx: mpsc::Sender<SupervisorCommand>,
+}
+
+impl SupervisorHandle {
+    /// Reloads the extractors configuration file.
+    pub async fn reload(&self) -> Result<(), ExtractionError> {
+        self.send(SupervisorCommand::Reload)
+            .await
+    }
+
+    /// Stops all extractors.
+    pub async fn stop(&self) -> Result<(), ExtractionError> {
+        self.send(SupervisorCommand::Stop).await
+    }
+
+    async fn send(&self, command: SupervisorCommand) -> Result<(), ExtractionError> {
+        self.tx
+            .send(command)
+            .await
+            .map_err(|_| ExtractionError::Unknown("Extractor supervisor stopped".to_string()))
+    }
+}
+
+struct RunningExtractor {
+    handle: ExtractorHandle,
+    task: JoinHandle<Result<(), ExtractionError>>,
+}
+
+/// Starts, stops and watches the extractors.
+///
+/// Like before extractors could be reloaded, an extractor exiting on its own is fatal: `run`
+/// returns with its result.
+pub struct ExtractorSupervisor<L> {
+    launcher: L,
+    /// If 'None' reloads are ignored.
+    config_path: Option<String>,
+    /// Configurations of the running extractors.
+    configs: ExtractorConfigs,
+    /// Running extractors by their key in the configuration file.
+    extractors: HashMap<String, RunningExtractor>,
+    /// Receives extractors added and removed by reloads.
+    updates: Option<mpsc::Sender<ExtractorUpdate>>,
+    commands: mpsc::Receiver<SupervisorCommand>,
+}
+
+impl<L: ExtractorLauncher> ExtractorSupervisor<L> {
+    pub fn new(launcher: L, config_path: Option<&str>) -> (Self, SupervisorHandle) {
+        let (tx, commands) = mpsc::channel(8);
+        let supervisor = Self {
+            launcher,
+            config_path: config_path.map(ToString::to_string),
+            configs: ExtractorConfigs::new(HashMap::new()),
+            extractors: HashMap::new(),
+            updates: None,
+            commands,
+        };
+        (supervisor, SupervisorHandle { tx })
+    }
+
+    pub fn updates(mut self, tx: mpsc::Sender<ExtractorUpdate>) -> Self {
+        self.updates = Some(tx);
+        self
+    }
+
+    /// Starts the initial extractors. Unlike extractors started by reloads, they are not sent as
+    /// updates and have to be registered with the services directly.
+    pub async fn start(
+        &mut self,
+        configs: ExtractorConfigs,
+    ) -> Result<Vec<ExtractorHandle>, ExtractionError> {
+        let mut configs: Vec<_> = configs.extractors.into_iter().collect();
+        configs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut handles = Vec::with_capacity(configs.len());
+        for (key, config) in configs {
+            handles.push(
+                self.start_extractor(&key, config)
+                    .await?,
+            );
+        }
+        Ok(handles)
+    }
+
+    /// Applies reloads until stopped or until one of the extractors exits.
+    pub async fn run(mut self) -> Result<(), ExtractionError> {
+        loop {
+            tokio::select! {
+                (key, res) = Self::any_exit(&mut self.extractors) => {
+                    error!(extractor = key, "Extractor exited");
+                    return res.unwrap_or_else(|err| Err(ExtractionError::Unknown(err.to_string())));
+                }
+                command = self.commands.recv() => match command {
+                    Some(SupervisorCommand::Reload) => self.reload().await,
+                    Some(SupervisorCommand::Stop) | None => {
+                        self.stop_all().await;
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Resolves once any of the running extractors exits.
+    async fn any_exit(
+        extractors: &mut HashMap<String, RunningExtractor>,
+    ) -> (String, Result<Result<(), ExtractionError>, JoinError>) {
+        if extractors.is_empty() {
+            return std::future::pending().await;
+        }
+        let exits = extractors
+            .iter_mut()
+            .map(|(key, extractor)| {
+                async move { (key.clone(), (&mut extractor.task).await) }.boxed()
+            });
+        select_all(exits).await.0
+    }
+
+    async fn reload(&mut self) {
+        let Some(path) = self.config_path.clone() else {
+            warn!("No extractors config to reload");
+            return;
+        };
+        let new = match ExtractorConfigs::from_yaml(&path) {
+            Ok(configs) => configs,
+            Err(err) => {
+                error!(error = %err, path, "Failed to load extractors config, keeping the running extractors");
+                return;
+            }
+        };
+        let diff = self.configs.diff(&new);
+        if diff.is_empty() {
+            info!(path, "Extractors config unchanged");
+            return;
+        }
+
+        info!(?diff, "Applying extractors config");
+        for key in diff
+            .removed
+            .iter()
+            .chain(diff.changed.iter())
+        {
+            self.stop_extractor(key).await;
+        }
+        for key in diff
+            .added
+            .iter()
+            .chain(diff.changed.iter())
+        {
+            let config = new.extractors[key].clone();
+            let handle = match self.start_extractor(key, config).await {
+                Ok(handle) => handle,
+                Err(err) => {
+                    // not recorded as running, so the next reload retries it
+                    error!(extractor = key, error = %err, "Failed to start extractor");
+                    continue;
+                }
+            };
+            self.notify(ExtractorUpdate::Added(handle))
+                .await;
+        }
+    }
+
+    async fn start_extractor(
+        &mut self,
+        key: &str,
+        config: ExtractorConfig,
+    ) -> Result<ExtractorHandle, ExtractionError> {
+        let (task, handle) = self.launcher.launch(&config).await?;
+        info!(extractor = key, "Extractor started");
+        self.extractors
+            .insert(key.to_string(), RunningExtractor { handle: handle.clone(), task });
+        self.configs
+            .extractors
+            .insert(key.to_string(), config);
+        Ok(handle)
+    }
+
+    /// Deregisters an extractor from the services, then stops it and waits for its task to end.
+    async fn stop_extractor(&mut self, key: &str) {
+        let Some(extractor) = self.extractors.remove(key) else {
+            return;
+        };
+        self.configs.extractors.remove(key);
+        self.notify(ExtractorUpdate::Removed(extractor.handle.get_id()))
+            .await;
+        if let Err(err) = extractor.handle.stop().await {
+            // the runner already exited
+            warn!(extractor = key, error = %err, "Failed to send stop signal");
+        }
+        match extractor.task.await {
+            Ok(Ok(())) => info!(extractor = key, "Extractor stopped"),
+            Ok(Err(err)) => warn!(extractor = key, error = %err, "Stopped extractor failed"),
+            Err(err) => warn!(extractor = key, error = %err, "Stopped extractor panicked"),
+        }
+    }
+
+    async fn stop_all(&mut self) {
+        let mut keys: Vec<_> = self
+            .extractors
+            .keys()
+            .cloned()
+            .collect();
+        keys.sort();
+        for key in keys {
+            self.stop_extractor(&key).await;
+        }
+    }
+
+    async fn notify(&self, update: ExtractorUpdate) {
+        if let Some(updates) = &self.updates {
+            if updates.send(update).await.is_err() {
+                warn!("Services stopped receiving extractor updates");
+            }
+        }
+    }
+}
+
+/// Requests a reload of the extractors configuration on SIGHUP and, if `poll_interval` is set,
+/// whenever the modification time of the file at `path` changes. Returns once the supervisor
+/// stopped.
+pub async fn watch_config(
+    path: String,
+    poll_interval: Option<Duration>,
+    supervisor: SupervisorHandle,
+) -> Result<(), ExtractionError> {
+    let mut hangup = signal(SignalKind::hangup())
+        .map_err(|err| ExtractionError::Setup(format!("Failed to listen for SIGHUP: {}", err)))?;
+    let mut interval = poll_interval.map(tokio::time::interval);
+    let mut modified = modified_at(&path);
+    loop {
+        let reload = tokio::select! {
+            _ = hangup.recv() => {
+                info!(path, "SIGHUP received, reloading extractors config");
+                true
+            }
+            _ = async {
+                match interval.as_mut() {
+                    Some(interval) => {
+                        interval.tick().await;
+                    }
+                    None => std::future::pending().await,
+                }
+            } => {
+                let current = modified_at(&path);
+                let changed = current != modified;
+                modified = current;
+                if changed {
+                    info!(path, "Extractors config changed, reloading");
+                }
+                changed
+            }
+        };
+        if reload && supervisor.reload().await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn modified_at(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use tycho_core::models::Chain;
+
+    use super::*;
+    use crate::extractor::runner::ControlMessage;
+
+    fn extractor_yaml(name: &str, start_block: i64) -> String {
+        format!(
+            r#"
+  {name}:
+    name: "{name}"
+    chain: "ethereum"
+    implementation_type: "Custom"
+    sync_batch_size: 1000
+    start_block: {start_block}
+    protocol_types:
+      - name: "{name}_pool"
+        financial_type: "Swap"
+    spkg: "substreams/{name}.spkg"
+    module_name: "map_pool_events"
+"#
+        )
+    }
+
+    fn configs_yaml(extractors: &[(&str, i64)]) -> String {
+        let mut yaml = "extractors:".to_string();
+        for (name, start_block) in extractors {
+            yaml.push_str(&extractor_yaml(name, *start_block));
+        }
+        yaml
+    }
+
+    fn configs(extractors: &[(&str, i64)]) -> ExtractorConfigs {
+        serde_yaml::from_str(&configs_yaml(extractors)).expect("invalid config")
+    }
+
+    #[test]
+    fn test_diff() {
+        let current = configs(&[("uniswap_v2", 1), ("uniswap_v3", 1), ("sushiswap_v2", 1)]);
+        let new = configs(&[("uniswap_v2", 1), ("uniswap_v3", 2), ("balancer_v2", 1)]);
+
+        let diff = current.diff(&new);
+
+        assert_eq!(
+            diff,
+            ConfigDiff {
+                added: vec!["balancer_v2".to_string()],
+                removed: vec!["sushiswap_v2".to_string()],
+                changed: vec!["uniswap_v3".to_string()],
+            }
+        );
+        assert!(new.diff(&new).is_empty());
+    }
+
+    #[derive(Clone, Default)]
+    struct FakeLauncher {
+        launched: Arc<Mutex<Vec<String>>>,
+        stopped: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl ExtractorLauncher for FakeLauncher {
+        async fn launch(&self, config: &ExtractorConfig) -> Result<HandleResult, ExtractionError> {
+            let name = config.name().to_string();
+            self.launched
+                .lock()
+                .unwrap()
+                .push(name.clone());
+            let (tx, mut rx) = mpsc::channel(8);
+            let stopped = self.stopped.clone();
+            let task = tokio::spawn(async move {
+                while let Some(msg) = rx.recv().await {
+                    if let ControlMessage::Stop = msg {
+                        stopped.lock().unwrap().push(name);
+                        return Ok(());
+                    }
+                }
+                Ok(())
+            });
+            let id = ExtractorIdentity::new(Chain::Ethereum, config.name());
+            Ok((task, ExtractorHandle::new(id, tx, Default::default(), None)))
+        }
+    }
+
+    fn describe(update: ExtractorUpdate) -> String {
+        match update {
+            ExtractorUpdate::Added(handle) => format!("added {}", handle.get_id().name),
+            ExtractorUpdate::Removed(id) => format!("removed {}", id.name),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let path = std::env::temp_dir().join(format!("extractors-{}.yaml", uuid::Uuid::new_v4()));
+        let path_str = path.to_str().unwrap().to_string();
+        let launcher = FakeLauncher::default();
+        let (updates_tx, mut updates_rx) = mpsc::channel(8);
+        let (supervisor, handle) = ExtractorSupervisor::new(launcher.clone(), Some(&path_str));
+        let mut supervisor = supervisor.updates(updates_tx);
+
+        let initial = supervisor
+            .start(configs(&[("uniswap_v2", 1), ("uniswap_v3", 1), ("sushiswap_v2", 1)]))
+            .await
+            .expect("start failed");
+        let task = tokio::spawn(supervisor.run());
+        std::fs::write(
+            &path,
+            configs_yaml(&[("uniswap_v2", 1), ("uniswap_v3", 2), ("balancer_v2", 1)]),
+        )
+        .unwrap();
+        handle.reload().await.unwrap();
+        let mut updates = Vec::new();
+        for _ in 0..4 {
+            let update = tokio::time::timeout(Duration::from_secs(1), updates_rx.recv())
+                .await
+                .expect("update timed out")
+                .expect("updates closed");
+            updates.push(describe(update));
+        }
+        handle.stop().await.unwrap();
+        let res = task.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(initial.len(), 3);
+        assert_eq!(
+            updates,
+            vec![
+                "removed sushiswap_v2",
+                "removed uniswap_v3",
+                "added balancer_v2",
+                "added uniswap_v3"
+            ]
+        );
+        assert_eq!(
+            *launcher.launched.lock().unwrap(),
+            vec!["sushiswap_v2", "uniswap_v2", "uniswap_v3", "balancer_v2", "uniswap_v3"]
+        );
+        assert_eq!(
+            launcher.stopped.lock().unwrap()[..2],
+            ["sushiswap_v2".to_string(), "uniswap_v3".to_string()]
+        );
+        assert_eq!(res, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_run_returns_on_extractor_exit() {
+        let launcher = FakeLauncher::default();
+        let (mut supervisor, _handle) = ExtractorSupervisor::new(launcher, None);
+        let handles = supervisor
+            .start(configs(&[("uniswap_v2", 1)]))
+            .await
+            .expect("start failed");
+        let task = tokio::spawn(supervisor.run());
+
+        // stopping the extractor directly bypasses the supervisor
+        handles[0].stop().await.unwrap();
+        let res = tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .expect("supervisor did not exit")
+            .unwrap();
+
+        assert_eq!(res, Ok(()));
+    }
+}
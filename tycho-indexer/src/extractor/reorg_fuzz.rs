@@ -0,0 +1,818 @@
+//! Property-based reorg fuzzing for `ProtocolExtractor` and `PendingDeltas`.
+//!
+//! Random chains with forks are fed through a `ProtocolExtractor` backed by an in-memory
+//! gateway, and every emitted message is inserted into `PendingDeltas` and applied by a
+//! simulated client. Blocks create and delete components and change their attributes and
+//! balances as well as contract storage. After each step the persisted state, the state served
+//! over RPC and the client's state are compared against a reference recomputed from the
+//! canonical chain.
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use proptest::prelude::*;
+use tycho_core::{
+    models::{
+        blockchain::{BlockAggregatedChanges, BlockTag, TxWithChanges},
+        contract::{Account, AccountDelta},
+        protocol::{ComponentBalance, ProtocolComponentState, ProtocolComponentStateDelta},
+        token::{CurrencyToken, TokenAnalysis},
+        Address, Chain, ChangeType, ProtocolType,
+    },
+    storage::StorageError,
+    traits::{TokenOwnerFinding, TokenPreProcessor},
+    Bytes,
+};
+use tycho_substreams::pb::tycho::evm::v1 as tycho_substreams;
+
+use crate::{
+    extractor::{
+        chain_state::ChainState,
+        models::BlockChanges,
+        protocol_cache::ProtocolMemoryCache,
+        protocol_extractor::{MockExtractorGateway, ProtocolExtractor},
+        Extractor, ExtractorMsg,
+    },
+    pb::sf::substreams::{rpc::v2::BlockUndoSignal, v1::BlockRef},
+    services::deltas_buffer::{PendingDeltas, PendingDeltasBuffer},
+    testing::{fixtures as pb_fixtures, MockGateway},
+};
+
+const EXTRACTOR_NAME: &str = "native:fuzz";
+const PROTOCOL_SYSTEM: &str = "fuzz_protocol";
+const PROTOCOL_TYPE: &str = "pt_1";
+/// Number of blocks the finalized block trails the chain head by.
+const FINALITY_LAG: u64 = 4;
+const COMPONENTS: [&str; 4] = ["component_0", "component_1", "component_2", "component_3"];
+const ATTRIBUTES: [&str; 3] = ["attr_0", "attr_1", "attr_2"];
+/// Number of tokens held by every component.
+const TOKENS: usize = 2;
+const CONTRACTS: usize = 2;
+/// Number of storage slots per contract.
+const SLOTS: usize = 3;
+
+fn token(index: usize) -> Bytes {
+    Bytes::from(0x7000 + index as u64).lpad(20, 0)
+}
+
+fn contract(index: usize) -> Bytes {
+    Bytes::from(0xc000 + index as u64).lpad(20, 0)
+}
+
+fn slot(index: usize) -> Bytes {
+    Bytes::from(index as u64).lpad(32, 0)
+}
+
+/// Keyed values, e.g. attribute values keyed by component id and attribute name.
+type Values<K, V> = HashMap<K, HashMap<V, Bytes>>;
+
+/// Protocol and contract state as seen by the persisted state, the pending deltas or a client.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct State {
+    /// Attribute values keyed by component id and attribute name.
+    attributes: Values<String, String>,
+    /// Balances keyed by component id and token address.
+    balances: Values<String, Bytes>,
+    /// Storage keyed by contract address and slot.
+    storage: Values<Bytes, Bytes>,
+    /// The last creation or deletion of every created component.
+    components: HashMap<String, ChangeType>,
+}
+
+impl State {
+    /// Drops empty values and entries, which are indistinguishable from unknown ones. Reverts
+    /// restore balances and slots unknown before the reverted blocks as empty values.
+    fn normalised(self) -> Self {
+        Self {
+            attributes: normalised(self.attributes),
+            balances: normalised(self.balances),
+            storage: normalised(self.storage),
+            components: self.components,
+        }
+    }
+
+    /// Applies the changes of a transaction, as the database does when a block is committed.
+    fn apply_tx(&mut self, tx: &TxWithChanges) {
+        for (id, component) in &tx.protocol_components {
+            self.components
+                .insert(id.clone(), component.change);
+        }
+        for delta in tx.state_updates.values() {
+            self.apply_state_delta(delta);
+        }
+        self.apply_balances(&tx.balance_changes);
+        for (address, delta) in &tx.account_deltas {
+            self.apply_account_delta(address, delta);
+        }
+    }
+
+    /// Applies a message emitted by the extractor, as a client does.
+    fn apply_changes(&mut self, changes: &BlockAggregatedChanges) {
+        for delta in changes.state_deltas.values() {
+            self.apply_state_delta(delta);
+        }
+        self.apply_balances(&changes.component_balances);
+        for (address, delta) in &changes.account_deltas {
+            self.apply_account_delta(address, delta);
+        }
+        // Forward messages report deletions as new components with a deletion change, reverts
+        // report undone deletions as new components with a creation change.
+        for (id, component) in &changes.new_protocol_components {
+            self.components
+                .insert(id.clone(), component.change);
+        }
+        // Only reverts report deleted components, their creation was undone.
+        for id in changes
+            .deleted_protocol_components
+            .keys()
+        {
+            self.components.remove(id);
+            self.attributes.remove(id);
+            self.balances.remove(id);
+        }
+    }
+
+    fn apply_state_delta(&mut self, delta: &ProtocolComponentStateDelta) {
+        let attributes = self
+            .attributes
+            .entry(delta.component_id.clone())
+            .or_default();
+        attributes.extend(delta.updated_attributes.clone());
+        attributes.retain(|attr, _| !delta.deleted_attributes.contains(attr));
+    }
+
+    fn apply_balances(&mut self, balances: &HashMap<String, HashMap<Bytes, ComponentBalance>>) {
+        for (id, balances) in balances {
+            self.balances
+                .entry(id.clone())
+                .or_default()
+                .extend(
+                    balances
+                        .iter()
+                        .map(|(token, balance)| (token.clone(), balance.balance.clone())),
+                );
+        }
+    }
+
+    fn apply_account_delta(&mut self, address: &Bytes, delta: &AccountDelta) {
+        self.storage
+            .entry(address.clone())
+            .or_default()
+            .extend(
+                delta
+                    .slots
+                    .iter()
+                    .map(|(slot, value)| (slot.clone(), value.clone().unwrap_or_default())),
+            );
+    }
+}
+
+fn normalised<K: Eq + Hash, V: Eq + Hash>(values: Values<K, V>) -> Values<K, V> {
+    values
+        .into_iter()
+        .map(|(key, values)| {
+            let values = values
+                .into_iter()
+                .filter(|(_, value)| !value.is_empty())
+                .collect::<HashMap<_, _>>();
+            (key, values)
+        })
+        .filter(|(_, values)| !values.is_empty())
+        .collect()
+}
+
+/// A single change, emitted in its own transaction. Changes that are invalid at the point they
+/// are applied are dropped when the block is built.
+#[derive(Debug, Clone)]
+enum Change {
+    /// Sets an attribute of an existing component as (component, attribute, value).
+    Attribute(usize, usize, u8),
+    /// Sets a balance of an existing component as (component, token, value).
+    Balance(usize, usize, u8),
+    /// Creates a component that was never created before.
+    Create(usize),
+    /// Deletes a component that existed at the start of the block.
+    Delete(usize),
+    /// Sets a storage slot of a contract as (contract, slot, value). Native balances and code
+    /// are not changed, reverts do not restore them yet.
+    Storage(usize, usize, u8),
+}
+
+#[derive(Debug, Clone)]
+enum Step {
+    /// Appends a block with the given changes to the chain head.
+    Block(Vec<Change>),
+    /// Reverts the given number of blocks, capped so the finalized block is never reverted.
+    Revert(u64),
+}
+
+fn change_strategy() -> impl Strategy<Value = Change> {
+    let component = 0..COMPONENTS.len();
+    prop_oneof![
+        3 => (component.clone(), 0..ATTRIBUTES.len(), any::<u8>())
+            .prop_map(|(component, attribute, value)| Change::Attribute(component, attribute, value)),
+        2 => (component.clone(), 0..TOKENS, any::<u8>())
+            .prop_map(|(component, token, value)| Change::Balance(component, token, value)),
+        2 => component.clone().prop_map(Change::Create),
+        1 => component.prop_map(Change::Delete),
+        2 => (0..CONTRACTS, 0..SLOTS, any::<u8>())
+            .prop_map(|(contract, slot, value)| Change::Storage(contract, slot, value)),
+    ]
+}
+
+fn step_strategy() -> impl Strategy<Value = Step> {
+    prop_oneof![
+        3 => prop::collection::vec(change_strategy(), 0..6).prop_map(Step::Block),
+        1 => (1..=FINALITY_LAG).prop_map(Step::Revert),
+    ]
+}
+
+#[derive(Debug, Clone)]
+struct SimBlock {
+    number: u64,
+    hash: Bytes,
+    parent_hash: Bytes,
+    changes: Vec<Change>,
+}
+
+/// Hashes are unique per fork, so a block rebuilt after a revert never reuses a reverted hash.
+fn block_hash(fork: u64, number: u64) -> Bytes {
+    Bytes::from((fork << 32) | number).lpad(32, 0)
+}
+
+/// Reference model of the canonical chain.
+#[derive(Default)]
+struct CanonicalChain {
+    blocks: Vec<SimBlock>,
+    finalized: u64,
+    fork: u64,
+}
+
+impl CanonicalChain {
+    fn head(&self) -> &SimBlock {
+        self.blocks
+            .last()
+            .expect("chain has no blocks")
+    }
+
+    /// Appends a block with the given changes, dropping those invalid on top of the head.
+    fn push(&mut self, changes: Vec<Change>) -> &SimBlock {
+        let (number, parent_hash) = match self.blocks.last() {
+            Some(head) => (head.number + 1, head.hash.clone()),
+            None => (1, block_hash(0, 0)),
+        };
+        let existing = self.state_at(number - 1).components;
+        let mut components = existing.clone();
+        let changes = changes
+            .into_iter()
+            .filter(|change| match *change {
+                Change::Attribute(component, ..) | Change::Balance(component, ..) => {
+                    components.get(COMPONENTS[component]) == Some(&ChangeType::Creation)
+                }
+                Change::Create(component) => {
+                    let id = COMPONENTS[component];
+                    let new = !components.contains_key(id);
+                    if new {
+                        components.insert(id.to_string(), ChangeType::Creation);
+                    }
+                    new
+                }
+                Change::Delete(component) => {
+                    let id = COMPONENTS[component];
+                    let exists = existing.get(id) == Some(&ChangeType::Creation) &&
+                        components.get(id) == Some(&ChangeType::Creation);
+                    if exists {
+                        components.insert(id.to_string(), ChangeType::Deletion);
+                    }
+                    exists
+                }
+                Change::Storage(..) => true,
+            })
+            .collect();
+        self.finalized = self
+            .finalized
+            .max(number.saturating_sub(FINALITY_LAG))
+            .max(1);
+        self.blocks.push(SimBlock {
+            number,
+            hash: block_hash(self.fork, number),
+            parent_hash,
+            changes,
+        });
+        self.head()
+    }
+
+    /// Drops up to `depth` unfinalized blocks and returns the new head, or `None` if there was
+    /// nothing to revert.
+    fn revert(&mut self, depth: u64) -> Option<&SimBlock> {
+        let head = self.blocks.last()?.number;
+        let depth = depth.min(head - self.finalized);
+        if depth == 0 {
+            return None;
+        }
+        self.blocks
+            .truncate(self.blocks.len() - depth as usize);
+        self.fork += 1;
+        Some(self.head())
+    }
+
+    fn canonical_changes(&self) -> impl Iterator<Item = (u64, &Change)> {
+        self.blocks.iter().flat_map(|block| {
+            block
+                .changes
+                .iter()
+                .map(move |change| (block.number, change))
+        })
+    }
+
+    /// State after applying every canonical block up to and including `number`.
+    fn state_at(&self, number: u64) -> State {
+        let mut state = State::default();
+        for (_, change) in self
+            .canonical_changes()
+            .take_while(|(block, _)| *block <= number)
+        {
+            match *change {
+                Change::Attribute(component, attribute, value) => {
+                    state
+                        .attributes
+                        .entry(COMPONENTS[component].to_string())
+                        .or_default()
+                        .insert(ATTRIBUTES[attribute].to_string(), Bytes::from(vec![value]));
+                }
+                Change::Balance(component, index, value) => {
+                    state
+                        .balances
+                        .entry(COMPONENTS[component].to_string())
+                        .or_default()
+                        .insert(token(index), Bytes::from(vec![value]));
+                }
+                Change::Create(component) => {
+                    state
+                        .components
+                        .insert(COMPONENTS[component].to_string(), ChangeType::Creation);
+                }
+                Change::Delete(component) => {
+                    state
+                        .components
+                        .insert(COMPONENTS[component].to_string(), ChangeType::Deletion);
+                }
+                Change::Storage(index, slot_index, value) => {
+                    state
+                        .storage
+                        .entry(contract(index))
+                        .or_default()
+                        .insert(slot(slot_index), Bytes::from(vec![value]));
+                }
+            }
+        }
+        state
+    }
+
+    /// The last creation or deletion of every component changed from block `number` onwards.
+    fn component_changes_from(&self, number: u64) -> HashMap<String, ChangeType> {
+        self.canonical_changes()
+            .filter(|(block, _)| *block >= number)
+            .filter_map(|(_, change)| match *change {
+                Change::Create(component) => {
+                    Some((COMPONENTS[component].to_string(), ChangeType::Creation))
+                }
+                Change::Delete(component) => {
+                    Some((COMPONENTS[component].to_string(), ChangeType::Deletion))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+fn pb_component(
+    component: usize,
+    change: tycho_substreams::ChangeType,
+) -> tycho_substreams::ProtocolComponent {
+    tycho_substreams::ProtocolComponent {
+        id: COMPONENTS[component].to_owned(),
+        tokens: (0..TOKENS)
+            .map(|index| token(index).to_vec())
+            .collect(),
+        contracts: vec![],
+        static_att: vec![],
+        change: change.into(),
+        protocol_type: Some(tycho_substreams::ProtocolType {
+            name: PROTOCOL_TYPE.to_string(),
+            financial_type: 0,
+            attribute_schema: vec![],
+            implementation_type: 0,
+        }),
+    }
+}
+
+fn pb_transaction_changes(
+    block: &SimBlock,
+    index: usize,
+    change: &Change,
+) -> tycho_substreams::TransactionChanges {
+    let mut changes = tycho_substreams::TransactionChanges {
+        tx: Some(pb_fixtures::pb_transactions(block.number, index as u64 + 1)),
+        contract_changes: vec![],
+        entity_changes: vec![],
+        component_changes: vec![],
+        balance_changes: vec![],
+    };
+    match *change {
+        Change::Attribute(component, attribute, value) => {
+            changes
+                .entity_changes
+                .push(tycho_substreams::EntityChanges {
+                    component_id: COMPONENTS[component].to_owned(),
+                    attributes: vec![tycho_substreams::Attribute {
+                        name: ATTRIBUTES[attribute].to_owned(),
+                        value: vec![value],
+                        change: tycho_substreams::ChangeType::Update.into(),
+                    }],
+                })
+        }
+        Change::Balance(component, index, value) => {
+            changes
+                .balance_changes
+                .push(tycho_substreams::BalanceChange {
+                    token: token(index).to_vec(),
+                    balance: vec![value],
+                    component_id: COMPONENTS[component]
+                        .as_bytes()
+                        .to_vec(),
+                })
+        }
+        Change::Create(component) => changes
+            .component_changes
+            .push(pb_component(component, tycho_substreams::ChangeType::Creation)),
+        Change::Delete(component) => changes
+            .component_changes
+            .push(pb_component(component, tycho_substreams::ChangeType::Deletion)),
+        Change::Storage(index, slot_index, value) => {
+            changes
+                .contract_changes
+                .push(tycho_substreams::ContractChange {
+                    address: contract(index).to_vec(),
+                    balance: vec![],
+                    code: vec![],
+                    slots: vec![tycho_substreams::ContractSlot {
+                        slot: slot(slot_index).to_vec(),
+                        value: vec![value],
+                    }],
+                    change: tycho_substreams::ChangeType::Update.into(),
+                    token_balances: vec![],
+                })
+        }
+    }
+    changes
+}
+
+fn pb_block_changes(block: &SimBlock) -> tycho_substreams::BlockChanges {
+    tycho_substreams::BlockChanges {
+        block: Some(tycho_substreams::Block {
+            number: block.number,
+            hash: block.hash.to_vec(),
+            parent_hash: block.parent_hash.to_vec(),
+            ts: pb_fixtures::pb_blocks(block.number).ts,
+        }),
+        changes: block
+            .changes
+            .iter()
+            .enumerate()
+            .map(|(index, change)| pb_transaction_changes(block, index, change))
+            .collect(),
+    }
+}
+
+fn account(address: &Bytes, slots: &HashMap<Bytes, Bytes>) -> Account {
+    AccountDelta::new(
+        Chain::Ethereum,
+        address.clone(),
+        slots
+            .iter()
+            .map(|(slot, value)| (slot.clone(), Some(value.clone())))
+            .collect(),
+        None,
+        None,
+        ChangeType::Creation,
+    )
+    .into_account_without_tx()
+}
+
+struct NoopTokenPreProcessor;
+
+#[async_trait]
+impl TokenPreProcessor for NoopTokenPreProcessor {
+    async fn get_tokens(
+        &self,
+        _addresses: Vec<Bytes>,
+        _token_finder: Arc<dyn TokenOwnerFinding>,
+        _block: BlockTag,
+    ) -> Vec<(CurrencyToken, TokenAnalysis)> {
+        Vec::new()
+    }
+}
+
+/// An extractor gateway persisting protocol and contract state in memory.
+fn in_memory_gateway(db: Arc<Mutex<State>>) -> MockExtractorGateway {
+    let mut gw = MockExtractorGateway::new();
+    gw.expect_get_cursor()
+        .returning(|| Err(StorageError::NotFound("Cursor".to_owned(), EXTRACTOR_NAME.to_owned())));
+    gw.expect_ensure_protocol_types()
+        .returning(|_| ());
+    let advance_db = db.clone();
+    gw.expect_advance()
+        .returning(move |changes: &BlockChanges, _: &str, _: bool| {
+            let mut db = advance_db.lock().unwrap();
+            for tx in &changes.txs_with_update {
+                db.apply_tx(tx);
+            }
+            Ok(())
+        });
+    let states_db = db.clone();
+    gw.expect_get_protocol_states()
+        .returning(move |component_ids: &[&str]| {
+            let db = states_db.lock().unwrap();
+            Ok(component_ids
+                .iter()
+                .map(|id| {
+                    ProtocolComponentState::new(
+                        id,
+                        db.attributes
+                            .get(*id)
+                            .cloned()
+                            .unwrap_or_default(),
+                        db.balances
+                            .get(*id)
+                            .cloned()
+                            .unwrap_or_default(),
+                    )
+                })
+                .collect())
+        });
+    let contracts_db = db.clone();
+    gw.expect_get_contracts()
+        .returning(move |addresses: &[Address]| {
+            let db = contracts_db.lock().unwrap();
+            Ok(addresses
+                .iter()
+                .filter_map(|address| {
+                    db.storage
+                        .get(address)
+                        .map(|slots| account(address, slots))
+                })
+                .collect())
+        });
+    gw.expect_get_components_balances()
+        .returning(move |component_ids: &[&str]| {
+            let db = db.lock().unwrap();
+            Ok(component_ids
+                .iter()
+                .filter_map(|id| {
+                    let balances = db.balances.get(*id)?;
+                    let balances = balances
+                        .iter()
+                        .map(|(token, balance)| {
+                            let balance = ComponentBalance {
+                                token: token.clone(),
+                                balance: balance.clone(),
+                                balance_float: 0.0,
+                                modify_tx: Bytes::new(),
+                                component_id: id.to_string(),
+                            };
+                            (token.clone(), balance)
+                        })
+                        .collect();
+                    Some((id.to_string(), balances))
+                })
+                .collect())
+        });
+    gw.expect_get_account_balances()
+        .returning(|_| Ok(HashMap::new()));
+    gw
+}
+
+/// A protocol gateway without token prices. Components are always found in the protocol cache,
+/// which learns about them when they are created.
+fn empty_protocol_gateway() -> MockGateway {
+    let mut gw = MockGateway::new();
+    gw.expect_get_token_prices()
+        .returning(|_| Box::pin(async { Ok(HashMap::new()) }));
+    gw
+}
+
+struct Harness {
+    extractor: ProtocolExtractor<MockExtractorGateway, NoopTokenPreProcessor>,
+    db: Arc<Mutex<State>>,
+    pending_deltas: PendingDeltas,
+    client: State,
+    chain: CanonicalChain,
+}
+
+impl Harness {
+    async fn new() -> Self {
+        let db = Arc::new(Mutex::new(State::default()));
+        let protocol_cache = ProtocolMemoryCache::new(
+            Chain::Ethereum,
+            chrono::Duration::seconds(900),
+            Arc::new(empty_protocol_gateway()),
+        );
+        let extractor = ProtocolExtractor::new(
+            in_memory_gateway(db.clone()),
+            EXTRACTOR_NAME,
+            Chain::Ethereum,
+            ChainState::default(),
+            PROTOCOL_SYSTEM.to_string(),
+            protocol_cache,
+            HashMap::from([(PROTOCOL_TYPE.to_string(), ProtocolType::default())]),
+            NoopTokenPreProcessor,
+            None,
+        )
+        .await
+        .expect("Failed to create extractor");
+        Self {
+            extractor,
+            db,
+            pending_deltas: PendingDeltas::new([EXTRACTOR_NAME]),
+            client: State::default(),
+            chain: CanonicalChain::default(),
+        }
+    }
+
+    async fn apply(&mut self, step: &Step) {
+        let msg = match step {
+            Step::Block(changes) => {
+                let block = self.chain.push(changes.clone()).clone();
+                let inp = pb_fixtures::pb_block_scoped_data(
+                    pb_block_changes(&block),
+                    Some(format!("cursor@{}", block.hash).as_str()),
+                    Some(self.chain.finalized),
+                );
+                self.extractor
+                    .handle_tick_scoped_data(inp)
+                    .await
+                    .expect("Failed to handle block")
+                    .expect("No message emitted for block")
+            }
+            Step::Revert(depth) => {
+                let target = match self.chain.revert(*depth) {
+                    Some(target) => target.clone(),
+                    None => return,
+                };
+                self.extractor
+                    .handle_revert(BlockUndoSignal {
+                        last_valid_block: Some(BlockRef {
+                            id: target.hash.to_string(),
+                            number: target.number,
+                        }),
+                        last_valid_cursor: format!("cursor@{}", target.hash),
+                    })
+                    .await
+                    .expect("Failed to handle revert")
+                    .expect("No message emitted for revert")
+            }
+        };
+        self.deliver(msg, matches!(step, Step::Revert(_)));
+        self.check();
+    }
+
+    /// Passes an emitted message to the client and to `PendingDeltas`, as the runner would.
+    fn deliver(&mut self, msg: ExtractorMsg, revert: bool) {
+        let changes = msg
+            .as_any()
+            .downcast_ref::<BlockAggregatedChanges>()
+            .expect("Unexpected message type");
+        let head = self.chain.head();
+        assert_eq!(changes.revert, revert);
+        assert_eq!(changes.block.number, head.number);
+        assert_eq!(changes.block.hash, head.hash);
+        self.client.apply_changes(changes);
+        self.pending_deltas
+            .insert_message(msg)
+            .expect("Failed to insert into pending deltas");
+    }
+
+    fn check(&self) {
+        let head = self.chain.head().number;
+        let expected = self.chain.state_at(head).normalised();
+
+        // Blocks are kept in the reorg buffer until a newer block is finalized.
+        let db = self.db.lock().unwrap().clone();
+        assert_eq!(
+            db.clone().normalised(),
+            self.chain
+                .state_at(self.chain.finalized - 1)
+                .normalised(),
+            "persisted state diverged at block {head}"
+        );
+
+        let component_ids = db
+            .attributes
+            .keys()
+            .chain(db.balances.keys())
+            .collect::<HashSet<_>>();
+        let mut states = component_ids
+            .into_iter()
+            .map(|id| {
+                ProtocolComponentState::new(
+                    id,
+                    db.attributes
+                        .get(id)
+                        .cloned()
+                        .unwrap_or_default(),
+                    db.balances
+                        .get(id)
+                        .cloned()
+                        .unwrap_or_default(),
+                )
+            })
+            .collect::<Vec<_>>();
+        self.pending_deltas
+            .merge_native_states(Some(&COMPONENTS), &mut states, None, EXTRACTOR_NAME)
+            .expect("Failed to merge pending deltas");
+        let (attributes, balances) = states
+            .into_iter()
+            .map(|state| {
+                (
+                    (state.component_id.clone(), state.attributes),
+                    (state.component_id, state.balances),
+                )
+            })
+            .unzip();
+        assert_eq!(
+            normalised(attributes),
+            expected.attributes,
+            "merged attributes diverged at block {head}"
+        );
+        assert_eq!(
+            normalised(balances),
+            expected.balances,
+            "merged balances diverged at block {head}"
+        );
+
+        let mut accounts = db
+            .storage
+            .iter()
+            .map(|(address, slots)| account(address, slots))
+            .collect::<Vec<_>>();
+        let addresses = expected
+            .storage
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        self.pending_deltas
+            .update_vm_states(Some(&addresses), &mut accounts, None, EXTRACTOR_NAME)
+            .expect("Failed to update vm states");
+        let storage = accounts
+            .into_iter()
+            .map(|account| (account.address, account.slots))
+            .collect();
+        assert_eq!(
+            normalised(storage),
+            expected.storage,
+            "merged storage diverged at block {head}"
+        );
+
+        let new_components = self
+            .pending_deltas
+            .get_new_components(None, EXTRACTOR_NAME, None)
+            .expect("Failed to get new components")
+            .into_iter()
+            .map(|component| (component.id, component.change))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(
+            new_components,
+            self.chain
+                .component_changes_from(self.chain.finalized),
+            "buffered components diverged at block {head}"
+        );
+
+        assert_eq!(
+            self.client.clone().normalised(),
+            expected,
+            "client state diverged at block {head}"
+        );
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn test_reorgs_keep_states_consistent(steps in prop::collection::vec(step_strategy(), 1..40)) {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut harness = Harness::new().await;
+                for step in &steps {
+                    harness.apply(step).await;
+                }
+            });
+    }
+}
@@ -1,35 +1,51 @@
-use std::{collections::HashMap, env, path::Path, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    ops::ControlFlow,
+    path::Path,
+    sync::{Arc, RwLock},
+};
 
 use anyhow::{format_err, Context, Result};
 use async_trait::async_trait;
 use aws_config::meta::region::RegionProviderChain;
 use aws_sdk_s3::Client;
+use chrono::NaiveDateTime;
+use futures03::{future::BoxFuture, FutureExt};
 use metrics::gauge;
 use prost::Message;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::{
     runtime::Handle,
     sync::{
         mpsc::{self, error::SendError, Receiver, Sender},
-        Mutex,
+        oneshot, Mutex,
     },
     task::JoinHandle,
 };
 use tokio_stream::StreamExt;
 use tracing::{debug, error, info, instrument, trace, warn, Instrument};
 use tycho_core::{
-    models::{Chain, ExtractorIdentity, FinancialType, ImplementationType, ProtocolType},
+    dto,
+    models::{
+        blockchain::Block, Chain, ExtractorIdentity, FinancialType, ImplementationType,
+        NormalisedMessage, ProtocolType,
+    },
+    traits::AccountExtractor,
     Bytes,
 };
-use tycho_ethereum::token_pre_processor::EthereumTokenPreProcessor;
-use tycho_storage::postgres::cache::CachedGateway;
+use tycho_ethereum::{token_pre_processor::EthereumTokenPreProcessor, RPCError};
+use tycho_storage::{
+    analytics::{ParquetSink, ParquetSinkConfig},
+    postgres::cache::CachedGateway,
+};
 
 use crate::{
     extractor::{
         chain_state::ChainState,
-        post_processors::POST_PROCESSOR_REGISTRY,
+        post_processors::{PostProcessorFn, POST_PROCESSOR_REGISTRY},
         protocol_cache::ProtocolMemoryCache,
-        protocol_extractor::{ExtractorPgGateway, ProtocolExtractor},
+        protocol_extractor::{AccountInitFailurePolicy, ExtractorPgGateway, ProtocolExtractor},
         ExtractionError, Extractor, ExtractorMsg,
     },
     pb::sf::substreams::v1::Package,
@@ -41,6 +57,56 @@ use crate::{
 pub enum ControlMessage {
     Stop,
     Subscribe(Sender<ExtractorMsg>),
+    /// Subscribes a subscriber of the service itself, which is kept when the extractor restarts.
+    SubscribeInternal(Sender<ExtractorMsg>),
+    /// Stops consuming the substreams until resumed.
+    Pause,
+    /// Resumes a paused extractor from its last processed cursor.
+    Resume,
+    /// Reloads the extractor's state from the database and restarts the substreams. The result
+    /// is sent back once the restart was applied or rejected.
+    Restart(RestartPosition, oneshot::Sender<Result<(), ExtractionError>>),
+}
+
+/// Where to restart an extractor's substreams from.
+///
+/// Positions may not be ahead of the last block committed to the database, as the blocks in
+/// between would be missing. Positions before it rewind the substreams: the blocks up to the
+/// committed one are received again but skipped, as their changes are stored already.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestartPosition {
+    /// Continue after the last block committed to the database.
+    Committed,
+    /// Continue from the given substreams cursor.
+    Cursor(String),
+    /// Start from the given block without a cursor.
+    Block(i64),
+}
+
+impl RestartPosition {
+    /// Validates the position against the last committed block and returns the number of the
+    /// block up to which received blocks must be skipped, if any.
+    ///
+    /// Cursors are opaque, so whether they are ahead is only checked once the first block is
+    /// received.
+    fn validate(&self, committed: Option<u64>) -> Result<Option<u64>, ExtractionError> {
+        let Some(committed) = committed else {
+            return Ok(None);
+        };
+        match self {
+            RestartPosition::Committed => Ok(None),
+            RestartPosition::Cursor(_) => Ok(Some(committed)),
+            RestartPosition::Block(block) if *block < 0 => {
+                Err(ExtractionError::Setup(format!("Invalid restart block {block}")))
+            }
+            RestartPosition::Block(block) if *block as u64 > committed + 1 => {
+                Err(ExtractionError::Setup(format!(
+                    "Restart block {block} is ahead of the committed block {committed}"
+                )))
+            }
+            RestartPosition::Block(_) => Ok(Some(committed)),
+        }
+    }
 }
 
 /// A trait for a message sender that can be used to subscribe to messages
@@ -49,17 +115,136 @@ pub enum ControlMessage {
 #[async_trait]
 pub trait MessageSender: Send + Sync {
     async fn subscribe(&self) -> Result<Receiver<ExtractorMsg>, SendError<ControlMessage>>;
+
+    /// Subscribes a subscriber of the service itself. Unlike other subscriptions it is not closed
+    /// when the extractor restarts, instead it receives an [ExtractorRestarted] message before the
+    /// messages of the restarted extractor.
+    async fn subscribe_internal(
+        &self,
+    ) -> Result<Receiver<ExtractorMsg>, SendError<ControlMessage>> {
+        self.subscribe().await
+    }
+}
+
+/// Sent to internal subscribers when the extractor restarts. The messages received before were
+/// produced by the previous extractor and may conflict with the ones that follow, e.g. for blocks
+/// that are received again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtractorRestarted {
+    pub extractor_id: ExtractorIdentity,
+}
+
+impl std::fmt::Display for ExtractorRestarted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "restarted: {}", self.extractor_id)
+    }
+}
+
+#[typetag::serde]
+impl NormalisedMessage for ExtractorRestarted {
+    fn source(&self) -> ExtractorIdentity {
+        self.extractor_id.clone()
+    }
+
+    fn drop_state(&self) -> Arc<dyn NormalisedMessage> {
+        Arc::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+/// Lifecycle state of an extractor runner.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunnerState {
+    /// The runner has not processed any block yet.
+    Starting,
+    Running,
+    /// The runner was paused and does not consume the substreams.
+    Paused,
+    /// The runner exited with the given error.
+    Failed(String),
+}
+
+impl From<RunnerState> for dto::ExtractorState {
+    fn from(value: RunnerState) -> Self {
+        match value {
+            RunnerState::Starting => dto::ExtractorState::Starting,
+            RunnerState::Running => dto::ExtractorState::Running,
+            RunnerState::Paused => dto::ExtractorState::Paused,
+            RunnerState::Failed(err) => dto::ExtractorState::Failed(err),
+        }
+    }
+}
+
+/// Progress of an extractor as recorded by its runner.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtractorStatus {
+    pub state: RunnerState,
+    /// The last block the extractor processed.
+    pub last_block: Option<Block>,
+    /// When the last block was processed.
+    pub last_processed_at: Option<NaiveDateTime>,
+    /// The substreams cursor of the last processed block.
+    pub cursor: Option<String>,
+    /// Number of subscribers receiving the extractor's messages.
+    pub subscribers: usize,
+}
+
+impl Default for ExtractorStatus {
+    fn default() -> Self {
+        Self {
+            state: RunnerState::Starting,
+            last_block: None,
+            last_processed_at: None,
+            cursor: None,
+            subscribers: 0,
+        }
+    }
+}
+
+/// A trait to report the status of a running extractor
+///
+/// Extracted out of the [ExtractorHandle] to allow for easier testing
+#[async_trait]
+pub trait StatusReporter: Send + Sync {
+    fn get_id(&self) -> ExtractorIdentity;
+
+    fn status(&self) -> ExtractorStatus;
+
+    /// Returns the block number of the chain head, if known.
+    async fn chain_head(&self) -> Option<u64>;
+}
+
+/// A trait to control a running extractor at runtime
+///
+/// Extracted out of the [ExtractorHandle] to allow for easier testing
+#[async_trait]
+pub trait ExtractorController: StatusReporter {
+    async fn pause(&self) -> Result<(), ExtractionError>;
+
+    async fn resume(&self) -> Result<(), ExtractionError>;
+
+    async fn restart(&self, position: RestartPosition) -> Result<(), ExtractionError>;
 }
 
 #[derive(Clone)]
 pub struct ExtractorHandle {
     id: ExtractorIdentity,
     control_tx: Sender<ControlMessage>,
+    status: Arc<RwLock<ExtractorStatus>>,
+    chain_state: Option<ChainState>,
 }
 
 impl ExtractorHandle {
-    fn new(id: ExtractorIdentity, control_tx: Sender<ControlMessage>) -> Self {
-        Self { id, control_tx }
+    pub(crate) fn new(
+        id: ExtractorIdentity,
+        control_tx: Sender<ControlMessage>,
+        status: Arc<RwLock<ExtractorStatus>>,
+        chain_state: Option<ChainState>,
+    ) -> Self {
+        Self { id, control_tx, status, chain_state }
     }
 
     pub fn get_id(&self) -> ExtractorIdentity {
@@ -69,28 +254,29 @@ impl ExtractorHandle {
     #[instrument(skip(self))]
     pub async fn stop(&self) -> Result<(), ExtractionError> {
         // TODO: send a oneshot along here and wait for it
+        self.send(ControlMessage::Stop).await
+    }
+
+    async fn send(&self, msg: ControlMessage) -> Result<(), ExtractionError> {
         self.control_tx
-            .send(ControlMessage::Stop)
+            .send(msg)
             .await
             .map_err(|err| ExtractionError::Unknown(err.to_string()))
     }
 }
 
-#[async_trait]
-impl MessageSender for ExtractorHandle {
-    #[instrument(skip(self))]
-    async fn subscribe(&self) -> Result<Receiver<ExtractorMsg>, SendError<ControlMessage>> {
+impl ExtractorHandle {
+    async fn send_subscription(
+        &self,
+        subscribe: fn(Sender<ExtractorMsg>) -> ControlMessage,
+    ) -> Result<Receiver<ExtractorMsg>, SendError<ControlMessage>> {
         let (tx, rx) = mpsc::channel(16);
         // Define a timeout duration
         let timeout_duration = std::time::Duration::from_secs(5); // 5 seconds timeout
 
         // Wrap the send operation with a timeout
-        let send_result = tokio::time::timeout(
-            timeout_duration,
-            self.control_tx
-                .send(ControlMessage::Subscribe(tx)),
-        )
-        .await;
+        let send_result =
+            tokio::time::timeout(timeout_duration, self.control_tx.send(subscribe(tx))).await;
 
         match send_result {
             Ok(Ok(())) => Ok(rx),
@@ -101,35 +287,123 @@ impl MessageSender for ExtractorHandle {
     }
 }
 
+#[async_trait]
+impl MessageSender for ExtractorHandle {
+    #[instrument(skip(self))]
+    async fn subscribe(&self) -> Result<Receiver<ExtractorMsg>, SendError<ControlMessage>> {
+        self.send_subscription(ControlMessage::Subscribe)
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn subscribe_internal(
+        &self,
+    ) -> Result<Receiver<ExtractorMsg>, SendError<ControlMessage>> {
+        self.send_subscription(ControlMessage::SubscribeInternal)
+            .await
+    }
+}
+
+#[async_trait]
+impl StatusReporter for ExtractorHandle {
+    fn get_id(&self) -> ExtractorIdentity {
+        self.id.clone()
+    }
+
+    fn status(&self) -> ExtractorStatus {
+        self.status
+            .read()
+            .expect("status lock poisoned")
+            .clone()
+    }
+
+    async fn chain_head(&self) -> Option<u64> {
+        match &self.chain_state {
+            Some(chain_state) => Some(chain_state.current_block().await),
+            None => None,
+        }
+    }
+}
+
+#[async_trait]
+impl ExtractorController for ExtractorHandle {
+    #[instrument(skip(self))]
+    async fn pause(&self) -> Result<(), ExtractionError> {
+        self.send(ControlMessage::Pause).await
+    }
+
+    #[instrument(skip(self))]
+    async fn resume(&self) -> Result<(), ExtractionError> {
+        self.send(ControlMessage::Resume).await
+    }
+
+    #[instrument(skip(self))]
+    async fn restart(&self, position: RestartPosition) -> Result<(), ExtractionError> {
+        let (tx, rx) = oneshot::channel();
+        self.send(ControlMessage::Restart(position, tx))
+            .await?;
+        rx.await
+            .map_err(|err| ExtractionError::Unknown(err.to_string()))?
+    }
+}
+
 // Define the SubscriptionsMap type alias
 type SubscriptionsMap = HashMap<u64, Sender<ExtractorMsg>>;
 
+/// Creates a substreams stream starting at the given cursor, or at the given block if no cursor
+/// is passed. Without a block the configured start block is used.
+pub type StreamFactory = Arc<dyn Fn(Option<String>, Option<i64>) -> SubstreamsStream + Send + Sync>;
+
+/// Creates an extractor with its state loaded from the database.
+pub type ExtractorFactory =
+    Arc<dyn Fn() -> BoxFuture<'static, Result<Arc<dyn Extractor>, ExtractionError>> + Send + Sync>;
+
 pub struct ExtractorRunner {
     extractor: Arc<dyn Extractor>,
     substreams: SubstreamsStream,
     subscriptions: Arc<Mutex<SubscriptionsMap>>,
+    /// Ids of the subscriptions kept on restart, see [MessageSender::subscribe_internal].
+    internal_subscribers: HashSet<u64>,
     next_subscriber_id: u64,
     control_rx: Receiver<ControlMessage>,
     /// Handle of the tokio runtime on which the extraction tasks will be run.
     /// If 'None' the default runtime will be used.
     runtime_handle: Option<Handle>,
+    status: Arc<RwLock<ExtractorStatus>>,
+    paused: bool,
+    stream_factory: StreamFactory,
+    /// Used to reload the extractor on restart. If 'None' restarts are not supported.
+    extractor_factory: Option<ExtractorFactory>,
+    /// Set after restarting before the committed block. Blocks up to this number are stored
+    /// already and are skipped, together with any reverts, until the stream passes it.
+    skip_until: Option<u64>,
 }
 
 impl ExtractorRunner {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         extractor: Arc<dyn Extractor>,
         substreams: SubstreamsStream,
         subscriptions: Arc<Mutex<SubscriptionsMap>>,
         control_rx: Receiver<ControlMessage>,
         runtime_handle: Option<Handle>,
+        status: Arc<RwLock<ExtractorStatus>>,
+        stream_factory: StreamFactory,
+        extractor_factory: Option<ExtractorFactory>,
     ) -> Self {
         ExtractorRunner {
             extractor,
             substreams,
             subscriptions,
+            internal_subscribers: HashSet::new(),
             next_subscriber_id: 0,
             control_rx,
             runtime_handle,
+            status,
+            paused: false,
+            stream_factory,
+            extractor_factory,
+            skip_until: None,
         }
     }
 
@@ -151,20 +425,40 @@ impl ExtractorRunner {
                     block_number = tracing::field::Empty,
                     otel.status_code = tracing::field::Empty,
                 );
-                async {
+                let res = async {
                     tokio::select! {
                     Some(ctrl) = self.control_rx.recv() =>  {
                         match ctrl {
                             ControlMessage::Stop => {
                                 warn!("Stop signal received; exiting!");
-                                return Ok(())
+                                if let Err(err) = self.extractor.flush().await {
+                                    error!(error = %err, "Failed to flush extractor on stop");
+                                }
+                                return Ok(ControlFlow::Break(()))
                             },
                             ControlMessage::Subscribe(sender) => {
-                                self.subscribe(sender).await;
+                                self.subscribe(sender, false).await;
+                            },
+                            ControlMessage::SubscribeInternal(sender) => {
+                                self.subscribe(sender, true).await;
+                            },
+                            ControlMessage::Pause => {
+                                self.pause();
+                            },
+                            ControlMessage::Resume => {
+                                self.resume().await;
+                            },
+                            ControlMessage::Restart(position, reply) => {
+                                let res = self.restart(position).await;
+                                if let Err(err) = &res {
+                                    error!(error = %err, "Failed to restart extractor");
+                                }
+                                // the requester might have given up waiting
+                                let _ = reply.send(res);
                             },
                         }
                     }
-                    val = self.substreams.next() => {
+                    val = self.substreams.next(), if !self.paused => {
                         match val {
                             None => {
                                 error!("stream ended");
@@ -174,6 +468,9 @@ impl ExtractorRunner {
                             Some(Ok(BlockResponse::New(data))) => {
                                 let block_number = data.clock.as_ref().map(|v| v.number).unwrap_or(0);
                                 tracing::Span::current().record("block_number", block_number);
+                                if self.skip_committed(block_number).await {
+                                    return Ok(ControlFlow::Continue(()));
+                                }
                                 gauge!(
                                     "extractor_current_block_number", 
                                     "chain" => id.chain.to_string(), 
@@ -199,6 +496,8 @@ impl ExtractorRunner {
                                     }
                                 }
 
+                                self.record_progress().await;
+
                                 let duration = start_time.elapsed();
                                 gauge!(
                                     "block_processing_time_ms", 
@@ -207,14 +506,20 @@ impl ExtractorRunner {
                                 ).set(duration.as_millis() as f64);
                             }
                             Some(Ok(BlockResponse::Undo(undo_signal))) => {
+                                if self.skip_until.is_some() {
+                                    debug!(block=?&undo_signal.last_valid_block, "Skipping revert of committed blocks");
+                                    return Ok(ControlFlow::Continue(()));
+                                }
                                 info!(block=?&undo_signal.last_valid_block,  "Revert requested!");
                                 match self.extractor.handle_revert(undo_signal.clone()).await {
                                     Ok(Some(msg)) => {
                                         trace!("Propagating block undo message.");
-                                        Self::propagate_msg(&self.subscriptions, msg).await
+                                        Self::propagate_msg(&self.subscriptions, msg).await;
+                                        self.record_progress().await;
                                     }
                                     Ok(None) => {
                                         trace!("No message to propagate.");
+                                        self.record_progress().await;
                                     }
                                     Err(err) => {
                                         error!(error = %err, "Error while processing revert!");
@@ -230,24 +535,171 @@ impl ExtractorRunner {
                             }
                         };
                     }
+                    else => {
+                        // only reachable while paused, the stream is not polled then
+                        error!("Control channel closed while paused");
+                        return Err(ExtractionError::Unknown(format!("{}: control channel closed", id)));
+                    }
                 };
                     tracing::Span::current().record("otel.status_code", "ok");
-                    Ok(())
-                }.instrument(loop_span).await?
+                    Ok(ControlFlow::Continue(()))
+                }.instrument(loop_span).await;
+                if let Err(err) = &res {
+                    self.status
+                        .write()
+                        .expect("status lock poisoned")
+                        .state = RunnerState::Failed(err.to_string());
+                }
+                if res?.is_break() {
+                    return Ok(());
+                }
             }
         })
     }
 
+    /// Records the last block processed by the extractor in the runner's status.
+    async fn record_progress(&mut self) {
+        let last_block = self
+            .extractor
+            .get_last_processed_block()
+            .await;
+        let cursor = self.extractor.get_cursor().await;
+        let subscribers = self.subscriptions.lock().await.len();
+        let mut status = self
+            .status
+            .write()
+            .expect("status lock poisoned");
+        status.state = RunnerState::Running;
+        status.last_block = last_block;
+        status.last_processed_at = Some(chrono::Utc::now().naive_utc());
+        status.cursor = Some(cursor);
+        status.subscribers = subscribers;
+    }
+
     #[instrument(skip_all)]
-    async fn subscribe(&mut self, sender: Sender<ExtractorMsg>) {
+    async fn subscribe(&mut self, sender: Sender<ExtractorMsg>, internal: bool) {
         let subscriber_id = self.next_subscriber_id;
         self.next_subscriber_id += 1;
         tracing::Span::current().record("subscriber_id", subscriber_id);
-        info!(?subscriber_id, "New subscription");
-        self.subscriptions
-            .lock()
+        info!(?subscriber_id, internal, "New subscription");
+        if internal {
+            self.internal_subscribers
+                .insert(subscriber_id);
+        }
+        let mut subscriptions = self.subscriptions.lock().await;
+        subscriptions.insert(subscriber_id, sender);
+        self.status
+            .write()
+            .expect("status lock poisoned")
+            .subscribers = subscriptions.len();
+    }
+
+    fn pause(&mut self) {
+        info!("Pausing extractor");
+        self.paused = true;
+        self.status
+            .write()
+            .expect("status lock poisoned")
+            .state = RunnerState::Paused;
+    }
+
+    /// Resumes consuming the substreams. The stream is recreated from the extractor's cursor as
+    /// the previous connection might have timed out while paused.
+    async fn resume(&mut self) {
+        if !self.paused {
+            return;
+        }
+        let cursor = self.extractor.get_cursor().await;
+        info!(%cursor, "Resuming extractor");
+        self.substreams = (self.stream_factory)(Some(cursor), None);
+        self.paused = false;
+        let mut status = self
+            .status
+            .write()
+            .expect("status lock poisoned");
+        status.state = match status.last_block {
+            Some(_) => RunnerState::Running,
+            None => RunnerState::Starting,
+        };
+    }
+
+    /// Replaces the extractor by one loaded from the database and restarts the substreams at
+    /// `position`. Fails without restarting if the position is ahead of the committed block.
+    ///
+    /// All subscriptions except the internal ones are closed, so subscribers resubscribe and don't
+    /// mix the unfinalized state of the previous extractor with the one of the new extractor.
+    /// Internal subscribers receive an [ExtractorRestarted] message instead, ahead of any message
+    /// of the new extractor, so they don't miss blocks sent before they could resubscribe.
+    async fn restart(&mut self, position: RestartPosition) -> Result<(), ExtractionError> {
+        info!(?position, "Restarting extractor");
+        let factory = self
+            .extractor_factory
+            .clone()
+            .ok_or_else(|| ExtractionError::Setup("Extractor can't be restarted".to_string()))?;
+        if let Err(err) = self.extractor.flush().await {
+            error!(error = %err, "Failed to flush extractor before restart");
+        }
+        let extractor = factory().await?;
+        let committed = extractor
+            .get_last_processed_block()
             .await
-            .insert(subscriber_id, sender);
+            .map(|block| block.number);
+        self.skip_until = position.validate(committed)?;
+        let (cursor, start_block) = match position {
+            RestartPosition::Committed => (Some(extractor.get_cursor().await), None),
+            RestartPosition::Cursor(cursor) => (Some(cursor), None),
+            RestartPosition::Block(block) => (None, Some(block)),
+        };
+        self.substreams = (self.stream_factory)(cursor, start_block);
+        self.extractor = extractor;
+        self.paused = false;
+        {
+            let mut subscriptions = self.subscriptions.lock().await;
+            subscriptions.retain(|id, _| self.internal_subscribers.contains(id));
+            self.internal_subscribers
+                .retain(|id| subscriptions.contains_key(id));
+        }
+        let restarted = ExtractorRestarted { extractor_id: self.extractor.get_id() };
+        Self::propagate_msg(&self.subscriptions, Arc::new(restarted)).await;
+        let subscribers = self.subscriptions.lock().await.len();
+        let last_block = self
+            .extractor
+            .get_last_processed_block()
+            .await;
+        let cursor = self.extractor.get_cursor().await;
+        *self
+            .status
+            .write()
+            .expect("status lock poisoned") =
+            ExtractorStatus { last_block, cursor: Some(cursor), subscribers, ..Default::default() };
+        Ok(())
+    }
+
+    /// Returns whether a received block was committed before the last restart and must be skipped.
+    ///
+    /// If the restart cursor turns out to be ahead of the committed block, the substreams are
+    /// restarted from the committed cursor instead and the block is skipped as well.
+    async fn skip_committed(&mut self, block_number: u64) -> bool {
+        let Some(committed) = self.skip_until else {
+            return false;
+        };
+        if block_number <= committed {
+            debug!(block_number, committed, "Skipping committed block");
+            return true;
+        }
+        self.skip_until = None;
+        if block_number > committed + 1 {
+            let cursor = self.extractor.get_cursor().await;
+            error!(
+                block_number,
+                committed,
+                %cursor,
+                "Restart cursor is ahead of the committed block, continuing from the committed cursor"
+            );
+            self.substreams = (self.stream_factory)(Some(cursor), None);
+            return true;
+        }
+        false
     }
 
     // TODO: add message tracing_id to the log
@@ -284,7 +736,7 @@ impl ExtractorRunner {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct ProtocolTypeConfig {
     name: String,
     financial_type: FinancialType,
@@ -294,9 +746,13 @@ impl ProtocolTypeConfig {
     pub fn new(name: String, financial_type: FinancialType) -> Self {
         Self { name, financial_type }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct ExtractorConfig {
     name: String,
     chain: Chain,
@@ -309,8 +765,19 @@ pub struct ExtractorConfig {
     module_name: String,
     #[serde(default)]
     pub initialized_accounts: Vec<Bytes>,
+    /// Storage slots to read per initialized account if the node doesn't support
+    /// `debug_storageRangeAt` to enumerate their storage.
+    #[serde(default)]
+    pub initialized_account_slots: HashMap<Bytes, Vec<Bytes>>,
     #[serde(default)]
     pub initialized_accounts_block: i64,
+    /// Initialize contracts referenced by new components that are not indexed yet with their
+    /// state fetched from the node.
+    #[serde(default)]
+    pub auto_initialize_accounts: bool,
+    /// What to do with a block if the contracts to initialize can't be fetched from the node.
+    #[serde(default)]
+    pub account_init_failure_policy: AccountInitFailurePolicy,
     #[serde(default)]
     pub post_processor: Option<String>,
 }
@@ -342,10 +809,102 @@ impl ExtractorConfig {
             spkg,
             module_name,
             initialized_accounts,
+            initialized_account_slots: HashMap::new(),
             initialized_accounts_block,
+            auto_initialize_accounts: false,
+            account_init_failure_policy: AccountInitFailurePolicy::default(),
             post_processor,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn module_name(&self) -> &str {
+        &self.module_name
+    }
+
+    pub fn protocol_types(&self) -> &[ProtocolTypeConfig] {
+        &self.protocol_types
+    }
+
+    /// The configured protocol types by their name.
+    pub fn protocol_type_map(&self) -> HashMap<String, ProtocolType> {
+        self.protocol_types
+            .iter()
+            .map(|pt| {
+                (
+                    pt.name.clone(),
+                    ProtocolType::new(
+                        pt.name.clone(),
+                        pt.financial_type.clone(),
+                        None,
+                        self.implementation_type.clone(),
+                    ),
+                )
+            })
+            .collect()
+    }
+
+    /// Looks up the configured post processor in the registry.
+    pub fn resolve_post_processor(&self) -> Result<Option<PostProcessorFn>, ExtractionError> {
+        self.post_processor
+            .as_ref()
+            .map(|name| {
+                POST_PROCESSOR_REGISTRY
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| {
+                        ExtractionError::Setup(format!(
+                            "Post processor '{}' not found in registry",
+                            name
+                        ))
+                    })
+            })
+            .transpose()
+    }
+
+    pub fn chain(&self) -> Chain {
+        self.chain
+    }
+
+    pub fn start_block(&self) -> i64 {
+        self.start_block
+    }
+
+    pub fn stop_block(&self) -> Option<i64> {
+        self.stop_block
+    }
+
+    /// Reads and decodes the spkg, downloading it from the s3 bucket first if it doesn't exist
+    /// locally.
+    pub async fn load_spkg(&self, s3_bucket: Option<&str>) -> Result<Package, ExtractionError> {
+        // Pull spkg from s3 and copy it at `spkg_path`
+        if !Path::new(&self.spkg).exists() {
+            download_file_from_s3(
+                s3_bucket.ok_or_else(|| {
+                    ExtractionError::Setup(format!(
+                        "Missing spkg and s3 bucket config for {}",
+                        &self.spkg
+                    ))
+                })?,
+                &self.spkg,
+                Path::new(&self.spkg),
+            )
+            .await
+            .map_err(|e| {
+                ExtractionError::Setup(format!("Failed to download {} from s3. {}", &self.spkg, e))
+            })?;
+        }
+
+        let content = std::fs::read(&self.spkg)
+            .context(format_err!("read package from file '{}'", self.spkg))
+            .map_err(|err| ExtractionError::SubstreamsError(err.to_string()))?;
+        Package::decode(content.as_ref())
+            .context("decode command")
+            .map_err(|err| ExtractionError::SubstreamsError(err.to_string()))
+    }
 }
 
 pub struct ExtractorBuilder {
@@ -355,9 +914,17 @@ pub struct ExtractorBuilder {
     token: String,
     extractor: Option<Arc<dyn Extractor>>,
     final_block_only: bool,
+    /// If set, changes of finalized blocks are exported to Parquet files.
+    analytics_sink: Option<ParquetSinkConfig>,
+    /// If set, contracts referenced by new components are initialized from the node.
+    account_extractor: Option<Arc<dyn AccountExtractor<Error = RPCError> + Send + Sync>>,
     /// Handle of the tokio runtime on which the extraction tasks will be run.
     /// If 'None' the default runtime will be used.
     runtime_handle: Option<Handle>,
+    /// Used to report how far the extractor lags behind the chain head. Set on build.
+    chain_state: Option<ChainState>,
+    /// Used to reload the extractor from the database on restart. Set on build.
+    extractor_factory: Option<ExtractorFactory>,
 }
 
 pub type HandleResult = (JoinHandle<Result<(), ExtractionError>>, ExtractorHandle);
@@ -371,7 +938,11 @@ impl ExtractorBuilder {
             token: env::var("SUBSTREAMS_API_TOKEN").unwrap_or("".to_string()),
             extractor: None,
             final_block_only: false,
+            analytics_sink: None,
+            account_extractor: None,
             runtime_handle: None,
+            chain_state: None,
+            extractor_factory: None,
         }
     }
 
@@ -400,6 +971,19 @@ impl ExtractorBuilder {
         self
     }
 
+    pub fn analytics_sink(mut self, config: ParquetSinkConfig) -> Self {
+        self.analytics_sink = Some(config);
+        self
+    }
+
+    pub fn account_extractor(
+        mut self,
+        account_extractor: Arc<dyn AccountExtractor<Error = RPCError> + Send + Sync>,
+    ) -> Self {
+        self.account_extractor = Some(account_extractor);
+        self
+    }
+
     pub fn set_runtime(mut self, runtime: Handle) -> Self {
         self.runtime_handle = Some(runtime);
         self
@@ -411,30 +995,6 @@ impl ExtractorBuilder {
         self
     }
 
-    async fn ensure_spkg(&self) -> Result<(), ExtractionError> {
-        // Pull spkg from s3 and copy it at `spkg_path`
-        if !Path::new(&self.config.spkg).exists() {
-            download_file_from_s3(
-                self.s3_bucket.as_ref().ok_or_else(|| {
-                    ExtractionError::Setup(format!(
-                        "Missing spkg and s3 bucket config for {}",
-                        &self.config.spkg
-                    ))
-                })?,
-                &self.config.spkg,
-                Path::new(&self.config.spkg),
-            )
-            .await
-            .map_err(|e| {
-                ExtractionError::Setup(format!(
-                    "Failed to download {} from s3. {}",
-                    &self.config.spkg, e
-                ))
-            })?;
-        }
-        Ok(())
-    }
-
     pub async fn build(
         mut self,
         chain_state: ChainState,
@@ -442,61 +1002,60 @@ impl ExtractorBuilder {
         token_pre_processor: &EthereumTokenPreProcessor,
         protocol_cache: &ProtocolMemoryCache,
     ) -> Result<Self, ExtractionError> {
-        let protocol_types = self
-            .config
-            .protocol_types
-            .iter()
-            .map(|pt| {
-                (
-                    pt.name.clone(),
-                    ProtocolType::new(
-                        pt.name.clone(),
-                        pt.financial_type.clone(),
-                        None,
-                        self.config.implementation_type.clone(),
-                    ),
+        let protocol_types = self.config.protocol_type_map();
+        let post_processor = self.config.resolve_post_processor()?;
+
+        let config = self.config.clone();
+        let cached_gw = cached_gw.clone();
+        let protocol_cache = protocol_cache.clone();
+        let token_pre_processor = token_pre_processor.clone();
+        let analytics_sink = self.analytics_sink.take();
+        let account_extractor = self.account_extractor.take();
+        let factory_chain_state = chain_state.clone();
+        let factory: ExtractorFactory = Arc::new(move || {
+            let config = config.clone();
+            let chain_state = factory_chain_state.clone();
+            let gw = ExtractorPgGateway::new(
+                &config.name,
+                config.chain,
+                config.sync_batch_size,
+                cached_gw.clone(),
+            );
+            let protocol_cache = protocol_cache.clone();
+            let protocol_types = protocol_types.clone();
+            let token_pre_processor = token_pre_processor.clone();
+            let analytics_sink = analytics_sink.clone();
+            let account_extractor = account_extractor.clone();
+            async move {
+                let mut extractor = ProtocolExtractor::new(
+                    gw,
+                    &config.name,
+                    config.chain,
+                    chain_state,
+                    config.name.clone(),
+                    protocol_cache,
+                    protocol_types,
+                    token_pre_processor,
+                    post_processor,
                 )
-            })
-            .collect();
+                .await?;
+                if let Some(config) = analytics_sink {
+                    extractor = extractor.with_analytics_sink(ParquetSink::new(config));
+                }
+                if let Some(account_extractor) = account_extractor {
+                    extractor = extractor.with_account_extractor(
+                        account_extractor,
+                        config.account_init_failure_policy,
+                    );
+                }
+                Ok(Arc::new(extractor) as Arc<dyn Extractor>)
+            }
+            .boxed()
+        });
 
-        let gw = ExtractorPgGateway::new(
-            &self.config.name,
-            self.config.chain,
-            self.config.sync_batch_size,
-            cached_gw.clone(),
-        );
-
-        let post_processor = self
-            .config
-            .post_processor
-            .as_ref()
-            .map(|name| {
-                POST_PROCESSOR_REGISTRY
-                    .get(name)
-                    .cloned()
-                    .ok_or_else(|| {
-                        ExtractionError::Setup(format!(
-                            "Post processor '{}' not found in registry",
-                            name
-                        ))
-                    })
-            })
-            .transpose()?;
-
-        self.extractor = Some(Arc::new(
-            ProtocolExtractor::new(
-                gw,
-                &self.config.name,
-                self.config.chain,
-                chain_state,
-                self.config.name.clone(),
-                protocol_cache.clone(),
-                protocol_types,
-                token_pre_processor.clone(),
-                post_processor,
-            )
-            .await?,
-        ));
+        self.extractor = Some(factory().await?);
+        self.chain_state = Some(chain_state);
+        self.extractor_factory = Some(factory);
 
         Ok(self)
     }
@@ -510,44 +1069,50 @@ impl ExtractorBuilder {
 
         tracing::Span::current().record("id", format!("{}", extractor.get_id()));
 
-        self.ensure_spkg().await?;
-
-        let content = std::fs::read(&self.config.spkg)
-            .context(format_err!("read package from file '{}'", self.config.spkg))
-            .map_err(|err| ExtractionError::SubstreamsError(err.to_string()))?;
-        let spkg = Package::decode(content.as_ref())
-            .context("decode command")
-            .map_err(|err| ExtractionError::SubstreamsError(err.to_string()))?;
+        let spkg = self
+            .config
+            .load_spkg(self.s3_bucket.as_deref())
+            .await?;
         let endpoint = Arc::new(
             SubstreamsEndpoint::new(&self.endpoint_url, Some(self.token))
                 .await
                 .map_err(|err| ExtractionError::SubstreamsError(err.to_string()))?,
         );
 
+        let id = extractor.get_id();
+        let config = self.config;
+        let final_block_only = self.final_block_only;
+        let stream_id = id.to_string();
+        let stream_factory: StreamFactory = Arc::new(move |cursor, start_block| {
+            SubstreamsStream::new(
+                endpoint.clone(),
+                cursor,
+                spkg.modules.clone(),
+                config.module_name.clone(),
+                start_block.unwrap_or(config.start_block),
+                config.stop_block.unwrap_or(0) as u64,
+                final_block_only,
+                stream_id.clone(),
+            )
+        });
         let cursor = extractor.get_cursor().await;
-        let stream = SubstreamsStream::new(
-            endpoint,
-            Some(cursor),
-            spkg.modules.clone(),
-            self.config.module_name,
-            self.config.start_block,
-            self.config.stop_block.unwrap_or(0) as u64,
-            self.final_block_only,
-            extractor.get_id().to_string(),
-        );
+        let stream = stream_factory(Some(cursor), None);
 
-        let id = extractor.get_id();
         let (ctrl_tx, ctrl_rx) = mpsc::channel(128);
+        let status = Arc::new(RwLock::new(ExtractorStatus::default()));
         let runner = ExtractorRunner::new(
             extractor,
             stream,
             Arc::new(Mutex::new(HashMap::new())),
             ctrl_rx,
             self.runtime_handle,
+            status.clone(),
+            stream_factory,
+            self.extractor_factory,
         );
 
         let handle = runner.run();
-        Ok((handle, ExtractorHandle::new(id, ctrl_tx)))
+        Ok((handle, ExtractorHandle::new(id, ctrl_tx, status, self.chain_state)))
     }
 }
 
@@ -590,10 +1155,14 @@ async fn download_file_from_s3(
 #[cfg(test)]
 mod test {
     use serde::Serialize;
-    use tycho_core::models::NormalisedMessage;
+    use tycho_core::models::{blockchain::BlockAggregatedChanges, NormalisedMessage};
 
     use super::*;
-    use crate::extractor::MockExtractor;
+    use crate::{
+        extractor::MockExtractor,
+        services::deltas_buffer::{PendingDeltas, PendingDeltasBuffer},
+        testing::block,
+    };
 
     #[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
     struct DummyMessage {
@@ -621,6 +1190,206 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_restart_position_validate() {
+        let cursor = RestartPosition::Cursor("cursor@10".to_string());
+
+        assert_eq!(
+            RestartPosition::Block(20)
+                .validate(None)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            RestartPosition::Committed
+                .validate(Some(10))
+                .unwrap(),
+            None
+        );
+        assert_eq!(cursor.validate(Some(10)).unwrap(), Some(10));
+        assert_eq!(
+            RestartPosition::Block(11)
+                .validate(Some(10))
+                .unwrap(),
+            Some(10)
+        );
+        assert_eq!(
+            RestartPosition::Block(5)
+                .validate(Some(10))
+                .unwrap(),
+            Some(10)
+        );
+        assert!(RestartPosition::Block(12)
+            .validate(Some(10))
+            .is_err());
+        assert!(RestartPosition::Block(-1)
+            .validate(Some(10))
+            .is_err());
+    }
+
+    #[test]
+    fn test_extractor_config_initialized_account_slots() {
+        let config: ExtractorConfig = serde_yaml::from_str(
+            r#"
+name: "vm:balancer"
+chain: "ethereum"
+implementation_type: "Vm"
+sync_batch_size: 1000
+start_block: 18000000
+protocol_types: []
+spkg: "balancer.spkg"
+module_name: "map_changes"
+initialized_accounts:
+  - "0xba12222222228d8ba445958a75a0704d566bf2c8"
+initialized_account_slots:
+  "0xba12222222228d8ba445958a75a0704d566bf2c8": ["0x00", "0x01"]
+"#,
+        )
+        .expect("invalid config");
+
+        assert_eq!(
+            config.initialized_account_slots,
+            HashMap::from([(
+                Bytes::from("0xba12222222228d8ba445958a75a0704d566bf2c8"),
+                vec![Bytes::from("0x00"), Bytes::from("0x01")]
+            )])
+        );
+    }
+
+    /// Hands out a single internal subscription, external subscriptions are rejected.
+    struct InternalSubscription(std::sync::Mutex<Option<Receiver<ExtractorMsg>>>);
+
+    #[async_trait]
+    impl MessageSender for InternalSubscription {
+        async fn subscribe(&self) -> Result<Receiver<ExtractorMsg>, SendError<ControlMessage>> {
+            Err(SendError(ControlMessage::Stop))
+        }
+
+        async fn subscribe_internal(
+            &self,
+        ) -> Result<Receiver<ExtractorMsg>, SendError<ControlMessage>> {
+            self.0
+                .lock()
+                .unwrap()
+                .take()
+                .ok_or(SendError(ControlMessage::Stop))
+        }
+    }
+
+    fn restart_test_extractor(last_block: Option<Block>) -> MockExtractor {
+        let mut extractor = MockExtractor::new();
+        extractor
+            .expect_get_id()
+            .returning(ExtractorIdentity::default);
+        extractor
+            .expect_get_cursor()
+            .returning(|| "cursor@1".to_string());
+        extractor
+            .expect_get_last_processed_block()
+            .returning(move || last_block.clone());
+        extractor
+            .expect_flush()
+            .returning(|| Ok(()));
+        extractor
+    }
+
+    fn block_changes(block: Block) -> ExtractorMsg {
+        Arc::new(BlockAggregatedChanges {
+            extractor: "test".to_string(),
+            chain: Chain::Ethereum,
+            block,
+            finalized_block_height: 1,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_restart_resets_pending_deltas_in_band() {
+        let endpoint = Arc::new(
+            SubstreamsEndpoint::new("http://127.0.0.1:1", None)
+                .await
+                .unwrap(),
+        );
+        // the streams are never polled, so the endpoint is never connected to
+        let stream_factory: StreamFactory = Arc::new(move |cursor, start_block| {
+            SubstreamsStream::new(
+                endpoint.clone(),
+                cursor,
+                None,
+                "map_changes".to_string(),
+                start_block.unwrap_or(1),
+                0,
+                false,
+                "test".to_string(),
+            )
+        });
+        let extractor_factory: ExtractorFactory = Arc::new(|| {
+            async { Ok(Arc::new(restart_test_extractor(Some(block(1)))) as Arc<dyn Extractor>) }
+                .boxed()
+        });
+        let (_control_tx, control_rx) = mpsc::channel(1);
+        let status = Arc::new(RwLock::new(ExtractorStatus::default()));
+        let mut runner = ExtractorRunner::new(
+            Arc::new(restart_test_extractor(None)),
+            stream_factory(None, None),
+            Arc::new(Mutex::new(HashMap::new())),
+            control_rx,
+            None,
+            status.clone(),
+            stream_factory,
+            Some(extractor_factory),
+        );
+        let (internal_tx, internal_rx) = mpsc::channel(16);
+        let (external_tx, mut external_rx) = mpsc::channel(16);
+        runner
+            .subscribe(internal_tx, true)
+            .await;
+        runner
+            .subscribe(external_tx, false)
+            .await;
+        let pending = PendingDeltas::new(["test"]);
+        let subscription: Arc<dyn MessageSender + Send + Sync> =
+            Arc::new(InternalSubscription(std::sync::Mutex::new(Some(internal_rx))));
+        let (_added_tx, added_rx) = mpsc::channel(1);
+        tokio::spawn(
+            pending
+                .clone()
+                .run([("test".to_string(), subscription)], added_rx),
+        );
+        let mut replayed = block(2);
+        replayed.hash = Bytes::from(22u8).lpad(32, 0);
+
+        ExtractorRunner::propagate_msg(&runner.subscriptions, block_changes(block(1))).await;
+        ExtractorRunner::propagate_msg(&runner.subscriptions, block_changes(block(2))).await;
+        runner
+            .restart(RestartPosition::Committed)
+            .await
+            .unwrap();
+        // sent right after the restart, before an asynchronous resubscription could complete
+        ExtractorRunner::propagate_msg(&runner.subscriptions, block_changes(replayed.clone()))
+            .await;
+
+        let find = |hash: Bytes| {
+            pending
+                .search_block(&|b| b.block.hash == hash, "test")
+                .unwrap()
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while find(replayed.hash.clone()).is_none() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("replayed block was not buffered");
+        assert!(find(block(1).hash).is_none());
+        assert!(find(block(2).hash).is_none());
+        assert_eq!(status.read().unwrap().subscribers, 1);
+        // external subscribers are closed and resubscribe on their own
+        assert!(external_rx.recv().await.is_some());
+        assert!(external_rx.recv().await.is_some());
+        assert!(external_rx.recv().await.is_none());
+    }
+
     #[tokio::test]
     async fn test_extractor_runner_builder() {
         // Mock the Extractor
@@ -0,0 +1,407 @@
+//! Compares the changes two versions of a substreams package emit over the same blocks.
+use std::{collections::BTreeMap, fmt, io::Write};
+
+use anyhow::Error;
+use futures03::Stream;
+use serde::Serialize;
+use tracing::info;
+use tycho_core::{keccak256, models::blockchain::BlockAggregatedChanges, Bytes};
+
+use crate::{
+    extractor::{dry_run::BlockDecoder, runner::ExtractorConfig, ExtractionError},
+    substreams::stream::BlockResponse,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DifferenceKind {
+    Block,
+    Component,
+    Attribute,
+    ComponentBalance,
+    AccountBalance,
+    Slot,
+    NativeBalance,
+    Code,
+}
+
+/// A value that differs between the two packages. `None` means the package didn't emit it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Difference {
+    pub kind: DifferenceKind,
+    /// Identifies the value within its kind, e.g. `component_id/attribute`.
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {}: {} -> {}",
+            self.kind,
+            self.key,
+            self.old.as_deref().unwrap_or("-"),
+            self.new.as_deref().unwrap_or("-")
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BlockDiff {
+    pub block: u64,
+    pub differences: Vec<Difference>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DiffSummary {
+    pub blocks_compared: usize,
+    pub blocks_with_differences: usize,
+}
+
+/// Flattens the changes of a block into sorted values, so they can be compared independently of
+/// the order in which a package emits them.
+fn flatten(changes: &BlockAggregatedChanges) -> BTreeMap<(DifferenceKind, String), String> {
+    let mut values = BTreeMap::new();
+    let mut insert = |kind, key: String, value: String| {
+        values.insert((kind, key), value);
+    };
+
+    insert(DifferenceKind::Block, "hash".to_string(), changes.block.hash.to_string());
+    insert(DifferenceKind::Block, "parent_hash".to_string(), changes.block.parent_hash.to_string());
+    insert(DifferenceKind::Block, "ts".to_string(), changes.block.ts.to_string());
+
+    for (id, component) in changes.new_protocol_components.iter() {
+        insert(DifferenceKind::Component, id.clone(), "created".to_string());
+        insert(
+            DifferenceKind::Component,
+            format!("{}/protocol_type_name", id),
+            component.protocol_type_name.clone(),
+        );
+        insert(DifferenceKind::Component, format!("{}/tokens", id), join(&component.tokens));
+        let mut contracts = component.contract_addresses.clone();
+        contracts.sort();
+        insert(DifferenceKind::Component, format!("{}/contract_addresses", id), join(&contracts));
+        for (name, value) in component.static_attributes.iter() {
+            insert(
+                DifferenceKind::Component,
+                format!("{}/static_attributes/{}", id, name),
+                value.to_string(),
+            );
+        }
+    }
+    for id in changes
+        .deleted_protocol_components
+        .keys()
+    {
+        insert(DifferenceKind::Component, id.clone(), "deleted".to_string());
+    }
+
+    for (id, delta) in changes.state_deltas.iter() {
+        for (name, value) in delta.updated_attributes.iter() {
+            insert(DifferenceKind::Attribute, format!("{}/{}", id, name), value.to_string());
+        }
+        for name in delta.deleted_attributes.iter() {
+            insert(DifferenceKind::Attribute, format!("{}/{}", id, name), "deleted".to_string());
+        }
+    }
+
+    for (id, balances) in changes.component_balances.iter() {
+        for (token, balance) in balances.iter() {
+            insert(
+                DifferenceKind::ComponentBalance,
+                format!("{}/{}", id, token),
+                balance.balance.to_string(),
+            );
+        }
+    }
+    for (account, balances) in changes.account_balances.iter() {
+        for (token, balance) in balances.iter() {
+            insert(
+                DifferenceKind::AccountBalance,
+                format!("{}/{}", account, token),
+                balance.balance.to_string(),
+            );
+        }
+    }
+
+    for (address, delta) in changes.account_deltas.iter() {
+        for (slot, value) in delta.slots.iter() {
+            let value = value
+                .as_ref()
+                .map_or_else(|| "deleted".to_string(), Bytes::to_string);
+            insert(DifferenceKind::Slot, format!("{}/{}", address, slot), value);
+        }
+        if let Some(balance) = &delta.balance {
+            insert(DifferenceKind::NativeBalance, address.to_string(), balance.to_string());
+        }
+        // Code is compared by its hash to keep the report readable.
+        if let Some(code) = &delta.code {
+            insert(
+                DifferenceKind::Code,
+                address.to_string(),
+                Bytes::from(keccak256(code)).to_string(),
+            );
+        }
+    }
+    values
+}
+
+fn join(addresses: &[Bytes]) -> String {
+    addresses
+        .iter()
+        .map(Bytes::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Compares the changes both packages emitted for a block. A package that emitted nothing for the
+/// block is passed as `None`.
+pub fn diff_block(
+    old: Option<&BlockAggregatedChanges>,
+    new: Option<&BlockAggregatedChanges>,
+) -> Vec<Difference> {
+    let mut old = old.map(flatten).unwrap_or_default();
+    let new = new.map(flatten).unwrap_or_default();
+    let mut differences = Vec::new();
+    for ((kind, key), new_value) in new.into_iter() {
+        let old_value = old.remove(&(kind, key.clone()));
+        if old_value.as_ref() != Some(&new_value) {
+            differences.push(Difference { kind, key, old: old_value, new: Some(new_value) });
+        }
+    }
+    differences.extend(
+        old.into_iter()
+            .map(|((kind, key), old_value)| Difference {
+                kind,
+                key,
+                old: Some(old_value),
+                new: None,
+            }),
+    );
+    differences.sort_by(|a, b| (a.kind, &a.key).cmp(&(b.kind, &b.key)));
+    differences
+}
+
+/// Decodes the final blocks of two packages and writes the differences of each block that differs
+/// as one JSON line.
+pub struct SpkgDiff<W> {
+    old: BlockDecoder,
+    new: BlockDecoder,
+    output: W,
+}
+
+impl<W: Write> SpkgDiff<W> {
+    pub fn new(
+        old_config: &ExtractorConfig,
+        new_config: &ExtractorConfig,
+        output: W,
+    ) -> Result<Self, ExtractionError> {
+        Ok(Self {
+            old: BlockDecoder::new(old_config)?,
+            new: BlockDecoder::new(new_config)?,
+            output,
+        })
+    }
+
+    /// Consumes both streams until they end. Blocks are matched by their number, a block only one
+    /// of the packages emitted is compared against nothing.
+    pub async fn run<S1, S2>(
+        mut self,
+        mut old_stream: S1,
+        mut new_stream: S2,
+    ) -> Result<DiffSummary, ExtractionError>
+    where
+        S1: Stream<Item = Result<BlockResponse, Error>> + Unpin,
+        S2: Stream<Item = Result<BlockResponse, Error>> + Unpin,
+    {
+        let mut summary = DiffSummary::default();
+        let mut old_block = self
+            .old
+            .next_block(&mut old_stream)
+            .await?;
+        let mut new_block = self
+            .new
+            .next_block(&mut new_stream)
+            .await?;
+        loop {
+            let (old, new) = match (old_block.take(), new_block.take()) {
+                (None, None) => break,
+                (Some(old), Some(new)) if old.block.number == new.block.number => {
+                    (Some(old), Some(new))
+                }
+                (Some(old), Some(new)) if old.block.number < new.block.number => {
+                    new_block = Some(new);
+                    (Some(old), None)
+                }
+                (Some(old), Some(new)) => {
+                    old_block = Some(old);
+                    (None, Some(new))
+                }
+                (old, new) => (old, new),
+            };
+            if old.is_some() {
+                old_block = self
+                    .old
+                    .next_block(&mut old_stream)
+                    .await?;
+            }
+            if new.is_some() {
+                new_block = self
+                    .new
+                    .next_block(&mut new_stream)
+                    .await?;
+            }
+
+            let number = old
+                .as_ref()
+                .or(new.as_ref())
+                .map(|changes| changes.block.number)
+                .expect("at least one block");
+            let differences = diff_block(old.as_ref(), new.as_ref());
+            summary.blocks_compared += 1;
+            if differences.is_empty() {
+                continue;
+            }
+            summary.blocks_with_differences += 1;
+            let line = serde_json::to_string(&BlockDiff { block: number, differences }).map_err(
+                |err| ExtractionError::Unknown(format!("Failed to serialize differences: {}", err)),
+            )?;
+            writeln!(self.output, "{}", line).map_err(|err| {
+                ExtractionError::Unknown(format!("Failed to write differences: {}", err))
+            })?;
+        }
+        self.output
+            .flush()
+            .map_err(|err| ExtractionError::Unknown(format!("Failed to flush output: {}", err)))?;
+        info!(
+            blocks = summary.blocks_compared,
+            differing = summary.blocks_with_differences,
+            "Spkg comparison finished"
+        );
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures03::stream;
+    use serde_json::Value;
+    use tycho_substreams::pb::tycho::evm::v1 as tycho_substreams;
+
+    use super::*;
+    use crate::testing::fixtures as pb_fixtures;
+
+    fn config(spkg: &str) -> ExtractorConfig {
+        serde_yaml::from_str(&format!(
+            r#"
+name: "native:test"
+chain: "ethereum"
+implementation_type: "Custom"
+sync_batch_size: 1000
+start_block: 1
+protocol_types:
+  - name: "WeightedPool"
+    financial_type: "Swap"
+spkg: "{}"
+module_name: "map_changes"
+"#,
+            spkg
+        ))
+        .expect("invalid config")
+    }
+
+    fn recorded(
+        blocks: Vec<tycho_substreams::BlockChanges>,
+    ) -> impl Stream<Item = Result<BlockResponse, Error>> + Unpin {
+        stream::iter(
+            blocks
+                .into_iter()
+                .map(|msg| {
+                    Ok(BlockResponse::New(pb_fixtures::pb_block_scoped_data(msg, None, None)))
+                })
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn block_changes(number: u64) -> tycho_substreams::BlockChanges {
+        let mut msg = pb_fixtures::pb_native_block_changes(0);
+        let block = msg.block.as_mut().unwrap();
+        block.number = number;
+        block.hash = vec![number as u8; 4];
+        msg
+    }
+
+    #[test]
+    fn test_diff_block() {
+        let decoder = BlockDecoder::new(&config("old.spkg")).unwrap();
+        let decode = |msg| {
+            decoder
+                .decode(pb_fixtures::pb_block_scoped_data(msg, None, None))
+                .unwrap()
+                .unwrap()
+        };
+        let old = decode(block_changes(1));
+        let mut msg = block_changes(1);
+        msg.changes[1].entity_changes[0].attributes[0].value =
+            Bytes::from(700u64).lpad(32, 0).to_vec();
+        msg.changes[0].entity_changes.remove(1);
+        let new = decode(msg);
+        let value = |v: u64| Some(Bytes::from(v).lpad(32, 0).to_string());
+
+        let differences = diff_block(Some(&old), Some(&new));
+
+        assert!(diff_block(Some(&old), Some(&old)).is_empty());
+        assert_eq!(
+            differences,
+            vec![
+                Difference {
+                    kind: DifferenceKind::Attribute,
+                    key: "State1/reserve".to_string(),
+                    old: value(600),
+                    new: value(700),
+                },
+                Difference {
+                    kind: DifferenceKind::Attribute,
+                    key: "State2/reserve".to_string(),
+                    old: value(1_000),
+                    new: None,
+                },
+                Difference {
+                    kind: DifferenceKind::Attribute,
+                    key: "State2/static_attribute".to_string(),
+                    old: value(1),
+                    new: None,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spkg_diff() {
+        let mut output = Vec::new();
+        let diff = SpkgDiff::new(&config("old.spkg"), &config("new.spkg"), &mut output).unwrap();
+        let old = recorded(vec![block_changes(1), block_changes(2)]);
+        let new = recorded(vec![
+            block_changes(1),
+            tycho_substreams::BlockChanges { block: None, changes: vec![] },
+            block_changes(3),
+        ]);
+
+        let summary = diff.run(old, new).await.unwrap();
+
+        let lines: Vec<Value> = std::str::from_utf8(&output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(summary, DiffSummary { blocks_compared: 3, blocks_with_differences: 2 });
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["block"], 2);
+        assert_eq!(lines[0]["differences"][0]["kind"], "block");
+        assert_eq!(lines[0]["differences"][0]["new"], Value::Null);
+        assert_eq!(lines[1]["block"], 3);
+        assert_eq!(lines[1]["differences"][0]["old"], Value::Null);
+    }
+}
@@ -7,17 +7,24 @@ use tycho_core::{
     models::{
         blockchain::BlockTag,
         protocol::QualityRange,
-        token::{CurrencyToken, TokenOwnerStore, TokenQuality},
+        token::{CurrencyToken, TokenAnalysis, TokenFlag, TokenOwnerStore, TokenQuality},
         Chain, PaginationParams,
     },
     storage::ProtocolGateway,
     traits::TokenAnalyzer,
     Bytes,
 };
-use tycho_ethereum::token_analyzer::trace_call::TraceCallDetector;
+use tycho_ethereum::token_pre_processor::{AnalysisMethod, MethodAnalyzer};
 
 use crate::cli::AnalyzeTokenArgs;
 
+/// Quality of tokens whose analysis failed when they were added. See
+/// [`TokensRequestBody::min_quality`](tycho_core::dto::TokensRequestBody).
+pub const FAILED_AT_CREATION_QUALITY: u32 = 10;
+/// Quality of tokens whose analysis failed on every retry. Each failed retry lowers the quality
+/// by one, starting from [`FAILED_AT_CREATION_QUALITY`]. These tokens are not analyzed again.
+pub const GAVE_UP_QUALITY: u32 = 5;
+
 pub async fn analyze_tokens(
     analyze_args: AnalyzeTokenArgs,
     gw: Arc<dyn ProtocolGateway + Send + Sync>,
@@ -33,7 +40,7 @@ pub async fn analyze_tokens(
                 analyze_args.chain,
                 None,
                 // Skip tokens that failed previously and ones we already analyzed successfully
-                QualityRange::new(6, 10),
+                QualityRange::new(GAVE_UP_QUALITY as i32 + 1, FAILED_AT_CREATION_QUALITY as i32),
                 None,
                 Some(&pagination_params),
             )
@@ -80,8 +87,32 @@ async fn analyze_batch(
         .iter()
         .map(|t| t.address.clone())
         .collect::<Vec<_>>();
+    let analyzer = MethodAnalyzer::new_from_url(
+        AnalysisMethod::TraceCall,
+        eth_rpc_url.as_str(),
+        Arc::new(token_owner_store(chain, &addresses, gw.as_ref()).await?),
+    );
+    let mut analyses = Vec::with_capacity(tokens.len());
+    for t in tokens.iter_mut() {
+        analyses.push(analyze_token(&analyzer, chain, t).await);
+    }
+
+    if !tokens.is_empty() {
+        gw.update_tokens(&tokens, &analyses)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Finds the components holding the most liquidity of each token. Their pools are used as the
+/// owners to transfer tokens from during the analysis.
+pub(crate) async fn token_owner_store(
+    chain: Chain,
+    addresses: &[Bytes],
+    gw: &(dyn ProtocolGateway + Send + Sync),
+) -> anyhow::Result<TokenOwnerStore> {
     let token_owner = gw
-        .get_token_owners(&chain, &addresses, Some(100_000f64))
+        .get_token_owners(&chain, addresses, Some(100_000f64))
         .await?;
     let component_ids = token_owner
         .values()
@@ -133,49 +164,74 @@ async fn analyze_batch(
             }
         })
         .collect::<HashMap<_, _>>();
-    let analyzer = TraceCallDetector::new(
-        eth_rpc_url.as_str(),
-        Arc::new(TokenOwnerStore::new(liquidity_token_owners)),
-    );
-    for t in tokens.iter_mut() {
-        debug!(?t.address, "Analyzing token");
-        let (token_quality, gas, tax) = match analyzer
-            .analyze(t.address.clone(), BlockTag::Latest)
-            .await
-        {
-            Ok(t) => t,
-            Err(error) => {
-                warn!(?error, "Token quality detection failed");
-                continue;
-            }
-        };
+    Ok(TokenOwnerStore::new(liquidity_token_owners))
+}
 
-        match token_quality {
-            TokenQuality::Good => {
-                t.quality = 100;
-            }
-            TokenQuality::Bad { reason } => {
-                debug!(?t.address, ?reason, "Token quality detected as bad!");
-                // Remove 1 to the quality for each attempt. If it fails 5 times we won't try again.
-                t.quality -= 1;
-            }
+/// Analyzes a single token and applies the result to it. Returns the record of the analysis
+/// run, to be stored together with the updated token.
+///
+/// Failed detections leave the token unchanged. Tokens that are currently good are also left
+/// unchanged if found bad, so a single bad run doesn't exclude them.
+pub(crate) async fn analyze_token(
+    analyzer: &MethodAnalyzer,
+    chain: Chain,
+    t: &mut CurrencyToken,
+) -> TokenAnalysis {
+    debug!(?t.address, "Analyzing token");
+    let result = analyzer
+        .analyze(t.address.clone(), BlockTag::Latest)
+        .await;
+    let (token_quality, gas, tax) = match result.clone() {
+        Ok(t) => t,
+        Err(error) => {
+            warn!(?error, "Token quality detection failed");
+            // Record the failed attempt, the token itself is left unchanged.
+            return TokenAnalysis::new(&t.address, chain, None, &result, t.quality);
         }
+    };
 
-        // If it's a fee token, set quality to 50
-        if tax.is_some_and(|tax_value| tax_value > 0) {
-            t.quality = 50;
+    match token_quality {
+        TokenQuality::Good => {
+            t.quality = 100;
         }
+        TokenQuality::Bad { reason } if t.quality > FAILED_AT_CREATION_QUALITY => {
+            warn!(?t.address, ?reason, "Previously good token detected as bad!");
+            return TokenAnalysis::new(&t.address, chain, None, &result, t.quality);
+        }
+        TokenQuality::Bad { reason } => {
+            debug!(?t.address, ?reason, "Token quality detected as bad!");
+            // Remove 1 to the quality for each attempt. Once it reaches `GAVE_UP_QUALITY` we
+            // won't try again.
+            t.quality = t
+                .quality
+                .saturating_sub(1)
+                .max(GAVE_UP_QUALITY);
+        }
+    }
 
-        t.tax = tax.unwrap_or(0);
-        t.gas = gas
-            .map(|g| vec![Some(g)])
-            .unwrap_or_else(Vec::new);
+    // If it's a fee token, set quality to 50
+    if tax.is_some_and(|tax_value| tax_value > 0) {
+        t.quality = 50;
     }
 
-    if !tokens.is_empty() {
-        gw.update_tokens(&tokens).await?;
+    match analyzer
+        .analyze_flags(t.address.clone(), BlockTag::Latest)
+        .await
+    {
+        Ok(flags) => t.flags = flags,
+        Err(error) => warn!(?error, "Token flag detection failed"),
     }
-    Ok(())
+
+    // If it's a rebase token, set quality to 75
+    if t.quality == 100 && t.flags.contains(&TokenFlag::Rebasing) {
+        t.quality = 75;
+    }
+
+    t.tax = tax.unwrap_or(0);
+    t.gas = gas
+        .map(|g| vec![Some(g)])
+        .unwrap_or_else(Vec::new);
+    TokenAnalysis::new(&t.address, chain, None, &result, t.quality)
 }
 
 #[cfg(test)]
@@ -230,16 +286,18 @@ mod test {
                     })
                 })
             });
+        let mut blitz = CurrencyToken::new(
+            &Bytes::from("0x228c6fcd7376177ff0cff304043f461189752750"),
+            "BLITZ",
+            9,
+            500,
+            &[Some(66_960)],
+            Chain::Ethereum,
+            50,
+        );
+        blitz.flags = vec![TokenFlag::FeeOnTransfer];
         let exp = vec![
-            CurrencyToken::new(
-                &Bytes::from("0x228c6fcd7376177ff0cff304043f461189752750"),
-                "BLITZ",
-                9,
-                500,
-                &[Some(66_960)],
-                Chain::Ethereum,
-                50,
-            ),
+            blitz,
             CurrencyToken::new(
                 &Bytes::from("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
                 "WETH",
@@ -296,8 +354,17 @@ mod test {
             });
         gw.expect_update_tokens()
             .once()
-            .returning(move |updated| {
-                assert_eq!(updated, &exp);
+            .returning(move |updated, analyses| {
+                assert_eq!(updated, exp);
+                assert_eq!(
+                    analyses
+                        .iter()
+                        .map(|a| (&a.token, a.quality))
+                        .collect::<Vec<_>>(),
+                    exp.iter()
+                        .map(|t| (&t.address, t.quality))
+                        .collect::<Vec<_>>()
+                );
                 Box::pin(async { Ok(()) })
             });
 
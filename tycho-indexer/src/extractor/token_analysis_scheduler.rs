@@ -0,0 +1,642 @@
+//! Continuous re-analysis of stored tokens while indexing.
+//!
+//! Tokens whose analysis failed are retried with exponential backoff until their quality drops
+//! to [`GAVE_UP_QUALITY`]. Good tokens are analyzed again periodically, as their transfer tax
+//! may change, and demoted once they are found bad several times in a row.
+//!
+//! Each round only loads the tokens that are due: scheduled tokens are loaded by address once
+//! their next analysis is due, and tokens that are not scheduled yet are discovered by sweeping
+//! the stored tokens one page per round. Tokens held by high TVL components are scheduled and
+//! analyzed first.
+//! Analyses stop for the round once the RPC requests they sent exhaust the RPC budget.
+
+use std::{
+    cmp,
+    collections::{HashMap, HashSet},
+    future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::{NaiveDateTime, Utc};
+use futures03::{stream, StreamExt};
+use metrics::{counter, gauge, histogram};
+use tracing::{debug, info, warn};
+use tycho_core::{
+    models::{
+        protocol::QualityRange,
+        token::{CurrencyToken, TokenAnalysisOutcome},
+        Address, Chain, PaginationParams,
+    },
+    storage::{ProtocolGateway, StorageError},
+};
+use tycho_ethereum::token_pre_processor::MethodAnalyzer;
+
+use crate::{
+    cli::TokenAnalysisSchedulerArgs,
+    extractor::token_analysis_cron::{
+        analyze_token, token_owner_store, FAILED_AT_CREATION_QUALITY, GAVE_UP_QUALITY,
+    },
+};
+
+/// Maximum number of tokens whose analysis history is loaded per round. Tokens are only
+/// scheduled once their history is known, this spreads the lookups after a restart.
+const HISTORY_LOOKUPS_PER_ROUND: usize = 500;
+
+/// Number of past analyses loaded to count the consecutive failures of a token.
+const HISTORY_DEPTH: i64 = 16;
+
+/// Maximum number of scheduled tokens loaded per round because they are due.
+const DUE_TOKENS_PER_ROUND: usize = 500;
+
+/// Page size of the sweep over the stored tokens that discovers unscheduled tokens.
+const SWEEP_PAGE_SIZE: i64 = 500;
+
+/// Number of bad analyses in a row after which a good token is demoted to
+/// [`FAILED_AT_CREATION_QUALITY`].
+const DEMOTE_AFTER_BAD_ANALYSES: u32 = 3;
+
+/// When a token was last analyzed and how many analyses in a row did not find it good.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ScheduleEntry {
+    last_analyzed: Option<NaiveDateTime>,
+    failed_attempts: u32,
+    /// Number of the `failed_attempts` that found the token bad, as opposed to failing.
+    bad_analyses: u32,
+}
+
+impl ScheduleEntry {
+    const UNANALYZED: Self = Self { last_analyzed: None, failed_attempts: 0, bad_analyses: 0 };
+
+    /// Builds the entry of a token from its analysis history, newest first.
+    fn from_history(history: &[tycho_core::models::token::TokenAnalysis]) -> Self {
+        let failed = history
+            .iter()
+            .take_while(|a| a.outcome != TokenAnalysisOutcome::Good);
+        Self {
+            last_analyzed: history.first().map(|a| a.analyzed_at),
+            failed_attempts: failed.clone().count() as u32,
+            bad_analyses: failed
+                .filter(|a| a.outcome == TokenAnalysisOutcome::Bad)
+                .count() as u32,
+        }
+    }
+
+    fn record(&mut self, outcome: TokenAnalysisOutcome, analyzed_at: NaiveDateTime) {
+        self.last_analyzed = Some(analyzed_at);
+        match outcome {
+            TokenAnalysisOutcome::Good => {
+                self.failed_attempts = 0;
+                self.bad_analyses = 0;
+            }
+            TokenAnalysisOutcome::Bad => {
+                self.failed_attempts += 1;
+                self.bad_analyses += 1;
+            }
+            TokenAnalysisOutcome::Failed => self.failed_attempts += 1,
+        }
+    }
+}
+
+/// Timing of re-analyses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Backoff {
+    /// Delay after the first failed attempt. Doubles with every further failed attempt.
+    retry: chrono::Duration,
+    /// Upper bound of the retry delay.
+    max_retry: chrono::Duration,
+    /// Delay before a good token is analyzed again.
+    recheck: chrono::Duration,
+}
+
+impl Backoff {
+    fn from_args(args: &TokenAnalysisSchedulerArgs) -> Self {
+        Self {
+            retry: chrono::Duration::seconds(args.retry_backoff_secs as i64),
+            max_retry: chrono::Duration::seconds(args.max_retry_backoff_secs as i64),
+            recheck: chrono::Duration::hours(args.recheck_hours as i64),
+        }
+    }
+
+    /// Returns when the token of `entry` is due for its next analysis.
+    fn next_due(&self, entry: &ScheduleEntry) -> Option<NaiveDateTime> {
+        let delay = match entry.failed_attempts {
+            0 => self.recheck,
+            attempts => {
+                let delay = self
+                    .retry
+                    .num_milliseconds()
+                    .saturating_mul(2i64.saturating_pow(attempts - 1));
+                chrono::Duration::milliseconds(cmp::min(delay, self.max_retry.num_milliseconds()))
+            }
+        };
+        entry
+            .last_analyzed
+            .map(|last| last + delay)
+    }
+
+    /// Returns when the token of `entry` is due, tokens without any analysis are due
+    /// immediately.
+    fn due_at(&self, entry: &ScheduleEntry) -> NaiveDateTime {
+        self.next_due(entry)
+            .unwrap_or(NaiveDateTime::MIN)
+    }
+}
+
+/// Token bucket limiting the RPC requests spent on token analysis. The bucket holds at most
+/// one minute worth of requests.
+///
+/// Requests are counted as they are sent. Analyses that are already running when the budget
+/// runs out may overdraw it, the debt is paid off by later refills.
+#[derive(Debug)]
+struct RpcBudget {
+    requests_per_minute: f64,
+    available: f64,
+    refilled_at: Instant,
+}
+
+impl RpcBudget {
+    fn new(requests_per_minute: u32, now: Instant) -> Self {
+        let requests_per_minute = requests_per_minute as f64;
+        Self { requests_per_minute, available: requests_per_minute, refilled_at: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.available = (self.available + elapsed * self.requests_per_minute / 60.0)
+            .min(self.requests_per_minute);
+        self.refilled_at = now;
+    }
+
+    /// Whether the budget is used up once `pending` more requests are spent.
+    fn is_exhausted(&self, pending: u64) -> bool {
+        pending as f64 >= self.available
+    }
+
+    fn spend(&mut self, requests: u64) {
+        self.available -= requests as f64;
+    }
+}
+
+/// Returns the scheduled tokens that are due at `now`, high priority tokens first and then by
+/// how long they have been due. At most `limit` addresses are returned.
+fn due_addresses(
+    schedule: &HashMap<Address, ScheduleEntry>,
+    backoff: &Backoff,
+    priority: &HashSet<Address>,
+    now: NaiveDateTime,
+    limit: usize,
+) -> Vec<Address> {
+    let mut due = schedule
+        .iter()
+        .map(|(address, entry)| (!priority.contains(address), backoff.due_at(entry), address))
+        .filter(|(_, due_at, _)| *due_at <= now)
+        .collect::<Vec<_>>();
+    due.sort_by_key(|(low_priority, due_at, _)| (*low_priority, *due_at));
+    due.into_iter()
+        .take(limit)
+        .map(|(_, _, address)| address.clone())
+        .collect()
+}
+
+/// Returns the tokens that are due at `now`, high priority tokens first and then by how long
+/// they have been due.
+fn select_due<'a>(
+    tokens: &'a [CurrencyToken],
+    schedule: &HashMap<Address, ScheduleEntry>,
+    backoff: &Backoff,
+    priority: &HashSet<Address>,
+    now: NaiveDateTime,
+) -> Vec<&'a CurrencyToken> {
+    let mut due = tokens
+        .iter()
+        .filter_map(|token| {
+            let due_at = backoff.due_at(schedule.get(&token.address)?);
+            (due_at <= now).then_some((!priority.contains(&token.address), due_at, token))
+        })
+        .collect::<Vec<_>>();
+    due.sort_by_key(|(low_priority, due_at, _)| (*low_priority, *due_at));
+    due.into_iter()
+        .map(|(_, _, token)| token)
+        .collect()
+}
+
+/// Demotes a good token that was found bad [`DEMOTE_AFTER_BAD_ANALYSES`] times in a row.
+/// Returns whether the token was demoted.
+fn demote_if_bad(token: &mut CurrencyToken, entry: &ScheduleEntry) -> bool {
+    if token.quality <= FAILED_AT_CREATION_QUALITY || entry.bad_analyses < DEMOTE_AFTER_BAD_ANALYSES
+    {
+        return false;
+    }
+    token.quality = FAILED_AT_CREATION_QUALITY;
+    true
+}
+
+/// Periodically re-analyzes stored tokens of a chain.
+pub struct TokenAnalysisScheduler {
+    chain: Chain,
+    rpc_url: String,
+    gw: Arc<dyn ProtocolGateway + Send + Sync>,
+    args: TokenAnalysisSchedulerArgs,
+    backoff: Backoff,
+    budget: RpcBudget,
+    schedule: HashMap<Address, ScheduleEntry>,
+    /// Next page of the sweep over the stored tokens.
+    sweep_page: i64,
+}
+
+impl TokenAnalysisScheduler {
+    pub fn new(
+        chain: Chain,
+        rpc_url: &str,
+        gw: Arc<dyn ProtocolGateway + Send + Sync>,
+        args: TokenAnalysisSchedulerArgs,
+    ) -> Self {
+        Self {
+            chain,
+            rpc_url: rpc_url.to_string(),
+            gw,
+            backoff: Backoff::from_args(&args),
+            budget: RpcBudget::new(args.rpc_budget, Instant::now()),
+            args,
+            schedule: HashMap::new(),
+            sweep_page: 0,
+        }
+    }
+
+    /// Runs scheduling rounds forever. Errors of a round are logged and the round is retried
+    /// at the next interval.
+    pub async fn run(mut self) {
+        info!(chain = %self.chain, args = ?self.args, "Starting token analysis scheduler");
+        let mut interval = tokio::time::interval(Duration::from_secs(self.args.interval_secs));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let start = Instant::now();
+            if let Err(error) = self.run_round().await {
+                warn!(?error, "Token analysis round failed");
+                counter!("token_analysis_rounds_failed", "chain" => self.chain.to_string())
+                    .increment(1);
+            }
+            histogram!("token_analysis_round_duration_seconds", "chain" => self.chain.to_string())
+                .record(start.elapsed().as_secs_f64());
+        }
+    }
+
+    async fn run_round(&mut self) -> anyhow::Result<()> {
+        let chain_label = self.chain.to_string();
+        self.budget.refill(Instant::now());
+        gauge!("token_analysis_rpc_budget_available", "chain" => chain_label.clone())
+            .set(self.budget.available);
+        if self.budget.is_exhausted(0) {
+            debug!("RPC budget exhausted, skipping token analysis round");
+            return Ok(());
+        }
+
+        let priority = self.priority_tokens().await?;
+        let tokens = self.load_candidates(&priority).await?;
+        self.load_history(&tokens, &priority)
+            .await?;
+        let due =
+            select_due(&tokens, &self.schedule, &self.backoff, &priority, Utc::now().naive_utc());
+        gauge!("token_analysis_due_tokens", "chain" => chain_label.clone()).set(due.len() as f64);
+        if due.is_empty() {
+            return Ok(());
+        }
+
+        let mut batch = due
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        let addresses = batch
+            .iter()
+            .map(|t| t.address.clone())
+            .collect::<Vec<_>>();
+        let analyzer = MethodAnalyzer::new_from_url(
+            self.args.method,
+            &self.rpc_url,
+            Arc::new(token_owner_store(self.chain, &addresses, self.gw.as_ref()).await?),
+        );
+        let chain = self.chain;
+        let budget = &self.budget;
+        // Analyses are only started while the requests sent so far leave budget.
+        let mut analyses = stream::iter(batch.iter_mut())
+            .take_while(|_| future::ready(!budget.is_exhausted(analyzer.rpc_requests())))
+            .map(|token| {
+                let analyzer = &analyzer;
+                async move { analyze_token(analyzer, chain, token).await }
+            })
+            .buffered(self.args.concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        let requests = analyzer.rpc_requests();
+        self.budget.spend(requests);
+        counter!("token_analysis_rpc_requests", "chain" => chain_label.clone()).increment(requests);
+        gauge!("token_analysis_rpc_budget_available", "chain" => chain_label.clone())
+            .set(self.budget.available);
+        if analyses.len() < batch.len() {
+            debug!(
+                due = batch.len(),
+                analyzed = analyses.len(),
+                "RPC budget exhausted, deferring token analyses"
+            );
+            counter!("token_analysis_deferred", "chain" => chain_label.clone())
+                .increment((batch.len() - analyses.len()) as u64);
+        }
+        batch.truncate(analyses.len());
+
+        let now = Utc::now().naive_utc();
+        for (token, analysis) in batch
+            .iter_mut()
+            .zip(analyses.iter_mut())
+        {
+            counter!("token_analysis_runs", "chain" => chain_label.clone(), "outcome" => analysis.outcome.to_string())
+                .increment(1);
+            let entry = self
+                .schedule
+                .entry(token.address.clone())
+                .or_insert(ScheduleEntry::UNANALYZED);
+            entry.record(analysis.outcome, now);
+            if demote_if_bad(token, entry) {
+                analysis.quality = token.quality;
+                warn!(address = ?token.address, "Demoting token found bad repeatedly");
+                counter!("token_analysis_demotions", "chain" => chain_label.clone()).increment(1);
+            }
+            if token.quality <= GAVE_UP_QUALITY {
+                self.schedule.remove(&token.address);
+            }
+        }
+        self.gw
+            .update_tokens(&batch, &analyses)
+            .await?;
+        info!(n_tokens = batch.len(), requests, "Re-analyzed tokens");
+        Ok(())
+    }
+
+    /// Loads the tokens that may be analyzed this round: scheduled tokens that are due and the
+    /// next page of the sweep.
+    async fn load_candidates(
+        &mut self,
+        priority: &HashSet<Address>,
+    ) -> Result<Vec<CurrencyToken>, StorageError> {
+        let quality = QualityRange::min_only(GAVE_UP_QUALITY as i32 + 1);
+        let due = due_addresses(
+            &self.schedule,
+            &self.backoff,
+            priority,
+            Utc::now().naive_utc(),
+            DUE_TOKENS_PER_ROUND,
+        );
+        let mut tokens = if due.is_empty() {
+            Vec::new()
+        } else {
+            let due_refs = due.iter().collect::<Vec<_>>();
+            self.gw
+                .get_tokens(self.chain, Some(&due_refs), quality.clone(), None, None)
+                .await?
+                .entity
+        };
+        // Tokens that are no longer candidates, e.g. because they were given up, are forgotten.
+        let loaded = tokens
+            .iter()
+            .map(|t| t.address.clone())
+            .collect::<HashSet<_>>();
+        for address in due
+            .iter()
+            .filter(|a| !loaded.contains(*a))
+        {
+            self.schedule.remove(address);
+        }
+
+        let pagination = PaginationParams::new(self.sweep_page, SWEEP_PAGE_SIZE);
+        let page = self
+            .gw
+            .get_tokens(self.chain, None, quality, None, Some(&pagination))
+            .await?
+            .entity;
+        self.sweep_page =
+            if page.len() < SWEEP_PAGE_SIZE as usize { 0 } else { self.sweep_page + 1 };
+        tokens.extend(
+            page.into_iter()
+                .filter(|t| !loaded.contains(&t.address)),
+        );
+        Ok(tokens)
+    }
+
+    /// Returns the tokens of components with at least the configured TVL.
+    async fn priority_tokens(&self) -> Result<HashSet<Address>, StorageError> {
+        Ok(self
+            .gw
+            .get_protocol_components(&self.chain, None, None, Some(self.args.priority_tvl), None)
+            .await?
+            .entity
+            .into_iter()
+            .flat_map(|component| component.tokens)
+            .collect())
+    }
+
+    /// Loads the analysis history of tokens that are not scheduled yet, high priority tokens
+    /// first.
+    async fn load_history(
+        &mut self,
+        tokens: &[CurrencyToken],
+        priority: &HashSet<Address>,
+    ) -> Result<(), StorageError> {
+        let mut unknown = tokens
+            .iter()
+            .map(|t| &t.address)
+            .filter(|address| !self.schedule.contains_key(*address))
+            .collect::<Vec<_>>();
+        unknown.sort_by_key(|address| !priority.contains(*address));
+        for address in unknown
+            .into_iter()
+            .take(HISTORY_LOOKUPS_PER_ROUND)
+        {
+            let history = self
+                .gw
+                .get_token_analyses(&self.chain, address, HISTORY_DEPTH)
+                .await?;
+            self.schedule
+                .insert(address.clone(), ScheduleEntry::from_history(&history));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tycho_core::{models::token::TokenAnalysis, Bytes};
+
+    use super::*;
+
+    fn ts(seconds: i64) -> NaiveDateTime {
+        NaiveDateTime::default() + chrono::Duration::seconds(seconds)
+    }
+
+    fn backoff() -> Backoff {
+        Backoff {
+            retry: chrono::Duration::seconds(10),
+            max_retry: chrono::Duration::seconds(100),
+            recheck: chrono::Duration::seconds(1000),
+        }
+    }
+
+    fn entry(last_analyzed: Option<i64>, failed_attempts: u32) -> ScheduleEntry {
+        ScheduleEntry { last_analyzed: last_analyzed.map(ts), failed_attempts, bad_analyses: 0 }
+    }
+
+    fn analysis(seconds: i64, outcome: TokenAnalysisOutcome) -> TokenAnalysis {
+        TokenAnalysis {
+            token: Bytes::from("0x01"),
+            chain: Chain::Ethereum,
+            analyzed_at: ts(seconds),
+            block_number: None,
+            outcome,
+            reason: None,
+            quality: 10,
+            gas: None,
+            tax: None,
+        }
+    }
+
+    #[test]
+    fn test_schedule_entry_from_history() {
+        let history = vec![
+            analysis(30, TokenAnalysisOutcome::Failed),
+            analysis(20, TokenAnalysisOutcome::Bad),
+            analysis(10, TokenAnalysisOutcome::Good),
+            analysis(0, TokenAnalysisOutcome::Bad),
+        ];
+
+        let entry = ScheduleEntry::from_history(&history);
+
+        assert_eq!(
+            entry,
+            ScheduleEntry { last_analyzed: Some(ts(30)), failed_attempts: 2, bad_analyses: 1 }
+        );
+        assert_eq!(ScheduleEntry::from_history(&[]), ScheduleEntry::UNANALYZED);
+    }
+
+    #[test]
+    fn test_backoff_next_due() {
+        let backoff = backoff();
+        let entry = |failed_attempts| ScheduleEntry {
+            last_analyzed: Some(ts(0)),
+            failed_attempts,
+            bad_analyses: 0,
+        };
+
+        assert_eq!(backoff.next_due(&entry(0)), Some(ts(1000)));
+        assert_eq!(backoff.next_due(&entry(1)), Some(ts(10)));
+        assert_eq!(backoff.next_due(&entry(2)), Some(ts(20)));
+        assert_eq!(backoff.next_due(&entry(4)), Some(ts(80)));
+        assert_eq!(backoff.next_due(&entry(5)), Some(ts(100)));
+        assert_eq!(backoff.next_due(&entry(64)), Some(ts(100)));
+        assert_eq!(backoff.next_due(&ScheduleEntry::UNANALYZED), None);
+    }
+
+    #[test]
+    fn test_schedule_entry_record() {
+        let mut entry = ScheduleEntry::UNANALYZED;
+
+        entry.record(TokenAnalysisOutcome::Failed, ts(1));
+        entry.record(TokenAnalysisOutcome::Bad, ts(2));
+        assert_eq!(
+            entry,
+            ScheduleEntry { last_analyzed: Some(ts(2)), failed_attempts: 2, bad_analyses: 1 }
+        );
+
+        entry.record(TokenAnalysisOutcome::Good, ts(3));
+        assert_eq!(
+            entry,
+            ScheduleEntry { last_analyzed: Some(ts(3)), failed_attempts: 0, bad_analyses: 0 }
+        );
+    }
+
+    #[test]
+    fn test_rpc_budget() {
+        let start = Instant::now();
+        let mut budget = RpcBudget::new(60, start);
+
+        assert!(!budget.is_exhausted(59));
+        assert!(budget.is_exhausted(60));
+        // Requests of analyses that were already running overdraw the budget.
+        budget.spend(80);
+        assert!(budget.is_exhausted(0));
+
+        budget.refill(start + Duration::from_secs(30));
+        assert!(!budget.is_exhausted(9));
+        assert!(budget.is_exhausted(10));
+
+        // The budget never exceeds one minute worth of requests.
+        budget.refill(start + Duration::from_secs(600));
+        assert!(!budget.is_exhausted(59));
+        assert!(budget.is_exhausted(60));
+    }
+
+    #[test]
+    fn test_select_due() {
+        let token = |address: &str| {
+            CurrencyToken::new(&Bytes::from(address), "TKN", 18, 0, &[], Chain::Ethereum, 10)
+        };
+        let tokens =
+            vec![token("0x01"), token("0x02"), token("0x03"), token("0x04"), token("0x05")];
+        let schedule = HashMap::from([
+            // failed once at 0, due at 10
+            (Bytes::from("0x01"), entry(Some(0), 1)),
+            // failed once at 5, due at 15
+            (Bytes::from("0x02"), entry(Some(5), 1)),
+            // good at 0, due at 1000
+            (Bytes::from("0x03"), entry(Some(0), 0)),
+            // never analyzed
+            (Bytes::from("0x04"), entry(None, 0)),
+            // 0x05 has no history loaded yet
+        ]);
+        let priority = HashSet::from([Bytes::from("0x02")]);
+
+        let due = select_due(&tokens, &schedule, &backoff(), &priority, ts(20))
+            .into_iter()
+            .map(|t| t.address.clone())
+            .collect::<Vec<_>>();
+
+        assert_eq!(due, vec![Bytes::from("0x02"), Bytes::from("0x04"), Bytes::from("0x01")]);
+    }
+
+    #[test]
+    fn test_due_addresses() {
+        let schedule = HashMap::from([
+            (Bytes::from("0x01"), entry(Some(0), 1)),
+            (Bytes::from("0x02"), entry(Some(5), 1)),
+            (Bytes::from("0x03"), entry(Some(0), 0)),
+            (Bytes::from("0x04"), entry(None, 0)),
+        ]);
+        let priority = HashSet::from([Bytes::from("0x02")]);
+
+        let due = due_addresses(&schedule, &backoff(), &priority, ts(20), 3);
+
+        assert_eq!(due, vec![Bytes::from("0x02"), Bytes::from("0x04"), Bytes::from("0x01")]);
+        assert_eq!(due_addresses(&schedule, &backoff(), &priority, ts(20), 1).len(), 1);
+    }
+
+    #[test]
+    fn test_demote_if_bad() {
+        let mut token =
+            CurrencyToken::new(&Bytes::from("0x01"), "TKN", 18, 0, &[], Chain::Ethereum, 100);
+        let bad = |bad_analyses| ScheduleEntry {
+            last_analyzed: Some(ts(0)),
+            failed_attempts: bad_analyses,
+            bad_analyses,
+        };
+
+        assert!(!demote_if_bad(&mut token, &bad(DEMOTE_AFTER_BAD_ANALYSES - 1)));
+        assert_eq!(token.quality, 100);
+
+        assert!(demote_if_bad(&mut token, &bad(DEMOTE_AFTER_BAD_ANALYSES)));
+        assert_eq!(token.quality, FAILED_AT_CREATION_QUALITY);
+
+        // Tokens that already failed are lowered by the analysis itself.
+        assert!(!demote_if_bad(&mut token, &bad(DEMOTE_AFTER_BAD_ANALYSES + 1)));
+    }
+}
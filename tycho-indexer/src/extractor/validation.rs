@@ -0,0 +1,243 @@
+//! Checks of the extractors configuration that would otherwise only fail once the extractors run.
+use std::{collections::HashSet, fmt};
+
+use crate::{
+    extractor::{
+        post_processors::POST_PROCESSOR_REGISTRY, protocol_extractor::SUPPORTED_OUTPUT_TYPES,
+        reload::ExtractorConfigs, runner::ExtractorConfig,
+    },
+    pb::sf::substreams::v1::{module::Kind, Package},
+};
+
+/// A problem found in the configuration of an extractor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigProblem {
+    /// Key of the extractor in the configuration file.
+    pub extractor: String,
+    pub problem: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.extractor, self.problem)
+    }
+}
+
+/// Validates all extractors and returns every problem found, ordered by extractor.
+///
+/// Spkgs missing locally are downloaded from `s3_bucket`, like on startup.
+pub async fn validate_configs(
+    configs: &ExtractorConfigs,
+    s3_bucket: Option<&str>,
+) -> Vec<ConfigProblem> {
+    let mut keys: Vec<_> = configs.extractors.keys().collect();
+    keys.sort();
+    let mut problems = Vec::new();
+    for key in keys {
+        let config = &configs.extractors[key];
+        let mut found = check_config(key, config);
+        match config.load_spkg(s3_bucket).await {
+            Ok(package) => found.extend(check_module(config, &package)),
+            Err(err) => found.push(format!("Failed to load spkg: {}", err)),
+        }
+        problems.extend(
+            found
+                .into_iter()
+                .map(|problem| ConfigProblem { extractor: key.clone(), problem }),
+        );
+    }
+    problems
+}
+
+/// Checks the parts of the configuration that don't depend on the spkg.
+fn check_config(key: &str, config: &ExtractorConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    // The key is used as protocol system when setting up the database, the name by the extractor.
+    if config.name() != key {
+        problems.push(format!("Name '{}' differs from its key '{}'", config.name(), key));
+    }
+    if let Some(name) = &config.post_processor {
+        if !POST_PROCESSOR_REGISTRY.contains_key(name) {
+            let mut known: Vec<_> = POST_PROCESSOR_REGISTRY
+                .keys()
+                .map(String::as_str)
+                .collect();
+            known.sort();
+            problems.push(format!(
+                "Unknown post processor '{}', expected one of: {}",
+                name,
+                known.join(", ")
+            ));
+        }
+    }
+    if config.protocol_types().is_empty() {
+        problems.push("No protocol types configured".to_string());
+    }
+    let mut seen = HashSet::new();
+    for protocol_type in config.protocol_types() {
+        if !seen.insert(protocol_type.name()) {
+            problems.push(format!("Duplicate protocol type '{}'", protocol_type.name()));
+        }
+    }
+    problems
+}
+
+/// Checks that the configured module exists in the package and emits a type the extractor can
+/// decode.
+fn check_module(config: &ExtractorConfig, package: &Package) -> Vec<String> {
+    let modules = package
+        .modules
+        .as_ref()
+        .map(|modules| modules.modules.as_slice())
+        .unwrap_or_default();
+    let Some(module) = modules
+        .iter()
+        .find(|module| module.name == config.module_name())
+    else {
+        let available: Vec<_> = modules
+            .iter()
+            .map(|module| module.name.as_str())
+            .collect();
+        return vec![format!(
+            "Module '{}' not found, available modules: {}",
+            config.module_name(),
+            available.join(", ")
+        )];
+    };
+    let Some(Kind::KindMap(map)) = &module.kind else {
+        return vec![format!("Module '{}' is not a map module", module.name)];
+    };
+    let output_type = module
+        .output
+        .as_ref()
+        .map(|output| output.r#type.as_str())
+        .unwrap_or(map.output_type.as_str());
+    if !SUPPORTED_OUTPUT_TYPES
+        .iter()
+        .any(|supported| output_type.ends_with(supported))
+    {
+        return vec![format!(
+            "Module '{}' emits '{}', expected one of: {}",
+            module.name,
+            output_type,
+            SUPPORTED_OUTPUT_TYPES.join(", ")
+        )];
+    }
+    Vec::new()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pb::sf::substreams::v1::{
+        module::{KindMap, KindStore, Output},
+        Module, Modules,
+    };
+
+    fn config(yaml: &str) -> ExtractorConfig {
+        serde_yaml::from_str(yaml).expect("invalid config")
+    }
+
+    fn uniswap_v2() -> ExtractorConfig {
+        config(
+            r#"
+name: "uniswap_v2"
+chain: "ethereum"
+implementation_type: "Custom"
+sync_batch_size: 1000
+start_block: 10008300
+protocol_types:
+  - name: "uniswap_v2_pool"
+    financial_type: "Swap"
+spkg: "./test/spkg/substreams-ethereum-quickstart-v1.0.0.spkg"
+module_name: "map_pool_events"
+post_processor: "add_default_usv2_attributes_then_transcode_balances"
+"#,
+        )
+    }
+
+    fn package(modules: Vec<Module>) -> Package {
+        Package { modules: Some(Modules { modules, binaries: vec![] }), ..Default::default() }
+    }
+
+    fn map_module(name: &str, output_type: &str) -> Module {
+        Module {
+            name: name.to_string(),
+            kind: Some(Kind::KindMap(KindMap { output_type: output_type.to_string() })),
+            output: Some(Output { r#type: output_type.to_string() }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_config() {
+        let invalid = config(
+            r#"
+name: "uniswap_v2"
+chain: "ethereum"
+implementation_type: "Custom"
+sync_batch_size: 1000
+start_block: 10008300
+protocol_types:
+  - name: "uniswap_v2_pool"
+    financial_type: "Swap"
+  - name: "uniswap_v2_pool"
+    financial_type: "Swap"
+spkg: "uniswap_v2.spkg"
+module_name: "map_pool_events"
+post_processor: "unknown"
+"#,
+        );
+
+        let problems = check_config("uniswap_v2_renamed", &invalid);
+
+        assert!(check_config("uniswap_v2", &uniswap_v2()).is_empty());
+        assert_eq!(problems.len(), 3);
+        assert_eq!(problems[0], "Name 'uniswap_v2' differs from its key 'uniswap_v2_renamed'");
+        assert!(problems[1].starts_with("Unknown post processor 'unknown', expected one of: "));
+        assert_eq!(problems[2], "Duplicate protocol type 'uniswap_v2_pool'");
+    }
+
+    #[test]
+    fn test_check_module() {
+        let config = uniswap_v2();
+        let valid =
+            package(vec![map_module("map_pool_events", "proto:tycho.evm.v1.BlockEntityChanges")]);
+        let store = package(vec![Module {
+            name: "map_pool_events".to_string(),
+            kind: Some(Kind::KindStore(KindStore::default())),
+            ..Default::default()
+        }]);
+        let wrong_type = package(vec![map_module("map_pool_events", "proto:acme.v1.BlockMeta")]);
+
+        assert!(check_module(&config, &valid).is_empty());
+        assert_eq!(
+            check_module(&config, &store),
+            vec!["Module 'map_pool_events' is not a map module".to_string()]
+        );
+        assert_eq!(
+            check_module(&config, &wrong_type),
+            vec!["Module 'map_pool_events' emits 'proto:acme.v1.BlockMeta', expected one of: BlockChanges, BlockContractChanges, BlockEntityChanges".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate_configs() {
+        let configs = ExtractorConfigs::new(
+            [("uniswap_v2".to_string(), uniswap_v2())]
+                .into_iter()
+                .collect(),
+        );
+
+        let problems = validate_configs(&configs, None).await;
+
+        assert_eq!(
+            problems,
+            vec![ConfigProblem {
+                extractor: "uniswap_v2".to_string(),
+                problem: "Module 'map_pool_events' not found, available modules: map_block"
+                    .to_string()
+            }]
+        );
+    }
+}
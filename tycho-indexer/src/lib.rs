@@ -2,6 +2,7 @@ pub mod cli;
 pub mod extractor;
 pub mod pb;
 pub mod services;
+pub mod snapshot;
 pub mod substreams;
 
 #[cfg(test)]
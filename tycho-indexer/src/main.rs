@@ -2,18 +2,19 @@
 use std::{
     collections::HashMap,
     fs::File,
-    io::Read,
+    io::{BufWriter, Write},
     process,
     str::FromStr,
     sync::{mpsc, Arc},
+    time::Duration,
 };
 
 use actix_web::{dev::ServerHandle, web, App, HttpResponse, HttpServer, Responder};
+use async_trait::async_trait;
 use chrono::{NaiveDateTime, Utc};
 use clap::Parser;
 use futures03::future::select_all;
 use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
-use serde::Deserialize;
 use tokio::{runtime::Handle, select, task::JoinHandle};
 use tracing::{debug, error, info, instrument, warn};
 use tracing_subscriber::EnvFilter;
@@ -21,30 +22,54 @@ use tycho_core::{
     models::{
         blockchain::{Block, Transaction},
         contract::AccountDelta,
-        Address, Chain, ExtractionState, ImplementationType,
+        Address, Chain, ExtractionState, ImplementationType, StoreKey,
+    },
+    storage::{
+        BlockIdentifier, ChainGateway, ContractStateGateway, ExtractionStateGateway,
+        ProtocolGateway,
     },
-    storage::{ChainGateway, ContractStateGateway, ExtractionStateGateway},
     traits::AccountExtractor,
     Bytes,
 };
 use tycho_ethereum::{
-    account_extractor::contract::EVMAccountExtractor,
-    token_analyzer::rpc_client::EthereumRpcClient, token_pre_processor::EthereumTokenPreProcessor,
+    account_extractor::{contract::EVMAccountExtractor, state_reader::EVMStateReader},
+    token_analyzer::rpc_client::EthereumRpcClient,
+    token_pre_processor::EthereumTokenPreProcessor,
 };
 use tycho_indexer::{
-    cli::{AnalyzeTokenArgs, Cli, Command, GlobalArgs, IndexArgs, RunSpkgArgs},
+    cli::{
+        AnalyzeTokenArgs, CheckConsistencyArgs, Cli, Command, DiffSpkgArgs, ExportArgs, GlobalArgs,
+        ImportArgs, IndexArgs, MockSubstreamsArgs, RemoveProtocolSystemArgs, RunSpkgArgs,
+        TokenAnalysisSchedulerArgs, ValidateConfigArgs,
+    },
     extractor::{
         chain_state::ChainState,
+        consistency_check::ConsistencyChecker,
+        dry_run::DryRun,
         protocol_cache::ProtocolMemoryCache,
-        runner::{
-            ExtractorBuilder, ExtractorConfig, ExtractorHandle, HandleResult, ProtocolTypeConfig,
+        reload::{
+            watch_config, ExtractorConfigs, ExtractorLauncher, ExtractorSupervisor,
+            SupervisorHandle,
         },
+        runner::{ExtractorBuilder, ExtractorConfig, HandleResult, ProtocolTypeConfig},
+        spkg_diff::SpkgDiff,
         token_analysis_cron::analyze_tokens,
+        token_analysis_scheduler::TokenAnalysisScheduler,
+        validation::validate_configs,
         ExtractionError,
     },
-    services::ServicesBuilder,
+    services::{RpcCacheConfig, ServicesBuilder},
+    snapshot::{export_snapshot, import_snapshot, SnapshotReader},
+    substreams::{
+        mock::{load_recording, MockSubstreams},
+        stream::SubstreamsStream,
+        SubstreamsEndpoint,
+    },
+};
+use tycho_storage::{
+    analytics::ParquetSinkConfig,
+    postgres::{builder::GatewayBuilder, cache::CachedGateway},
 };
-use tycho_storage::postgres::{builder::GatewayBuilder, cache::CachedGateway};
 
 mod ot;
 
@@ -53,25 +78,6 @@ mod ot;
 #[macro_use]
 extern crate pretty_assertions;
 
-#[derive(Debug, Deserialize)]
-struct ExtractorConfigs {
-    extractors: std::collections::HashMap<String, ExtractorConfig>,
-}
-
-impl ExtractorConfigs {
-    fn new(extractors: std::collections::HashMap<String, ExtractorConfig>) -> Self {
-        Self { extractors }
-    }
-
-    fn from_yaml(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut file = File::open(path)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        let config: ExtractorConfigs = serde_yaml::from_str(&contents)?;
-        Ok(config)
-    }
-}
-
 type ExtractionTasks = Vec<JoinHandle<Result<(), ExtractionError>>>;
 type ServerTasks = Vec<JoinHandle<Result<(), ExtractionError>>>; //TODO: introduce an error type for it
 fn main() {
@@ -87,6 +93,19 @@ fn main() {
             run_tycho_ethereum(global_args, analyze_args).unwrap();
         }
         Command::Rpc => run_rpc(global_args).unwrap(),
+        Command::Export(export_args) => run_export(global_args, export_args).unwrap(),
+        Command::Import(import_args) => run_import(global_args, import_args).unwrap(),
+        Command::RemoveProtocolSystem(remove_args) => {
+            run_remove_protocol_system(global_args, remove_args).unwrap()
+        }
+        Command::ValidateConfig(validate_args) => {
+            run_validate_config(global_args, validate_args).unwrap()
+        }
+        Command::DiffSpkg(diff_args) => run_diff_spkg(global_args, diff_args).unwrap(),
+        Command::MockSubstreams(mock_args) => run_mock_substreams(mock_args).unwrap(),
+        Command::CheckConsistency(check_args) => {
+            run_check_consistency(global_args, check_args).unwrap()
+        }
     }
 }
 
@@ -211,6 +230,12 @@ fn run_indexer(global_args: GlobalArgs, index_args: IndexArgs) -> Result<(), Ext
                     .collect::<Vec<_>>(),
                 retention_horizon,
                 extractors_config,
+                Some(&index_args.extractors_config),
+                index_args
+                    .extractors_config_poll_interval
+                    .map(Duration::from_secs),
+                index_args.analytics_dir.as_deref(),
+                Some(&index_args.token_analysis),
                 Some(extraction_runtime.handle()),
             )
             .await?;
@@ -253,28 +278,41 @@ async fn run_spkg(global_args: GlobalArgs, run_args: RunSpkgArgs) -> Result<(),
     create_tracing_subscriber();
     info!("Starting Tycho");
 
-    let config = ExtractorConfigs::new(HashMap::from([(
+    let stop_block = run_args.stop_block();
+    let extractor_config = ExtractorConfig::new(
         "test_protocol".to_string(),
-        ExtractorConfig::new(
-            "test_protocol".to_string(),
-            Chain::from_str(&run_args.chain).unwrap(),
-            ImplementationType::Vm,
-            1, /* TODO: if we want to increase this, we need to commit the cache when we reached
-                * `end_block` */
-            run_args.start_block,
-            run_args.stop_block(),
-            run_args
-                .protocol_type_names
-                .into_iter()
-                .map(|name| ProtocolTypeConfig::new(name, tycho_core::models::FinancialType::Swap))
-                .collect::<Vec<_>>(),
-            run_args.spkg,
-            run_args.module,
-            run_args.initialized_accounts,
-            run_args.initialization_block,
-            None,
-        ),
-    )]));
+        Chain::from_str(&run_args.chain).unwrap(),
+        ImplementationType::Vm,
+        1, /* TODO: if we want to increase this, we need to commit the cache when we reached
+            * `end_block` */
+        run_args.start_block,
+        stop_block,
+        run_args
+            .protocol_type_names
+            .into_iter()
+            .map(|name| ProtocolTypeConfig::new(name, tycho_core::models::FinancialType::Swap))
+            .collect::<Vec<_>>(),
+        run_args.spkg,
+        run_args.module,
+        run_args.initialized_accounts,
+        run_args.initialization_block,
+        run_args.post_processor,
+    );
+
+    if let Some(output_path) = run_args.dry_run_output {
+        return dry_run(
+            &global_args,
+            &run_args
+                .substreams_args
+                .substreams_api_token,
+            extractor_config,
+            &output_path,
+        )
+        .await;
+    }
+
+    let config =
+        ExtractorConfigs::new(HashMap::from([("test_protocol".to_string(), extractor_config)]));
 
     let (extraction_tasks, mut other_tasks) = create_indexing_tasks(
         &global_args,
@@ -283,6 +321,10 @@ async fn run_spkg(global_args: GlobalArgs, run_args: RunSpkgArgs) -> Result<(),
         Utc::now().naive_utc(),
         config,
         None,
+        None,
+        None,
+        None,
+        None,
     )
     .await?;
 
@@ -293,6 +335,57 @@ async fn run_spkg(global_args: GlobalArgs, run_args: RunSpkgArgs) -> Result<(),
     res.expect("Extractor- nor ServiceTasks should panic!")
 }
 
+/// Streams the final blocks of a package and writes their changes to `output_path` without
+/// touching the database.
+async fn dry_run(
+    global_args: &GlobalArgs,
+    token: &str,
+    config: ExtractorConfig,
+    output_path: &str,
+) -> Result<(), ExtractionError> {
+    let dry_run = DryRun::new(
+        &config,
+        BufWriter::new(File::create(output_path).map_err(|err| {
+            ExtractionError::Setup(format!("Failed to create {}: {}", output_path, err))
+        })?),
+    )?;
+    let stream = final_blocks_stream(global_args, token, &config).await?;
+
+    info!(output_path, "Starting dry run");
+    let blocks = dry_run.run(stream).await?;
+    info!(blocks, output_path, "Wrote dry run output");
+    Ok(())
+}
+
+/// Opens a substreams stream of the final blocks between the configured start and stop block.
+async fn final_blocks_stream(
+    global_args: &GlobalArgs,
+    token: &str,
+    config: &ExtractorConfig,
+) -> Result<SubstreamsStream, ExtractionError> {
+    let stop_block = config.stop_block().ok_or_else(|| {
+        ExtractionError::Setup(format!("{}: a stop block is required", config.name()))
+    })?;
+    let spkg = config
+        .load_spkg(global_args.s3_bucket.as_deref())
+        .await?;
+    let endpoint = Arc::new(
+        SubstreamsEndpoint::new(&global_args.endpoint_url, Some(token.to_string()))
+            .await
+            .map_err(|err| ExtractionError::SubstreamsError(err.to_string()))?,
+    );
+    Ok(SubstreamsStream::new(
+        endpoint,
+        None,
+        spkg.modules,
+        config.module_name().to_string(),
+        config.start_block(),
+        stop_block as u64,
+        true,
+        config.name().to_string(),
+    ))
+}
+
 #[tokio::main]
 async fn run_rpc(global_args: GlobalArgs) -> Result<(), ExtractionError> {
     create_tracing_subscriber();
@@ -306,21 +399,34 @@ async fn run_rpc(global_args: GlobalArgs) -> Result<(), ExtractionError> {
         .prefix(&global_args.server_version_prefix)
         .bind(&global_args.server_ip)
         .port(global_args.server_port)
+        .cache_config(RpcCacheConfig::from(&global_args.rpc_cache))
+        .max_extractor_lag(global_args.max_extractor_lag)
         .register_extractors(vec![])
         .run()?;
     info!(server_url, "Http and Ws server started");
-    let shutdown_task = tokio::spawn(shutdown_handler(server_handle, vec![], None));
+    let shutdown_task = tokio::spawn(shutdown_handler(server_handle, None, None));
     let (res, _, _) = select_all([server_task, shutdown_task]).await;
     res.expect("ServiceTasks shouldn't panic!")
 }
 
+/// How often the chain head is fetched from the node, used to report the lag of the extractors.
+const CHAIN_HEAD_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
 /// Creates extraction and server tasks.
+///
+/// If `config_path` is set, the extractors are reloaded from it on SIGHUP and, if a
+/// `poll_interval` is given, whenever the file changes.
+#[allow(clippy::too_many_arguments)]
 async fn create_indexing_tasks(
     global_args: &GlobalArgs,
     rpc_url: &str,
     chains: &[Chain],
     retention_horizon: NaiveDateTime,
     extractors_config: ExtractorConfigs,
+    config_path: Option<&str>,
+    poll_interval: Option<Duration>,
+    analytics_dir: Option<&str>,
+    token_analysis_args: Option<&TokenAnalysisSchedulerArgs>,
     extraction_runtime: Option<&Handle>,
 ) -> Result<(ExtractionTasks, ServerTasks), ExtractionError> {
     let rpc_client = EthereumRpcClient::new_from_url(rpc_url);
@@ -329,7 +435,12 @@ async fn create_indexing_tasks(
         .await
         .expect("Error getting block number");
 
-    let chain_state = ChainState::new(chrono::Local::now().naive_utc(), block_number, 12); //TODO: remove hardcoded blocktime
+    // The RPC only serves the first chain, so only its head is tracked.
+    let chain = *chains
+        .first()
+        .expect("No chain provided"); //TODO: handle multichain?
+    let chain_state = ChainState::new(block_number);
+    chain_state.track(chain, rpc_client, CHAIN_HEAD_POLL_INTERVAL);
 
     let protocol_systems: Vec<String> = extractors_config
         .extractors
@@ -348,97 +459,195 @@ async fn create_indexing_tasks(
         *chains
             .first()
             .expect("No chain provided"), //TODO: handle multichain?
+    )
+    .with_flag_detection(token_analysis_args.is_some_and(|args| args.detect_flags_on_creation))
+    .with_analysis_method(
+        token_analysis_args
+            .map(|args| args.method)
+            .unwrap_or_default(),
     );
 
-    let (tasks, extractor_handles): (Vec<_>, Vec<_>) =
-        // TODO: accept substreams configuration from cli.
-        build_all_extractors(&extractors_config, chain_state, chains, &global_args.endpoint_url,global_args.s3_bucket.as_deref(), &cached_gw, &token_processor, rpc_url, extraction_runtime)
-            .await
-            .map_err(|e| ExtractionError::Setup(format!("Failed to create extractors: {}", e)))?
-            .into_iter()
-            .unzip();
+    info!("Building protocol cache");
+    let protocol_cache = ProtocolMemoryCache::new(
+        *chains
+            .first()
+            .expect("No chain provided"), //TODO: handle multichain?
+        chrono::Duration::seconds(900),
+        Arc::new(cached_gw.clone()),
+    );
+    protocol_cache.populate().await?;
+
+    // TODO: accept substreams configuration from cli.
+    let launcher = Launcher {
+        chain_state,
+        chain,
+        endpoint_url: global_args.endpoint_url.clone(),
+        s3_bucket: global_args.s3_bucket.clone(),
+        cached_gw: cached_gw.clone(),
+        token_pre_processor: token_processor,
+        protocol_cache,
+        rpc_url: rpc_url.to_string(),
+        analytics_dir: analytics_dir.map(ToString::to_string),
+        runtime: extraction_runtime
+            .cloned()
+            .unwrap_or_else(Handle::current),
+    };
+    let (updates_tx, updates_rx) = tokio::sync::mpsc::channel(16);
+    let (mut supervisor, supervisor_handle) = ExtractorSupervisor::new(launcher, config_path);
+    supervisor = supervisor.updates(updates_tx);
+    let extractor_handles = supervisor
+        .start(extractors_config)
+        .await
+        .map_err(|e| ExtractionError::Setup(format!("Failed to create extractors: {}", e)))?;
+    let tasks = vec![tokio::spawn(supervisor.run())];
+
+    if let Some(path) = config_path {
+        let watcher = watch_config(path.to_string(), poll_interval, supervisor_handle.clone());
+        tokio::spawn(async move {
+            if let Err(err) = watcher.await {
+                error!(error = %err, "Failed to watch extractors config");
+            }
+        });
+    }
 
     let server_url = format!("http://{}:{}", global_args.server_ip, global_args.server_port);
-    let (server_handle, server_task) = ServicesBuilder::new(cached_gw.clone())
+    let mut services = ServicesBuilder::new(cached_gw.clone())
         .prefix(&global_args.server_version_prefix)
         .bind(&global_args.server_ip)
         .port(global_args.server_port)
-        .register_extractors(extractor_handles.clone())
-        .run()?;
+        .cache_config(RpcCacheConfig::from(&global_args.rpc_cache))
+        .max_extractor_lag(global_args.max_extractor_lag)
+        .register_extractors(extractor_handles)
+        .extractor_updates(updates_rx);
+    if let Some(token) = &global_args.admin_token {
+        services = services
+            .admin_token(token)
+            .admin_bind(&global_args.admin_server_ip)
+            .admin_port(global_args.admin_server_port);
+    }
+    let (server_handle, server_task) = services.run()?;
     info!(server_url, "Http and Ws server started");
 
-    let shutdown_task =
-        tokio::spawn(shutdown_handler(server_handle, extractor_handles, Some(gw_writer_handle)));
+    let shutdown_task = tokio::spawn(shutdown_handler(
+        server_handle,
+        Some(supervisor_handle),
+        Some(gw_writer_handle),
+    ));
+    let mut other_tasks = vec![server_task, shutdown_task];
+
+    if let Some(args) = token_analysis_args.filter(|args| args.enabled) {
+        // The RPC only serves the chain the extractors run on, so tokens of the other chains
+        // can't be analyzed with it and the single scheduler gets the whole RPC budget.
+        let scheduler = TokenAnalysisScheduler::new(
+            *chains
+                .first()
+                .expect("No chain provided"), //TODO: handle multichain?
+            rpc_url,
+            Arc::new(cached_gw.clone()),
+            args.clone(),
+        );
+        other_tasks.push(tokio::spawn(async move {
+            scheduler.run().await;
+            Ok(())
+        }));
+    }
 
-    Ok((tasks, vec![server_task, shutdown_task]))
+    Ok((tasks, other_tasks))
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn build_all_extractors(
-    config: &ExtractorConfigs,
+/// Starts the extractors configured in the extractors configuration file, initially and on
+/// reloads.
+struct Launcher {
     chain_state: ChainState,
-    chains: &[Chain],
-    endpoint_url: &str,
-    s3_bucket: Option<&str>,
-    cached_gw: &CachedGateway,
-    token_pre_processor: &EthereumTokenPreProcessor,
-    rpc_url: &str,
-    runtime: Option<&tokio::runtime::Handle>,
-) -> Result<Vec<HandleResult>, ExtractionError> {
-    let mut extractor_handles = Vec::new();
+    chain: Chain,
+    endpoint_url: String,
+    s3_bucket: Option<String>,
+    cached_gw: CachedGateway,
+    token_pre_processor: EthereumTokenPreProcessor,
+    protocol_cache: ProtocolMemoryCache,
+    rpc_url: String,
+    analytics_dir: Option<String>,
+    /// Runtime on which the extraction tasks are run.
+    runtime: Handle,
+}
 
-    info!("Building protocol cache");
-    let protocol_cache = ProtocolMemoryCache::new(
-        *chains
-            .first()
-            .expect("No chain provided"), //TODO: handle multichain?
-        chrono::Duration::seconds(900),
-        Arc::new(cached_gw.clone()),
-    );
-    protocol_cache.populate().await?;
+#[async_trait]
+impl ExtractorLauncher for Launcher {
+    async fn launch(&self, config: &ExtractorConfig) -> Result<HandleResult, ExtractionError> {
+        // Extractors added by a reload may belong to a protocol system unknown to the gateway.
+        self.cached_gw
+            .ensure_protocol_system(config.name())
+            .await?;
 
-    for extractor_config in config.extractors.values() {
         initialize_accounts(
-            extractor_config
-                .initialized_accounts
-                .clone(),
-            extractor_config.initialized_accounts_block,
-            rpc_url,
-            *chains.first().unwrap(),
-            cached_gw,
+            config.initialized_accounts.clone(),
+            config.initialized_account_slots.clone(),
+            config.initialized_accounts_block,
+            &self.rpc_url,
+            self.chain,
+            &self.cached_gw,
         )
-        .await;
+        .await?;
 
-        let runtime = runtime
-            .cloned()
-            .unwrap_or_else(|| tokio::runtime::Handle::current());
+        let mut builder =
+            ExtractorBuilder::new(config, &self.endpoint_url, self.s3_bucket.as_deref());
+        if let Some(dir) = &self.analytics_dir {
+            builder = builder.analytics_sink(ParquetSinkConfig::new(dir));
+        }
+        if config.auto_initialize_accounts {
+            let account_extractor = EVMAccountExtractor::new(&self.rpc_url, self.chain)
+                .await
+                .map_err(|err| ExtractionError::Setup(err.to_string()))?;
+            // The storage of contracts referenced by new components can only be enumerated,
+            // there are no known slots to read instead.
+            let supported = account_extractor
+                .supports_storage_range()
+                .await
+                .map_err(|err| ExtractionError::Setup(err.to_string()))?;
+            if !supported {
+                return Err(ExtractionError::Setup(format!(
+                    "{}: auto_initialize_accounts requires a node supporting debug_storageRangeAt",
+                    config.name()
+                )));
+            }
+            builder = builder.account_extractor(Arc::new(account_extractor));
+        }
 
-        let (task, handle) = ExtractorBuilder::new(extractor_config, endpoint_url, s3_bucket)
-            .build(chain_state, cached_gw, token_pre_processor, &protocol_cache)
+        let (task, handle) = builder
+            .build(
+                self.chain_state.clone(),
+                &self.cached_gw,
+                &self.token_pre_processor,
+                &self.protocol_cache,
+            )
             .await?
-            .set_runtime(runtime)
+            .set_runtime(self.runtime.clone())
             .run()
             .await?;
 
         info!("Extractor {} started!", handle.get_id());
-        extractor_handles.push((task, handle));
+        Ok((task, handle))
     }
-
-    Ok(extractor_handles)
 }
 
+/// Extracts the given accounts at `block_id` from the node and stores them.
+///
+/// `slots` are read for accounts whose storage can't be enumerated because the node doesn't
+/// support `debug_storageRangeAt`.
 #[instrument(skip_all, fields(n_accounts = %accounts.len(), block_id = block_id))]
 async fn initialize_accounts(
     accounts: Vec<Address>,
+    slots: HashMap<Address, Vec<StoreKey>>,
     block_id: i64,
     rpc_url: &str,
     chain: Chain,
     cached_gw: &CachedGateway,
-) {
+) -> Result<(), ExtractionError> {
     if accounts.is_empty() {
-        return;
+        return Ok(());
     }
-    let (block, extracted_accounts) = get_accounts_data(accounts, block_id, rpc_url, chain).await;
+    let (block, extracted_accounts) =
+        get_accounts_data(accounts, slots, block_id, rpc_url, chain).await?;
 
     info!(block_number = block.number, "Initializing accounts");
 
@@ -492,39 +701,42 @@ async fn initialize_accounts(
         .commit_transaction(0)
         .await
         .expect("Failed to commit transaction");
+    Ok(())
 }
 
 async fn get_accounts_data(
     accounts: Vec<Address>,
+    slots: HashMap<Address, Vec<StoreKey>>,
     block_id: i64,
     rpc_url: &str,
     chain: Chain,
-) -> (Block, HashMap<Bytes, AccountDelta>) {
+) -> Result<(Block, HashMap<Bytes, AccountDelta>), ExtractionError> {
     let account_extractor = EVMAccountExtractor::new(rpc_url, chain)
         .await
-        .expect("Failed to create account extractor");
+        .map_err(|err| ExtractionError::Setup(err.to_string()))?
+        .with_slots(slots);
 
     let block = account_extractor
         .get_block_data(block_id)
         .await
-        .expect("Failed to get block data");
+        .map_err(|err| ExtractionError::AccountExtractionError(err.to_string()))?;
 
     let extracted_accounts: HashMap<Bytes, AccountDelta> = account_extractor
         .get_accounts(block.clone(), accounts)
         .await
-        .expect("Failed to extract accounts");
-    (block, extracted_accounts)
+        .map_err(|err| ExtractionError::AccountExtractionError(err.to_string()))?;
+    Ok((block, extracted_accounts))
 }
 
 async fn shutdown_handler(
     server_handle: ServerHandle,
-    extractors: Vec<ExtractorHandle>,
+    extractors: Option<SupervisorHandle>,
     db_write_executor_handle: Option<JoinHandle<()>>,
 ) -> Result<(), ExtractionError> {
     // listen for ctrl-c
     tokio::signal::ctrl_c().await.unwrap();
-    for e in extractors.iter() {
-        e.stop().await.unwrap();
+    if let Some(supervisor) = extractors {
+        supervisor.stop().await.unwrap();
     }
     server_handle.stop(true).await;
     if let Some(handle) = db_write_executor_handle {
@@ -556,6 +768,229 @@ async fn run_tycho_ethereum(
     Ok(())
 }
 
+#[tokio::main]
+async fn run_export(global_args: GlobalArgs, export_args: ExportArgs) -> anyhow::Result<()> {
+    create_tracing_subscriber();
+    let cached_gw = GatewayBuilder::new(&global_args.database_url)
+        .build_gw()
+        .await?;
+    let block = match export_args.block {
+        Some(number) => BlockIdentifier::Number((export_args.chain, number)),
+        None => BlockIdentifier::Latest(export_args.chain),
+    };
+    let file = File::create(&export_args.output)?;
+    export_snapshot(
+        &cached_gw,
+        export_args.chain,
+        &block,
+        &export_args.protocol_systems,
+        std::io::BufWriter::new(file),
+    )
+    .await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn run_import(global_args: GlobalArgs, import_args: ImportArgs) -> anyhow::Result<()> {
+    create_tracing_subscriber();
+    let reader = SnapshotReader::new(File::open(&import_args.input)?)?;
+    let header = reader.header().clone();
+    let (cached_gw, gw_writer_handle) = GatewayBuilder::new(&global_args.database_url)
+        .set_chains(&[header.chain])
+        .set_protocol_systems(&header.protocol_systems)
+        .build()
+        .await?;
+
+    import_snapshot(&cached_gw, reader).await?;
+    gw_writer_handle.abort();
+    Ok(())
+}
+
+#[tokio::main]
+async fn run_remove_protocol_system(
+    global_args: GlobalArgs,
+    remove_args: RemoveProtocolSystemArgs,
+) -> anyhow::Result<()> {
+    create_tracing_subscriber();
+    let cached_gw = GatewayBuilder::new(&global_args.database_url)
+        .build_gw()
+        .await?;
+    let removal = cached_gw
+        .remove_protocol_system(&remove_args.protocol_system, remove_args.dry_run)
+        .await?;
+    info!(
+        protocol_system = remove_args.protocol_system,
+        dry_run = remove_args.dry_run,
+        protocol_components = removal.protocol_components,
+        protocol_states = removal.protocol_states,
+        component_balances = removal.component_balances,
+        contracts = removal.contracts,
+        tokens = removal.tokens,
+        extraction_states = removal.extraction_states,
+        "ProtocolSystemRemoved"
+    );
+    Ok(())
+}
+
+/// Prints all problems found in the extractors configuration and exits with a non-zero code if
+/// there are any.
+#[tokio::main]
+async fn run_validate_config(
+    global_args: GlobalArgs,
+    validate_args: ValidateConfigArgs,
+) -> anyhow::Result<()> {
+    let path = &validate_args.extractors_config;
+    let configs = ExtractorConfigs::from_yaml(path)
+        .map_err(|err| anyhow::format_err!("Failed to parse {}: {}", path, err))?;
+    let problems = validate_configs(&configs, global_args.s3_bucket.as_deref()).await;
+    if problems.is_empty() {
+        println!("{}: {} extractors are valid", path, configs.extractors.len());
+        return Ok(());
+    }
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    println!("{}: found {} problems", path, problems.len());
+    process::exit(1);
+}
+
+/// Prints the differences between the changes of two packages and exits with a non-zero code if
+/// there are any.
+#[tokio::main]
+async fn run_diff_spkg(global_args: GlobalArgs, diff_args: DiffSpkgArgs) -> anyhow::Result<()> {
+    create_tracing_subscriber();
+    let chain = Chain::from_str(&diff_args.chain)
+        .map_err(|_| anyhow::format_err!("Unknown chain: {}", diff_args.chain))?;
+    let config = |name: &str, spkg: String, module: String| {
+        ExtractorConfig::new(
+            name.to_string(),
+            chain,
+            ImplementationType::Vm,
+            1,
+            diff_args.start_block,
+            Some(diff_args.stop_block),
+            diff_args
+                .protocol_type_names
+                .iter()
+                .map(|name| {
+                    ProtocolTypeConfig::new(name.clone(), tycho_core::models::FinancialType::Swap)
+                })
+                .collect::<Vec<_>>(),
+            spkg,
+            module,
+            vec![],
+            0,
+            diff_args.post_processor.clone(),
+        )
+    };
+    let new_module = diff_args
+        .new_module
+        .clone()
+        .unwrap_or_else(|| diff_args.module.clone());
+    let old_config = config("old", diff_args.spkg.clone(), diff_args.module.clone());
+    let new_config = config("new", diff_args.new_spkg.clone(), new_module);
+
+    let output: Box<dyn Write> = match &diff_args.output {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path)
+                .map_err(|err| anyhow::format_err!("Failed to create {}: {}", path, err))?,
+        )),
+        None => Box::new(std::io::stdout()),
+    };
+    let diff = SpkgDiff::new(&old_config, &new_config, output)?;
+    let token = &diff_args.substreams_api_token;
+    let old_stream = final_blocks_stream(&global_args, token, &old_config).await?;
+    let new_stream = final_blocks_stream(&global_args, token, &new_config).await?;
+    let summary = diff.run(old_stream, new_stream).await?;
+
+    eprintln!(
+        "{} and {}: {} of {} blocks differ",
+        diff_args.spkg,
+        diff_args.new_spkg,
+        summary.blocks_with_differences,
+        summary.blocks_compared
+    );
+    if summary.blocks_with_differences > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Serves a recording as Substreams endpoint until interrupted.
+#[tokio::main]
+async fn run_mock_substreams(mock_args: MockSubstreamsArgs) -> anyhow::Result<()> {
+    create_tracing_subscriber();
+    let responses = load_recording(&mock_args.recording)
+        .map_err(|err| anyhow::format_err!("Failed to read {}: {}", mock_args.recording, err))?;
+    let addr = mock_args
+        .addr
+        .parse()
+        .map_err(|err| anyhow::format_err!("Invalid address {}: {}", mock_args.addr, err))?;
+    let mock = MockSubstreams::from_responses(responses)
+        .serve(addr)
+        .await?;
+    info!(url = mock.url, recording = mock_args.recording, "Serving recorded substreams");
+    tokio::signal::ctrl_c().await?;
+    mock.shutdown().await;
+    Ok(())
+}
+
+/// Compares a sample of the indexed state with the node and writes the results to a report.
+///
+/// Runs once and exits with a non-zero code if mismatches are found, unless an interval is
+/// given, in which case the check is repeated and its results exposed as metrics. Failed checks
+/// are then logged and retried at the next interval.
+#[tokio::main]
+async fn run_check_consistency(
+    global_args: GlobalArgs,
+    check_args: CheckConsistencyArgs,
+) -> anyhow::Result<()> {
+    create_tracing_subscriber();
+    let cached_gw = GatewayBuilder::new(&global_args.database_url)
+        .build_gw()
+        .await?;
+    let reader = EVMStateReader::new(&check_args.rpc_url)?;
+    let checker = ConsistencyChecker::new(check_args.chain, Arc::new(cached_gw), reader)
+        .with_sample_size(check_args.sample_size)
+        .with_slots_per_contract(check_args.slots_per_contract);
+    let _metrics_task = check_args
+        .interval
+        .map(|_| create_metrics_exporter());
+
+    loop {
+        let report = match checker
+            .check(&check_args.protocol_systems)
+            .await
+        {
+            Ok(report) => report,
+            // Database errors, e.g. a dropped connection, shouldn't end a periodic check.
+            Err(err) => match check_args.interval {
+                Some(interval) => {
+                    error!(error = %err, "ConsistencyCheckFailed");
+                    tokio::time::sleep(Duration::from_secs(interval)).await;
+                    continue;
+                }
+                None => return Err(err.into()),
+            },
+        };
+        let file = File::create(&check_args.report).map_err(|err| {
+            anyhow::format_err!("Failed to create {}: {}", check_args.report, err)
+        })?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &report)?;
+        info!(
+            report = check_args.report,
+            mismatches = report.mismatches(),
+            "ConsistencyReportWritten"
+        );
+
+        match check_args.interval {
+            Some(interval) => tokio::time::sleep(Duration::from_secs(interval)).await,
+            None if report.mismatches() > 0 => process::exit(1),
+            None => return Ok(()),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test_serial_db {
     use tycho_storage::postgres::testing::run_against_db;
@@ -580,7 +1015,16 @@ mod test_serial_db {
                 .build()
                 .await
                 .expect("Failed to create Gateway");
-            initialize_accounts(accounts, block_id, rpc_url.as_str(), chain, &cached_gw).await;
+            initialize_accounts(
+                accounts,
+                HashMap::new(),
+                block_id,
+                rpc_url.as_str(),
+                chain,
+                &cached_gw,
+            )
+            .await
+            .expect("Failed to initialize accounts");
 
             let contracts = cached_gw
                 .get_contracts(&chain, None, None, true, None)
@@ -613,7 +1057,16 @@ mod test_serial_db {
                 .await
                 .expect("Failed to create Gateway");
 
-            initialize_accounts(accounts, block_id, rpc_url.as_str(), chain, &cached_gw).await;
+            initialize_accounts(
+                accounts,
+                HashMap::new(),
+                block_id,
+                rpc_url.as_str(),
+                chain,
+                &cached_gw,
+            )
+            .await
+            .expect("Failed to initialize accounts");
 
             let contracts = cached_gw
                 .get_contracts(&chain, None, None, true, None)
@@ -644,10 +1097,28 @@ mod test_serial_db {
                 .await
                 .expect("Failed to create Gateway");
 
-            initialize_accounts(accounts, block_id, rpc_url.as_str(), chain, &cached_gw).await;
+            initialize_accounts(
+                accounts,
+                HashMap::new(),
+                block_id,
+                rpc_url.as_str(),
+                chain,
+                &cached_gw,
+            )
+            .await
+            .expect("Failed to initialize accounts");
             let accounts =
                 vec![Address::from_str("0x3175Df0976dFA876431C2E9eE6Bc45b65d3473CC").unwrap()];
-            initialize_accounts(accounts, 20378315, rpc_url.as_str(), chain, &cached_gw).await;
+            initialize_accounts(
+                accounts,
+                HashMap::new(),
+                20378315,
+                rpc_url.as_str(),
+                chain,
+                &cached_gw,
+            )
+            .await
+            .expect("Failed to initialize accounts");
 
             let contracts = cached_gw
                 .get_contracts(&chain, None, None, true, None)
@@ -676,7 +1147,9 @@ mod test_serial_db {
                 .await
                 .expect("Failed to create Gateway");
 
-            initialize_accounts(accounts, block_id, rpc_url, chain, &cached_gw).await;
+            initialize_accounts(accounts, HashMap::new(), block_id, rpc_url, chain, &cached_gw)
+                .await
+                .expect("Failed to initialize accounts");
         })
         .await;
     }
@@ -0,0 +1,382 @@
+//! Authenticated admin API to control the extractors at runtime.
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse, ResponseError};
+use reqwest::StatusCode;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use tracing::{info, warn};
+use tycho_core::{
+    dto,
+    models::{Chain, ExtractorIdentity},
+};
+
+use crate::extractor::{
+    runner::{ExtractorController, RestartPosition},
+    ExtractionError,
+};
+
+#[derive(Error, Debug)]
+pub enum AdminError {
+    #[error("Missing or invalid admin token")]
+    Unauthorized,
+
+    #[error("Unknown extractor: {0}")]
+    UnknownExtractor(String),
+
+    #[error("Invalid request: {0}")]
+    BadRequest(String),
+
+    #[error("Failed to control extractor: {0}")]
+    Control(#[from] ExtractionError),
+}
+
+impl ResponseError for AdminError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).body(self.to_string())
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AdminError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AdminError::UnknownExtractor(_) => StatusCode::NOT_FOUND,
+            AdminError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            // e.g. a restart position ahead of the committed block
+            AdminError::Control(ExtractionError::Setup(_)) => StatusCode::BAD_REQUEST,
+            AdminError::Control(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Serves the admin endpoints. Every request must carry the admin token as bearer token in the
+/// `Authorization` header.
+pub struct AdminHandler {
+    token: String,
+    extractors: RwLock<HashMap<ExtractorIdentity, Arc<dyn ExtractorController>>>,
+}
+
+impl AdminHandler {
+    pub fn new(token: String, extractors: Vec<Arc<dyn ExtractorController>>) -> Self {
+        Self {
+            token,
+            extractors: RwLock::new(
+                extractors
+                    .into_iter()
+                    .map(|e| (e.get_id(), e))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Makes an extractor started at runtime controllable.
+    pub fn add_extractor(&self, extractor: Arc<dyn ExtractorController>) {
+        self.extractors
+            .write()
+            .expect("extractors lock poisoned")
+            .insert(extractor.get_id(), extractor);
+    }
+
+    /// Drops an extractor removed at runtime.
+    pub fn remove_extractor(&self, id: &ExtractorIdentity) {
+        self.extractors
+            .write()
+            .expect("extractors lock poisoned")
+            .remove(id);
+    }
+
+    fn authorize(&self, req: &HttpRequest) -> Result<(), AdminError> {
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token)
+                if token
+                    .as_bytes()
+                    .ct_eq(self.token.as_bytes())
+                    .into() =>
+            {
+                Ok(())
+            }
+            _ => {
+                warn!(path = req.path(), "Unauthorized admin request");
+                Err(AdminError::Unauthorized)
+            }
+        }
+    }
+
+    fn get_extractor(
+        &self,
+        chain: &str,
+        name: &str,
+    ) -> Result<Arc<dyn ExtractorController>, AdminError> {
+        let chain = Chain::from_str(chain)
+            .map_err(|_| AdminError::BadRequest(format!("Unknown chain: {}", chain)))?;
+        self.extractors
+            .read()
+            .expect("extractors lock poisoned")
+            .get(&ExtractorIdentity::new(chain, name))
+            .cloned()
+            .ok_or_else(|| AdminError::UnknownExtractor(format!("{}:{}", chain, name)))
+    }
+
+    fn list(&self) -> Vec<dto::ExtractorInfo> {
+        let extractors = self
+            .extractors
+            .read()
+            .expect("extractors lock poisoned");
+        let mut ids: Vec<_> = extractors.iter().collect();
+        ids.sort_by_key(|(id, _)| id.to_string());
+        ids.into_iter()
+            .map(|(id, controller)| {
+                let status = controller.status();
+                dto::ExtractorInfo {
+                    chain: id.chain.into(),
+                    name: id.name.clone(),
+                    state: status.state.into(),
+                    cursor: status.cursor,
+                    last_block: status
+                        .last_block
+                        .map(|block| block.number),
+                    subscribers: status.subscribers,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Lists all extractors with their cursor, last processed block and number of subscribers.
+pub async fn list_extractors(
+    req: HttpRequest,
+    handler: web::Data<AdminHandler>,
+) -> Result<HttpResponse, AdminError> {
+    handler.authorize(&req)?;
+    Ok(HttpResponse::Ok().json(handler.list()))
+}
+
+/// Pauses an extractor. It stops consuming the substreams until resumed.
+pub async fn pause_extractor(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    handler: web::Data<AdminHandler>,
+) -> Result<HttpResponse, AdminError> {
+    handler.authorize(&req)?;
+    let (chain, name) = path.into_inner();
+    let extractor = handler.get_extractor(&chain, &name)?;
+    info!(%chain, %name, "Pausing extractor");
+    extractor.pause().await?;
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Resumes a paused extractor from its last processed cursor.
+pub async fn resume_extractor(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    handler: web::Data<AdminHandler>,
+) -> Result<HttpResponse, AdminError> {
+    handler.authorize(&req)?;
+    let (chain, name) = path.into_inner();
+    let extractor = handler.get_extractor(&chain, &name)?;
+    info!(%chain, %name, "Resuming extractor");
+    extractor.resume().await?;
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Restarts an extractor from the requested cursor or block, or after the last committed block if
+/// neither is given. Subscriptions to the extractor end and have to be renewed.
+pub async fn restart_extractor(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    body: web::Bytes,
+    handler: web::Data<AdminHandler>,
+) -> Result<HttpResponse, AdminError> {
+    handler.authorize(&req)?;
+    let (chain, name) = path.into_inner();
+    let extractor = handler.get_extractor(&chain, &name)?;
+    let body: dto::RestartExtractorRequest = if body.is_empty() {
+        Default::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|err| AdminError::BadRequest(err.to_string()))?
+    };
+    let position = match (body.cursor, body.block) {
+        (Some(_), Some(_)) => {
+            return Err(AdminError::BadRequest(
+                "Only one of cursor and block may be set".to_string(),
+            ))
+        }
+        (Some(cursor), None) => RestartPosition::Cursor(cursor),
+        (None, Some(block)) => RestartPosition::Block(block),
+        (None, None) => RestartPosition::Committed,
+    };
+    info!(%chain, %name, ?position, "Restarting extractor");
+    extractor.restart(position).await?;
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use actix_web::{body::to_bytes, test};
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        extractor::runner::{ExtractorStatus, RunnerState, StatusReporter},
+        testing::block,
+    };
+
+    #[derive(Default)]
+    struct FakeController {
+        calls: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl StatusReporter for FakeController {
+        fn get_id(&self) -> ExtractorIdentity {
+            ExtractorIdentity::new(Chain::Ethereum, "uniswap_v2")
+        }
+
+        fn status(&self) -> ExtractorStatus {
+            ExtractorStatus {
+                state: RunnerState::Running,
+                last_block: Some(block(3)),
+                cursor: Some("cursor@3".to_string()),
+                subscribers: 2,
+                ..Default::default()
+            }
+        }
+
+        async fn chain_head(&self) -> Option<u64> {
+            None
+        }
+    }
+
+    #[async_trait]
+    impl ExtractorController for FakeController {
+        async fn pause(&self) -> Result<(), ExtractionError> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push("pause".to_string());
+            Ok(())
+        }
+
+        async fn resume(&self) -> Result<(), ExtractionError> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push("resume".to_string());
+            Ok(())
+        }
+
+        async fn restart(&self, position: RestartPosition) -> Result<(), ExtractionError> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("restart {:?}", position));
+            Ok(())
+        }
+    }
+
+    fn handler(controller: Arc<FakeController>) -> web::Data<AdminHandler> {
+        web::Data::new(AdminHandler::new("secret".to_string(), vec![controller]))
+    }
+
+    fn request(token: Option<&str>) -> HttpRequest {
+        let mut req = test::TestRequest::default();
+        if let Some(token) = token {
+            req = req.insert_header((header::AUTHORIZATION, format!("Bearer {}", token)));
+        }
+        req.to_http_request()
+    }
+
+    fn path(chain: &str, name: &str) -> web::Path<(String, String)> {
+        web::Path::from((chain.to_string(), name.to_string()))
+    }
+
+    #[test]
+    async fn test_list_extractors() {
+        let handler = handler(Arc::new(FakeController::default()));
+
+        let res = list_extractors(request(Some("secret")), handler)
+            .await
+            .unwrap();
+        let body = to_bytes(res.into_body()).await.unwrap();
+        let extractors: Vec<dto::ExtractorInfo> = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            extractors,
+            vec![dto::ExtractorInfo {
+                chain: dto::Chain::Ethereum,
+                name: "uniswap_v2".to_string(),
+                state: dto::ExtractorState::Running,
+                cursor: Some("cursor@3".to_string()),
+                last_block: Some(3),
+                subscribers: 2,
+            }]
+        );
+    }
+
+    #[test]
+    async fn test_unauthorized() {
+        let controller = Arc::new(FakeController::default());
+        let handler = handler(controller.clone());
+
+        let missing = list_extractors(request(None), handler.clone()).await;
+        let wrong =
+            pause_extractor(request(Some("wrong")), path("ethereum", "uniswap_v2"), handler).await;
+
+        assert!(matches!(missing, Err(AdminError::Unauthorized)));
+        assert!(matches!(wrong, Err(AdminError::Unauthorized)));
+        assert!(controller
+            .calls
+            .lock()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    async fn test_control_extractor() {
+        let controller = Arc::new(FakeController::default());
+        let handler = handler(controller.clone());
+        let req = || request(Some("secret"));
+        let id = || path("ethereum", "uniswap_v2");
+
+        pause_extractor(req(), id(), handler.clone())
+            .await
+            .unwrap();
+        resume_extractor(req(), id(), handler.clone())
+            .await
+            .unwrap();
+        restart_extractor(req(), id(), web::Bytes::new(), handler.clone())
+            .await
+            .unwrap();
+        restart_extractor(req(), id(), web::Bytes::from(r#"{"block": 10}"#), handler.clone())
+            .await
+            .unwrap();
+        let both = web::Bytes::from(r#"{"cursor": "c", "block": 10}"#);
+        let invalid = restart_extractor(req(), id(), both, handler.clone()).await;
+        let malformed =
+            restart_extractor(req(), id(), web::Bytes::from("{"), handler.clone()).await;
+        let unknown = pause_extractor(req(), path("ethereum", "unknown"), handler).await;
+
+        assert_eq!(
+            *controller.calls.lock().unwrap(),
+            vec![
+                "pause".to_string(),
+                "resume".to_string(),
+                "restart Committed".to_string(),
+                "restart Block(10)".to_string(),
+            ]
+        );
+        assert!(matches!(invalid, Err(AdminError::BadRequest(_))));
+        assert!(matches!(malformed, Err(AdminError::BadRequest(_))));
+        assert!(matches!(unknown, Err(AdminError::UnknownExtractor(_))));
+    }
+}
@@ -1,10 +1,78 @@
-use std::{error::Error, fmt::Debug, hash::Hash, sync::Arc};
+use std::{
+    error::Error,
+    fmt::Debug,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
 use futures03::Future;
 use metrics::counter;
 use mini_moka::sync::Cache;
 use tracing::{instrument, trace, Level};
 
+use crate::cli::RpcCacheArgs;
+
+/// Capacity and time to live (in seconds) of a single cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    pub capacity: u64,
+    pub ttl: u64,
+}
+
+impl CacheConfig {
+    pub fn new(capacity: u64, ttl: u64) -> Self {
+        Self { capacity, ttl }
+    }
+}
+
+/// Configuration of the caches used by the RPC handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RpcCacheConfig {
+    pub tokens: CacheConfig,
+    pub contract_state: CacheConfig,
+    pub protocol_state: CacheConfig,
+    pub protocol_components: CacheConfig,
+    /// Time to live in seconds of responses at unfinalized versions. These entries are
+    /// additionally invalidated whenever the pending deltas buffer advances or reverts.
+    pub unfinalized_ttl: u64,
+}
+
+impl Default for RpcCacheConfig {
+    fn default() -> Self {
+        Self {
+            tokens: CacheConfig::new(50, 7 * 60),
+            contract_state: CacheConfig::new(50, 7 * 60),
+            protocol_state: CacheConfig::new(50, 7 * 60),
+            protocol_components: CacheConfig::new(500, 7 * 60),
+            unfinalized_ttl: 60,
+        }
+    }
+}
+
+impl From<&RpcCacheArgs> for RpcCacheConfig {
+    fn from(args: &RpcCacheArgs) -> Self {
+        Self {
+            tokens: CacheConfig::new(args.token_cache_capacity, args.token_cache_ttl),
+            contract_state: CacheConfig::new(
+                args.contract_state_cache_capacity,
+                args.contract_state_cache_ttl,
+            ),
+            protocol_state: CacheConfig::new(
+                args.protocol_state_cache_capacity,
+                args.protocol_state_cache_ttl,
+            ),
+            protocol_components: CacheConfig::new(
+                args.component_cache_capacity,
+                args.component_cache_ttl,
+            ),
+            unfinalized_ttl: args.unfinalized_cache_ttl,
+        }
+    }
+}
+
 pub struct RpcCache<R, V> {
     name: String,
     cache: Arc<Cache<R, Arc<tokio::sync::Mutex<Option<V>>>>>,
@@ -25,6 +93,15 @@ where
         Self { name: name.to_string(), cache }
     }
 
+    pub fn from_config(name: &str, config: CacheConfig) -> Self {
+        Self::new(name, config.capacity, config.ttl)
+    }
+
+    /// Drops all entries.
+    pub fn invalidate_all(&self) {
+        self.cache.invalidate_all();
+    }
+
     #[instrument(
         name = "rpc.cache.get",
         level = Level::TRACE,
@@ -89,6 +166,72 @@ where
     }
 }
 
+/// Caches responses to versioned requests.
+///
+/// Requests are expected to be keyed on a resolved version. Responses at finalized versions can
+/// not change anymore and are kept in a long lived cache. Responses at unfinalized versions are
+/// additionally keyed on the generation of the pending deltas buffer and are dropped as soon as a
+/// newer generation is observed, i.e. once the buffer advanced or reverted.
+pub struct VersionedRpcCache<R, V> {
+    finalized: RpcCache<R, V>,
+    unfinalized: RpcCache<(R, u64), V>,
+    generation: AtomicU64,
+}
+
+impl<R, V> VersionedRpcCache<R, V>
+where
+    R: Clone + Hash + Eq + Send + Sync + Debug + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    pub fn new(name: &str, config: CacheConfig, unfinalized_ttl: u64) -> Self {
+        Self {
+            finalized: RpcCache::from_config(name, config),
+            unfinalized: RpcCache::new(
+                &format!("{name}_unfinalized"),
+                config.capacity,
+                unfinalized_ttl,
+            ),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached response or calls `fallback` to compute it.
+    ///
+    /// `generation` must be `None` if the requested version is finalized. Otherwise it is the
+    /// current generation of the pending deltas buffer the response is derived from.
+    pub async fn get<
+        'a,
+        E: Error,
+        Fut: Future<Output = Result<(V, bool), E>> + Send + 'a,
+        F: Fn(R) -> Fut + Send + Sync,
+    >(
+        &'a self,
+        request: R,
+        generation: Option<u64>,
+        fallback: F,
+    ) -> Result<V, E> {
+        match generation {
+            None => {
+                self.finalized
+                    .get(request, fallback)
+                    .await
+            }
+            Some(generation) => {
+                let previous = self
+                    .generation
+                    .fetch_max(generation, Ordering::SeqCst);
+                if generation > previous {
+                    trace!(previous, generation, "UnfinalizedEntriesInvalidated");
+                    self.unfinalized.invalidate_all();
+                }
+                self.unfinalized
+                    .get((request, generation), |(r, _)| fallback(r))
+                    .await
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::sync::Arc;
@@ -96,7 +239,10 @@ mod test {
     use futures03::future::try_join_all;
     use tokio::sync::Mutex;
 
-    use crate::services::{cache::RpcCache, rpc::RpcError};
+    use crate::services::{
+        cache::{CacheConfig, RpcCache, VersionedRpcCache},
+        rpc::RpcError,
+    };
 
     #[test_log::test(tokio::test)]
     async fn test_sequential_access() {
@@ -170,4 +316,25 @@ mod test {
         let v = *access_counter.lock().await;
         assert_eq!(v, 10);
     }
+
+    #[test_log::test(tokio::test)]
+    async fn test_versioned_cache_invalidates_unfinalized_entries() {
+        let access_counter = Arc::new(Mutex::new(0));
+        let cache =
+            VersionedRpcCache::<String, i32>::new("test", CacheConfig::new(100, 3600), 3600);
+
+        for generation in [None, Some(1), None, Some(1), Some(2), Some(1)] {
+            cache
+                .get("k0".to_string(), generation, |_| async {
+                    increment_counter(access_counter.clone()).await
+                })
+                .await
+                .unwrap();
+        }
+
+        // One miss for the finalized entry, one per newly observed generation and one for the
+        // outdated generation whose entries were dropped.
+        let v = *access_counter.lock().await;
+        assert_eq!(v, 4);
+    }
 }
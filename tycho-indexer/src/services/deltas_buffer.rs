@@ -1,15 +1,18 @@
 use std::{
     collections::{HashMap, HashSet},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock, RwLockReadGuard,
+    },
 };
 
-use futures03::{stream, StreamExt};
+use futures03::{stream::FuturesUnordered, StreamExt};
 use thiserror::Error;
-use tokio_stream::wrappers::ReceiverStream;
-use tracing::{debug, error, instrument, trace, Level};
+use tokio::sync::mpsc;
+use tracing::{debug, error, instrument, trace, warn, Level};
 use tycho_core::{
     models::{
-        blockchain::BlockAggregatedChanges,
+        blockchain::{Block, BlockAggregatedChanges},
         contract::Account,
         protocol::{ProtocolComponent, ProtocolComponentState},
         DeltaError, NormalisedMessage,
@@ -20,7 +23,7 @@ use tycho_core::{
 
 use crate::extractor::{
     reorg_buffer::{BlockNumberOrTimestamp, FinalityStatus, ReorgBuffer},
-    runner::MessageSender,
+    runner::{ExtractorRestarted, MessageSender},
 };
 
 /// The `PendingDeltas` struct manages access to the reorg buffers maintained by each extractor.
@@ -34,7 +37,26 @@ use crate::extractor::{
 #[derive(Default, Clone)]
 pub struct PendingDeltas {
     // Map with the protocol system name as key and a `ReorgBuffer` as value.
-    buffers: HashMap<String, Arc<Mutex<ReorgBuffer<BlockAggregatedChanges>>>>,
+    // Extractors can be added and removed at runtime.
+    buffers: Arc<RwLock<HashMap<String, SharedBuffer>>>,
+    // Incremented each time any of the buffers advances or reverts.
+    generation: Arc<AtomicU64>,
+    // Set while the task inserting extractor messages is running.
+    running: Arc<AtomicBool>,
+}
+
+type SharedBuffer = Arc<Mutex<ReorgBuffer<BlockAggregatedChanges>>>;
+
+/// An extractor whose messages are inserted into the buffers, identified by its name.
+pub type BufferedExtractor = (String, Arc<dyn MessageSender + Send + Sync>);
+
+/// Clears the running flag once the `PendingDeltas::run` task ends, including by panicking.
+struct RunningGuard(Arc<AtomicBool>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
 }
 
 #[derive(Error, Debug, PartialEq)]
@@ -89,21 +111,99 @@ pub trait PendingDeltasBuffer {
         f: &dyn Fn(&BlockAggregatedChanges) -> bool,
         protocol_system: &str,
     ) -> Result<Option<BlockAggregatedChanges>>;
+
+    fn get_block_at(
+        &self,
+        version: BlockNumberOrTimestamp,
+        protocol_system: &str,
+    ) -> Result<Option<Block>>;
+
+    fn generation(&self) -> u64;
 }
 
 impl PendingDeltas {
     pub fn new<'a>(extractors: impl IntoIterator<Item = &'a str>) -> Self {
         Self {
-            buffers: extractors
-                .into_iter()
-                .map(|e| {
-                    debug!("Creating new ReorgBuffer for {}", e);
-                    (e.to_string(), Arc::new(Mutex::new(ReorgBuffer::new())))
-                })
-                .collect(),
+            buffers: Arc::new(RwLock::new(
+                extractors
+                    .into_iter()
+                    .map(|e| {
+                        debug!("Creating new ReorgBuffer for {}", e);
+                        (e.to_string(), Arc::new(Mutex::new(ReorgBuffer::new())))
+                    })
+                    .collect(),
+            )),
+            generation: Arc::new(AtomicU64::new(0)),
+            running: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    /// Returns whether the task inserting extractor messages into the buffers is running.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_running(&self, running: bool) {
+        self.running
+            .store(running, Ordering::SeqCst);
+    }
+
+    /// Adds an empty buffer for an extractor started at runtime. An existing buffer of the same
+    /// extractor is replaced.
+    pub fn register(&self, extractor: &str) -> Result<()> {
+        debug!("Creating new ReorgBuffer for {}", extractor);
+        self.buffers
+            .write()
+            .map_err(|e| PendingDeltasError::LockError("buffers".to_string(), e.to_string()))?
+            .insert(extractor.to_string(), Arc::new(Mutex::new(ReorgBuffer::new())));
+        self.generation
+            .fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Drops the buffer of an extractor removed at runtime.
+    pub fn deregister(&self, extractor: &str) -> Result<()> {
+        debug!("Removing ReorgBuffer of {}", extractor);
+        self.buffers
+            .write()
+            .map_err(|e| PendingDeltasError::LockError("buffers".to_string(), e.to_string()))?
+            .remove(extractor);
+        self.generation
+            .fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn buffers(&self) -> Result<RwLockReadGuard<'_, HashMap<String, SharedBuffer>>> {
+        self.buffers
+            .read()
+            .map_err(|e| PendingDeltasError::LockError("buffers".to_string(), e.to_string()))
+    }
+
+    fn buffer(&self, protocol_system: &str) -> Result<SharedBuffer> {
+        self.buffers()?
+            .get(protocol_system)
+            .cloned()
+            .ok_or_else(|| {
+                error!("Missing reorg buffer for {}", protocol_system);
+                PendingDeltasError::UnknownExtractor(protocol_system.to_string())
+            })
+    }
+
+    /// Returns whether `buffer` is still the registered buffer of the extractor.
+    fn is_registered(&self, extractor: &str, buffer: &SharedBuffer) -> Result<bool> {
+        Ok(self
+            .buffers()?
+            .get(extractor)
+            .is_some_and(|current| Arc::ptr_eq(current, buffer)))
+    }
+
+    /// Inserts a message as if it was received from its extractor.
+    #[cfg(test)]
+    pub(crate) fn insert_message(&self, message: Arc<dyn NormalisedMessage>) -> Result<()> {
+        self.insert(message)
+    }
+
     fn insert(&self, message: Arc<dyn NormalisedMessage>) -> Result<()> {
         let maybe_convert: Option<BlockAggregatedChanges> = message
             .as_any()
@@ -111,7 +211,10 @@ impl PendingDeltas {
             .cloned();
         match maybe_convert {
             Some(msg) => {
-                let maybe_buffer = self.buffers.get(&msg.extractor);
+                let maybe_buffer = self
+                    .buffers()?
+                    .get(&msg.extractor)
+                    .cloned();
 
                 match maybe_buffer {
                     Some(buffer) => {
@@ -135,6 +238,8 @@ impl PendingDeltas {
                             guard.insert_block(msg.clone())?;
                             guard.drain_new_finalized_blocks(msg.finalized_block_height)?;
                         }
+                        self.generation
+                            .fetch_add(1, Ordering::SeqCst);
                     }
                     _ => return Err(PendingDeltasError::UnknownExtractor(msg.extractor.clone())),
                 }
@@ -153,13 +258,7 @@ impl PendingDeltas {
     ) -> Result<bool> {
         let mut change_found = false;
 
-        let buffer = self
-            .buffers
-            .get(protocol_system)
-            .ok_or_else(|| {
-                error!("Missing reorg buffer for {}", protocol_system);
-                PendingDeltasError::UnknownExtractor(protocol_system.to_string())
-            })?;
+        let buffer = self.buffer(protocol_system)?;
 
         let guard = buffer.lock().map_err(|e| {
             PendingDeltasError::LockError(protocol_system.to_string(), e.to_string())
@@ -197,13 +296,7 @@ impl PendingDeltas {
     ) -> Result<bool> {
         let mut change_found = false;
 
-        let buffer = self
-            .buffers
-            .get(protocol_system)
-            .ok_or_else(|| {
-                error!("Missing reorg buffer for {}", protocol_system);
-                PendingDeltasError::UnknownExtractor(protocol_system.to_string())
-            })?;
+        let buffer = self.buffer(protocol_system)?;
 
         let guard = buffer.lock().map_err(|e| {
             PendingDeltasError::LockError(protocol_system.to_string(), e.to_string())
@@ -232,7 +325,7 @@ impl PendingDeltas {
         version: Option<BlockNumberOrTimestamp>,
     ) -> Result<Account> {
         let mut account: Option<Account> = None;
-        for buffer in self.buffers.values() {
+        for buffer in self.buffers()?.values() {
             let guard = buffer
                 .lock()
                 .map_err(|e| PendingDeltasError::LockError("VM".to_string(), e.to_string()))?;
@@ -252,26 +345,91 @@ impl PendingDeltas {
         )))
     }
 
+    /// Inserts the messages of the given extractors into their buffers until all subscriptions
+    /// end. Extractors started at runtime are received through `added`, after their buffer was
+    /// registered.
     pub async fn run(
         self,
-        extractors: impl IntoIterator<Item = Arc<dyn MessageSender + Send + Sync>>,
+        extractors: impl IntoIterator<Item = BufferedExtractor>,
+        mut added: mpsc::Receiver<BufferedExtractor>,
     ) -> anyhow::Result<()> {
-        let mut rxs = Vec::new();
-        for extractor in extractors.into_iter() {
-            let res = ReceiverStream::new(extractor.subscribe().await?);
-            rxs.push(res);
+        self.running
+            .store(true, Ordering::SeqCst);
+        let _guard = RunningGuard(self.running.clone());
+        let mut tasks = FuturesUnordered::new();
+        for (name, extractor) in extractors {
+            let buffer = self.buffer(&name)?;
+            tasks.push(
+                self.clone()
+                    .consume(name, extractor, buffer),
+            );
         }
 
-        let all_messages = stream::select_all(rxs);
+        loop {
+            tokio::select! {
+                Some(res) = tasks.next() => res?,
+                Some((name, extractor)) = added.recv() => match self.buffer(&name) {
+                    Ok(buffer) => tasks.push(self.clone().consume(name, extractor, buffer)),
+                    Err(err) => warn!(error = %err, "Skipping extractor removed before it was added"),
+                },
+                else => return Ok(()),
+            }
+        }
+    }
 
-        // What happens if an extractor restarts - it might just end here and be dropped?
-        // Ideally the Runner should never restart.
-        all_messages
-            .for_each(|message| async {
+    /// Inserts the messages of an extractor into its buffer. The buffer is reset when the extractor
+    /// restarts. If the subscription ends otherwise, the buffer is reset as well and the extractor
+    /// is subscribed to again. Returns once the buffer was deregistered or the extractor stopped,
+    /// in which case its buffer is reset as its blocks will never be finalized or reverted.
+    async fn consume(
+        self,
+        name: String,
+        extractor: Arc<dyn MessageSender + Send + Sync>,
+        buffer: SharedBuffer,
+    ) -> anyhow::Result<()> {
+        loop {
+            let mut rx = match extractor.subscribe_internal().await {
+                Ok(rx) => rx,
+                Err(_) if !self.is_registered(&name, &buffer)? => return Ok(()),
+                Err(_) => {
+                    // The control channel closes once the runner exits, whether it was stopped
+                    // or failed. Failures are reported through the extractor's status.
+                    warn!(name, "Extractor stopped, stop buffering its messages");
+                    self.reset(&buffer, &name)?;
+                    return Ok(());
+                }
+            };
+            while let Some(message) = rx.recv().await {
+                if !self.is_registered(&name, &buffer)? {
+                    break;
+                }
+                if message
+                    .as_any()
+                    .is::<ExtractorRestarted>()
+                {
+                    debug!(name, "Extractor restarted, resetting its buffer");
+                    self.reset(&buffer, &name)?;
+                    continue;
+                }
                 self.insert(message).unwrap();
-            })
-            .await;
+            }
+            if !self.is_registered(&name, &buffer)? {
+                debug!(name, "Extractor was removed, stop buffering its messages");
+                return Ok(());
+            }
+            debug!(name, "Extractor subscription ended, resubscribing");
+            self.reset(&buffer, &name)?;
+        }
+    }
 
+    /// Drops all buffered blocks of an extractor.
+    fn reset(&self, buffer: &SharedBuffer, extractor: &str) -> Result<()> {
+        *buffer
+            .lock()
+            .map_err(|e| PendingDeltasError::LockError(extractor.to_string(), e.to_string()))? =
+            ReorgBuffer::new();
+        self.generation
+            .fetch_add(1, Ordering::SeqCst);
         Ok(())
     }
 }
@@ -388,13 +546,7 @@ impl PendingDeltasBuffer for PendingDeltas {
         let requested_ids: Option<HashSet<&str>> = ids.map(|ids| ids.iter().cloned().collect());
         let mut new_components = Vec::new();
 
-        let buffer = self
-            .buffers
-            .get(protocol_system)
-            .ok_or_else(|| {
-                error!("Missing reorg buffer for {}", protocol_system);
-                PendingDeltasError::UnknownExtractor(protocol_system.to_string())
-            })?;
+        let buffer = self.buffer(protocol_system)?;
 
         let guard = buffer.lock().map_err(|e| {
             PendingDeltasError::LockError(protocol_system.to_string(), e.to_string())
@@ -441,13 +593,7 @@ impl PendingDeltasBuffer for PendingDeltas {
         version: BlockNumberOrTimestamp,
         protocol_system: &str,
     ) -> Result<Option<FinalityStatus>> {
-        let buffer = self
-            .buffers
-            .get(protocol_system)
-            .ok_or_else(|| {
-                error!("Missing reorg buffer for {}", protocol_system);
-                PendingDeltasError::UnknownExtractor(protocol_system.to_string())
-            })?;
+        let buffer = self.buffer(protocol_system)?;
         let guard = buffer.lock().map_err(|e| {
             PendingDeltasError::LockError(protocol_system.to_string(), e.to_string())
         })?;
@@ -460,13 +606,7 @@ impl PendingDeltasBuffer for PendingDeltas {
         f: &dyn Fn(&BlockAggregatedChanges) -> bool,
         protocol_system: &str,
     ) -> Result<Option<BlockAggregatedChanges>> {
-        let buffer = self
-            .buffers
-            .get(protocol_system)
-            .ok_or_else(|| {
-                error!("Missing reorg buffer for {}", protocol_system);
-                PendingDeltasError::UnknownExtractor(protocol_system.to_string())
-            })?;
+        let buffer = self.buffer(protocol_system)?;
         let guard = buffer.lock().map_err(|e| {
             PendingDeltasError::LockError(protocol_system.to_string(), e.to_string())
         })?;
@@ -479,6 +619,31 @@ impl PendingDeltasBuffer for PendingDeltas {
 
         Ok(None)
     }
+
+    /// Returns the last buffered block included when state is requested at the given version, i.e.
+    /// the block whose deltas are applied last. Returns None if the buffer is empty.
+    fn get_block_at(
+        &self,
+        version: BlockNumberOrTimestamp,
+        protocol_system: &str,
+    ) -> Result<Option<Block>> {
+        let buffer = self.buffer(protocol_system)?;
+        let guard = buffer.lock().map_err(|e| {
+            PendingDeltasError::LockError(protocol_system.to_string(), e.to_string())
+        })?;
+
+        Ok(guard
+            .get_block_range(None, Some(version))
+            .ok()
+            .and_then(|blocks| blocks.last())
+            .map(|block| block.block.clone()))
+    }
+
+    /// Returns a counter that increases every time a buffer advances or reverts. Responses
+    /// derived from buffered state are only valid as long as the generation does not change.
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
 }
 
 #[cfg(test)]
@@ -492,7 +657,10 @@ mod test {
     };
 
     use super::*;
-    use crate::{extractor::models::fixtures, testing::block};
+    use crate::{
+        extractor::{models::fixtures, runner::ControlMessage, ExtractorMsg},
+        testing::block,
+    };
 
     fn vm_state() -> Account {
         Account::new(
@@ -766,8 +934,7 @@ mod test {
             .expect("insert failed");
 
         let reorg_buffer = buffer
-            .buffers
-            .get("vm:extractor")
+            .buffer("vm:extractor")
             .expect("extractor buffer missing");
         let binding = reorg_buffer.lock().unwrap();
         let res = binding
@@ -1006,4 +1173,79 @@ mod test {
 
         assert_eq!(res, expected_res);
     }
+
+    #[test]
+    fn test_get_block_at_and_generation() {
+        let buffer = PendingDeltas::new(["native:extractor"]);
+        assert_eq!(buffer.generation(), 0);
+        assert_eq!(
+            buffer
+                .get_block_at(BlockNumberOrTimestamp::Number(1), "native:extractor")
+                .unwrap(),
+            None
+        );
+
+        let deltas = native_block_deltas();
+        buffer
+            .insert(Arc::new(deltas.clone()))
+            .unwrap();
+
+        assert_eq!(buffer.generation(), 1);
+        let latest =
+            BlockNumberOrTimestamp::Timestamp(deltas.block.ts + chrono::Duration::hours(1));
+        assert_eq!(
+            buffer
+                .get_block_at(latest, "native:extractor")
+                .unwrap(),
+            Some(deltas.block)
+        );
+        assert!(buffer
+            .get_block_at(latest, "unknown_system")
+            .is_err());
+    }
+
+    /// Hands out a single subscription and then behaves like a stopped extractor.
+    struct StoppingExtractor(Mutex<Option<mpsc::Receiver<ExtractorMsg>>>);
+
+    #[async_trait::async_trait]
+    impl MessageSender for StoppingExtractor {
+        async fn subscribe(
+            &self,
+        ) -> std::result::Result<mpsc::Receiver<ExtractorMsg>, mpsc::error::SendError<ControlMessage>>
+        {
+            self.0
+                .lock()
+                .unwrap()
+                .take()
+                .ok_or(mpsc::error::SendError(ControlMessage::Stop))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_consume_stopped_extractor() {
+        let pending = PendingDeltas::new(["native:extractor"]);
+        let buffer = pending
+            .buffer("native:extractor")
+            .unwrap();
+        let (tx, rx) = mpsc::channel(1);
+        tx.send(Arc::new(native_block_deltas()) as ExtractorMsg)
+            .await
+            .unwrap();
+        drop(tx);
+        let extractor = Arc::new(StoppingExtractor(Mutex::new(Some(rx))));
+
+        pending
+            .clone()
+            .consume("native:extractor".to_string(), extractor, buffer.clone())
+            .await
+            .unwrap();
+
+        assert!(buffer
+            .lock()
+            .unwrap()
+            .get_block_range(None, None)
+            .unwrap()
+            .next()
+            .is_none());
+    }
 }
@@ -0,0 +1,304 @@
+//! Readiness and status reporting of the Tycho services.
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use tracing::warn;
+use tycho_core::{
+    dto,
+    models::{Chain, ExtractorIdentity},
+    storage::{BlockIdentifier, ChainGateway, StorageError},
+};
+
+use crate::{
+    extractor::runner::{ExtractorStatus, StatusReporter},
+    services::deltas_buffer::PendingDeltas,
+};
+
+/// How long the database may take to answer the connectivity check.
+const DB_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Derives the readiness of the service from the database, the pending deltas task and the
+/// extractors.
+///
+/// The service is ready once the database is reachable, the pending deltas task is running and
+/// every extractor processed a block and is at most `max_lag` blocks behind the chain head.
+#[derive(Clone)]
+pub struct HealthChecker {
+    extractors: Arc<RwLock<Vec<Arc<dyn StatusReporter>>>>,
+    pending_deltas: Option<PendingDeltas>,
+    max_lag: u64,
+}
+
+impl HealthChecker {
+    pub fn new(
+        extractors: Vec<Arc<dyn StatusReporter>>,
+        pending_deltas: Option<PendingDeltas>,
+        max_lag: u64,
+    ) -> Self {
+        Self { extractors: Arc::new(RwLock::new(extractors)), pending_deltas, max_lag }
+    }
+
+    /// Includes an extractor started at runtime in the checks.
+    pub fn add_extractor(&self, extractor: Arc<dyn StatusReporter>) {
+        self.extractors
+            .write()
+            .expect("extractors lock poisoned")
+            .push(extractor);
+    }
+
+    /// Excludes an extractor removed at runtime from the checks.
+    pub fn remove_extractor(&self, id: &ExtractorIdentity) {
+        self.extractors
+            .write()
+            .expect("extractors lock poisoned")
+            .retain(|extractor| &extractor.get_id() != id);
+    }
+
+    fn extractors(&self) -> Vec<Arc<dyn StatusReporter>> {
+        self.extractors
+            .read()
+            .expect("extractors lock poisoned")
+            .clone()
+    }
+
+    /// Collects the status of all components and derives the overall health from it.
+    pub async fn status<G: ChainGateway + Sync>(&self, db_gateway: &G) -> dto::ServiceStatus {
+        let database = self.check_database(db_gateway).await;
+        let pending_deltas = self
+            .pending_deltas
+            .as_ref()
+            .map(PendingDeltas::is_running);
+        let reporters = self.extractors();
+        let mut extractors = Vec::with_capacity(reporters.len());
+        for reporter in reporters.iter() {
+            let id = reporter.get_id();
+            let status = reporter.status();
+            let chain_head = reporter.chain_head().await;
+            extractors.push(Self::extractor_status(id.chain, id.name, status, chain_head));
+        }
+        let health = self.health(database, pending_deltas, &extractors);
+        dto::ServiceStatus { health, database, pending_deltas, extractors }
+    }
+
+    /// Checks that the database answers queries. A missing block still proves connectivity.
+    async fn check_database<G: ChainGateway + Sync>(&self, db_gateway: &G) -> bool {
+        let chain = self
+            .extractors()
+            .first()
+            .map(|reporter| reporter.get_id().chain)
+            .unwrap_or(Chain::Ethereum);
+        let res = tokio::time::timeout(
+            DB_CHECK_TIMEOUT,
+            db_gateway.get_block(&BlockIdentifier::Latest(chain)),
+        )
+        .await;
+        match res {
+            Ok(Ok(_)) | Ok(Err(StorageError::NotFound(..))) => true,
+            Ok(Err(err)) => {
+                warn!(error = %err, "Database health check failed");
+                false
+            }
+            Err(_) => {
+                warn!("Database health check timed out");
+                false
+            }
+        }
+    }
+
+    fn extractor_status(
+        chain: Chain,
+        name: String,
+        status: ExtractorStatus,
+        chain_head: Option<u64>,
+    ) -> dto::ExtractorStatus {
+        let lag = match (&status.last_block, chain_head) {
+            (Some(block), Some(head)) => Some(head.saturating_sub(block.number)),
+            _ => None,
+        };
+        dto::ExtractorStatus {
+            chain: chain.into(),
+            name,
+            state: status.state.into(),
+            last_block: status
+                .last_block
+                .as_ref()
+                .map(|block| block.number),
+            last_block_ts: status
+                .last_block
+                .as_ref()
+                .map(|block| block.ts),
+            last_processed_at: status.last_processed_at,
+            chain_head,
+            lag,
+        }
+    }
+
+    fn health(
+        &self,
+        database: bool,
+        pending_deltas: Option<bool>,
+        extractors: &[dto::ExtractorStatus],
+    ) -> dto::Health {
+        if !database {
+            return dto::Health::NotReady("No db connection".to_string());
+        }
+        if pending_deltas == Some(false) {
+            return dto::Health::NotReady("Pending deltas task is not running".to_string());
+        }
+        for extractor in extractors {
+            if let dto::ExtractorState::Failed(err) = &extractor.state {
+                return dto::Health::NotReady(format!(
+                    "Extractor {}:{} failed: {}",
+                    extractor.chain, extractor.name, err
+                ));
+            }
+        }
+        for extractor in extractors {
+            if extractor.state == dto::ExtractorState::Starting {
+                return dto::Health::Starting(format!(
+                    "Extractor {}:{} has not processed a block yet",
+                    extractor.chain, extractor.name
+                ));
+            }
+            if extractor.state == dto::ExtractorState::Paused {
+                // paused on purpose, falling behind is expected
+                continue;
+            }
+            if let Some(lag) = extractor.lag {
+                if lag > self.max_lag {
+                    return dto::Health::NotReady(format!(
+                        "Extractor {}:{} is {} blocks behind the chain head",
+                        extractor.chain, extractor.name, lag
+                    ));
+                }
+            }
+        }
+        dto::Health::Ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use tycho_core::models::{blockchain::Block, ExtractorIdentity};
+
+    use super::*;
+    use crate::{
+        extractor::runner::RunnerState,
+        testing::{block, MockGateway},
+    };
+
+    struct StaticReporter {
+        id: ExtractorIdentity,
+        status: ExtractorStatus,
+        chain_head: Option<u64>,
+    }
+
+    #[async_trait]
+    impl StatusReporter for StaticReporter {
+        fn get_id(&self) -> ExtractorIdentity {
+            self.id.clone()
+        }
+
+        fn status(&self) -> ExtractorStatus {
+            self.status.clone()
+        }
+
+        async fn chain_head(&self) -> Option<u64> {
+            self.chain_head
+        }
+    }
+
+    fn reporter(state: RunnerState, last_block: Option<u64>) -> Arc<dyn StatusReporter> {
+        Arc::new(StaticReporter {
+            id: ExtractorIdentity::new(Chain::Ethereum, "uniswap_v2"),
+            status: ExtractorStatus {
+                state,
+                last_block: last_block.map(block),
+                ..Default::default()
+            },
+            chain_head: Some(100),
+        })
+    }
+
+    fn gateway(result: fn() -> Result<Block, StorageError>) -> MockGateway {
+        let mut gw = MockGateway::new();
+        gw.expect_get_block()
+            .returning(move |_| result());
+        gw
+    }
+
+    #[tokio::test]
+    async fn test_status_ready() {
+        let checker = HealthChecker::new(
+            vec![reporter(RunnerState::Running, Some(95))],
+            Some(PendingDeltas::new(["uniswap_v2"])),
+            10,
+        );
+        checker
+            .pending_deltas
+            .as_ref()
+            .unwrap()
+            .set_running(true);
+
+        let status = checker
+            .status(&gateway(|| Err(StorageError::NotFound("Block".into(), "latest".into()))))
+            .await;
+
+        assert_eq!(status.health, dto::Health::Ready);
+        assert!(status.database);
+        assert_eq!(status.pending_deltas, Some(true));
+        assert_eq!(status.extractors[0].last_block, Some(95));
+        assert_eq!(status.extractors[0].lag, Some(5));
+    }
+
+    #[tokio::test]
+    async fn test_status_not_ready() {
+        let running = reporter(RunnerState::Running, Some(95));
+        let lagging = reporter(RunnerState::Running, Some(50));
+        let failed = reporter(RunnerState::Failed("stream ended".into()), Some(95));
+        let starting = reporter(RunnerState::Starting, None);
+        let db_ok = gateway(|| Ok(block(1)));
+
+        let no_db = HealthChecker::new(vec![running.clone()], None, 10)
+            .status(&gateway(|| Err(StorageError::Unexpected("connection refused".into()))))
+            .await;
+        let no_deltas =
+            HealthChecker::new(vec![running.clone()], Some(PendingDeltas::new(["uniswap_v2"])), 10)
+                .status(&db_ok)
+                .await;
+        let lag = HealthChecker::new(vec![running.clone(), lagging], None, 10)
+            .status(&db_ok)
+            .await;
+        let fail = HealthChecker::new(vec![starting.clone(), failed], None, 10)
+            .status(&db_ok)
+            .await;
+        let start = HealthChecker::new(vec![running.clone(), starting], None, 10)
+            .status(&db_ok)
+            .await;
+        let paused =
+            HealthChecker::new(vec![running, reporter(RunnerState::Paused, Some(50))], None, 10)
+                .status(&db_ok)
+                .await;
+
+        assert!(!no_db.database);
+        assert!(matches!(no_db.health, dto::Health::NotReady(_)));
+        assert_eq!(no_deltas.pending_deltas, Some(false));
+        assert!(matches!(no_deltas.health, dto::Health::NotReady(_)));
+        assert_eq!(
+            lag.health,
+            dto::Health::NotReady(
+                "Extractor ethereum:uniswap_v2 is 50 blocks behind the chain head".to_string()
+            )
+        );
+        assert_eq!(
+            fail.health,
+            dto::Health::NotReady("Extractor ethereum:uniswap_v2 failed: stream ended".to_string())
+        );
+        assert!(matches!(start.health, dto::Health::Starting(_)));
+        assert_eq!(paused.health, dto::Health::Ready);
+        assert_eq!(paused.extractors[1].state, dto::ExtractorState::Paused);
+    }
+}
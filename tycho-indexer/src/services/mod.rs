@@ -7,39 +7,58 @@ use actix_web::{dev::ServerHandle, web, App, HttpServer};
 use actix_web_opentelemetry::RequestTracing;
 use deltas_buffer::PendingDeltasBuffer;
 use futures03::future::try_join_all;
-use tokio::task::JoinHandle;
-use tracing::info;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{error, info, warn};
 use tycho_core::{
     dto::{
-        AccountUpdate, BlockParam, Chain, ChangeType, ContractId, Health, PaginationParams,
-        PaginationResponse, ProtocolComponent, ProtocolComponentRequestResponse,
-        ProtocolComponentsRequestBody, ProtocolId, ProtocolStateDelta, ProtocolStateRequestBody,
-        ProtocolStateRequestResponse, ProtocolSystemsRequestBody, ProtocolSystemsRequestResponse,
-        ResponseAccount, ResponseProtocolState, ResponseToken, StateRequestBody,
-        StateRequestResponse, TokensRequestBody, TokensRequestResponse, VersionParam,
+        AccountUpdate, BlockParam, Chain, ChangeType, ContractId, ExtractorState, ExtractorStatus,
+        Health, PaginationParams, PaginationResponse, ProtocolComponent,
+        ProtocolComponentRequestResponse, ProtocolComponentsRequestBody, ProtocolId,
+        ProtocolStateDelta, ProtocolStateRequestBody, ProtocolStateRequestResponse,
+        ProtocolSystemsRequestBody, ProtocolSystemsRequestResponse, ResponseAccount,
+        ResponseProtocolState, ResponseToken, ResponseTokenAnalysis, ServiceStatus,
+        StateRequestBody, StateRequestResponse, TokenAnalysisResponse, TokensRequestBody,
+        TokensRequestResponse, VersionParam,
     },
+    models::ExtractorIdentity,
     storage::Gateway,
 };
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
 use crate::{
-    extractor::{runner::ExtractorHandle, ExtractionError},
-    services::deltas_buffer::PendingDeltas,
+    extractor::{
+        reload::ExtractorUpdate,
+        runner::{ExtractorController, ExtractorHandle, StatusReporter},
+        ExtractionError,
+    },
+    services::{admin::AdminHandler, deltas_buffer::PendingDeltas, health::HealthChecker},
 };
 
+mod admin;
 mod cache;
 mod deltas_buffer;
+mod health;
 mod rpc;
 mod ws;
 
+pub use cache::{CacheConfig, RpcCacheConfig};
+
 /// Helper struct to build Tycho services such as HTTP and WS server.
 pub struct ServicesBuilder<G> {
     prefix: String,
     port: u16,
     bind: String,
     extractor_handles: ws::MessageSenderMap,
+    status_reporters: Vec<Arc<dyn StatusReporter>>,
+    controllers: Vec<Arc<dyn ExtractorController>>,
     db_gateway: G,
+    cache_config: RpcCacheConfig,
+    max_extractor_lag: u64,
+    admin_token: Option<String>,
+    admin_bind: String,
+    admin_port: u16,
+    extractor_updates: Option<mpsc::Receiver<ExtractorUpdate>>,
 }
 
 impl<G> ServicesBuilder<G>
@@ -52,7 +71,15 @@ where
             port: 4242,
             bind: "0.0.0.0".to_owned(),
             extractor_handles: HashMap::new(),
+            status_reporters: Vec::new(),
+            controllers: Vec::new(),
             db_gateway,
+            cache_config: RpcCacheConfig::default(),
+            max_extractor_lag: 50,
+            admin_token: None,
+            admin_bind: "127.0.0.1".to_owned(),
+            admin_port: 4243,
+            extractor_updates: None,
         }
     }
 
@@ -60,6 +87,10 @@ where
     pub fn register_extractors(mut self, handles: Vec<ExtractorHandle>) -> Self {
         for e in handles {
             let id = e.get_id();
+            self.status_reporters
+                .push(Arc::new(e.clone()));
+            self.controllers
+                .push(Arc::new(e.clone()));
             self.extractor_handles
                 .insert(id, Arc::new(e));
         }
@@ -84,20 +115,60 @@ where
         self
     }
 
+    /// Sets the capacity and time to live of the RPC response caches
+    pub fn cache_config(mut self, v: RpcCacheConfig) -> Self {
+        self.cache_config = v;
+        self
+    }
+
+    /// Sets how many blocks an extractor may lag behind the chain head before the service is
+    /// reported as not ready
+    pub fn max_extractor_lag(mut self, v: u64) -> Self {
+        self.max_extractor_lag = v;
+        self
+    }
+
+    /// Enables the admin endpoints, authenticated by the given bearer token
+    pub fn admin_token(mut self, v: &str) -> Self {
+        self.admin_token = Some(v.to_owned());
+        self
+    }
+
+    /// Sets the IP address for the admin server
+    pub fn admin_bind(mut self, v: &str) -> Self {
+        v.clone_into(&mut self.admin_bind);
+        self
+    }
+
+    /// Sets the port for the admin server
+    pub fn admin_port(mut self, v: u16) -> Self {
+        self.admin_port = v;
+        self
+    }
+
+    /// Applies extractors added or removed at runtime to the running services
+    pub fn extractor_updates(mut self, v: mpsc::Receiver<ExtractorUpdate>) -> Self {
+        self.extractor_updates = Some(v);
+        self
+    }
+
     /// Starts the Tycho server. Returns a tuple containing a handle for the server and a Tokio
-    /// handle for the tasks. If no extractor tasks are registered, it starts the server without
-    /// running the delta tasks.
+    /// handle for the tasks. If no extractor tasks are registered and no extractors can be added at
+    /// runtime, it starts the server without running the delta tasks.
     pub fn run(
-        self,
+        mut self,
     ) -> Result<(ServerHandle, JoinHandle<Result<(), ExtractionError>>), ExtractionError> {
         #[derive(OpenApi)]
         #[openapi(
             paths(
                 rpc::contract_state,
                 rpc::tokens,
+                rpc::token_analysis,
                 rpc::protocol_components,
                 rpc::protocol_state,
                 rpc::health,
+                rpc::ready,
+                rpc::status,
                 rpc::protocol_systems
             ),
             components(
@@ -113,6 +184,8 @@ where
                 schemas(PaginationParams),
                 schemas(PaginationResponse),
                 schemas(ResponseToken),
+                schemas(TokenAnalysisResponse),
+                schemas(ResponseTokenAnalysis),
                 schemas(ProtocolComponentsRequestBody),
                 schemas(ProtocolComponentRequestResponse),
                 schemas(ProtocolComponent),
@@ -124,6 +197,9 @@ where
                 schemas(ChangeType),
                 schemas(ProtocolStateDelta),
                 schemas(Health),
+                schemas(ServiceStatus),
+                schemas(ExtractorStatus),
+                schemas(ExtractorState),
                 schemas(ProtocolSystemsRequestBody),
                 schemas(ProtocolSystemsRequestResponse),
             )
@@ -133,9 +209,11 @@ where
         let open_api = ApiDoc::openapi();
 
         // If no extractors are registered, run the server without spawning extractor-related tasks.
-        if self.extractor_handles.is_empty() {
+        if self.extractor_handles.is_empty() && self.extractor_updates.is_none() {
             info!("Starting standalone rpc server");
-            self.start_server(None, open_api, None)
+            let health = HealthChecker::new(Vec::new(), None, self.max_extractor_lag);
+            let admin = self.admin_handler();
+            self.start_server(None, open_api, None, health, admin)
         } else {
             info!("Starting full server");
             self.start_server_with_deltas(open_api)
@@ -145,7 +223,7 @@ where
     /// Runs the server with both RPC and WebSocket services, and spawns tasks for handling
     /// pending delta processing.
     fn start_server_with_deltas(
-        self,
+        mut self,
         openapi: utoipa::openapi::OpenApi,
     ) -> Result<(ServerHandle, JoinHandle<Result<(), ExtractionError>>), ExtractionError> {
         let pending_deltas = PendingDeltas::new(
@@ -156,20 +234,45 @@ where
         let extractor_handles_clone = self
             .extractor_handles
             .clone()
-            .into_values();
+            .into_iter()
+            .map(|(id, handle)| (id.name, handle));
+        let (added_tx, added_rx) = mpsc::channel(16);
         let pending_deltas_clone = pending_deltas.clone();
         let deltas_task = tokio::spawn(async move {
             pending_deltas_clone
-                .run(extractor_handles_clone)
+                .run(extractor_handles_clone, added_rx)
                 .await
                 .map_err(|err| ExtractionError::Unknown(err.to_string()))
         });
         let ws_data = web::Data::new(ws::WsData::new(self.extractor_handles.clone()));
-        let (server_handle, server_task) =
-            self.start_server(Some(ws_data), openapi, Some(Arc::new(pending_deltas)))?;
+        let health = HealthChecker::new(
+            self.status_reporters.clone(),
+            Some(pending_deltas.clone()),
+            self.max_extractor_lag,
+        );
+        let admin = self.admin_handler();
+        let mut tasks = vec![deltas_task];
+        if let Some(updates) = self.extractor_updates.take() {
+            let registry = ExtractorRegistry {
+                ws_data: ws_data.clone(),
+                pending_deltas: pending_deltas.clone(),
+                added: added_tx,
+                health: health.clone(),
+                admin: admin.clone(),
+            };
+            tasks.push(tokio::spawn(registry.run(updates)));
+        }
+        let (server_handle, server_task) = self.start_server(
+            Some(ws_data),
+            openapi,
+            Some(Arc::new(pending_deltas)),
+            health,
+            admin,
+        )?;
+        tasks.push(server_task);
 
         let task = tokio::spawn(async move {
-            try_join_all(vec![deltas_task, server_task])
+            try_join_all(tasks)
                 .await
                 .map_err(|err| ExtractionError::Unknown(err.to_string()))?;
             Ok(())
@@ -178,18 +281,35 @@ where
         Ok((server_handle, task))
     }
 
+    /// Creates the admin handler if an admin token is set.
+    fn admin_handler(&mut self) -> Option<web::Data<AdminHandler>> {
+        let controllers = std::mem::take(&mut self.controllers);
+        self.admin_token
+            .take()
+            .map(|token| web::Data::new(AdminHandler::new(token, controllers)))
+    }
+
     /// Helper to spawn the main server task, optionally enabling WebSocket services.
     fn start_server(
         self,
         ws_data: Option<web::Data<ws::WsData>>,
         openapi: utoipa::openapi::OpenApi,
         pending_deltas: Option<Arc<dyn PendingDeltasBuffer + Send + Sync>>,
+        health: HealthChecker,
+        admin_data: Option<web::Data<AdminHandler>>,
     ) -> Result<(ServerHandle, JoinHandle<Result<(), ExtractionError>>), ExtractionError> {
-        let rpc_data = web::Data::new(rpc::RpcHandler::new(self.db_gateway, pending_deltas));
+        let rpc_data = web::Data::new(rpc::RpcHandler::new(
+            self.db_gateway,
+            pending_deltas,
+            self.cache_config,
+        ));
+        let health_data = web::Data::new(health);
+        let admin_prefix = self.prefix.clone();
 
         let server = HttpServer::new(move || {
             let mut app = App::new()
                 .app_data(rpc_data.clone())
+                .app_data(health_data.clone())
                 .service(
                     web::resource(format!("/{}/contract_state", self.prefix))
                         .route(web::post().to(rpc::contract_state::<G>)),
@@ -202,6 +322,10 @@ where
                     web::resource(format!("/{}/tokens", self.prefix))
                         .route(web::post().to(rpc::tokens::<G>)),
                 )
+                .service(
+                    web::resource(format!("/{}/tokens/{{address}}/analysis", self.prefix))
+                        .route(web::get().to(rpc::token_analysis::<G>)),
+                )
                 .service(
                     web::resource(format!("/{}/protocol_components", self.prefix))
                         .route(web::post().to(rpc::protocol_components::<G>)),
@@ -210,6 +334,14 @@ where
                     web::resource(format!("/{}/health", self.prefix))
                         .route(web::get().to(rpc::health)),
                 )
+                .service(
+                    web::resource(format!("/{}/ready", self.prefix))
+                        .route(web::get().to(rpc::ready::<G>)),
+                )
+                .service(
+                    web::resource(format!("/{}/status", self.prefix))
+                        .route(web::get().to(rpc::status::<G>)),
+                )
                 .service(
                     web::resource(format!("/{}/protocol_systems", self.prefix))
                         .route(web::post().to(rpc::protocol_systems::<G>)),
@@ -235,12 +367,137 @@ where
         .bind_auto_h2c((self.bind, self.port)) // allow HTTP2 requests over http connections
         .map_err(|err| ExtractionError::ServiceError(err.to_string()))?
         .run();
+        let admin_handle = admin_data
+            .map(|admin_data| {
+                Self::start_admin_server(
+                    admin_prefix,
+                    (self.admin_bind, self.admin_port),
+                    admin_data,
+                )
+            })
+            .transpose()?;
         let handle = server.handle();
         let task = tokio::spawn(async move {
-            server
-                .await
-                .map_err(|err| ExtractionError::Unknown(err.to_string()))
+            let res = server.await;
+            if let Some(admin_handle) = admin_handle {
+                admin_handle.stop(true).await;
+            }
+            res.map_err(|err| ExtractionError::Unknown(err.to_string()))
         });
         Ok((handle, task))
     }
+
+    /// Spawns the admin server. It is kept separate from the public server so it can be bound to
+    /// a private interface, and is stopped together with it.
+    fn start_admin_server(
+        prefix: String,
+        bind: (String, u16),
+        admin_data: web::Data<AdminHandler>,
+    ) -> Result<ServerHandle, ExtractionError> {
+        info!(bind = bind.0.as_str(), port = bind.1, "Starting admin server");
+        let server = HttpServer::new(move || {
+            let extractor = format!("/{}/admin/extractors/{{chain}}/{{name}}", prefix);
+            App::new()
+                .app_data(admin_data.clone())
+                .service(
+                    web::resource(format!("/{}/admin/extractors", prefix))
+                        .route(web::get().to(admin::list_extractors)),
+                )
+                .service(
+                    web::resource(format!("{}/pause", extractor))
+                        .route(web::post().to(admin::pause_extractor)),
+                )
+                .service(
+                    web::resource(format!("{}/resume", extractor))
+                        .route(web::post().to(admin::resume_extractor)),
+                )
+                .service(
+                    web::resource(format!("{}/restart", extractor))
+                        .route(web::post().to(admin::restart_extractor)),
+                )
+                .wrap(RequestTracing::new())
+        })
+        .bind(bind)
+        .map_err(|err| ExtractionError::ServiceError(err.to_string()))?
+        .run();
+        let handle = server.handle();
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!(error = %err, "Admin server failed");
+            }
+        });
+        Ok(handle)
+    }
+}
+
+/// Registers extractors added at runtime with the services and deregisters removed ones.
+struct ExtractorRegistry {
+    ws_data: web::Data<ws::WsData>,
+    pending_deltas: PendingDeltas,
+    /// Hands added extractors to the task filling the pending deltas buffers.
+    added: mpsc::Sender<deltas_buffer::BufferedExtractor>,
+    health: HealthChecker,
+    admin: Option<web::Data<AdminHandler>>,
+}
+
+impl ExtractorRegistry {
+    async fn run(
+        self,
+        mut updates: mpsc::Receiver<ExtractorUpdate>,
+    ) -> Result<(), ExtractionError> {
+        while let Some(update) = updates.recv().await {
+            match update {
+                ExtractorUpdate::Added(handle) => self.add(handle).await?,
+                ExtractorUpdate::Removed(id) => self.remove(&id)?,
+            }
+        }
+        Ok(())
+    }
+
+    async fn add(&self, handle: ExtractorHandle) -> Result<(), ExtractionError> {
+        let id = handle.get_id();
+        info!(extractor = %id, "Registering extractor");
+        // the buffer must exist before the extractor can be queried or subscribed to
+        self.pending_deltas
+            .register(&id.name)
+            .map_err(|err| ExtractionError::ServiceError(err.to_string()))?;
+        self.added
+            .send((id.name.clone(), Arc::new(handle.clone())))
+            .await
+            .map_err(|_| {
+                ExtractionError::ServiceError("Pending deltas task stopped".to_string())
+            })?;
+        self.health
+            .add_extractor(Arc::new(handle.clone()));
+        if let Some(admin) = &self.admin {
+            admin.add_extractor(Arc::new(handle.clone()));
+        }
+        self.ws_data
+            .subscribers
+            .lock()
+            .expect("subscribers lock poisoned")
+            .insert(id, Arc::new(handle));
+        Ok(())
+    }
+
+    fn remove(&self, id: &ExtractorIdentity) -> Result<(), ExtractionError> {
+        info!(extractor = %id, "Deregistering extractor");
+        if self
+            .ws_data
+            .subscribers
+            .lock()
+            .expect("subscribers lock poisoned")
+            .remove(id)
+            .is_none()
+        {
+            warn!(extractor = %id, "Removed extractor was not registered");
+        }
+        self.health.remove_extractor(id);
+        if let Some(admin) = &self.admin {
+            admin.remove_extractor(id);
+        }
+        self.pending_deltas
+            .deregister(&id.name)
+            .map_err(|err| ExtractionError::ServiceError(err.to_string()))
+    }
 }
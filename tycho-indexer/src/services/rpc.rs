@@ -1,10 +1,10 @@
 //! This module contains Tycho RPC implementation
 #![allow(deprecated)]
-use std::{collections::HashSet, sync::Arc};
+use std::{collections::HashSet, str::FromStr, sync::Arc};
 
 use actix_web::{web, HttpResponse, ResponseError};
 use anyhow::Error;
-use chrono::{Duration, Utc};
+use chrono::{Duration, NaiveDateTime, Utc};
 use diesel_async::pooled_connection::deadpool;
 use metrics::counter;
 use reqwest::StatusCode;
@@ -13,8 +13,9 @@ use tracing::{debug, error, info, instrument, trace, warn};
 use tycho_core::{
     dto::{self, PaginationResponse},
     models::{
-        blockchain::BlockAggregatedChanges, protocol::QualityRange, Address, Chain,
-        PaginationParams,
+        blockchain::{Block, BlockAggregatedChanges},
+        protocol::QualityRange,
+        Address, Chain, PaginationParams,
     },
     storage::{BlockIdentifier, BlockOrTimestamp, Gateway, StorageError, Version, VersionKind},
     Bytes,
@@ -23,8 +24,9 @@ use tycho_core::{
 use crate::{
     extractor::reorg_buffer::{BlockNumberOrTimestamp, FinalityStatus},
     services::{
-        cache::RpcCache,
+        cache::{RpcCache, RpcCacheConfig, VersionedRpcCache},
         deltas_buffer::{PendingDeltasBuffer, PendingDeltasError},
+        health::HealthChecker,
     },
 };
 
@@ -76,11 +78,20 @@ pub struct RpcHandler<G> {
     // generics
     pending_deltas: Option<Arc<dyn PendingDeltasBuffer + Send + Sync>>,
     token_cache: RpcCache<dto::TokensRequestBody, dto::TokensRequestResponse>,
-    contract_storage_cache: RpcCache<dto::StateRequestBody, dto::StateRequestResponse>,
+    contract_storage_cache: VersionedRpcCache<dto::StateRequestBody, dto::StateRequestResponse>,
     protocol_state_cache:
-        RpcCache<dto::ProtocolStateRequestBody, dto::ProtocolStateRequestResponse>,
-    component_cache:
-        RpcCache<dto::ProtocolComponentsRequestBody, dto::ProtocolComponentRequestResponse>,
+        VersionedRpcCache<dto::ProtocolStateRequestBody, dto::ProtocolStateRequestResponse>,
+    component_cache: VersionedRpcCache<
+        dto::ProtocolComponentsRequestBody,
+        dto::ProtocolComponentRequestResponse,
+    >,
+}
+
+/// The versions a state request is served at, and the block the response reflects.
+struct ResolvedVersion {
+    db_version: Version,
+    deltas_version: Option<BlockNumberOrTimestamp>,
+    block: dto::ResolvedBlock,
 }
 
 impl<G> RpcHandler<G>
@@ -90,29 +101,38 @@ where
     pub fn new(
         db_gateway: G,
         pending_deltas: Option<Arc<dyn PendingDeltasBuffer + Send + Sync>>,
+        cache_config: RpcCacheConfig,
     ) -> Self {
-        let token_cache = RpcCache::<dto::TokensRequestBody, dto::TokensRequestResponse>::new(
-            "token",
-            50,
-            7 * 60,
-        );
+        let token_cache =
+            RpcCache::<dto::TokensRequestBody, dto::TokensRequestResponse>::from_config(
+                "token",
+                cache_config.tokens,
+            );
 
         let contract_storage_cache =
-            RpcCache::<dto::StateRequestBody, dto::StateRequestResponse>::new(
+            VersionedRpcCache::<dto::StateRequestBody, dto::StateRequestResponse>::new(
                 "contract_storage",
-                50,
-                7 * 60,
+                cache_config.contract_state,
+                cache_config.unfinalized_ttl,
             );
 
-        let protocol_state_cache = RpcCache::<
+        let protocol_state_cache = VersionedRpcCache::<
             dto::ProtocolStateRequestBody,
             dto::ProtocolStateRequestResponse,
-        >::new("protocol_state", 50, 7 * 60);
+        >::new(
+            "protocol_state",
+            cache_config.protocol_state,
+            cache_config.unfinalized_ttl,
+        );
 
-        let component_cache = RpcCache::<
+        let component_cache = VersionedRpcCache::<
             dto::ProtocolComponentsRequestBody,
             dto::ProtocolComponentRequestResponse,
-        >::new("protocol_components", 500, 7 * 60);
+        >::new(
+            "protocol_components",
+            cache_config.protocol_components,
+            cache_config.unfinalized_ttl,
+        );
 
         Self {
             db_gateway,
@@ -130,8 +150,12 @@ where
         request: &dto::StateRequestBody,
     ) -> Result<dto::StateRequestResponse, RpcError> {
         info!(?request, "Getting contract state.");
+        let (version, generation) = self
+            .resolve_cache_version(&request.version, &request.protocol_system, request.chain)
+            .await?;
+        let request = dto::StateRequestBody { version, ..request.clone() };
         self.contract_storage_cache
-            .get(request.clone(), |r| async {
+            .get(request, generation, |r| async {
                 self.get_contract_state_inner(r)
                     .await
                     .map(|res| (res, true))
@@ -145,8 +169,8 @@ where
     ) -> Result<dto::StateRequestResponse, RpcError> {
         let at = BlockOrTimestamp::try_from(&request.version)?;
         let chain = request.chain.into();
-        let (db_version, deltas_version) = self
-            .calculate_versions(&at, &request.protocol_system.clone(), chain)
+        let ResolvedVersion { db_version, deltas_version, block } = self
+            .resolve_version(&at, &request.protocol_system, chain)
             .await?;
 
         let pagination_params: PaginationParams = (&request.pagination).into();
@@ -207,20 +231,23 @@ where
                                                              * addresses are not specified */
         };
 
-        Ok(dto::StateRequestResponse::new(
+        let mut response = dto::StateRequestResponse::new(
             accounts
                 .into_iter()
                 .map(dto::ResponseAccount::from)
                 .collect(),
             PaginationResponse::new(pagination_params.page, pagination_params.page_size, total),
-        ))
+        );
+        response.block = Some(block);
+        Ok(response)
     }
 
-    /// Calculates versions for state retrieval.
+    /// Resolves the versions for state retrieval.
     ///
     /// This method will calculate:
     /// - The finalized version to be retrieved from the database.
     /// - An "ordered" version to be retrieved from the pending deltas buffer.
+    /// - The block the resulting state reflects, together with its finality.
     ///
     /// To calculate the finalized version, it queries the pending deltas buffer for the requested
     /// version's finality. If the version is already finalized, it can be simply passed on to
@@ -228,18 +255,93 @@ where
     /// the db version to the latest available version and will later apply any pending
     /// changes from the buffer on top of the retrieved version. We also return a deltas
     /// version which must be either block number or timestamps based.
-    async fn calculate_versions(
+    ///
+    /// Fails if the block can't be determined, so responses never silently omit it.
+    async fn resolve_version(
         &self,
         request_version: &BlockOrTimestamp,
         protocol_system: &str,
         chain: Chain,
-    ) -> Result<(Version, Option<BlockNumberOrTimestamp>), RpcError> {
-        let ordered_version = match request_version {
+    ) -> Result<ResolvedVersion, RpcError> {
+        let (ordered_version, known_block) = self
+            .lookup_ordered_version(request_version, protocol_system)
+            .await?;
+        let request_version_finality = self.get_version_finality(ordered_version, protocol_system);
+
+        debug!(
+            ?request_version_finality,
+            ?request_version,
+            ?ordered_version,
+            "Version finality calculated!"
+        );
+
+        let (db_version, deltas_version) = match request_version_finality {
+            FinalityStatus::Finalized => {
+                (Version(request_version.clone(), VersionKind::Last), None)
+            }
+            FinalityStatus::Unfinalized => (
+                Version(BlockOrTimestamp::Block(BlockIdentifier::Latest(chain)), VersionKind::Last),
+                Some(ordered_version),
+            ),
+            FinalityStatus::Unseen => {
+                match request_version {
+                    BlockOrTimestamp::Timestamp(_) => {
+                        // If the request is based on a timestamp, return the latest valid version
+                        (
+                            Version(
+                                BlockOrTimestamp::Block(BlockIdentifier::Latest(chain)),
+                                VersionKind::Last,
+                            ),
+                            Some(ordered_version),
+                        )
+                    }
+                    BlockOrTimestamp::Block(_) => {
+                        // If the request is based on a block and it's unseen, return an error
+                        return Err(RpcError::Storage(StorageError::NotFound(
+                            "Version".to_string(),
+                            format!("{:?}", request_version),
+                        )));
+                    }
+                }
+            }
+        };
+
+        let block = match known_block {
+            Some(block) => block,
+            None => {
+                self.find_block(ordered_version, request_version_finality, protocol_system, chain)
+                    .await?
+            }
+        };
+        let block = self.resolved_block(&block, protocol_system);
+        Ok(ResolvedVersion { db_version, deltas_version, block })
+    }
+
+    /// Converts the requested version into a block number or timestamp that can be compared
+    /// against the blocks in the pending deltas buffer.
+    async fn get_ordered_version(
+        &self,
+        request_version: &BlockOrTimestamp,
+        protocol_system: &str,
+    ) -> Result<BlockNumberOrTimestamp, RpcError> {
+        self.lookup_ordered_version(request_version, protocol_system)
+            .await
+            .map(|(ordered_version, _)| ordered_version)
+    }
+
+    /// Like [`Self::get_ordered_version`], but also returns the block if one had to be looked up
+    /// to order the version, i.e. if it was requested by hash or as the latest block.
+    async fn lookup_ordered_version(
+        &self,
+        request_version: &BlockOrTimestamp,
+        protocol_system: &str,
+    ) -> Result<(BlockNumberOrTimestamp, Option<Block>), RpcError> {
+        let (ordered_version, block) = match request_version {
             BlockOrTimestamp::Block(BlockIdentifier::Number((_, no))) => {
-                BlockNumberOrTimestamp::Number(*no as u64)
+                (BlockNumberOrTimestamp::Number(*no as u64), None)
             }
             BlockOrTimestamp::Block(BlockIdentifier::Hash(hash)) => {
-                let block_number = if let Some(block_number) = self
+                let block = if let Some(block) = self
                     .pending_deltas
                     .as_ref()
                     .and_then(|pending| {
@@ -250,15 +352,14 @@ where
                             )
                             .ok()
                     })
-                    .and_then(|block| block.map(|b| b.block.number))
+                    .and_then(|block| block.map(|b| b.block))
                 {
-                    Some(block_number)
+                    Some(block)
                 } else {
                     self.db_gateway
                         .get_block(&BlockIdentifier::Hash(hash.clone()))
                         .await
                         .ok()
-                        .map(|block| block.number)
                 }
                 .ok_or_else(|| {
                     RpcError::Storage(StorageError::NotFound(
@@ -267,71 +368,226 @@ where
                     ))
                 })?;
 
-                BlockNumberOrTimestamp::Number(block_number)
+                (BlockNumberOrTimestamp::Number(block.number), Some(block))
             }
-            BlockOrTimestamp::Timestamp(ts) => BlockNumberOrTimestamp::Timestamp(*ts),
-            BlockOrTimestamp::Block(block_id) => BlockNumberOrTimestamp::Number(
-                self.db_gateway
+            BlockOrTimestamp::Timestamp(ts) => (BlockNumberOrTimestamp::Timestamp(*ts), None),
+            BlockOrTimestamp::Block(block_id) => {
+                let block = self
+                    .db_gateway
                     .get_block(block_id)
-                    .await?
-                    .number,
-            ),
+                    .await?;
+                (BlockNumberOrTimestamp::Number(block.number), Some(block))
+            }
         };
-        let request_version_finality =
-            self.pending_deltas
-                .as_ref()
-                .map_or(FinalityStatus::Finalized, |pending| {
-                    pending
-                        .get_block_finality(ordered_version, protocol_system)
-                        .ok()
-                        .and_then(|finality| finality)
-                        .unwrap_or_else(|| {
-                            warn!(
-                                ?ordered_version,
-                                ?protocol_system,
-                                "No finality found for version."
-                            );
-                            FinalityStatus::Finalized
-                        })
-                });
+        Ok((ordered_version, block))
+    }
 
-        debug!(
-            ?request_version_finality,
-            ?request_version,
-            ?ordered_version,
-            "Version finality calculated!"
-        );
+    /// Returns the finality of a version according to the pending deltas buffer. Versions are
+    /// assumed to be finalized if there is no buffer or the buffer has no information on them.
+    fn get_version_finality(
+        &self,
+        ordered_version: BlockNumberOrTimestamp,
+        protocol_system: &str,
+    ) -> FinalityStatus {
+        self.buffered_finality(ordered_version, protocol_system)
+            .unwrap_or_else(|| {
+                if self.pending_deltas.is_some() {
+                    warn!(?ordered_version, ?protocol_system, "No finality found for version.");
+                }
+                FinalityStatus::Finalized
+            })
+    }
 
-        match request_version_finality {
-            FinalityStatus::Finalized => {
-                Ok((Version(request_version.clone(), VersionKind::Last), None))
-            }
-            FinalityStatus::Unfinalized => Ok((
-                Version(BlockOrTimestamp::Block(BlockIdentifier::Latest(chain)), VersionKind::Last),
-                Some(ordered_version),
-            )),
-            FinalityStatus::Unseen => {
-                match request_version {
-                    BlockOrTimestamp::Timestamp(_) => {
-                        // If the request is based on a timestamp, return the latest valid version
-                        Ok((
-                            Version(
-                                BlockOrTimestamp::Block(BlockIdentifier::Latest(chain)),
-                                VersionKind::Last,
-                            ),
-                            Some(ordered_version),
-                        ))
+    /// Returns the finality of a version according to the pending deltas buffer, or `None` if
+    /// there is no buffer or it has no information on the version.
+    fn buffered_finality(
+        &self,
+        ordered_version: BlockNumberOrTimestamp,
+        protocol_system: &str,
+    ) -> Option<FinalityStatus> {
+        self.pending_deltas
+            .as_ref()?
+            .get_block_finality(ordered_version, protocol_system)
+            .ok()
+            .flatten()
+    }
+
+    /// Resolves the requested version for use in a cache key.
+    ///
+    /// Wherever possible the version is pinned to a concrete block number, so that e.g. requests
+    /// for the latest state share a cache entry until a new block arrives. Finalized timestamps
+    /// are pinned to the last stored block before them.
+    ///
+    /// If the pending deltas buffer has no information on the version, it is compared to the
+    /// latest stored block instead. Versions after it are not seen yet and timestamps after it
+    /// are pinned to it, so they are only cached until the next block.
+    ///
+    /// Also returns the pending deltas generation the response depends on, or `None` if the
+    /// version is finalized and the response may be cached long-term.
+    async fn resolve_cache_version(
+        &self,
+        version: &dto::VersionParam,
+        protocol_system: &str,
+        chain: dto::Chain,
+    ) -> Result<(dto::VersionParam, Option<u64>), RpcError> {
+        let at = BlockOrTimestamp::try_from(version)?;
+        let ordered_version = self
+            .get_ordered_version(&at, protocol_system)
+            .await?;
+        let (finality, head) = match self.buffered_finality(ordered_version, protocol_system) {
+            Some(finality) => (finality, None),
+            None => {
+                let head = self
+                    .db_gateway
+                    .get_block(&BlockIdentifier::Latest(chain.into()))
+                    .await?;
+                let finality = match ordered_version {
+                    BlockNumberOrTimestamp::Number(number) if number <= head.number => {
+                        FinalityStatus::Finalized
                     }
-                    BlockOrTimestamp::Block(_) => {
-                        // If the request is based on a block and it's unseen, return an error
-                        Err(RpcError::Storage(StorageError::NotFound(
-                            "Version".to_string(),
-                            format!("{:?}", request_version),
-                        )))
+                    BlockNumberOrTimestamp::Timestamp(ts) if ts < head.ts => {
+                        FinalityStatus::Finalized
                     }
-                }
+                    _ => FinalityStatus::Unseen,
+                };
+                (finality, Some(head))
+            }
+        };
+
+        let block_number = match ordered_version {
+            BlockNumberOrTimestamp::Number(number) => Some(number),
+            BlockNumberOrTimestamp::Timestamp(ts) if finality == FinalityStatus::Finalized => self
+                .db_gateway
+                .get_block_at_timestamp(&chain.into(), &ts)
+                .await
+                .ok()
+                .map(|block| block.number),
+            BlockNumberOrTimestamp::Timestamp(_) => match head {
+                Some(head) => Some(head.number),
+                None => self
+                    .pending_deltas
+                    .as_ref()
+                    .and_then(|pending| {
+                        pending
+                            .get_block_at(ordered_version, protocol_system)
+                            .ok()
+                    })
+                    .flatten()
+                    .map(|block| block.number),
+            },
+        };
+        let resolved = match block_number {
+            Some(number) => dto::VersionParam::new(
+                None,
+                Some(dto::BlockParam {
+                    hash: None,
+                    chain: Some(chain),
+                    number: Some(number as i64),
+                }),
+            ),
+            None => version.clone(),
+        };
+        let generation = match finality {
+            FinalityStatus::Finalized => None,
+            _ => Some(
+                self.pending_deltas
+                    .as_ref()
+                    .map_or(0, |pending| pending.generation()),
+            ),
+        };
+
+        trace!(?version, ?resolved, ?finality, ?generation, "Resolved cache version");
+        Ok((resolved, generation))
+    }
+
+    /// Attaches the finality of a block according to the pending deltas buffer.
+    fn resolved_block(&self, block: &Block, protocol_system: &str) -> dto::ResolvedBlock {
+        let finality = match self
+            .get_version_finality(BlockNumberOrTimestamp::Number(block.number), protocol_system)
+        {
+            FinalityStatus::Finalized => dto::FinalityStatus::Finalized,
+            _ => dto::FinalityStatus::Unfinalized,
+        };
+        dto::ResolvedBlock::new(block, finality)
+    }
+
+    /// Returns the most recent block of a protocol system. This is the block protocol component
+    /// responses reflect, as they include components from the pending deltas buffer.
+    async fn get_latest_block(
+        &self,
+        protocol_system: &str,
+        chain: Chain,
+    ) -> Result<dto::ResolvedBlock, RpcError> {
+        let buffered = self
+            .pending_deltas
+            .as_ref()
+            .and_then(|pending| {
+                pending
+                    .get_block_at(
+                        BlockNumberOrTimestamp::Timestamp(NaiveDateTime::MAX),
+                        protocol_system,
+                    )
+                    .ok()
+            })
+            .flatten();
+        let block = match buffered {
+            Some(block) => block,
+            None => {
+                self.db_gateway
+                    .get_block(&BlockIdentifier::Latest(chain))
+                    .await?
             }
+        };
+        Ok(self.resolved_block(&block, protocol_system))
+    }
+
+    /// Finds the block that state at `ordered_version` reflects.
+    async fn find_block(
+        &self,
+        ordered_version: BlockNumberOrTimestamp,
+        finality: FinalityStatus,
+        protocol_system: &str,
+        chain: Chain,
+    ) -> Result<Block, RpcError> {
+        // Prefer the buffer, finalized blocks at its start might not have been written to the db
+        // yet.
+        let buffered = self
+            .pending_deltas
+            .as_ref()
+            .and_then(|pending| match ordered_version {
+                BlockNumberOrTimestamp::Number(number) => pending
+                    .search_block(
+                        &|b: &BlockAggregatedChanges| b.block.number == number,
+                        protocol_system,
+                    )
+                    .ok()
+                    .flatten()
+                    .map(|b| b.block),
+                BlockNumberOrTimestamp::Timestamp(_) => match finality {
+                    FinalityStatus::Finalized => None,
+                    _ => pending
+                        .get_block_at(ordered_version, protocol_system)
+                        .ok()
+                        .flatten(),
+                },
+            });
+        if let Some(block) = buffered {
+            return Ok(block);
         }
+
+        let block = match ordered_version {
+            BlockNumberOrTimestamp::Number(number) => {
+                self.db_gateway
+                    .get_block(&BlockIdentifier::Number((chain, number as i64)))
+                    .await?
+            }
+            BlockNumberOrTimestamp::Timestamp(ts) => {
+                self.db_gateway
+                    .get_block_at_timestamp(&chain, &ts)
+                    .await?
+            }
+        };
+        Ok(block)
     }
 
     #[instrument(skip(self, request))]
@@ -340,8 +596,12 @@ where
         request: &dto::ProtocolStateRequestBody,
     ) -> Result<dto::ProtocolStateRequestResponse, RpcError> {
         debug!(?request, "Getting protocol state.");
+        let (version, generation) = self
+            .resolve_cache_version(&request.version, &request.protocol_system, request.chain)
+            .await?;
+        let request = dto::ProtocolStateRequestBody { version, ..request.clone() };
         self.protocol_state_cache
-            .get(request.clone(), |r| async {
+            .get(request, generation, |r| async {
                 self.get_protocol_state_inner(r)
                     .await
                     .map(|res| (res, true))
@@ -355,8 +615,8 @@ where
     ) -> Result<dto::ProtocolStateRequestResponse, RpcError> {
         let at = BlockOrTimestamp::try_from(&request.version)?;
         let chain = request.chain.into();
-        let (db_version, deltas_version) = self
-            .calculate_versions(&at, &request.protocol_system.clone(), chain)
+        let ResolvedVersion { db_version, deltas_version, block } = self
+            .resolve_version(&at, &request.protocol_system, chain)
             .await?;
 
         let pagination_params: PaginationParams = (&request.pagination).into();
@@ -447,13 +707,15 @@ where
 
         trace!(db_state = ?states, "Updated states with buffer.");
 
-        Ok(dto::ProtocolStateRequestResponse::new(
+        let mut response = dto::ProtocolStateRequestResponse::new(
             states
                 .into_iter()
                 .map(dto::ResponseProtocolState::from)
                 .collect(),
             PaginationResponse::new(pagination_params.page, pagination_params.page_size, total),
-        ))
+        );
+        response.block = Some(block);
+        Ok(response)
     }
 
     #[instrument(skip(self, request))]
@@ -569,14 +831,51 @@ where
         }
     }
 
+    #[instrument(skip(self, params))]
+    async fn get_token_analysis(
+        &self,
+        token: &str,
+        params: &dto::TokenAnalysisParams,
+    ) -> Result<dto::TokenAnalysisResponse, RpcError> {
+        let address = Address::from_str(token)
+            .map_err(|err| RpcError::Parse(format!("Invalid token address {token}: {err}")))?;
+        let limit = params.limit.unwrap_or(20);
+        debug!(?address, limit, "Getting token analyses.");
+
+        match self
+            .db_gateway
+            .get_token_analyses(&params.chain.into(), &address, limit)
+            .await
+        {
+            Ok(analyses) => Ok(dto::TokenAnalysisResponse::new(
+                params.chain,
+                address,
+                analyses
+                    .into_iter()
+                    .map(dto::ResponseTokenAnalysis::from)
+                    .collect(),
+            )),
+            Err(err) => {
+                error!(error = %err, "Error while getting token analyses.");
+                Err(err.into())
+            }
+        }
+    }
+
     #[instrument(skip(self, request))]
     async fn get_protocol_components(
         &self,
         request: &dto::ProtocolComponentsRequestBody,
     ) -> Result<dto::ProtocolComponentRequestResponse, RpcError> {
         info!(?request, "Getting protocol components.");
+        // Responses include the components of the pending deltas, so they are only valid as long
+        // as the pending deltas don't change.
+        let generation = self
+            .pending_deltas
+            .as_ref()
+            .map(|pending| pending.generation());
         self.component_cache
-            .get(request.clone(), |r| async {
+            .get(request.clone(), generation, |r| async {
                 self.get_protocol_components_inner(r)
                     .await
                     .map(|res| {
@@ -604,6 +903,9 @@ where
     ) -> Result<dto::ProtocolComponentRequestResponse, RpcError> {
         let system = request.protocol_system.clone();
         let pagination_params: PaginationParams = (&request.pagination).into();
+        let block = self
+            .get_latest_block(&system, request.chain.into())
+            .await?;
 
         let ids_strs: Option<Vec<&str>> = request
             .component_ids
@@ -645,14 +947,16 @@ where
                     })
                     .collect();
 
-                return Ok(dto::ProtocolComponentRequestResponse::new(
+                let mut response = dto::ProtocolComponentRequestResponse::new(
                     response_components,
                     PaginationResponse::new(
                         pagination_params.page,
                         pagination_params.page_size,
                         total,
                     ),
-                ));
+                );
+                response.block = Some(block);
+                return Ok(response);
             }
         }
 
@@ -703,14 +1007,16 @@ where
                         pc
                     })
                     .collect::<Vec<dto::ProtocolComponent>>();
-                Ok(dto::ProtocolComponentRequestResponse::new(
+                let mut response = dto::ProtocolComponentRequestResponse::new(
                     response_components,
                     PaginationResponse::new(
                         pagination_params.page,
                         pagination_params.page_size,
                         total,
                     ),
-                ))
+                );
+                response.block = Some(block);
+                Ok(response)
             }
             Err(err) => {
                 error!(error = %err, "Error while getting protocol components.");
@@ -818,6 +1124,55 @@ pub async fn tokens<G: Gateway>(
     }
 }
 
+/// Retrieve token analysis history
+///
+/// This endpoint retrieves the most recent analysis runs of a token, newest first. Each entry
+/// records when and at which block the token was analyzed, the outcome and the reason a token
+/// was found bad.
+#[utoipa::path(
+    get,
+    path = "/v1/tokens/{address}/analysis",
+    responses(
+        (status = 200, description = "OK", body = TokenAnalysisResponse),
+    ),
+    params(
+        ("address" = String, Path, description = "The token address"),
+        TokenAnalysisParams,
+    ),
+)]
+pub async fn token_analysis<G: Gateway>(
+    address: web::Path<String>,
+    params: web::Query<dto::TokenAnalysisParams>,
+    handler: web::Data<RpcHandler<G>>,
+) -> HttpResponse {
+    counter!("rpc_requests", "endpoint" => "token_analysis").increment(1);
+
+    if params
+        .limit
+        .is_some_and(|limit| !(1..=100).contains(&limit))
+    {
+        counter!("rpc_requests_failed", "endpoint" => "token_analysis", "status" => "400")
+            .increment(1);
+        return HttpResponse::BadRequest().body("Limit must be between 1 and 100.");
+    }
+
+    let response = handler
+        .into_inner()
+        .get_token_analysis(&address, &params)
+        .await;
+
+    match response {
+        Ok(analysis) => HttpResponse::Ok().json(analysis),
+        Err(err) => {
+            error!(error = %err, ?address, ?params, "Error while getting token analyses.");
+            let status = err.status_code().as_u16().to_string();
+            counter!("rpc_requests_failed", "endpoint" => "token_analysis", "status" => status)
+                .increment(1);
+            HttpResponse::from_error(err)
+        }
+    }
+}
+
 /// Retrieve protocol components
 ///
 /// This endpoint retrieves components within a specific execution environment, filtered by various
@@ -967,6 +1322,56 @@ pub async fn health() -> HttpResponse {
     HttpResponse::Ok().json(dto::Health::Ready)
 }
 
+/// Readiness check endpoint
+///
+/// This endpoint is used to check the readiness of the service. It responds with 503 unless the
+/// database is reachable, the pending deltas task is running and all extractors are in sync.
+#[utoipa::path(
+    get,
+    path = "/v1/ready",
+    responses(
+        (status = 200, description = "OK", body=Health),
+        (status = 503, description = "Starting or not ready", body=Health),
+    ),
+)]
+pub async fn ready<G: Gateway>(
+    handler: web::Data<RpcHandler<G>>,
+    checker: web::Data<HealthChecker>,
+) -> HttpResponse {
+    counter!("rpc_requests", "endpoint" => "ready").increment(1);
+    let health = checker
+        .status(&handler.db_gateway)
+        .await
+        .health;
+    match health {
+        dto::Health::Ready => HttpResponse::Ok().json(health),
+        _ => HttpResponse::ServiceUnavailable().json(health),
+    }
+}
+
+/// Service status endpoint
+///
+/// This endpoint returns the readiness of the service together with the status of the database,
+/// the pending deltas task and each extractor.
+#[utoipa::path(
+    get,
+    path = "/v1/status",
+    responses(
+        (status = 200, description = "OK", body=ServiceStatus),
+    ),
+)]
+pub async fn status<G: Gateway>(
+    handler: web::Data<RpcHandler<G>>,
+    checker: web::Data<HealthChecker>,
+) -> HttpResponse {
+    counter!("rpc_requests", "endpoint" => "status").increment(1);
+    HttpResponse::Ok().json(
+        checker
+            .status(&handler.db_gateway)
+            .await,
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use std::{collections::HashMap, str::FromStr};
@@ -978,14 +1383,14 @@ mod tests {
         models::{
             contract::Account,
             protocol::{ProtocolComponent, ProtocolComponentState},
-            token::CurrencyToken,
+            token::{CurrencyToken, TokenAnalysis, TokenAnalysisOutcome},
             ChangeType,
         },
         storage::WithTotal,
     };
 
     use super::*;
-    use crate::testing::{evm_contract_slots, MockGateway};
+    use crate::testing::{block, evm_contract_slots, MockGateway};
 
     const WETH: &str = "C02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2";
     const USDC: &str = "A0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
@@ -1028,6 +1433,14 @@ mod tests {
                 f: &dyn Fn(&BlockAggregatedChanges) -> bool,
                 protocol_system: &'a str,
             ) -> Result<Option<BlockAggregatedChanges>,PendingDeltasError>;
+
+            fn get_block_at<'a>(
+                &self,
+                version: BlockNumberOrTimestamp,
+                protocol_system: &'a str,
+            ) -> Result<Option<Block>, PendingDeltasError>;
+
+            fn generation(&self) -> u64;
         }
     }
 
@@ -1190,9 +1603,13 @@ mod tests {
             });
         mock_buffer
             .expect_get_block_finality()
-            .return_once(|_, _| Ok(Some(FinalityStatus::Unfinalized)));
+            .returning(|_, _| Ok(Some(FinalityStatus::Unfinalized)));
+        mock_buffer
+            .expect_get_block_at()
+            .return_once(|_, _| Ok(Some(block(2))));
 
-        let req_handler = RpcHandler::new(gw, Some(Arc::new(mock_buffer)));
+        let req_handler =
+            RpcHandler::new(gw, Some(Arc::new(mock_buffer)), RpcCacheConfig::default());
 
         let request = dto::StateRequestBody {
             contract_ids: Some(vec![
@@ -1213,6 +1630,118 @@ mod tests {
         assert_eq!(state.accounts[0], expected.into());
         assert_eq!(state.accounts[1], buf_expected.into());
         assert_eq!(state.pagination.total, 2);
+        assert_eq!(
+            state.block,
+            Some(dto::ResolvedBlock::new(&block(2), dto::FinalityStatus::Unfinalized))
+        );
+    }
+
+    #[test]
+    async fn test_resolve_cache_version() {
+        let mut mock_buffer = MockPendingDeltas::new();
+        mock_buffer
+            .expect_get_block_finality()
+            .returning(|version, _| {
+                Ok(Some(match version {
+                    BlockNumberOrTimestamp::Number(n) if n <= 1 => FinalityStatus::Finalized,
+                    BlockNumberOrTimestamp::Number(_) => FinalityStatus::Unfinalized,
+                    BlockNumberOrTimestamp::Timestamp(_) => FinalityStatus::Unseen,
+                }))
+            });
+        mock_buffer
+            .expect_get_block_at()
+            .returning(|_, _| Ok(Some(block(3))));
+        mock_buffer
+            .expect_generation()
+            .return_const(7u64);
+        let req_handler = RpcHandler::new(
+            MockGateway::new(),
+            Some(Arc::new(mock_buffer)),
+            RpcCacheConfig::default(),
+        );
+        let at_block = |number| {
+            dto::VersionParam::new(
+                None,
+                Some(dto::BlockParam {
+                    hash: None,
+                    chain: Some(dto::Chain::Ethereum),
+                    number: Some(number),
+                }),
+            )
+        };
+
+        // latest timestamp is pinned to the most recent buffered block
+        let latest = dto::VersionParam::new(Some(Utc::now().naive_utc()), None);
+        let res = req_handler
+            .resolve_cache_version(&latest, "uniswap_v2", dto::Chain::Ethereum)
+            .await
+            .unwrap();
+        assert_eq!(res, (at_block(3), Some(7)));
+
+        let res = req_handler
+            .resolve_cache_version(&at_block(2), "uniswap_v2", dto::Chain::Ethereum)
+            .await
+            .unwrap();
+        assert_eq!(res, (at_block(2), Some(7)));
+
+        // finalized versions don't depend on the buffer
+        let res = req_handler
+            .resolve_cache_version(&at_block(1), "uniswap_v2", dto::Chain::Ethereum)
+            .await
+            .unwrap();
+        assert_eq!(res, (at_block(1), None));
+    }
+
+    #[test]
+    async fn test_resolve_cache_version_without_buffer() {
+        let mut gw = MockGateway::new();
+        gw.expect_get_block()
+            .returning(|_| Ok(block(5)));
+        gw.expect_get_block_at_timestamp()
+            .returning(|_, _| Ok(block(2)));
+        let req_handler = RpcHandler::new(gw, None, RpcCacheConfig::default());
+        let at_block = |number| {
+            dto::VersionParam::new(
+                None,
+                Some(dto::BlockParam {
+                    hash: None,
+                    chain: Some(dto::Chain::Ethereum),
+                    number: Some(number),
+                }),
+            )
+        };
+
+        // the current time is pinned to the latest stored block and not cached long-term
+        let res = req_handler
+            .resolve_cache_version(
+                &dto::VersionParam::default(),
+                "uniswap_v2",
+                dto::Chain::Ethereum,
+            )
+            .await
+            .unwrap();
+        assert_eq!(res, (at_block(5), Some(0)));
+
+        // past timestamps are pinned to the block they refer to
+        let past = dto::VersionParam::new(Some(block(2).ts + chrono::Duration::seconds(1)), None);
+        let res = req_handler
+            .resolve_cache_version(&past, "uniswap_v2", dto::Chain::Ethereum)
+            .await
+            .unwrap();
+        assert_eq!(res, (at_block(2), None));
+
+        let res = req_handler
+            .resolve_cache_version(&at_block(4), "uniswap_v2", dto::Chain::Ethereum)
+            .await
+            .unwrap();
+        assert_eq!(res, (at_block(4), None));
+
+        // blocks after the latest stored block are not seen yet
+        let res = req_handler
+            .resolve_cache_version(&at_block(6), "uniswap_v2", dto::Chain::Ethereum)
+            .await
+            .unwrap();
+        assert_eq!(res, (at_block(6), Some(0)));
     }
 
     #[test]
@@ -1252,7 +1781,7 @@ mod tests {
         // ensure the gateway is only accessed once - the second request should hit cache
         gw.expect_get_tokens()
             .return_once(|_, _, _, _, _| Box::pin(async move { mock_response }));
-        let req_handler = RpcHandler::new(gw, None);
+        let req_handler = RpcHandler::new(gw, None, RpcCacheConfig::default());
 
         // request for 2 tokens that are in the DB (WETH and USDC)
         let request = dto::TokensRequestBody {
@@ -1291,6 +1820,41 @@ mod tests {
         assert_eq!(tokens.tokens[1].symbol, "WETH");
     }
 
+    #[tokio::test]
+    async fn test_get_token_analysis() {
+        let token: Bytes = USDC.parse().unwrap();
+        let analysis = TokenAnalysis {
+            token: token.clone(),
+            chain: Chain::Ethereum,
+            analyzed_at: NaiveDateTime::default(),
+            block_number: Some(1),
+            outcome: TokenAnalysisOutcome::Bad,
+            reason: Some("Detection failed".to_string()),
+            quality: 10,
+            gas: None,
+            tax: None,
+        };
+        let mut gw = MockGateway::new();
+        gw.expect_get_token_analyses()
+            .withf(|_, _, limit| *limit == 5)
+            .return_once(move |_, _, _| Box::pin(async move { Ok(vec![analysis]) }));
+        let req_handler = RpcHandler::new(gw, None, RpcCacheConfig::default());
+
+        let res = req_handler
+            .get_token_analysis(
+                USDC,
+                &dto::TokenAnalysisParams { chain: dto::Chain::Ethereum, limit: Some(5) },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(res.token, token);
+        assert_eq!(res.analyses.len(), 1);
+        assert_eq!(res.analyses[0].outcome, "bad");
+        assert_eq!(res.analyses[0].reason, Some("Detection failed".to_string()));
+        assert_eq!(res.analyses[0].quality, 10);
+    }
+
     #[tokio::test]
     async fn test_get_protocol_state() {
         let mut gw = MockGateway::new();
@@ -1320,9 +1884,13 @@ mod tests {
             });
         mock_buffer
             .expect_get_block_finality()
-            .return_once(|_, _| Ok(Some(FinalityStatus::Unfinalized)));
+            .returning(|_, _| Ok(Some(FinalityStatus::Unfinalized)));
+        mock_buffer
+            .expect_get_block_at()
+            .return_once(|_, _| Ok(Some(block(2))));
 
-        let req_handler = RpcHandler::new(gw, Some(Arc::new(mock_buffer)));
+        let req_handler =
+            RpcHandler::new(gw, Some(Arc::new(mock_buffer)), RpcCacheConfig::default());
 
         let request = dto::ProtocolStateRequestBody {
             protocol_ids: Some(vec!["state1".to_owned(), "state_buff".to_owned()]),
@@ -1341,6 +1909,10 @@ mod tests {
         assert_eq!(res.states[0], expected.into());
         assert_eq!(res.states[1], buf_expected.into());
         assert_eq!(res.pagination.total, 2);
+        assert_eq!(
+            res.block,
+            Some(dto::ResolvedBlock::new(&block(2), dto::FinalityStatus::Unfinalized))
+        );
     }
 
     fn protocol_attributes<'a>(
@@ -1402,8 +1974,10 @@ mod tests {
         mock_buffer
             .expect_get_new_components()
             .return_once(move |_, _, _| Ok(vec![mock_res]));
+        expect_buffered_head(&mut mock_buffer, block(3));
 
-        let req_handler = RpcHandler::new(gw, Some(Arc::new(mock_buffer)));
+        let req_handler =
+            RpcHandler::new(gw, Some(Arc::new(mock_buffer)), RpcCacheConfig::default());
 
         let request = dto::ProtocolComponentsRequestBody {
             protocol_system: "ambient".to_string(),
@@ -1424,6 +1998,144 @@ mod tests {
         assert_eq!(components.pagination.total, 2);
         assert_eq!(components.pagination.page, 0);
         assert_eq!(components.pagination.page_size, 2);
+        assert_eq!(
+            components.block,
+            Some(dto::ResolvedBlock::new(&block(3), dto::FinalityStatus::Unfinalized))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_protocol_components_cache_invalidated_by_pending_deltas() {
+        let component = ProtocolComponent::new(
+            "comp_buff",
+            "ambient",
+            "pool",
+            Chain::Ethereum,
+            vec![Bytes::from_str("0x00").unwrap(), Bytes::from_str("0x01").unwrap()],
+            vec![],
+            HashMap::new(),
+            ChangeType::Creation,
+            "0x50449de1973d86f21bfafa7c72011854a7e33a226709dc3e2e4edcca34"
+                .parse()
+                .unwrap(),
+            NaiveDateTime::default(),
+        );
+        let mut mock_buffer = MockPendingDeltas::new();
+        // The components are only read again once the pending deltas changed.
+        mock_buffer
+            .expect_get_new_components()
+            .times(2)
+            .returning(move |_, _, _| Ok(vec![component.clone()]));
+        let calls = std::sync::atomic::AtomicU64::new(0);
+        mock_buffer
+            .expect_generation()
+            .returning(move || {
+                if calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) < 2 {
+                    1
+                } else {
+                    2
+                }
+            });
+        expect_buffered_head(&mut mock_buffer, block(3));
+        let req_handler = RpcHandler::new(
+            MockGateway::new(),
+            Some(Arc::new(mock_buffer)),
+            RpcCacheConfig::default(),
+        );
+        let request = dto::ProtocolComponentsRequestBody {
+            protocol_system: "ambient".to_string(),
+            component_ids: Some(vec!["comp_buff".to_string()]),
+            tvl_gt: None,
+            chain: dto::Chain::Ethereum,
+            pagination: dto::PaginationParams::new(0, 2),
+        };
+
+        for _ in 0..3 {
+            let components = req_handler
+                .get_protocol_components(&request)
+                .await
+                .unwrap();
+            assert_eq!(components.protocol_components.len(), 1);
+        }
+    }
+
+    fn expect_buffered_head(mock_buffer: &mut MockPendingDeltas, head: Block) {
+        let head_clone = head.clone();
+        mock_buffer
+            .expect_get_block_at()
+            .returning(move |_, _| Ok(Some(head_clone.clone())));
+        mock_buffer
+            .expect_search_block()
+            .returning(move |_, _| {
+                Ok(Some(BlockAggregatedChanges { block: head.clone(), ..Default::default() }))
+            });
+        mock_buffer
+            .expect_get_block_finality()
+            .returning(|_, _| Ok(Some(FinalityStatus::Unfinalized)));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_version_from_db() {
+        let mut gw = MockGateway::new();
+        gw.expect_get_block_at_timestamp()
+            .return_once(|_, _| Ok(block(1)));
+        let req_handler = RpcHandler::new(gw, None, RpcCacheConfig::default());
+
+        let res = req_handler
+            .resolve_version(
+                &BlockOrTimestamp::Timestamp(block(1).ts),
+                "uniswap_v2",
+                Chain::Ethereum,
+            )
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            res.db_version,
+            Version(BlockOrTimestamp::Timestamp(ts), VersionKind::Last) if ts == block(1).ts
+        ));
+        assert!(res.deltas_version.is_none());
+        assert_eq!(res.block, dto::ResolvedBlock::new(&block(1), dto::FinalityStatus::Finalized));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_version_reuses_looked_up_block() {
+        let mut gw = MockGateway::new();
+        gw.expect_get_block()
+            .times(1)
+            .returning(|_| Ok(block(5)));
+        let req_handler = RpcHandler::new(gw, None, RpcCacheConfig::default());
+
+        let res = req_handler
+            .resolve_version(
+                &BlockOrTimestamp::Block(BlockIdentifier::Latest(Chain::Ethereum)),
+                "uniswap_v2",
+                Chain::Ethereum,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(res.block, dto::ResolvedBlock::new(&block(5), dto::FinalityStatus::Finalized));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_version_fails_without_block() {
+        let mut gw = MockGateway::new();
+        gw.expect_get_block_at_timestamp()
+            .return_once(|_, _| {
+                Err(StorageError::NotFound("Block".to_string(), "timestamp".to_string()))
+            });
+        let req_handler = RpcHandler::new(gw, None, RpcCacheConfig::default());
+
+        let res = req_handler
+            .resolve_version(
+                &BlockOrTimestamp::Timestamp(block(1).ts),
+                "uniswap_v2",
+                Chain::Ethereum,
+            )
+            .await;
+
+        assert!(res.is_err());
     }
 
     #[tokio::test]
@@ -1495,8 +2207,10 @@ mod tests {
                 let buf_expected2_clone = buf_expected2.clone();
                 move |_, _, _| Ok(vec![buf_expected1_clone.clone(), buf_expected2_clone.clone()])
             });
+        expect_buffered_head(&mut mock_buffer, block(3));
 
-        let req_handler = RpcHandler::new(gw, Some(Arc::new(mock_buffer)));
+        let req_handler =
+            RpcHandler::new(gw, Some(Arc::new(mock_buffer)), RpcCacheConfig::default());
 
         let request = dto::ProtocolComponentsRequestBody {
             protocol_system: "ambient".to_string(),
@@ -14,10 +14,13 @@ use metrics::{counter, gauge};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tracing::{debug, error, info, instrument, trace, warn};
-use tycho_core::models::ExtractorIdentity;
+use tycho_core::{
+    models::{blockchain::BlockAggregatedChanges, ExtractorIdentity},
+    storage::StorageError,
+};
 use uuid::Uuid;
 
-use crate::extractor::{runner::MessageSender, ExtractorMsg};
+use crate::extractor::{reorg_buffer::ReorgBuffer, runner::MessageSender, ExtractorMsg};
 
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -37,6 +40,9 @@ pub enum WebsocketError {
 
     #[error("Failed to subscribe to extractor: {0}")]
     SubscribeError(ExtractorIdentity),
+
+    #[error("Subscription {0} failed: {1}")]
+    SubscriptionFailed(Uuid, String),
 }
 
 impl Serialize for WebsocketError {
@@ -56,7 +62,57 @@ impl Serialize for WebsocketError {
             }
             WebsocketError::SubscribeError(extractor_id) => serializer
                 .serialize_str(&format!("Failed to subscribe to extractor: {:?}", extractor_id)),
+            WebsocketError::SubscriptionFailed(subscription_id, reason) => serializer
+                .serialize_str(&format!("Subscription {:?} failed: {}", subscription_id, reason)),
+        }
+    }
+}
+
+/// Holds back the messages of a finalized-only subscription until they are finalized.
+///
+/// Messages are kept in a `ReorgBuffer` of their own and released, one per block, once
+/// `drain_new_finalized_blocks` drains them. Reverts only purge the buffer. Subscriptions without
+/// state drop it before buffering, so only the state that is sent is held back.
+struct FinalizedBlocks {
+    buffer: ReorgBuffer<BlockAggregatedChanges>,
+}
+
+impl FinalizedBlocks {
+    fn new() -> Self {
+        Self { buffer: ReorgBuffer::new() }
+    }
+
+    /// Buffers a message and returns the blocks it finalized, ordered by ascending number.
+    fn push(&mut self, msg: &ExtractorMsg) -> Result<Vec<ExtractorMsg>, StorageError> {
+        let msg = msg
+            .as_any()
+            .downcast_ref::<BlockAggregatedChanges>()
+            .ok_or_else(|| StorageError::Unexpected("Unknown message type".to_string()))?;
+        if msg.revert {
+            match self
+                .buffer
+                .purge(msg.block.hash.clone())
+            {
+                // The revert reaches past the first block this subscription received.
+                Err(StorageError::NotFound(..)) => self.buffer = ReorgBuffer::new(),
+                Err(err) => return Err(err),
+                Ok(_) => {}
+            }
+            return Ok(Vec::new());
         }
+        self.buffer.insert_block(msg.clone())?;
+        let finalized = match self
+            .buffer
+            .drain_new_finalized_blocks(msg.finalized_block_height)
+        {
+            // The finalized block precedes the first block this subscription received.
+            Err(StorageError::NotFound(..)) => Vec::new(),
+            res => res?,
+        };
+        Ok(finalized
+            .into_iter()
+            .map(|block| Arc::new(block) as ExtractorMsg)
+            .collect())
     }
 }
 
@@ -165,6 +221,7 @@ impl WsActor {
         ctx: &mut ws::WebsocketContext<Self>,
         extractor_id: &ExtractorIdentity,
         include_state: bool,
+        finalized_only: bool,
     ) {
         {
             debug!(extractor=?extractor_id, "Acquire lock for subscribing..");
@@ -181,21 +238,36 @@ impl WsActor {
                 // Add the subscription_id to the current tracing span recorded fields
                 tracing::Span::current().record("subscription_id", subscription_id.to_string());
 
-                info!(extractor_id = %extractor_id, "Subscribing to extractor");
+                info!(extractor_id = %extractor_id, finalized_only, "Subscribing to extractor");
 
                 match block_on(message_sender.subscribe()) {
                     Ok(mut rx) => {
                         // The `rx` variable is a `Receiver` of `Result<String, String>`.
                         // The `rx` variable is a `Result<String, String>`.
                         let stream = async_stream::stream! {
+                            let mut finalized = finalized_only.then(FinalizedBlocks::new);
                             while let Some(item) = rx.recv().await {
-                                if !include_state {
-                                    let light = item.drop_state();
-                                    yield Ok((subscription_id, light));
-                                } else {
-                                    yield Ok((subscription_id, item));
+                                let item = if include_state { item } else { item.drop_state() };
+                                let items = match finalized.as_mut() {
+                                    None => vec![item],
+                                    Some(finalized) => match finalized.push(&item) {
+                                        Ok(items) => items,
+                                        Err(err) => {
+                                            error!(error = %err, "Failed to buffer message");
+                                            yield Err(WebsocketError::SubscriptionFailed(
+                                                subscription_id,
+                                                err.to_string(),
+                                            ));
+                                            break;
+                                        }
+                                    },
+                                };
+                                for item in items {
+                                    yield Ok((subscription_id, Some(item)));
                                 }
                             }
+                            // The extractor was stopped or restarted
+                            yield Ok((subscription_id, None));
                         };
 
                         let handle = ctx.add_stream(stream);
@@ -295,8 +367,15 @@ impl Actor for WsActor {
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
 #[serde(tag = "method", rename_all = "lowercase")]
 pub enum Command {
-    Subscribe { extractor_id: ExtractorIdentity, include_state: bool },
-    Unsubscribe { subscription_id: Uuid },
+    Subscribe {
+        extractor_id: ExtractorIdentity,
+        include_state: bool,
+        #[serde(default)]
+        finalized_only: bool,
+    },
+    Unsubscribe {
+        subscription_id: Uuid,
+    },
 }
 
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
@@ -315,25 +394,46 @@ struct DeltasMessage {
 }
 
 /// Handle incoming messages from the extractor and forward them to the WS connection
-impl StreamHandler<Result<(Uuid, ExtractorMsg), ws::ProtocolError>> for WsActor {
+///
+/// A `None` message marks the end of a subscription, e.g. because the extractor was removed. Only
+/// that subscription ends, the connection stays open. Errors are forwarded to the client before
+/// the subscription ends.
+impl StreamHandler<Result<(Uuid, Option<ExtractorMsg>), WebsocketError>> for WsActor {
     #[instrument(skip_all, fields(WsActor.id = %self.id))]
     fn handle(
         &mut self,
-        msg: Result<(Uuid, ExtractorMsg), ws::ProtocolError>,
+        msg: Result<(Uuid, Option<ExtractorMsg>), WebsocketError>,
         ctx: &mut Self::Context,
     ) {
         trace!("Message received from extractor");
         match msg {
-            Ok((subscription_id, deltas)) => {
+            Ok((subscription_id, Some(deltas))) => {
                 trace!("Forwarding message to client");
                 let msg = DeltasMessage { subscription_id, deltas };
                 ctx.text(serde_json::to_string(&msg).unwrap());
             }
+            Ok((subscription_id, None)) => {
+                if self
+                    .subscriptions
+                    .remove(&subscription_id)
+                    .is_some()
+                {
+                    info!(%subscription_id, "Extractor subscription ended");
+                    gauge!("websocket_extractor_subscriptions_active", "subscription_id" => subscription_id.to_string()).decrement(1);
+                    let message = Response::SubscriptionEnded { subscription_id };
+                    ctx.text(serde_json::to_string(&message).unwrap());
+                }
+            }
             Err(e) => {
                 error!(error = %e, "Failed to receive message from extractor");
+                ctx.text(serde_json::to_string(&e).unwrap());
             }
         }
     }
+
+    fn finished(&mut self, _ctx: &mut Self::Context) {
+        // Ended subscriptions are handled on their last message, keep the connection open.
+    }
 }
 
 /// Handle incoming messages from the WS connection
@@ -358,9 +458,9 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsActor {
                     Ok(message) => {
                         // Handle the message based on its variant
                         match message {
-                            Command::Subscribe { extractor_id, include_state } => {
+                            Command::Subscribe { extractor_id, include_state, finalized_only } => {
                                 debug!(%extractor_id, "Subscribing to extractor");
-                                self.subscribe(ctx, &extractor_id, include_state);
+                                self.subscribe(ctx, &extractor_id, include_state, finalized_only);
                             }
                             Command::Unsubscribe { subscription_id } => {
                                 debug!(%subscription_id, "Unsubscribing from subscription");
@@ -419,7 +519,7 @@ mod tests {
     use tycho_core::models::{Chain, NormalisedMessage};
 
     use super::*;
-    use crate::extractor::runner::ControlMessage;
+    use crate::{extractor::runner::ControlMessage, testing::block};
 
     #[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
     struct DummyMessage {
@@ -692,7 +792,11 @@ mod tests {
         debug!("Connected to test server");
 
         // Create and send a subscribe message from the client
-        let action = Command::Subscribe { extractor_id: extractor_id.clone(), include_state: true };
+        let action = Command::Subscribe {
+            extractor_id: extractor_id.clone(),
+            include_state: true,
+            finalized_only: false,
+        };
         connection
             .send(Message::Text(serde_json::to_string(&action).unwrap()))
             .await
@@ -721,8 +825,11 @@ mod tests {
         debug!("Received DummyMessage from server");
 
         // Create and send a second subscribe message from the client
-        let action =
-            Command::Subscribe { extractor_id: extractor_id2.clone(), include_state: true };
+        let action = Command::Subscribe {
+            extractor_id: extractor_id2.clone(),
+            include_state: true,
+            finalized_only: false,
+        };
         connection
             .send(Message::Text(serde_json::to_string(&action).unwrap()))
             .await
@@ -794,8 +901,80 @@ mod tests {
         // Create and send a subscribe message from the client
         let extractor_id =
             ExtractorIdentity { chain: Chain::Ethereum, name: "vm:ambient".to_owned() };
-        let action = Command::Subscribe { extractor_id, include_state: true };
+        let action =
+            Command::Subscribe { extractor_id, include_state: true, finalized_only: false };
         let res = serde_json::to_string(&action).unwrap();
         println!("{}", res);
     }
+
+    fn block_changes(number: u64, finalized_block_height: u64, revert: bool) -> ExtractorMsg {
+        Arc::new(BlockAggregatedChanges {
+            extractor: "test".to_string(),
+            chain: Chain::Ethereum,
+            block: block(number),
+            finalized_block_height,
+            revert,
+            ..Default::default()
+        })
+    }
+
+    fn block_numbers(msgs: Vec<ExtractorMsg>) -> Vec<u64> {
+        msgs.iter()
+            .map(|msg| {
+                msg.as_any()
+                    .downcast_ref::<BlockAggregatedChanges>()
+                    .unwrap()
+                    .block
+                    .number
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_finalized_blocks() {
+        let mut finalized = FinalizedBlocks::new();
+        let mut push = |number, finalized_block_height, revert| {
+            block_numbers(
+                finalized
+                    .push(&block_changes(number, finalized_block_height, revert))
+                    .unwrap(),
+            )
+        };
+
+        // Finalized block precedes the first received block
+        assert!(push(2, 1, false).is_empty());
+        assert!(push(3, 1, false).is_empty());
+        // Revert to block 2, block 3 is replaced afterwards
+        assert!(push(2, 1, true).is_empty());
+        assert!(push(3, 2, false).is_empty());
+        assert_eq!(push(4, 3, false), vec![2]);
+        assert_eq!(push(5, 5, false), vec![3, 4]);
+    }
+
+    #[test]
+    fn test_finalized_blocks_without_state() {
+        let mut finalized = FinalizedBlocks::new();
+
+        assert!(finalized
+            .push(&block_changes(1, 1, false).drop_state())
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            block_numbers(
+                finalized
+                    .push(&block_changes(2, 2, false).drop_state())
+                    .unwrap()
+            ),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn test_finalized_blocks_unknown_message() {
+        let mut finalized = FinalizedBlocks::new();
+        let msg: ExtractorMsg =
+            Arc::new(DummyMessage::new(ExtractorIdentity::new(Chain::Ethereum, "vm:ambient")));
+
+        assert!(finalized.push(&msg).is_err());
+    }
 }
@@ -0,0 +1,842 @@
+//! # Protocol state snapshots
+//!
+//! Exports the state of a set of protocol systems at a given block into a portable file and
+//! imports it into another storage. This allows bootstrapping a new environment without
+//! re-syncing substreams from the protocols' start blocks.
+//!
+//! ## Format
+//!
+//! A snapshot is a gzip compressed JSONL file. Each line contains a single [`SnapshotRecord`]
+//! tagged by its `type`. The first record is always a [`SnapshotHeader`] carrying the format
+//! version, the remaining records are written in dependency order: tokens, contracts,
+//! components, protocol states, component balances and extraction states. Readers must reject
+//! snapshots with an unknown format version.
+//!
+//! ## Limitations
+//!
+//! History is not part of a snapshot, all entities are imported as created by a single
+//! synthetic transaction within the snapshot block. Extraction states are only exported if they
+//! point to the snapshot block, otherwise the cursor would not match the imported state and
+//! extractors have to sync from their configured start block.
+//!
+//! Imports are committed in batches of [`IMPORT_BATCH_SIZE`] records, an interrupted import
+//! leaves the already committed batches behind and has to be repeated into a clean database.
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    io::{BufRead, BufReader, Read, Write},
+};
+
+use async_trait::async_trait;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{info, warn};
+use tycho_core::{
+    keccak256,
+    models::{
+        blockchain::{Block, Transaction},
+        contract::AccountDelta,
+        protocol::{
+            ComponentBalance, ProtocolComponent, ProtocolComponentStateDelta, QualityRange,
+        },
+        token::CurrencyToken,
+        Address, Chain, ExtractionState, PaginationParams, ProtocolType,
+    },
+    storage::{BlockIdentifier, Gateway, StorageError, Version},
+    Bytes,
+};
+use tycho_storage::{memory::MemoryGateway, postgres::cache::CachedGateway};
+
+/// The snapshot format version written by this module.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+/// Number of records that are written to storage at once during import.
+pub const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// Number of entities that are loaded from storage at once during export.
+pub const EXPORT_PAGE_SIZE: usize = 100;
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("Snapshot io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Snapshot serialization error: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("Snapshot storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("Invalid snapshot: {0}")]
+    Format(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    pub format_version: u32,
+    pub chain: Chain,
+    pub block: Block,
+    pub protocol_systems: Vec<String>,
+    pub protocol_types: Vec<ProtocolType>,
+}
+
+/// A contract together with its title.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotContract {
+    pub title: String,
+    pub account: AccountDelta,
+}
+
+/// A protocol component together with its tvl at export time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotComponent {
+    pub component: ProtocolComponent,
+    pub tvl: Option<f64>,
+}
+
+/// Extraction state of a protocol system at the snapshot block.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotExtractionState {
+    pub name: String,
+    pub attributes: serde_json::Value,
+    pub cursor: Bytes,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SnapshotRecord {
+    Header(SnapshotHeader),
+    Token(CurrencyToken),
+    Contract(SnapshotContract),
+    Component(SnapshotComponent),
+    State(ProtocolComponentStateDelta),
+    Balance(ComponentBalance),
+    ExtractionState(SnapshotExtractionState),
+}
+
+/// Number of entities contained in a snapshot.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SnapshotSummary {
+    pub tokens: usize,
+    pub contracts: usize,
+    pub components: usize,
+    pub states: usize,
+    pub balances: usize,
+    pub extraction_states: usize,
+}
+
+impl SnapshotSummary {
+    fn count(&mut self, record: &SnapshotRecord) {
+        match record {
+            SnapshotRecord::Header(_) => {}
+            SnapshotRecord::Token(_) => self.tokens += 1,
+            SnapshotRecord::Contract(_) => self.contracts += 1,
+            SnapshotRecord::Component(_) => self.components += 1,
+            SnapshotRecord::State(_) => self.states += 1,
+            SnapshotRecord::Balance(_) => self.balances += 1,
+            SnapshotRecord::ExtractionState(_) => self.extraction_states += 1,
+        }
+    }
+}
+
+/// Writes snapshot records as gzip compressed JSONL.
+pub struct SnapshotWriter<W: Write> {
+    encoder: GzEncoder<W>,
+    summary: SnapshotSummary,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    pub fn new(writer: W, header: &SnapshotHeader) -> Result<Self, SnapshotError> {
+        let mut snapshot_writer = Self {
+            encoder: GzEncoder::new(writer, Compression::default()),
+            summary: SnapshotSummary::default(),
+        };
+        snapshot_writer.write(&SnapshotRecord::Header(header.clone()))?;
+        Ok(snapshot_writer)
+    }
+
+    pub fn write(&mut self, record: &SnapshotRecord) -> Result<(), SnapshotError> {
+        serde_json::to_writer(&mut self.encoder, record)?;
+        self.encoder.write_all(b"\n")?;
+        self.summary.count(record);
+        Ok(())
+    }
+
+    /// Flushes the compressed stream and returns the number of written entities.
+    pub fn finish(self) -> Result<SnapshotSummary, SnapshotError> {
+        self.encoder.finish()?.flush()?;
+        Ok(self.summary)
+    }
+}
+
+/// Reads snapshot records written by [`SnapshotWriter`].
+pub struct SnapshotReader<R: Read> {
+    lines: std::io::Lines<BufReader<GzDecoder<R>>>,
+    header: SnapshotHeader,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    /// Opens a snapshot and validates its header.
+    pub fn new(reader: R) -> Result<Self, SnapshotError> {
+        let mut lines = BufReader::new(GzDecoder::new(reader)).lines();
+        let first = lines
+            .next()
+            .ok_or_else(|| SnapshotError::Format("Snapshot is empty".to_string()))??;
+        let header = match serde_json::from_str(&first)? {
+            SnapshotRecord::Header(header) => header,
+            _ => {
+                return Err(SnapshotError::Format(
+                    "Snapshot does not start with a header".to_string(),
+                ))
+            }
+        };
+        if header.format_version != SNAPSHOT_FORMAT_VERSION {
+            return Err(SnapshotError::Format(format!(
+                "Unsupported snapshot format version {}, expected {}",
+                header.format_version, SNAPSHOT_FORMAT_VERSION
+            )));
+        }
+        Ok(Self { lines, header })
+    }
+
+    pub fn header(&self) -> &SnapshotHeader {
+        &self.header
+    }
+}
+
+impl<R: Read> Iterator for SnapshotReader<R> {
+    type Item = Result<SnapshotRecord, SnapshotError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = match self.lines.next()? {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e.into())),
+        };
+        Some(serde_json::from_str(&line).map_err(SnapshotError::from))
+    }
+}
+
+/// Exports the state of `protocol_systems` at `block` into `writer`.
+///
+/// Only components that exist at the snapshot block are exported. Tokens and contracts are
+/// limited to the ones referenced by the exported components. Entities are loaded in pages of
+/// [`EXPORT_PAGE_SIZE`] and written as they arrive, only the components are kept in memory.
+pub async fn export_snapshot<G: Gateway, W: Write>(
+    gw: &G,
+    chain: Chain,
+    block: &BlockIdentifier,
+    protocol_systems: &[String],
+    writer: W,
+) -> Result<SnapshotSummary, SnapshotError> {
+    let block = gw.get_block(block).await?;
+    let version = Version::from_block_number(chain, block.number as i64);
+
+    let mut components = Vec::new();
+    for system in protocol_systems {
+        for page in 0.. {
+            let pagination = PaginationParams::new(page, EXPORT_PAGE_SIZE as i64);
+            let entity = gw
+                .get_protocol_components_at(&chain, system, &version, Some(&pagination))
+                .await?
+                .entity;
+            let last_page = entity.len() < EXPORT_PAGE_SIZE;
+            components.extend(entity);
+            if last_page {
+                break;
+            }
+        }
+    }
+    let protocol_type_names: BTreeSet<_> = components
+        .iter()
+        .map(|c| c.protocol_type_name.as_str())
+        .collect();
+    let protocol_type_names: Vec<_> = protocol_type_names
+        .into_iter()
+        .collect();
+    let protocol_types = gw
+        .get_protocol_types(Some(&protocol_type_names))
+        .await?;
+    let header = SnapshotHeader {
+        format_version: SNAPSHOT_FORMAT_VERSION,
+        chain,
+        block: block.clone(),
+        protocol_systems: protocol_systems.to_vec(),
+        protocol_types,
+    };
+    let mut snapshot = SnapshotWriter::new(writer, &header)?;
+
+    let token_addresses: BTreeSet<&Address> = components
+        .iter()
+        .flat_map(|c| c.tokens.iter())
+        .collect();
+    let token_addresses: Vec<_> = token_addresses.into_iter().collect();
+    for addresses in token_addresses.chunks(EXPORT_PAGE_SIZE) {
+        let tokens = gw
+            .get_tokens(chain, Some(addresses), QualityRange::None(), None, None)
+            .await?
+            .entity;
+        for token in tokens {
+            snapshot.write(&SnapshotRecord::Token(token))?;
+        }
+    }
+
+    let contract_addresses: BTreeSet<Address> = components
+        .iter()
+        .flat_map(|c| c.contract_addresses.iter().cloned())
+        .collect();
+    let contract_addresses: Vec<_> = contract_addresses.into_iter().collect();
+    for addresses in contract_addresses.chunks(EXPORT_PAGE_SIZE) {
+        let contracts = gw
+            .get_contracts(&chain, Some(addresses), Some(&version), true, None)
+            .await?
+            .entity;
+        for contract in contracts {
+            snapshot.write(&SnapshotRecord::Contract(SnapshotContract {
+                title: contract.title.clone(),
+                account: contract.into(),
+            }))?;
+        }
+    }
+
+    let component_ids: Vec<_> = components
+        .iter()
+        .map(|c| c.id.as_str())
+        .collect();
+    for (chunk, ids) in components
+        .chunks(EXPORT_PAGE_SIZE)
+        .zip(component_ids.chunks(EXPORT_PAGE_SIZE))
+    {
+        let mut tvls = gw
+            .get_component_tvls(&chain, Some(ids))
+            .await?;
+        for component in chunk {
+            snapshot.write(&SnapshotRecord::Component(SnapshotComponent {
+                component: component.clone(),
+                tvl: tvls.remove(&component.id),
+            }))?;
+        }
+    }
+
+    for ids in component_ids.chunks(EXPORT_PAGE_SIZE) {
+        let states = gw
+            .get_protocol_states(&chain, Some(version.clone()), None, Some(ids), false, None)
+            .await?
+            .entity;
+        for state in states {
+            snapshot.write(&SnapshotRecord::State(ProtocolComponentStateDelta::new(
+                &state.component_id,
+                state.attributes,
+                HashSet::new(),
+            )))?;
+        }
+    }
+
+    for ids in component_ids.chunks(EXPORT_PAGE_SIZE) {
+        let balances = gw
+            .get_component_balances(&chain, Some(ids), Some(&version))
+            .await?;
+        for balance in balances
+            .into_values()
+            .flat_map(|balances| balances.into_values())
+        {
+            snapshot.write(&SnapshotRecord::Balance(balance))?;
+        }
+    }
+
+    for system in protocol_systems {
+        match gw.get_state(system, &chain).await {
+            Ok(state) if state.block_hash == block.hash => {
+                snapshot.write(&SnapshotRecord::ExtractionState(SnapshotExtractionState {
+                    name: state.name,
+                    attributes: state.attributes,
+                    cursor: state.cursor.into(),
+                }))?;
+            }
+            Ok(state) => {
+                warn!(
+                    protocol_system = system,
+                    state_block = ?state.block_hash,
+                    "Extraction state does not match snapshot block, skipping it."
+                );
+            }
+            Err(StorageError::NotFound(..)) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let summary = snapshot.finish()?;
+    info!(block_number = block.number, ?summary, "Exported snapshot");
+    Ok(summary)
+}
+
+/// Returns the synthetic transaction all imported entities are attributed to.
+pub fn snapshot_transaction(block: &Block) -> Transaction {
+    let mut seed = b"tycho-snapshot".to_vec();
+    seed.extend_from_slice(&block.hash);
+    Transaction::new(
+        Bytes::from(keccak256(seed)),
+        block.hash.clone(),
+        Bytes::from([0u8; 20]),
+        None,
+        0,
+    )
+}
+
+/// A gateway that snapshots can be imported into.
+///
+/// Writes of a gateway that buffers them are committed after each batch instead of keeping the
+/// whole import in a single database transaction.
+#[async_trait]
+pub trait ImportTarget: Gateway {
+    /// Opens a write batch for the snapshot block.
+    async fn begin_batch(&self, block: &Block);
+
+    /// Commits all writes issued since the last call to `begin_batch`.
+    async fn commit_batch(&self) -> Result<(), StorageError>;
+}
+
+#[async_trait]
+impl ImportTarget for CachedGateway {
+    async fn begin_batch(&self, block: &Block) {
+        self.start_transaction(block, Some("snapshotImport"))
+            .await;
+    }
+
+    async fn commit_batch(&self) -> Result<(), StorageError> {
+        self.commit_transaction(0).await
+    }
+}
+
+#[async_trait]
+impl ImportTarget for MemoryGateway {
+    async fn begin_batch(&self, _block: &Block) {}
+
+    async fn commit_batch(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+/// Imports a snapshot into `gw`.
+///
+/// The snapshot block and a synthetic transaction within it are inserted first, all imported
+/// entities are attributed to that transaction. Records are committed in batches of at most
+/// [`IMPORT_BATCH_SIZE`], component tvl is written once all components are committed.
+pub async fn import_snapshot<G: ImportTarget, R: Read>(
+    gw: &G,
+    reader: SnapshotReader<R>,
+) -> Result<SnapshotSummary, SnapshotError> {
+    let header = reader.header().clone();
+    let tx = snapshot_transaction(&header.block);
+
+    gw.begin_batch(&header.block).await;
+    gw.upsert_block(std::slice::from_ref(&header.block))
+        .await?;
+    gw.upsert_tx(std::slice::from_ref(&tx))
+        .await?;
+    gw.commit_batch().await?;
+    gw.add_protocol_types(&header.protocol_types)
+        .await?;
+
+    let mut batch = ImportBatch::default();
+    let mut summary = SnapshotSummary::default();
+    let mut tvls = HashMap::new();
+    for record in reader {
+        let record = record?;
+        summary.count(&record);
+        if let SnapshotRecord::Component(SnapshotComponent { component, tvl: Some(tvl) }) = &record
+        {
+            tvls.insert(component.id.clone(), *tvl);
+        }
+        if !batch.accepts(&record) || batch.len() >= IMPORT_BATCH_SIZE {
+            batch.flush(gw, &header, &tx).await?;
+        }
+        batch.push(record)?;
+    }
+    batch.flush(gw, &header, &tx).await?;
+    if !tvls.is_empty() {
+        gw.upsert_component_tvl(&header.chain, &tvls)
+            .await?;
+    }
+
+    info!(block_number = header.block.number, ?summary, "Imported snapshot");
+    Ok(summary)
+}
+
+/// Records of a single kind that are written to storage together.
+#[derive(Default)]
+enum ImportBatch {
+    #[default]
+    Empty,
+    Tokens(Vec<CurrencyToken>),
+    Contracts(Vec<SnapshotContract>),
+    Components(Vec<SnapshotComponent>),
+    States(Vec<ProtocolComponentStateDelta>),
+    Balances(Vec<ComponentBalance>),
+    ExtractionStates(Vec<SnapshotExtractionState>),
+}
+
+impl ImportBatch {
+    fn len(&self) -> usize {
+        match self {
+            ImportBatch::Empty => 0,
+            ImportBatch::Tokens(v) => v.len(),
+            ImportBatch::Contracts(v) => v.len(),
+            ImportBatch::Components(v) => v.len(),
+            ImportBatch::States(v) => v.len(),
+            ImportBatch::Balances(v) => v.len(),
+            ImportBatch::ExtractionStates(v) => v.len(),
+        }
+    }
+
+    fn accepts(&self, record: &SnapshotRecord) -> bool {
+        matches!(
+            (self, record),
+            (ImportBatch::Empty, _) |
+                (ImportBatch::Tokens(_), SnapshotRecord::Token(_)) |
+                (ImportBatch::Contracts(_), SnapshotRecord::Contract(_)) |
+                (ImportBatch::Components(_), SnapshotRecord::Component(_)) |
+                (ImportBatch::States(_), SnapshotRecord::State(_)) |
+                (ImportBatch::Balances(_), SnapshotRecord::Balance(_)) |
+                (ImportBatch::ExtractionStates(_), SnapshotRecord::ExtractionState(_))
+        )
+    }
+
+    fn push(&mut self, record: SnapshotRecord) -> Result<(), SnapshotError> {
+        match (self, record) {
+            (_, SnapshotRecord::Header(_)) => {
+                return Err(SnapshotError::Format("Duplicated snapshot header".to_string()))
+            }
+            (ImportBatch::Tokens(v), SnapshotRecord::Token(r)) => v.push(r),
+            (ImportBatch::Contracts(v), SnapshotRecord::Contract(r)) => v.push(r),
+            (ImportBatch::Components(v), SnapshotRecord::Component(r)) => v.push(r),
+            (ImportBatch::States(v), SnapshotRecord::State(r)) => v.push(r),
+            (ImportBatch::Balances(v), SnapshotRecord::Balance(r)) => v.push(r),
+            (ImportBatch::ExtractionStates(v), SnapshotRecord::ExtractionState(r)) => v.push(r),
+            (batch, record) if batch.len() == 0 => {
+                *batch = match record {
+                    SnapshotRecord::Token(r) => ImportBatch::Tokens(vec![r]),
+                    SnapshotRecord::Contract(r) => ImportBatch::Contracts(vec![r]),
+                    SnapshotRecord::Component(r) => ImportBatch::Components(vec![r]),
+                    SnapshotRecord::State(r) => ImportBatch::States(vec![r]),
+                    SnapshotRecord::Balance(r) => ImportBatch::Balances(vec![r]),
+                    SnapshotRecord::ExtractionState(r) => ImportBatch::ExtractionStates(vec![r]),
+                    SnapshotRecord::Header(_) => unreachable!("headers are rejected above"),
+                };
+            }
+            _ => return Err(SnapshotError::Format("Mixed record batch".to_string())),
+        }
+        Ok(())
+    }
+
+    /// Writes the batch and commits it.
+    async fn flush<G: ImportTarget>(
+        &mut self,
+        gw: &G,
+        header: &SnapshotHeader,
+        tx: &Transaction,
+    ) -> Result<(), SnapshotError> {
+        let batch = std::mem::take(self);
+        if matches!(batch, ImportBatch::Empty) {
+            return Ok(());
+        }
+        gw.begin_batch(&header.block).await;
+        match batch {
+            ImportBatch::Empty => {}
+            ImportBatch::Tokens(tokens) => gw.add_tokens(&tokens).await?,
+            ImportBatch::Contracts(contracts) => {
+                for contract in contracts {
+                    let mut account = contract.account.into_account(tx);
+                    account.title = contract.title;
+                    gw.upsert_contract(&account).await?;
+                }
+            }
+            ImportBatch::Components(components) => {
+                let components: Vec<_> = components
+                    .into_iter()
+                    .map(|c| ProtocolComponent {
+                        creation_tx: tx.hash.clone(),
+                        created_at: header.block.ts,
+                        ..c.component
+                    })
+                    .collect();
+                gw.add_protocol_components(&components)
+                    .await?;
+            }
+            ImportBatch::States(states) => {
+                let states: Vec<_> = states
+                    .into_iter()
+                    .map(|s| (tx.hash.clone(), s))
+                    .collect();
+                gw.update_protocol_states(&states)
+                    .await?;
+            }
+            ImportBatch::Balances(balances) => {
+                let balances: Vec<_> = balances
+                    .into_iter()
+                    .map(|b| ComponentBalance { modify_tx: tx.hash.clone(), ..b })
+                    .collect();
+                gw.add_component_balances(&balances)
+                    .await?;
+            }
+            ImportBatch::ExtractionStates(states) => {
+                for state in states {
+                    gw.save_state(&ExtractionState::new(
+                        state.name,
+                        header.chain,
+                        Some(state.attributes),
+                        &state.cursor,
+                        header.block.hash.clone(),
+                    ))
+                    .await?;
+                }
+            }
+        }
+        gw.commit_batch().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use chrono::DateTime;
+    use tycho_core::{
+        models::{contract::Account, ChangeType, ContractId, FinancialType, ImplementationType},
+        storage::{ChainGateway, ContractStateGateway, ExtractionStateGateway, ProtocolGateway},
+    };
+
+    use super::*;
+
+    fn block(number: u64) -> Block {
+        Block::new(
+            number,
+            Chain::Ethereum,
+            Bytes::from(number).lpad(32, 0),
+            Bytes::from(number - 1).lpad(32, 0),
+            DateTime::from_timestamp(number as i64 * 12, 0)
+                .map(|d| d.naive_utc())
+                .unwrap(),
+        )
+    }
+
+    fn protocol_type() -> ProtocolType {
+        ProtocolType::new(
+            "pool".to_string(),
+            FinancialType::Debt,
+            Some(serde_json::json!({"reserve": "bytes"})),
+            ImplementationType::Vm,
+        )
+    }
+
+    fn contract_address() -> Address {
+        Bytes::from("0x0000000000000000000000000000000000000200")
+    }
+
+    async fn setup_gateway() -> MemoryGateway {
+        let gw = MemoryGateway::new(&[Chain::Ethereum], &["ambient".to_string()]);
+        let token = CurrencyToken::new(
+            &Bytes::from("0x0000000000000000000000000000000000000100"),
+            "TKN",
+            18,
+            0,
+            &[],
+            Chain::Ethereum,
+            100,
+        );
+        let tx = snapshot_transaction(&block(1));
+        gw.upsert_block(&[block(1), block(2)])
+            .await
+            .unwrap();
+        gw.upsert_tx(std::slice::from_ref(&tx))
+            .await
+            .unwrap();
+        gw.add_protocol_types(&[protocol_type()])
+            .await
+            .unwrap();
+        gw.upsert_contract(&Account::new(
+            Chain::Ethereum,
+            contract_address(),
+            "ambient_dex".to_string(),
+            HashMap::from([(Bytes::from("0x01"), Bytes::from("0x02"))]),
+            Bytes::from("0x00"),
+            HashMap::new(),
+            Bytes::from("0x6080"),
+            keccak256(Bytes::from("0x6080")).into(),
+            tx.hash.clone(),
+            tx.hash.clone(),
+            Some(tx.hash.clone()),
+        ))
+        .await
+        .unwrap();
+        gw.add_tokens(&[token.clone()])
+            .await
+            .unwrap();
+        gw.add_protocol_components(&[ProtocolComponent::new(
+            "pc_0",
+            "ambient",
+            "pool",
+            Chain::Ethereum,
+            vec![token.address.clone()],
+            vec![contract_address()],
+            HashMap::new(),
+            ChangeType::Creation,
+            tx.hash.clone(),
+            block(1).ts,
+        )])
+        .await
+        .unwrap();
+        gw.update_protocol_states(&[(
+            tx.hash.clone(),
+            ProtocolComponentStateDelta::new(
+                "pc_0",
+                HashMap::from([("reserve".to_string(), Bytes::from("0x01"))]),
+                HashSet::new(),
+            ),
+        )])
+        .await
+        .unwrap();
+        gw.add_component_balances(&[ComponentBalance::new(
+            token.address,
+            Bytes::from("0x64"),
+            100.0,
+            tx.hash,
+            "pc_0",
+        )])
+        .await
+        .unwrap();
+        gw.upsert_component_tvl(&Chain::Ethereum, &HashMap::from([("pc_0".to_string(), 42.0)]))
+            .await
+            .unwrap();
+        gw.save_state(&ExtractionState::new(
+            "ambient".to_string(),
+            Chain::Ethereum,
+            None,
+            b"cursor",
+            block(2).hash,
+        ))
+        .await
+        .unwrap();
+        gw
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_roundtrip() {
+        let source = setup_gateway().await;
+        let mut buffer = Vec::new();
+
+        let exported = export_snapshot(
+            &source,
+            Chain::Ethereum,
+            &BlockIdentifier::Number((Chain::Ethereum, 2)),
+            &["ambient".to_string()],
+            &mut buffer,
+        )
+        .await
+        .expect("export failed");
+        let target = MemoryGateway::new(&[Chain::Ethereum], &["ambient".to_string()]);
+        let reader = SnapshotReader::new(buffer.as_slice()).expect("invalid snapshot");
+        let imported = import_snapshot(&target, reader)
+            .await
+            .expect("import failed");
+
+        let expected_summary = SnapshotSummary {
+            tokens: 1,
+            contracts: 1,
+            components: 1,
+            states: 1,
+            balances: 1,
+            extraction_states: 1,
+        };
+        assert_eq!(exported, expected_summary);
+        assert_eq!(imported, expected_summary);
+        let states = target
+            .get_protocol_states(&Chain::Ethereum, None, None, None, true, None)
+            .await
+            .unwrap()
+            .entity;
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].attributes["reserve"], Bytes::from("0x01"));
+        assert_eq!(states[0].balances.len(), 1);
+        let state = target
+            .get_state("ambient", &Chain::Ethereum)
+            .await
+            .unwrap();
+        assert_eq!(state.cursor, b"cursor".to_vec());
+        assert_eq!(state.block_hash, block(2).hash);
+        let protocol_types = target
+            .get_protocol_types(None)
+            .await
+            .unwrap();
+        assert_eq!(protocol_types, vec![protocol_type()]);
+        let tvls = target
+            .get_component_tvls(&Chain::Ethereum, None)
+            .await
+            .unwrap();
+        assert_eq!(tvls, HashMap::from([("pc_0".to_string(), 42.0)]));
+        let contract = target
+            .get_contract(&ContractId::new(Chain::Ethereum, contract_address()), None, true)
+            .await
+            .unwrap();
+        assert_eq!(contract.title, "ambient_dex");
+        assert_eq!(contract.slots[&Bytes::from("0x01")], Bytes::from("0x02"));
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_export_at_version() {
+        let source = setup_gateway().await;
+        source
+            .upsert_block(&[block(3)])
+            .await
+            .unwrap();
+        let components = source
+            .get_protocol_components(&Chain::Ethereum, None, None, None, None)
+            .await
+            .unwrap()
+            .entity;
+        source
+            .delete_protocol_components(&components, block(3).ts)
+            .await
+            .unwrap();
+
+        let exported_before = export_snapshot(
+            &source,
+            Chain::Ethereum,
+            &BlockIdentifier::Number((Chain::Ethereum, 2)),
+            &["ambient".to_string()],
+            Vec::new(),
+        )
+        .await
+        .expect("export before deletion failed");
+        let exported_after = export_snapshot(
+            &source,
+            Chain::Ethereum,
+            &BlockIdentifier::Number((Chain::Ethereum, 3)),
+            &["ambient".to_string()],
+            Vec::new(),
+        )
+        .await
+        .expect("export after deletion failed");
+
+        assert_eq!(exported_before.components, 1);
+        assert_eq!(exported_before.states, 1);
+        assert_eq!(exported_after, SnapshotSummary::default());
+    }
+
+    #[test]
+    fn test_snapshot_reader_rejects_unknown_version() {
+        let header = SnapshotHeader {
+            format_version: SNAPSHOT_FORMAT_VERSION + 1,
+            chain: Chain::Ethereum,
+            block: block(1),
+            protocol_systems: vec![],
+            protocol_types: vec![],
+        };
+        let mut buffer = Vec::new();
+        SnapshotWriter::new(&mut buffer, &header)
+            .unwrap()
+            .finish()
+            .unwrap();
+
+        let res = SnapshotReader::new(buffer.as_slice());
+
+        assert!(matches!(res, Err(SnapshotError::Format(_))));
+    }
+}
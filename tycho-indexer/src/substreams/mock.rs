@@ -0,0 +1,537 @@
+//! Mock Substreams server
+//!
+//! Replays a scripted sequence of substreams responses over gRPC, so `SubstreamsStream` and
+//! everything consuming it can run without a real Substreams endpoint. The generated protobuf
+//! code doesn't include servers, therefore the `sf.substreams.rpc.v2.Stream` service is
+//! implemented directly on top of tonic's server primitives.
+use std::{
+    collections::HashSet,
+    convert::Infallible,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use futures03::{stream, Stream};
+use prost::Message as _;
+use tokio::{net::TcpListener, sync::oneshot, task::JoinHandle};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{
+    codegen::{empty_body, http, Body, BoxFuture, Context, Poll, Service, StdError},
+    server::{Grpc, NamedService, ServerStreamingService},
+    transport::Server,
+    Code, Status,
+};
+use tracing::{debug, info};
+
+use crate::pb::sf::substreams::{
+    rpc::v2::{
+        response::Message, BlockScopedData, BlockUndoSignal, Request, Response, SessionInit,
+    },
+    v1::BlockRef,
+};
+
+const BLOCKS_PATH: &str = "/sf.substreams.rpc.v2.Stream/Blocks";
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug)]
+enum Scripted {
+    Response(Response),
+    /// Ends the stream with an error status, only the first time it is reached.
+    Error(Code, String),
+}
+
+/// A scripted Substreams server.
+///
+/// Each request is answered with a session message followed by the scripted responses:
+/// - Without cursor, replay starts at the first response and blocks below the requested start block
+///   are skipped.
+/// - With a cursor, replay continues after the block or undo signal carrying that cursor.
+/// - Replay ends at the first block at or above the requested stop block, if one is set.
+/// - For `final_blocks_only` requests, undo signals and blocks above their final block height are
+///   skipped.
+#[derive(Clone, Debug, Default)]
+pub struct MockSubstreams {
+    script: Vec<Scripted>,
+}
+
+impl MockSubstreams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replays previously recorded responses, see [`load_recording`].
+    pub fn from_responses(responses: Vec<Response>) -> Self {
+        Self {
+            script: responses
+                .into_iter()
+                .map(Scripted::Response)
+                .collect(),
+        }
+    }
+
+    pub fn block(mut self, data: BlockScopedData) -> Self {
+        self.script
+            .push(Scripted::Response(Response { message: Some(Message::BlockScopedData(data)) }));
+        self
+    }
+
+    /// Reverts every block above `last_valid_block`. Clients resume from `last_valid_cursor`.
+    pub fn undo(mut self, last_valid_block: BlockRef, last_valid_cursor: &str) -> Self {
+        self.script
+            .push(Scripted::Response(Response {
+                message: Some(Message::BlockUndoSignal(BlockUndoSignal {
+                    last_valid_block: Some(last_valid_block),
+                    last_valid_cursor: last_valid_cursor.to_string(),
+                })),
+            }));
+        self
+    }
+
+    /// Ends the stream with an `UNAVAILABLE` status the first time this point is reached. Clients
+    /// are expected to reconnect with the cursor of the last block they received.
+    pub fn disconnect(mut self) -> Self {
+        self.script
+            .push(Scripted::Error(Code::Unavailable, "scripted disconnect".to_string()));
+        self
+    }
+
+    /// Binds to `addr` and serves the script until the returned handle is shut down or dropped.
+    /// Use port 0 to bind to a free port.
+    pub async fn serve(self, addr: SocketAddr) -> Result<MockSubstreamsHandle, std::io::Error> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(MockState {
+            script: self.script,
+            requests: Mutex::new(Vec::new()),
+            fired_errors: Mutex::new(HashSet::new()),
+        });
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let service = StreamService(state.clone());
+        let task = tokio::spawn(async move {
+            let res = Server::builder()
+                .add_service(service)
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                    shutdown_rx.await.ok();
+                })
+                .await;
+            if let Err(err) = res {
+                tracing::error!(%err, "Mock substreams server failed");
+            }
+        });
+        info!(%addr, "Mock substreams server started");
+        Ok(MockSubstreamsHandle { url: format!("http://{}", addr), state, shutdown_tx, task })
+    }
+}
+
+pub struct MockSubstreamsHandle {
+    /// Endpoint url to pass to `SubstreamsEndpoint`.
+    pub url: String,
+    state: Arc<MockState>,
+    shutdown_tx: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl MockSubstreamsHandle {
+    /// All requests received so far, e.g. to check which cursor a client resumed from.
+    pub fn requests(&self) -> Vec<Request> {
+        self.state
+            .requests
+            .lock()
+            .expect("requests lock poisoned")
+            .clone()
+    }
+
+    pub async fn shutdown(self) {
+        let _ = self.shutdown_tx.send(());
+        let _ = self.task.await;
+    }
+}
+
+#[derive(Debug)]
+struct MockState {
+    script: Vec<Scripted>,
+    requests: Mutex<Vec<Request>>,
+    fired_errors: Mutex<HashSet<usize>>,
+}
+
+impl MockState {
+    /// Selects the responses to send for a request, see [`MockSubstreams`].
+    #[allow(clippy::result_large_err)]
+    fn replay(&self, request: &Request) -> Result<Vec<Result<Response, Status>>, Status> {
+        let start = if request.start_cursor.is_empty() {
+            0
+        } else {
+            self.script
+                .iter()
+                .position(|item| cursor(item) == Some(request.start_cursor.as_str()))
+                .map(|idx| idx + 1)
+                .ok_or_else(|| {
+                    Status::invalid_argument(format!("Unknown cursor: {}", request.start_cursor))
+                })?
+        };
+
+        let mut responses = vec![Ok(Response {
+            message: Some(Message::Session(SessionInit {
+                trace_id: "mock".to_string(),
+                resolved_start_block: request.start_block_num as u64,
+                ..Default::default()
+            })),
+        })];
+        for (idx, item) in self
+            .script
+            .iter()
+            .enumerate()
+            .skip(start)
+        {
+            match item {
+                Scripted::Response(response) => match &response.message {
+                    Some(Message::BlockScopedData(data)) => {
+                        let number = data
+                            .clock
+                            .as_ref()
+                            .map_or(0, |clock| clock.number);
+                        if request.stop_block_num != 0 && number >= request.stop_block_num {
+                            break;
+                        }
+                        if (request.start_cursor.is_empty() &&
+                            number < request.start_block_num as u64) ||
+                            (request.final_blocks_only && number > data.final_block_height)
+                        {
+                            continue;
+                        }
+                        responses.push(Ok(response.clone()));
+                    }
+                    Some(Message::BlockUndoSignal(_)) if request.final_blocks_only => {}
+                    _ => responses.push(Ok(response.clone())),
+                },
+                Scripted::Error(code, message) => {
+                    if self
+                        .fired_errors
+                        .lock()
+                        .expect("fired errors lock poisoned")
+                        .insert(idx)
+                    {
+                        responses.push(Err(Status::new(*code, message.clone())));
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(responses)
+    }
+}
+
+fn cursor(item: &Scripted) -> Option<&str> {
+    match item {
+        Scripted::Response(Response { message: Some(Message::BlockScopedData(data)) }) => {
+            Some(&data.cursor)
+        }
+        Scripted::Response(Response { message: Some(Message::BlockUndoSignal(undo)) }) => {
+            Some(&undo.last_valid_cursor)
+        }
+        _ => None,
+    }
+}
+
+type ResponseStream = Pin<Box<dyn Stream<Item = Result<Response, Status>> + Send>>;
+
+#[derive(Clone)]
+struct StreamService(Arc<MockState>);
+
+impl ServerStreamingService<Request> for StreamService {
+    type Response = Response;
+    type ResponseStream = ResponseStream;
+    type Future = BoxFuture<tonic::Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: tonic::Request<Request>) -> Self::Future {
+        let state = self.0.clone();
+        Box::pin(async move {
+            let request = request.into_inner();
+            debug!(
+                start_block = request.start_block_num,
+                cursor = request.start_cursor,
+                "Mock substreams request"
+            );
+            let responses = state.replay(&request)?;
+            state
+                .requests
+                .lock()
+                .expect("requests lock poisoned")
+                .push(request);
+            Ok(tonic::Response::new(Box::pin(stream::iter(responses)) as ResponseStream))
+        })
+    }
+}
+
+impl<B> Service<http::Request<B>> for StreamService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let service = self.clone();
+        if req.uri().path() != BLOCKS_PATH {
+            return Box::pin(async move {
+                Ok(http::Response::builder()
+                    .status(200)
+                    .header("grpc-status", (Code::Unimplemented as i32).to_string())
+                    .header("content-type", "application/grpc")
+                    .body(empty_body())
+                    .expect("valid response"))
+            });
+        }
+        Box::pin(async move {
+            let mut grpc = Grpc::new(tonic::codec::ProstCodec::default());
+            Ok(grpc
+                .server_streaming(service, req)
+                .await)
+        })
+    }
+}
+
+impl NamedService for StreamService {
+    const NAME: &'static str = "sf.substreams.rpc.v2.Stream";
+}
+
+/// Reads responses written by [`save_recording`].
+pub fn load_recording(path: impl AsRef<Path>) -> Result<Vec<Response>, std::io::Error> {
+    let mut buf = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut buf)?;
+    let mut data = buf.as_slice();
+    let mut responses = Vec::new();
+    while !data.is_empty() {
+        responses.push(
+            Response::decode_length_delimited(&mut data)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?,
+        );
+    }
+    Ok(responses)
+}
+
+/// Writes responses as length delimited protobuf messages.
+pub fn save_recording(
+    path: impl AsRef<Path>,
+    responses: &[Response],
+) -> Result<(), std::io::Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for response in responses {
+        writer.write_all(&response.encode_length_delimited_to_vec())?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::RwLock};
+
+    use futures03::StreamExt;
+    use tokio::sync::mpsc;
+    use tycho_core::models::ExtractorIdentity;
+    use tycho_substreams::pb::tycho::evm::v1 as tycho_substreams;
+
+    use super::*;
+    use crate::{
+        extractor::{
+            runner::{ExtractorRunner, ExtractorStatus, RunnerState, StreamFactory},
+            ExtractionError, MockExtractor,
+        },
+        substreams::{
+            stream::{BlockResponse, SubstreamsStream},
+            SubstreamsEndpoint,
+        },
+        testing::fixtures as pb_fixtures,
+    };
+
+    fn block(number: u64) -> BlockScopedData {
+        let mut data = pb_fixtures::pb_block_scoped_data(
+            tycho_substreams::BlockChanges {
+                block: Some(pb_fixtures::pb_blocks(number)),
+                changes: vec![],
+            },
+            Some(&format!("cursor@{}", number)),
+            Some(number.saturating_sub(1)),
+        );
+        data.clock.as_mut().unwrap().number = number;
+        data
+    }
+
+    async fn consume(
+        mock: &MockSubstreamsHandle,
+        start_block: i64,
+        final_blocks_only: bool,
+    ) -> Vec<String> {
+        let endpoint = Arc::new(
+            SubstreamsEndpoint::new(&mock.url, None)
+                .await
+                .unwrap(),
+        );
+        SubstreamsStream::new(
+            endpoint,
+            None,
+            None,
+            "map_changes".to_string(),
+            start_block,
+            0,
+            final_blocks_only,
+            "test".to_string(),
+        )
+        .map(|response| match response.unwrap() {
+            BlockResponse::New(data) => data.cursor,
+            BlockResponse::Undo(undo) => format!("undo to {}", undo.last_valid_cursor),
+        })
+        .collect()
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_replay_with_reorg_and_disconnect() {
+        let mock = MockSubstreams::new()
+            .block(block(1))
+            .block(block(2))
+            .disconnect()
+            .block(block(3))
+            .undo(BlockRef { id: "0x02".to_string(), number: 2 }, "cursor@2")
+            .block(block(3))
+            .serve("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let received = consume(&mock, 2, false).await;
+        let cursors: Vec<_> = mock
+            .requests()
+            .into_iter()
+            .map(|request| request.start_cursor)
+            .collect();
+
+        assert_eq!(received, vec!["cursor@2", "cursor@3", "undo to cursor@2", "cursor@3"]);
+        assert_eq!(cursors, vec!["".to_string(), "cursor@2".to_string()]);
+        mock.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_replay_final_blocks_only() {
+        let mock = MockSubstreams::new()
+            .block(block(1))
+            .block(block(2))
+            .undo(BlockRef { id: "0x01".to_string(), number: 1 }, "cursor@1")
+            .serve("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let mut final_block = block(3);
+        final_block.final_block_height = 3;
+        let recorded = vec![Response { message: Some(Message::BlockScopedData(final_block)) }];
+        let path = std::env::temp_dir().join("mock_substreams_recording.bin");
+        save_recording(&path, &recorded).unwrap();
+        let replayed = MockSubstreams::from_responses(load_recording(&path).unwrap())
+            .serve("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let received = consume(&mock, 0, true).await;
+        let received_recorded = consume(&replayed, 0, true).await;
+
+        assert!(received.is_empty());
+        assert_eq!(received_recorded, vec!["cursor@3"]);
+        mock.shutdown().await;
+        replayed.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_extractor_runner_with_reorg() {
+        let mock = MockSubstreams::new()
+            .block(block(1))
+            .block(block(2))
+            .block(block(3))
+            .undo(BlockRef { id: "0x02".to_string(), number: 2 }, "cursor@2")
+            .block(block(3))
+            .block(block(4))
+            .serve("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let endpoint = Arc::new(
+            SubstreamsEndpoint::new(&mock.url, None)
+                .await
+                .unwrap(),
+        );
+        let stream_factory: StreamFactory = Arc::new(move |cursor, start_block| {
+            SubstreamsStream::new(
+                endpoint.clone(),
+                cursor,
+                None,
+                "map_changes".to_string(),
+                start_block.unwrap_or(1),
+                0,
+                false,
+                "test".to_string(),
+            )
+        });
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut extractor = MockExtractor::new();
+        extractor
+            .expect_get_id()
+            .returning(ExtractorIdentity::default);
+        extractor
+            .expect_get_cursor()
+            .returning(String::new);
+        extractor
+            .expect_get_last_processed_block()
+            .returning(|| None);
+        let tick_calls = calls.clone();
+        extractor
+            .expect_handle_tick_scoped_data()
+            .returning(move |data| {
+                let number = data.clock.unwrap().number;
+                tick_calls
+                    .lock()
+                    .unwrap()
+                    .push(format!("block {}", number));
+                Ok(None)
+            });
+        let revert_calls = calls.clone();
+        extractor
+            .expect_handle_revert()
+            .returning(move |undo| {
+                let number = undo.last_valid_block.unwrap().number;
+                revert_calls
+                    .lock()
+                    .unwrap()
+                    .push(format!("revert to {}", number));
+                Ok(None)
+            });
+        let (_control_tx, control_rx) = mpsc::channel(1);
+        let status = Arc::new(RwLock::new(ExtractorStatus::default()));
+        let runner = ExtractorRunner::new(
+            Arc::new(extractor),
+            stream_factory(None, None),
+            Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+            control_rx,
+            None,
+            status.clone(),
+            stream_factory,
+            None,
+        );
+
+        let res = runner.run().await.unwrap();
+
+        assert!(matches!(res, Err(ExtractionError::SubstreamsError(_))));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["block 1", "block 2", "block 3", "revert to 2", "block 3", "block 4"]
+        );
+        assert!(matches!(status.read().unwrap().state, RunnerState::Failed(_)));
+        mock.shutdown().await;
+    }
+}
@@ -2,6 +2,7 @@
 //!
 //! This module contains a substreams client. Taken from the
 //! Rust Sink template repo.
+pub mod mock;
 pub mod stream;
 use std::{fmt::Display, sync::Arc, time::Duration};
 
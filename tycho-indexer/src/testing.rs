@@ -11,13 +11,14 @@ use tycho_core::{
             ComponentBalance, ProtocolComponent, ProtocolComponentState,
             ProtocolComponentStateDelta, QualityRange,
         },
-        token::CurrencyToken,
+        token::{CurrencyToken, TokenAnalysis},
         Address, Chain, ComponentId, ContractId, ExtractionState, PaginationParams, ProtocolType,
-        TxHash,
+        StoreKey, StoreVal, TxHash,
     },
     storage::{
         BlockIdentifier, BlockOrTimestamp, ChainGateway, ContractStateGateway,
-        ExtractionStateGateway, Gateway, ProtocolGateway, StorageError, Version, WithTotal,
+        ExtractionStateGateway, Gateway, ProtocolGateway, ProtocolSystemRemoval, StorageError,
+        Version, WithTotal,
     },
     Bytes,
 };
@@ -34,6 +35,11 @@ mock! {
     impl ChainGateway for Gateway {
         async fn upsert_block(&self, new: &[Block]) -> Result<(), StorageError>;
         async fn get_block(&self, id: &BlockIdentifier) -> Result<Block, StorageError>;
+        async fn get_block_at_timestamp(
+            &self,
+            chain: &Chain,
+            ts: &NaiveDateTime,
+        ) -> Result<Block, StorageError>;
         async fn upsert_tx(&self, new: &[Transaction]) -> Result<(), StorageError>;
         async fn get_tx(&self, hash: &TxHash) -> Result<Transaction, StorageError>;
         async fn revert_state(&self, to: &BlockIdentifier) -> Result<(), StorageError>;
@@ -81,6 +87,27 @@ mock! {
             'life4: 'async_trait,
             Self: 'async_trait;
 
+        #[allow(clippy::type_complexity)]
+        fn sample_contract_slots<'life0, 'life1, 'life2, 'life3, 'async_trait>(
+            &'life0 self,
+            chain: &'life1 Chain,
+            address: &'life2 Address,
+            version: Option<&'life3 Version>,
+            limit: usize,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<
+                    Output = Result<HashMap<StoreKey, StoreVal>, StorageError>,
+                > + ::core::marker::Send + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            'life1: 'async_trait,
+            'life2: 'async_trait,
+            'life3: 'async_trait,
+            Self: 'async_trait;
+
         fn upsert_contract<'life0, 'life1, 'async_trait>(
             &'life0 self,
             new: &'life1 Account,
@@ -211,6 +238,30 @@ mock! {
             'life4: 'async_trait,
             Self: 'async_trait;
 
+        #[allow(clippy::type_complexity)]
+        fn get_protocol_components_at<'life0, 'life1, 'life2, 'life3, 'life4, 'async_trait>(
+            &'life0 self,
+            chain: &'life1 Chain,
+            system: &'life2 str,
+            version: &'life3 Version,
+            pagination_params: Option<&'life4 PaginationParams>,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<
+                    Output = Result<WithTotal<Vec<ProtocolComponent>>,
+                        StorageError,
+                    >,
+                > + ::core::marker::Send + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            'life1: 'async_trait,
+            'life2: 'async_trait,
+            'life3: 'async_trait,
+            'life4: 'async_trait,
+            Self: 'async_trait;
+
         #[allow(clippy::type_complexity)]
         fn get_token_owners<'life0, 'life1, 'life2, 'async_trait>(
             &'life0 self,
@@ -372,9 +423,25 @@ mock! {
             'life1: 'async_trait,
             Self: 'async_trait;
 
-        fn update_tokens<'life0, 'life1, 'async_trait>(
+        fn add_token_analyses<'life0, 'life1, 'async_trait>(
+            &'life0 self,
+            analyses: &'life1 [TokenAnalysis],
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<
+                    Output = Result<(), StorageError>,
+                > + ::core::marker::Send + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            'life1: 'async_trait,
+            Self: 'async_trait;
+
+        fn update_tokens<'life0, 'life1, 'life2, 'async_trait>(
             &'life0 self,
             tokens: &'life1 [CurrencyToken],
+            analyses: &'life2 [TokenAnalysis],
         ) -> ::core::pin::Pin<
             Box<
                 dyn ::core::future::Future<
@@ -385,6 +452,60 @@ mock! {
         where
             'life0: 'async_trait,
             'life1: 'async_trait,
+            'life2: 'async_trait,
+            Self: 'async_trait;
+
+        fn get_token_analyses<'life0, 'life1, 'life2, 'async_trait>(
+            &'life0 self,
+            chain: &'life1 Chain,
+            token: &'life2 Address,
+            limit: i64,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<
+                    Output = Result<Vec<TokenAnalysis>, StorageError>,
+                > + ::core::marker::Send + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            'life1: 'async_trait,
+            'life2: 'async_trait,
+            Self: 'async_trait;
+
+        fn get_protocol_types<'life0, 'life1, 'life2, 'async_trait>(
+            &'life0 self,
+            names: Option<&'life1 [&'life2 str]>,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<
+                    Output = Result<Vec<ProtocolType>, StorageError>,
+                > + ::core::marker::Send + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            'life1: 'async_trait,
+            'life2: 'async_trait,
+            Self: 'async_trait;
+
+        #[allow(clippy::type_complexity)]
+        fn get_component_tvls<'life0, 'life1, 'life2, 'life3, 'async_trait>(
+            &'life0 self,
+            chain: &'life1 Chain,
+            ids: Option<&'life2 [&'life3 str]>,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<
+                    Output = Result<HashMap<String, f64>, StorageError>,
+                > + ::core::marker::Send + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            'life1: 'async_trait,
+            'life2: 'async_trait,
+            'life3: 'async_trait,
             Self: 'async_trait;
 
         fn get_protocol_states_delta<'life0, 'life1, 'life2, 'life3, 'async_trait>(
@@ -502,6 +623,22 @@ mock! {
             'life1: 'async_trait,
             'life2: 'async_trait,
             Self: 'async_trait;
+
+        fn remove_protocol_system<'life0, 'life1, 'async_trait>(
+            &'life0 self,
+            system: &'life1 str,
+            dry_run: bool,
+        ) -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<
+                    Output = Result<ProtocolSystemRemoval, StorageError>,
+                > + ::core::marker::Send + 'async_trait,
+            >,
+        >
+        where
+            'life0: 'async_trait,
+            'life1: 'async_trait,
+            Self: 'async_trait;
     }
 
     impl Gateway for Gateway {}
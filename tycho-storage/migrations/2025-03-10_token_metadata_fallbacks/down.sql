@@ -0,0 +1,2 @@
+ALTER TABLE token
+DROP COLUMN metadata_fallbacks;
@@ -0,0 +1,2 @@
+ALTER TABLE token
+ADD COLUMN metadata_fallbacks TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,2 @@
+ALTER TABLE token
+ADD COLUMN flags TEXT[] NOT NULL DEFAULT '{}';
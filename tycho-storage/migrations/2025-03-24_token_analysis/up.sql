@@ -0,0 +1,23 @@
+CREATE TABLE IF NOT EXISTS token_analysis(
+    "id" bigserial PRIMARY KEY,
+    -- The token that was analyzed.
+    "token_id" bigint REFERENCES "token"(id) ON DELETE CASCADE NOT NULL,
+    -- Time at which the analysis ran.
+    "analyzed_at" timestamp NOT NULL,
+    -- The block the analysis ran at, NULL if it ran at the latest block.
+    "block_number" bigint,
+    -- One of good, bad or failed.
+    "outcome" varchar(16) NOT NULL,
+    -- Why the token was found bad or why the analysis failed.
+    "reason" text,
+    -- The token quality resulting from this analysis.
+    "quality" int NOT NULL,
+    -- The estimated amount of gas used per transfer.
+    "gas" bigint,
+    -- The tax the token charges on transfer.
+    "tax" bigint,
+    -- Timestamp this entry was inserted into this table.
+    "inserted_ts" timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_token_analysis_token_id_analyzed_at ON token_analysis(token_id, analyzed_at);
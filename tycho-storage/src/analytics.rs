@@ -0,0 +1,646 @@
+//! # Parquet analytics sink
+//!
+//! Writes the changes of finalized blocks into columnar Parquet files for offline
+//! analysis. The sink is meant to run next to the [`CachedGateway`] and is fed from the
+//! same finalized block drain, so it never sees blocks that could still be reverted.
+//!
+//! Three tables are written, each with a fixed schema:
+//!
+//! - `state_deltas`: one row per updated or deleted protocol component attribute.
+//! - `component_balances`: one row per component token balance change.
+//! - `component_tvl`: one row per component whose tvl changed.
+//!
+//! Files are partitioned hive style by protocol system and day of the block timestamp:
+//!
+//! ```text
+//! {root}/{table}/protocol_system={system}/date={YYYY-MM-DD}/{first_block}-{last_block}.parquet
+//! ```
+//!
+//! Parquet files can not be appended to once closed, so rows are buffered per partition
+//! and written as a new file once the buffer grows beyond the configured amount of rows,
+//! once blocks of a later day arrive, or when the sink is flushed or dropped. Files are
+//! first written to a temporary path and then renamed, readers will never observe a
+//! partially written file.
+//!
+//! Extractors flush the sink after every batch of finalized blocks, before the blocks and
+//! the cursor are committed to the database. After a crash, blocks exported but not yet
+//! committed are exported again, so readers should deduplicate rows by block.
+//!
+//! [`CachedGateway`]: crate::postgres::cache::CachedGateway
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use arrow_array::{
+    builder::{
+        BinaryBuilder, Float64Builder, StringBuilder, TimestampMicrosecondBuilder, UInt64Builder,
+    },
+    ArrayRef, RecordBatch,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{NaiveDate, NaiveDateTime};
+use parquet::{
+    arrow::ArrowWriter,
+    basic::{Compression, ZstdLevel},
+    file::properties::WriterProperties,
+};
+use tracing::{debug, error};
+use tycho_core::{
+    models::blockchain::{Block, BlockAggregatedChanges},
+    storage::StorageError,
+    Bytes,
+};
+
+/// Default amount of buffered rows per partition before a file is written.
+const DEFAULT_MAX_ROWS_PER_FILE: usize = 100_000;
+
+/// The tables written by the [`ParquetSink`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AnalyticsTable {
+    StateDeltas,
+    ComponentBalances,
+    ComponentTvl,
+}
+
+impl AnalyticsTable {
+    pub const ALL: [AnalyticsTable; 3] = [
+        AnalyticsTable::StateDeltas,
+        AnalyticsTable::ComponentBalances,
+        AnalyticsTable::ComponentTvl,
+    ];
+
+    /// Name of the table, used as top level directory.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AnalyticsTable::StateDeltas => "state_deltas",
+            AnalyticsTable::ComponentBalances => "component_balances",
+            AnalyticsTable::ComponentTvl => "component_tvl",
+        }
+    }
+
+    /// The arrow schema of the table.
+    ///
+    /// All tables start with the `block_number`, `block_hash` and `block_ts` columns,
+    /// followed by `component_id` and the table specific columns.
+    pub fn schema(&self) -> SchemaRef {
+        let mut fields = vec![
+            Field::new("block_number", DataType::UInt64, false),
+            Field::new("block_hash", DataType::Binary, false),
+            Field::new("block_ts", DataType::Timestamp(TimeUnit::Microsecond, None), false),
+            Field::new("component_id", DataType::Utf8, false),
+        ];
+        match self {
+            AnalyticsTable::StateDeltas => {
+                fields.push(Field::new("attribute", DataType::Utf8, false));
+                // null if the attribute was deleted
+                fields.push(Field::new("value", DataType::Binary, true));
+            }
+            AnalyticsTable::ComponentBalances => {
+                fields.push(Field::new("token", DataType::Binary, false));
+                fields.push(Field::new("balance", DataType::Binary, false));
+                fields.push(Field::new("balance_float", DataType::Float64, false));
+                fields.push(Field::new("modify_tx", DataType::Binary, false));
+            }
+            AnalyticsTable::ComponentTvl => {
+                fields.push(Field::new("tvl", DataType::Float64, false));
+            }
+        }
+        Arc::new(Schema::new(fields))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParquetSinkConfig {
+    /// Root directory, partitions are created below it.
+    pub root: PathBuf,
+    /// Amount of buffered rows per partition after which a file is written.
+    pub max_rows_per_file: usize,
+}
+
+impl ParquetSinkConfig {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into(), max_rows_per_file: DEFAULT_MAX_ROWS_PER_FILE }
+    }
+
+    pub fn max_rows_per_file(mut self, val: usize) -> Self {
+        self.max_rows_per_file = val;
+        self
+    }
+}
+
+/// Common block columns shared by all tables.
+#[derive(Debug, Clone)]
+struct BlockColumns {
+    number: u64,
+    hash: Bytes,
+    ts: NaiveDateTime,
+}
+
+impl From<&Block> for BlockColumns {
+    fn from(block: &Block) -> Self {
+        Self { number: block.number, hash: block.hash.clone(), ts: block.ts }
+    }
+}
+
+#[derive(Debug)]
+struct StateDeltaRow {
+    block: BlockColumns,
+    component_id: String,
+    attribute: String,
+    value: Option<Bytes>,
+}
+
+#[derive(Debug)]
+struct BalanceRow {
+    block: BlockColumns,
+    component_id: String,
+    token: Bytes,
+    balance: Bytes,
+    balance_float: f64,
+    modify_tx: Bytes,
+}
+
+#[derive(Debug)]
+struct TvlRow {
+    block: BlockColumns,
+    component_id: String,
+    tvl: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct PartitionKey {
+    protocol_system: String,
+    date: NaiveDate,
+}
+
+#[derive(Debug, Default)]
+struct PartitionBuffer {
+    first_block: u64,
+    last_block: u64,
+    state_deltas: Vec<StateDeltaRow>,
+    balances: Vec<BalanceRow>,
+    tvl: Vec<TvlRow>,
+}
+
+impl PartitionBuffer {
+    fn len(&self) -> usize {
+        self.state_deltas.len() + self.balances.len() + self.tvl.len()
+    }
+
+    fn push_block(&mut self, changes: &BlockAggregatedChanges) {
+        let block = BlockColumns::from(&changes.block);
+        if self.len() == 0 {
+            self.first_block = block.number;
+        }
+        self.last_block = block.number;
+
+        // Sort entries so output files are deterministic.
+        let mut state_deltas = changes
+            .state_deltas
+            .values()
+            .collect::<Vec<_>>();
+        state_deltas.sort_by(|a, b| a.component_id.cmp(&b.component_id));
+        for delta in state_deltas {
+            let mut updated = delta
+                .updated_attributes
+                .iter()
+                .collect::<Vec<_>>();
+            updated.sort_by(|a, b| a.0.cmp(b.0));
+            self.state_deltas.extend(
+                updated
+                    .into_iter()
+                    .map(|(attr, val)| StateDeltaRow {
+                        block: block.clone(),
+                        component_id: delta.component_id.clone(),
+                        attribute: attr.clone(),
+                        value: Some(val.clone()),
+                    }),
+            );
+            let mut deleted = delta
+                .deleted_attributes
+                .iter()
+                .collect::<Vec<_>>();
+            deleted.sort();
+            self.state_deltas.extend(
+                deleted
+                    .into_iter()
+                    .map(|attr| StateDeltaRow {
+                        block: block.clone(),
+                        component_id: delta.component_id.clone(),
+                        attribute: attr.clone(),
+                        value: None,
+                    }),
+            );
+        }
+
+        let mut balances = changes
+            .component_balances
+            .values()
+            .flat_map(|balances| balances.values())
+            .collect::<Vec<_>>();
+        balances.sort_by(|a, b| (&a.component_id, &a.token).cmp(&(&b.component_id, &b.token)));
+        self.balances.extend(
+            balances
+                .into_iter()
+                .map(|bal| BalanceRow {
+                    block: block.clone(),
+                    component_id: bal.component_id.clone(),
+                    token: bal.token.clone(),
+                    balance: bal.balance.clone(),
+                    balance_float: bal.balance_float,
+                    modify_tx: bal.modify_tx.clone(),
+                }),
+        );
+
+        let mut tvl = changes
+            .component_tvl
+            .iter()
+            .collect::<Vec<_>>();
+        tvl.sort_by(|a, b| a.0.cmp(b.0));
+        self.tvl.extend(
+            tvl.into_iter()
+                .map(|(component_id, tvl)| TvlRow {
+                    block: block.clone(),
+                    component_id: component_id.clone(),
+                    tvl: *tvl,
+                }),
+        );
+    }
+}
+
+/// Appends finalized block changes to partitioned Parquet files.
+///
+/// Blocks must be appended in ascending order, which is guaranteed if the sink is fed
+/// from the reorg buffer's finalized block drain.
+pub struct ParquetSink {
+    config: ParquetSinkConfig,
+    buffers: HashMap<PartitionKey, PartitionBuffer>,
+}
+
+impl ParquetSink {
+    pub fn new(config: ParquetSinkConfig) -> Self {
+        Self { config, buffers: HashMap::new() }
+    }
+
+    /// Buffers the changes of a finalized block.
+    ///
+    /// Writes out partitions of earlier days of the same protocol system, as well as the
+    /// partition of this block if it exceeds the configured amount of rows.
+    pub fn append(
+        &mut self,
+        protocol_system: &str,
+        changes: &BlockAggregatedChanges,
+    ) -> Result<(), StorageError> {
+        let key = PartitionKey {
+            protocol_system: protocol_system.to_string(),
+            date: changes.block.ts.date(),
+        };
+
+        let mut outdated = self
+            .buffers
+            .keys()
+            .filter(|k| k.protocol_system == key.protocol_system && k.date < key.date)
+            .cloned()
+            .collect::<Vec<_>>();
+        outdated.sort();
+        for k in outdated {
+            self.flush_partition(&k)?;
+        }
+
+        let buffer = self
+            .buffers
+            .entry(key.clone())
+            .or_default();
+        buffer.push_block(changes);
+        if buffer.len() >= self.config.max_rows_per_file {
+            self.flush_partition(&key)?;
+        }
+        Ok(())
+    }
+
+    /// Writes all buffered rows to disk.
+    pub fn flush(&mut self) -> Result<(), StorageError> {
+        let mut keys = self
+            .buffers
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort();
+        for key in keys {
+            self.flush_partition(&key)?;
+        }
+        Ok(())
+    }
+
+    fn flush_partition(&mut self, key: &PartitionKey) -> Result<(), StorageError> {
+        let Some(buffer) = self.buffers.remove(key) else {
+            return Ok(());
+        };
+        if buffer.len() == 0 {
+            return Ok(());
+        }
+        debug!(
+            protocol_system = key.protocol_system,
+            date = %key.date,
+            first_block = buffer.first_block,
+            last_block = buffer.last_block,
+            rows = buffer.len(),
+            "FlushingAnalyticsPartition"
+        );
+        for table in AnalyticsTable::ALL {
+            let batch = match table {
+                AnalyticsTable::StateDeltas if !buffer.state_deltas.is_empty() => {
+                    state_deltas_batch(&buffer.state_deltas)
+                }
+                AnalyticsTable::ComponentBalances if !buffer.balances.is_empty() => {
+                    balances_batch(&buffer.balances)
+                }
+                AnalyticsTable::ComponentTvl if !buffer.tvl.is_empty() => tvl_batch(&buffer.tvl),
+                _ => continue,
+            }?;
+            let dir = self.partition_dir(table, key);
+            let file_name = format!("{:012}-{:012}.parquet", buffer.first_block, buffer.last_block);
+            write_batch(&dir, &file_name, batch)?;
+        }
+        Ok(())
+    }
+
+    fn partition_dir(&self, table: AnalyticsTable, key: &PartitionKey) -> PathBuf {
+        self.config
+            .root
+            .join(table.name())
+            .join(format!("protocol_system={}", key.protocol_system))
+            .join(format!("date={}", key.date.format("%Y-%m-%d")))
+    }
+}
+
+impl Drop for ParquetSink {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            error!(?err, "Failed to flush analytics sink on drop");
+        }
+    }
+}
+
+fn parquet_err(err: impl std::fmt::Display) -> StorageError {
+    StorageError::Unexpected(format!("Parquet sink: {}", err))
+}
+
+fn block_columns<'a>(
+    blocks: impl Iterator<Item = &'a BlockColumns>,
+    capacity: usize,
+) -> Vec<ArrayRef> {
+    let mut number = UInt64Builder::with_capacity(capacity);
+    let mut hash = BinaryBuilder::with_capacity(capacity, capacity * 32);
+    let mut ts = TimestampMicrosecondBuilder::with_capacity(capacity);
+    for block in blocks {
+        number.append_value(block.number);
+        hash.append_value(&block.hash);
+        ts.append_value(block.ts.and_utc().timestamp_micros());
+    }
+    vec![Arc::new(number.finish()), Arc::new(hash.finish()), Arc::new(ts.finish())]
+}
+
+fn state_deltas_batch(rows: &[StateDeltaRow]) -> Result<RecordBatch, StorageError> {
+    let mut columns = block_columns(rows.iter().map(|r| &r.block), rows.len());
+    let mut component_id = StringBuilder::new();
+    let mut attribute = StringBuilder::new();
+    let mut value = BinaryBuilder::new();
+    for row in rows {
+        component_id.append_value(&row.component_id);
+        attribute.append_value(&row.attribute);
+        value.append_option(row.value.as_ref());
+    }
+    columns.push(Arc::new(component_id.finish()));
+    columns.push(Arc::new(attribute.finish()));
+    columns.push(Arc::new(value.finish()));
+    RecordBatch::try_new(AnalyticsTable::StateDeltas.schema(), columns).map_err(parquet_err)
+}
+
+fn balances_batch(rows: &[BalanceRow]) -> Result<RecordBatch, StorageError> {
+    let mut columns = block_columns(rows.iter().map(|r| &r.block), rows.len());
+    let mut component_id = StringBuilder::new();
+    let mut token = BinaryBuilder::new();
+    let mut balance = BinaryBuilder::new();
+    let mut balance_float = Float64Builder::with_capacity(rows.len());
+    let mut modify_tx = BinaryBuilder::new();
+    for row in rows {
+        component_id.append_value(&row.component_id);
+        token.append_value(&row.token);
+        balance.append_value(&row.balance);
+        balance_float.append_value(row.balance_float);
+        modify_tx.append_value(&row.modify_tx);
+    }
+    columns.push(Arc::new(component_id.finish()));
+    columns.push(Arc::new(token.finish()));
+    columns.push(Arc::new(balance.finish()));
+    columns.push(Arc::new(balance_float.finish()));
+    columns.push(Arc::new(modify_tx.finish()));
+    RecordBatch::try_new(AnalyticsTable::ComponentBalances.schema(), columns).map_err(parquet_err)
+}
+
+fn tvl_batch(rows: &[TvlRow]) -> Result<RecordBatch, StorageError> {
+    let mut columns = block_columns(rows.iter().map(|r| &r.block), rows.len());
+    let mut component_id = StringBuilder::new();
+    let mut tvl = Float64Builder::with_capacity(rows.len());
+    for row in rows {
+        component_id.append_value(&row.component_id);
+        tvl.append_value(row.tvl);
+    }
+    columns.push(Arc::new(component_id.finish()));
+    columns.push(Arc::new(tvl.finish()));
+    RecordBatch::try_new(AnalyticsTable::ComponentTvl.schema(), columns).map_err(parquet_err)
+}
+
+fn write_batch(dir: &Path, file_name: &str, batch: RecordBatch) -> Result<(), StorageError> {
+    fs::create_dir_all(dir).map_err(parquet_err)?;
+    let path = dir.join(file_name);
+    let tmp_path = dir.join(format!(".{}.tmp", file_name));
+
+    let props = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .build();
+    let file = File::create(&tmp_path).map_err(parquet_err)?;
+    let mut writer =
+        ArrowWriter::try_new(file, batch.schema(), Some(props)).map_err(parquet_err)?;
+    writer
+        .write(&batch)
+        .map_err(parquet_err)?;
+    writer.close().map_err(parquet_err)?;
+    fs::rename(&tmp_path, &path).map_err(parquet_err)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+
+    use arrow_array::{Array, BinaryArray, Float64Array, StringArray, UInt64Array};
+    use chrono::NaiveDateTime;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use tycho_core::models::{
+        protocol::{ComponentBalance, ProtocolComponentStateDelta},
+        Chain,
+    };
+
+    use super::*;
+
+    fn block_changes(number: u64, ts: &str) -> BlockAggregatedChanges {
+        let block = Block::new(
+            number,
+            Chain::Ethereum,
+            Bytes::from(number).lpad(32, 0),
+            Bytes::from(number - 1).lpad(32, 0),
+            NaiveDateTime::parse_from_str(ts, "%Y-%m-%dT%H:%M:%S").unwrap(),
+        );
+        let state_delta = ProtocolComponentStateDelta::new(
+            "pool_a",
+            HashMap::from([("reserve0".to_string(), Bytes::from(number))]),
+            HashSet::from(["stale".to_string()]),
+        );
+        let balance = ComponentBalance::new(
+            Bytes::from("0xaa"),
+            Bytes::from(number * 10),
+            (number * 10) as f64,
+            Bytes::from("0x01"),
+            "pool_a",
+        );
+        BlockAggregatedChanges {
+            extractor: "test".to_string(),
+            chain: Chain::Ethereum,
+            block,
+            finalized_block_height: number,
+            revert: false,
+            state_deltas: HashMap::from([("pool_a".to_string(), state_delta)]),
+            component_balances: HashMap::from([(
+                "pool_a".to_string(),
+                HashMap::from([(Bytes::from("0xaa"), balance)]),
+            )]),
+            component_tvl: HashMap::from([("pool_a".to_string(), number as f64)]),
+            ..Default::default()
+        }
+    }
+
+    fn read_table(path: &Path) -> RecordBatch {
+        let file = File::open(path).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut batches = reader
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 1);
+        batches.remove(0)
+    }
+
+    fn partition_file(root: &Path, table: AnalyticsTable, date: &str, file_name: &str) -> PathBuf {
+        root.join(table.name())
+            .join("protocol_system=test_protocol")
+            .join(format!("date={}", date))
+            .join(file_name)
+    }
+
+    #[test]
+    fn test_append_and_flush() {
+        let root = tempfile::tempdir().unwrap();
+        let mut sink = ParquetSink::new(ParquetSinkConfig::new(root.path()));
+
+        sink.append("test_protocol", &block_changes(1, "2024-06-01T10:00:00"))
+            .unwrap();
+        sink.append("test_protocol", &block_changes(2, "2024-06-01T23:59:59"))
+            .unwrap();
+        // nothing is written until the partition is complete
+        assert!(!root
+            .path()
+            .join(AnalyticsTable::StateDeltas.name())
+            .exists());
+
+        sink.append("test_protocol", &block_changes(3, "2024-06-02T00:00:11"))
+            .unwrap();
+        let states = read_table(&partition_file(
+            root.path(),
+            AnalyticsTable::StateDeltas,
+            "2024-06-01",
+            "000000000001-000000000002.parquet",
+        ));
+        assert_eq!(states.schema(), AnalyticsTable::StateDeltas.schema());
+        assert_eq!(states.num_rows(), 4);
+        let attributes = states
+            .column_by_name("attribute")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(attributes.value(0), "reserve0");
+        assert_eq!(attributes.value(1), "stale");
+        let values = states
+            .column_by_name("value")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<BinaryArray>()
+            .unwrap();
+        assert_eq!(values.value(0), Bytes::from(1u64).as_ref());
+        assert!(values.is_null(1));
+
+        drop(sink);
+        let balances = read_table(&partition_file(
+            root.path(),
+            AnalyticsTable::ComponentBalances,
+            "2024-06-02",
+            "000000000003-000000000003.parquet",
+        ));
+        assert_eq!(balances.num_rows(), 1);
+        let block_numbers = balances
+            .column_by_name("block_number")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        assert_eq!(block_numbers.value(0), 3);
+        let tvl = read_table(&partition_file(
+            root.path(),
+            AnalyticsTable::ComponentTvl,
+            "2024-06-02",
+            "000000000003-000000000003.parquet",
+        ));
+        let tvl = tvl
+            .column_by_name("tvl")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert_eq!(tvl.value(0), 3.0);
+    }
+
+    #[test]
+    fn test_flush_on_max_rows() {
+        let root = tempfile::tempdir().unwrap();
+        let mut sink = ParquetSink::new(ParquetSinkConfig::new(root.path()).max_rows_per_file(8));
+
+        for number in 1..=3 {
+            sink.append("test_protocol", &block_changes(number, "2024-06-01T10:00:00"))
+                .unwrap();
+        }
+
+        // each block produces 4 rows, so the second block fills the buffer
+        let first = partition_file(
+            root.path(),
+            AnalyticsTable::StateDeltas,
+            "2024-06-01",
+            "000000000001-000000000002.parquet",
+        );
+        assert!(first.exists());
+        sink.flush().unwrap();
+        let second = partition_file(
+            root.path(),
+            AnalyticsTable::StateDeltas,
+            "2024-06-01",
+            "000000000003-000000000003.parquet",
+        );
+        assert_eq!(read_table(&second).num_rows(), 2);
+    }
+}
@@ -3,4 +3,7 @@
 extern crate pretty_assertions;
 #[macro_use]
 extern crate lazy_static;
+#[cfg(feature = "analytics")]
+pub mod analytics;
+pub mod memory;
 pub mod postgres;
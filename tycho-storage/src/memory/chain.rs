@@ -0,0 +1,260 @@
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use tracing::warn;
+use tycho_core::{
+    models::{
+        blockchain::{Block, Transaction},
+        Chain, TxHash,
+    },
+    storage::{BlockIdentifier, ChainGateway, StorageError},
+};
+
+use super::MemoryGateway;
+
+#[async_trait]
+impl ChainGateway for MemoryGateway {
+    async fn upsert_block(&self, new: &[Block]) -> Result<(), StorageError> {
+        if new.is_empty() {
+            warn!("Upsert blocks called with empty blocks!");
+            return Ok(());
+        }
+        let mut state = self.state.write().await;
+        for block in new {
+            // assumes that block with the same hash will not appear with different values
+            if !state
+                .blocks
+                .iter()
+                .any(|b| b.hash == block.hash)
+            {
+                state.blocks.push(block.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_block(&self, id: &BlockIdentifier) -> Result<Block, StorageError> {
+        let state = self.state.read().await;
+        state.block(id).cloned()
+    }
+
+    async fn get_block_at_timestamp(
+        &self,
+        chain: &Chain,
+        ts: &NaiveDateTime,
+    ) -> Result<Block, StorageError> {
+        let state = self.state.read().await;
+        state
+            .blocks
+            .iter()
+            .filter(|b| &b.chain == chain && &b.ts <= ts)
+            .max_by_key(|b| b.number)
+            .cloned()
+            .ok_or_else(|| StorageError::NotFound("Block".to_owned(), ts.to_string()))
+    }
+
+    async fn upsert_tx(&self, new: &[Transaction]) -> Result<(), StorageError> {
+        if new.is_empty() {
+            warn!("Upsert tx called with empty transactions!");
+            return Ok(());
+        }
+        let mut state = self.state.write().await;
+        if let Some(missing) = new.iter().find(|tx| {
+            !state
+                .blocks
+                .iter()
+                .any(|b| b.hash == tx.block_hash)
+        }) {
+            return Err(StorageError::NoRelatedEntity(
+                "Block".to_string(),
+                "Transaction".to_string(),
+                format!("{}", missing.block_hash),
+            ));
+        }
+        for tx in new {
+            // assumes that tx with the same hash will not appear with different values
+            if !state
+                .transactions
+                .iter()
+                .any(|t| t.hash == tx.hash)
+            {
+                // postgres stores an empty `to` address for contract creations
+                let mut tx = tx.clone();
+                tx.to = Some(tx.to.unwrap_or_default());
+                state.transactions.push(tx);
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_tx(&self, hash: &TxHash) -> Result<Transaction, StorageError> {
+        let state = self.state.read().await;
+        state
+            .transactions
+            .iter()
+            .find(|tx| &tx.hash == hash)
+            .cloned()
+            .ok_or_else(|| StorageError::NotFound("Transaction".to_owned(), hex::encode(hash)))
+    }
+
+    async fn revert_state(&self, to: &BlockIdentifier) -> Result<(), StorageError> {
+        let mut state = self.state.write().await;
+        let block = state.block(to)?.clone();
+
+        // Remove all blocks after the `to` block, the `to` block and its connected data persists.
+        let (reverted_blocks, blocks): (Vec<_>, Vec<_>) = std::mem::take(&mut state.blocks)
+            .into_iter()
+            .partition(|b| b.chain == block.chain && b.number > block.number);
+        state.blocks = blocks;
+        let reverted_blocks: HashSet<_> = reverted_blocks
+            .into_iter()
+            .map(|b| b.hash)
+            .collect();
+
+        let (reverted_txs, txs): (Vec<_>, Vec<_>) = std::mem::take(&mut state.transactions)
+            .into_iter()
+            .partition(|tx| reverted_blocks.contains(&tx.block_hash));
+        state.transactions = txs;
+        let reverted_txs: HashSet<_> = reverted_txs
+            .into_iter()
+            .map(|tx| tx.hash)
+            .collect();
+
+        let is_reverted = |tx: &Option<TxHash>| {
+            tx.as_ref()
+                .is_some_and(|h| reverted_txs.contains(h))
+        };
+
+        // Entities created by reverted transactions are removed, all other entities get their
+        // versions reverted.
+        state
+            .accounts
+            .retain(|a| !is_reverted(&a.creation_tx));
+        for account in state.accounts.iter_mut() {
+            if is_reverted(&account.deletion_tx) ||
+                account
+                    .deleted_at
+                    .is_some_and(|ts| ts > block.ts)
+            {
+                account.deletion_tx = None;
+                account.deleted_at = None;
+            }
+            account
+                .code
+                .revert(&block.ts, &reverted_txs);
+            for history in account.balances.values_mut() {
+                history.revert(&block.ts, &reverted_txs);
+            }
+            account
+                .balances
+                .retain(|_, history| !history.is_empty());
+            for history in account.slots.values_mut() {
+                history.revert(&block.ts, &reverted_txs);
+            }
+            account
+                .slots
+                .retain(|_, history| !history.is_empty());
+        }
+
+        state
+            .components
+            .retain(|c| !reverted_txs.contains(&c.component.creation_tx));
+        for component in state.components.iter_mut() {
+            if component
+                .deleted_at
+                .is_some_and(|ts| ts > block.ts)
+            {
+                component.deleted_at = None;
+            }
+            for history in component.state.values_mut() {
+                history.revert(&block.ts, &reverted_txs);
+            }
+            component
+                .state
+                .retain(|_, history| !history.is_empty());
+            for history in component.balances.values_mut() {
+                history.revert(&block.ts, &reverted_txs);
+            }
+            component
+                .balances
+                .retain(|_, history| !history.is_empty());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::DateTime;
+    use tycho_core::{models::Chain, Bytes};
+
+    use super::*;
+
+    fn block(number: u64) -> Block {
+        Block::new(
+            number,
+            Chain::Ethereum,
+            Bytes::from(number.to_be_bytes()).lpad(32, 0),
+            Bytes::from((number - 1).to_be_bytes()).lpad(32, 0),
+            DateTime::from_timestamp(number as i64 * 12, 0)
+                .map(|d| d.naive_utc())
+                .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_get_block_latest() {
+        let gw = MemoryGateway::new(&[Chain::Ethereum], &[]);
+        gw.upsert_block(&[block(1), block(2)])
+            .await
+            .unwrap();
+
+        let latest = gw
+            .get_block(&BlockIdentifier::Latest(Chain::Ethereum))
+            .await
+            .unwrap();
+
+        assert_eq!(latest, block(2));
+    }
+
+    #[tokio::test]
+    async fn test_upsert_tx_missing_block() {
+        let gw = MemoryGateway::new(&[Chain::Ethereum], &[]);
+        let tx = Transaction::new(Bytes::from("0x01"), block(1).hash, Bytes::from("0x02"), None, 0);
+
+        let res = gw.upsert_tx(&[tx]).await;
+
+        assert!(matches!(res, Err(StorageError::NoRelatedEntity(..))));
+    }
+
+    #[tokio::test]
+    async fn test_revert_removes_blocks_and_txs() {
+        let gw = MemoryGateway::new(&[Chain::Ethereum], &[]);
+        gw.upsert_block(&[block(1), block(2), block(3)])
+            .await
+            .unwrap();
+        let tx = Transaction::new(
+            Bytes::from("0x01"),
+            block(3).hash,
+            Bytes::from("0x02"),
+            Some(Bytes::from("0x03")),
+            0,
+        );
+        gw.upsert_tx(std::slice::from_ref(&tx))
+            .await
+            .unwrap();
+
+        gw.revert_state(&BlockIdentifier::Number((Chain::Ethereum, 1)))
+            .await
+            .unwrap();
+
+        let latest = gw
+            .get_block(&BlockIdentifier::Latest(Chain::Ethereum))
+            .await
+            .unwrap();
+        assert_eq!(latest, block(1));
+        assert!(gw.get_tx(&tx.hash).await.is_err());
+    }
+}
@@ -0,0 +1,644 @@
+use std::collections::{HashMap, HashSet};
+
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use tycho_core::{
+    keccak256,
+    models::{
+        contract::{Account, AccountBalance, AccountDelta},
+        Address, Balance, Chain, ChangeType, Code, CodeHash, ContractId, ContractStore,
+        PaginationParams, StoreKey, StoreVal, TxHash,
+    },
+    storage::{BlockOrTimestamp, ContractStateGateway, StorageError, Version, WithTotal},
+    Bytes,
+};
+
+use super::{AccountEntry, MemoryGateway, MemoryState, TxRef};
+
+/// A pending write to one of the versioned account tables.
+enum AccountWrite {
+    Balance(Address, Balance),
+    Code(Code, CodeHash),
+    Slot(StoreKey, Option<StoreVal>),
+}
+
+impl MemoryState {
+    fn account_balances(
+        &self,
+        chain: &Chain,
+        accounts: Option<&[Address]>,
+        version_ts: Option<&NaiveDateTime>,
+        include_native: bool,
+    ) -> HashMap<Address, HashMap<Address, AccountBalance>> {
+        let native_token = chain.native_token().address;
+        self.accounts
+            .iter()
+            .filter(|a| &a.chain == chain)
+            .filter(|a| accounts.is_none_or(|addresses| addresses.contains(&a.address)))
+            .filter_map(|a| {
+                let valid: Vec<_> = a
+                    .balances
+                    .iter()
+                    .filter_map(|(token, history)| {
+                        history
+                            .at(version_ts)
+                            .map(|v| (token, &v.value))
+                    })
+                    .collect();
+                if valid.is_empty() {
+                    return None;
+                }
+                let balances = valid
+                    .into_iter()
+                    .filter(|(token, _)| include_native || token != &&native_token)
+                    .map(|(token, balance)| {
+                        (
+                            token.clone(),
+                            AccountBalance::new(
+                                a.address.clone(),
+                                token.clone(),
+                                balance.clone(),
+                                TxHash::from(
+                                    "0x0000000000000000000000000000000000000000000000000000000000000000",
+                                ),
+                            ),
+                        )
+                    })
+                    .collect();
+                Some((a.address.clone(), balances))
+            })
+            .collect()
+    }
+
+    /// Builds an account from its stored entry at the given version timestamp.
+    fn build_account(
+        &self,
+        entry: &AccountEntry,
+        balances: &mut HashMap<Address, HashMap<Address, AccountBalance>>,
+        version_ts: &NaiveDateTime,
+        include_slots: bool,
+    ) -> Result<Account, StorageError> {
+        let chain = entry.chain;
+        let account_balances = balances
+            .get_mut(&entry.address)
+            .ok_or_else(|| {
+                StorageError::NotFound("account_balances".to_string(), entry.address.to_string())
+            })?;
+        let native_balance = account_balances
+            .remove(&chain.native_token().address)
+            .ok_or_else(|| {
+                StorageError::NotFound("native_balance".to_string(), entry.address.to_string())
+            })?;
+        let code = entry
+            .code
+            .at(Some(version_ts))
+            .ok_or_else(|| {
+                StorageError::NoRelatedEntity(
+                    "ContractCode".to_owned(),
+                    hex::encode(&entry.address),
+                    "Account".to_owned(),
+                )
+            })?;
+        let slots = if include_slots {
+            entry
+                .slots
+                .iter()
+                .filter_map(|(slot, history)| {
+                    history
+                        .at(Some(version_ts))
+                        .map(|v| (slot.clone(), v.value.clone().unwrap_or_default()))
+                })
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        Ok(Account::new(
+            chain,
+            entry.address.clone(),
+            entry.title.clone(),
+            slots,
+            native_balance.balance,
+            account_balances.clone(),
+            code.value.0.clone(),
+            code.value.1.clone(),
+            // TODO: remove balance_modify_tx from Account
+            Bytes::zero(32),
+            code.modify_tx.clone(),
+            entry.creation_tx.clone(),
+        ))
+    }
+
+    fn contracts(
+        &self,
+        chain: &Chain,
+        addresses: Option<&[Address]>,
+        version_ts: Option<NaiveDateTime>,
+        include_slots: bool,
+        pagination_params: Option<&PaginationParams>,
+    ) -> Result<WithTotal<Vec<Account>>, StorageError> {
+        let balances_ts = version_ts;
+        let version_ts = version_ts.unwrap_or_else(|| Utc::now().naive_utc());
+
+        let matching: Vec<_> = self
+            .accounts
+            .iter()
+            .filter(|a| &a.chain == chain && a.exists_at(&version_ts))
+            .filter(|a| addresses.is_none_or(|addresses| addresses.contains(&a.address)))
+            .collect();
+        let total = matching.len() as i64;
+        let page: Vec<_> = match pagination_params {
+            Some(pagination) => matching
+                .into_iter()
+                .skip(pagination.offset() as usize)
+                .take(pagination.page_size as usize)
+                .collect(),
+            None => matching,
+        };
+
+        let with_code = page
+            .iter()
+            .filter(|a| a.code.at(Some(&version_ts)).is_some())
+            .count();
+        if with_code != page.len() {
+            return Err(StorageError::Unexpected(format!(
+                "Some accounts were missing either code. Got {} accounts and {} code entries.",
+                page.len(),
+                with_code,
+            )));
+        }
+
+        let mut balances = self.account_balances(chain, addresses, balances_ts.as_ref(), true);
+        let accounts = page
+            .into_iter()
+            .map(|entry| self.build_account(entry, &mut balances, &version_ts, include_slots))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(WithTotal { entity: accounts, total: Some(total) })
+    }
+
+    fn account_deltas(
+        &self,
+        chain: &Chain,
+        start_version_ts: &NaiveDateTime,
+        target_version_ts: &NaiveDateTime,
+    ) -> Result<Vec<AccountDelta>, StorageError> {
+        let native_token = chain.native_token().address;
+        let forward = start_version_ts <= target_version_ts;
+        let (start, end) = if forward {
+            (start_version_ts, target_version_ts)
+        } else {
+            (target_version_ts, start_version_ts)
+        };
+
+        let accounts: Vec<_> = self
+            .accounts
+            .iter()
+            .filter(|a| &a.chain == chain)
+            .collect();
+
+        // Find created or deleted accounts, handling both within the range is currently not
+        // supported, see the postgres backend for details.
+        let in_range = |ts: Option<NaiveDateTime>| ts.is_some_and(|ts| start < &ts && &ts <= end);
+        let cod_accounts: Vec<_> = accounts
+            .iter()
+            .filter(|a| in_range(a.created_at) || in_range(a.deleted_at))
+            .collect();
+        if cod_accounts
+            .iter()
+            .any(|a| in_range(a.created_at) && in_range(a.deleted_at))
+        {
+            return Err(StorageError::Unexpected(format!(
+                "Found account that was deleted and created within range {} - {}!",
+                start_version_ts, target_version_ts
+            )));
+        }
+
+        let (created, restored): (HashSet<Address>, HashMap<Address, AccountDelta>) = if forward {
+            let created = cod_accounts
+                .iter()
+                .filter(|a| a.deleted_at.is_none())
+                .map(|a| a.address.clone())
+                .collect();
+            let deleted = cod_accounts
+                .iter()
+                .filter(|a| a.deleted_at.is_some())
+                .map(|a| (a.address.clone(), AccountDelta::deleted(chain, &a.address)))
+                .collect();
+            (created, deleted)
+        } else {
+            // Restore the full state at the target version for accounts that were deleted.
+            let deleted_addresses: Vec<_> = cod_accounts
+                .iter()
+                .filter(|a| a.deleted_at.is_some())
+                .map(|a| a.address.clone())
+                .collect();
+            let restored: HashMap<Address, AccountDelta> = self
+                .contracts(chain, Some(&deleted_addresses), Some(*target_version_ts), true, None)?
+                .entity
+                .into_iter()
+                .map(|acc| (acc.address.clone(), acc.into()))
+                .collect();
+            let deltas = cod_accounts
+                .iter()
+                .map(|a| {
+                    let delta = restored
+                        .get(&a.address)
+                        .cloned()
+                        .unwrap_or_else(|| AccountDelta::deleted(chain, &a.address));
+                    (a.address.clone(), delta)
+                })
+                .collect();
+            (HashSet::new(), deltas)
+        };
+
+        let mut deltas = HashMap::new();
+        for account in accounts {
+            let balance_changed = account.balances.values().any(|h| {
+                h.changed_within(start, end)
+                    .next()
+                    .is_some()
+            });
+            let balance = if balance_changed {
+                account
+                    .balances
+                    .get(&native_token)
+                    .and_then(|h| h.at(Some(target_version_ts)))
+                    .map(|v| v.value.clone())
+            } else {
+                None
+            };
+
+            let code = if account
+                .code
+                .changed_within(start, end)
+                .next()
+                .is_some()
+            {
+                account
+                    .code
+                    .at(Some(target_version_ts))
+                    .map(|v| v.value.0.clone())
+            } else {
+                None
+            };
+
+            let slots: ContractStore = account
+                .slots
+                .iter()
+                .filter_map(|(slot, history)| {
+                    let mut changes = history.changed_within(start, end);
+                    let value = if forward {
+                        // the latest change within the range
+                        changes.last()?.value.clone()
+                    } else {
+                        // the value before the first change within the range
+                        changes
+                            .next()?
+                            .previous_value
+                            .clone()
+                            .flatten()
+                    };
+                    Some((slot.clone(), value))
+                })
+                .collect();
+
+            if balance.is_none() && code.is_none() && slots.is_empty() {
+                continue;
+            }
+            let change = if created.contains(&account.address) {
+                ChangeType::Creation
+            } else {
+                ChangeType::Update
+            };
+            deltas.insert(
+                account.address.clone(),
+                AccountDelta::new(*chain, account.address.clone(), slots, balance, code, change),
+            );
+        }
+        deltas.extend(restored);
+        Ok(deltas.into_values().collect())
+    }
+}
+
+#[async_trait]
+impl ContractStateGateway for MemoryGateway {
+    async fn get_contract(
+        &self,
+        id: &ContractId,
+        version: Option<&Version>,
+        include_slots: bool,
+    ) -> Result<Account, StorageError> {
+        let state = self.state.read().await;
+        let entry = state
+            .account(&id.chain, &id.address)
+            .ok_or_else(|| {
+                StorageError::NotFound("Account".to_owned(), hex::encode(&id.address))
+            })?;
+        let version_ts = version
+            .map(|v| state.version_ts(v))
+            .transpose()?;
+        let mut balances = state.account_balances(
+            &id.chain,
+            Some(std::slice::from_ref(&id.address)),
+            version_ts.as_ref(),
+            true,
+        );
+        state.build_account(
+            entry,
+            &mut balances,
+            &version_ts.unwrap_or_else(|| Utc::now().naive_utc()),
+            include_slots,
+        )
+    }
+
+    async fn get_contracts(
+        &self,
+        chain: &Chain,
+        addresses: Option<&[Address]>,
+        version: Option<&Version>,
+        include_slots: bool,
+        pagination_params: Option<&PaginationParams>,
+    ) -> Result<WithTotal<Vec<Account>>, StorageError> {
+        let state = self.state.read().await;
+        let version_ts = version
+            .map(|v| state.version_ts(v))
+            .transpose()?;
+        state.contracts(chain, addresses, version_ts, include_slots, pagination_params)
+    }
+
+    async fn sample_contract_slots(
+        &self,
+        chain: &Chain,
+        address: &Address,
+        version: Option<&Version>,
+        limit: usize,
+    ) -> Result<HashMap<StoreKey, StoreVal>, StorageError> {
+        let state = self.state.read().await;
+        let version_ts = match version {
+            Some(version) => state.version_ts(version)?,
+            None => Utc::now().naive_utc(),
+        };
+        // Hash map iteration order is already arbitrary, which is good enough for sampling.
+        Ok(state
+            .account(chain, address)
+            .map(|entry| {
+                entry
+                    .slots
+                    .iter()
+                    .filter_map(|(slot, history)| {
+                        history
+                            .at(Some(&version_ts))
+                            .map(|v| (slot.clone(), v.value.clone().unwrap_or_default()))
+                    })
+                    .take(limit)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn upsert_contract(&self, new: &Account) -> Result<(), StorageError> {
+        let mut state = self.state.write().await;
+        let creation_tx = match &new.creation_tx {
+            Some(hash) => Some(state.tx(hash).ok_or_else(|| {
+                StorageError::NoRelatedEntity(
+                    "Transaction".to_owned(),
+                    hex::encode(hash),
+                    "Account".to_owned(),
+                )
+            })?),
+            None => None,
+        };
+        let created_at = creation_tx
+            .as_ref()
+            .map(|tx| tx.ts)
+            .unwrap_or_else(|| Utc::now().naive_utc());
+
+        if state
+            .account(&new.chain, &new.address)
+            .is_none()
+        {
+            state.accounts.push(AccountEntry::new(
+                new.chain,
+                new.address.clone(),
+                new.title.clone(),
+            ));
+        }
+        let entry = state
+            .account_mut(&new.chain, &new.address)
+            .expect("account was inserted");
+        entry.title.clone_from(&new.title);
+        entry
+            .creation_tx
+            .clone_from(&new.creation_tx);
+        entry.created_at = Some(created_at);
+
+        // we can only insert balance and contract_code if we have a creation transaction.
+        if let Some(tx) = creation_tx {
+            entry
+                .balances
+                .entry(new.chain.native_token().address)
+                .or_default()
+                .push(new.native_balance.clone(), &tx);
+            entry
+                .code
+                .push((new.code.clone(), new.code_hash.clone()), &tx);
+            for (slot, value) in new.slots.iter() {
+                entry
+                    .slots
+                    .entry(slot.clone())
+                    .or_default()
+                    .push(Some(value.clone()), &tx);
+            }
+        }
+        Ok(())
+    }
+
+    async fn update_contracts(&self, new: &[(TxHash, AccountDelta)]) -> Result<(), StorageError> {
+        let mut state = self.state.write().await;
+
+        let mut writes: Vec<(TxRef, ContractId, AccountWrite)> = Vec::new();
+        let mut slot_updates: HashSet<(TxHash, Address)> = HashSet::new();
+        for (tx_hash, delta) in new.iter() {
+            let contract_id = delta.contract_id();
+            if state
+                .account(&contract_id.chain, &contract_id.address)
+                .is_none()
+            {
+                return Err(StorageError::NotFound(
+                    "Account".to_owned(),
+                    hex::encode(&contract_id.address),
+                ));
+            }
+            let tx = state.tx(tx_hash).ok_or_else(|| {
+                StorageError::NoRelatedEntity(
+                    "Transaction".to_owned(),
+                    "Account".to_owned(),
+                    hex::encode(tx_hash),
+                )
+            })?;
+
+            if let Some(balance) = delta.balance.clone() {
+                writes.push((
+                    tx.clone(),
+                    contract_id.clone(),
+                    AccountWrite::Balance(delta.chain.native_token().address, balance),
+                ));
+            }
+            if let Some(code) = delta.code.clone() {
+                let hash = keccak256(&code);
+                writes.push((
+                    tx.clone(),
+                    contract_id.clone(),
+                    AccountWrite::Code(code, hash.into()),
+                ));
+            }
+            if !delta.slots.is_empty() {
+                if !slot_updates.insert((tx_hash.clone(), contract_id.address.clone())) {
+                    return Err(StorageError::Unexpected(format!(
+                        "Ambiguous update! Contract 0x{} received different updates in same tx!",
+                        hex::encode(&contract_id.address)
+                    )));
+                }
+                for (slot, value) in delta.slots.iter() {
+                    writes.push((
+                        tx.clone(),
+                        contract_id.clone(),
+                        AccountWrite::Slot(slot.clone(), value.clone()),
+                    ));
+                }
+            }
+        }
+
+        writes.sort_by_key(|(tx, _, _)| (tx.ts, tx.index));
+        for (tx, id, write) in writes {
+            let entry = state
+                .account_mut(&id.chain, &id.address)
+                .expect("account existence was checked");
+            match write {
+                AccountWrite::Balance(token, balance) => entry
+                    .balances
+                    .entry(token)
+                    .or_default()
+                    .push(balance, &tx),
+                AccountWrite::Code(code, hash) => entry.code.push((code, hash), &tx),
+                AccountWrite::Slot(slot, value) => entry
+                    .slots
+                    .entry(slot)
+                    .or_default()
+                    .push(value, &tx),
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete_contract(&self, id: &ContractId, at_tx: &TxHash) -> Result<(), StorageError> {
+        let mut state = self.state.write().await;
+        let tx = state.tx(at_tx);
+        let entry = state
+            .account_mut(&id.chain, &id.address)
+            .ok_or_else(|| StorageError::NotFound("Account".to_owned(), id.to_string()))?;
+        let tx = tx.ok_or_else(|| {
+            StorageError::NoRelatedEntity(
+                "Account".to_owned(),
+                hex::encode(at_tx),
+                "Transaction".to_owned(),
+            )
+        })?;
+        if let Some(deletion_tx) = &entry.deletion_tx {
+            if deletion_tx != at_tx {
+                return Err(StorageError::Unexpected(format!(
+                    "Account {} was already deleted at {:?}!",
+                    hex::encode(&entry.address),
+                    entry.deleted_at,
+                )));
+            }
+            // Noop if called twice on deleted contract
+            return Ok(());
+        }
+        entry.deletion_tx = Some(at_tx.clone());
+        entry.deleted_at = Some(tx.ts);
+        for history in entry.slots.values_mut() {
+            history.delete(tx.ts);
+        }
+        for history in entry.balances.values_mut() {
+            history.delete(tx.ts);
+        }
+        entry.code.delete(tx.ts);
+        Ok(())
+    }
+
+    async fn get_accounts_delta(
+        &self,
+        chain: &Chain,
+        start_version: Option<&BlockOrTimestamp>,
+        end_version: &BlockOrTimestamp,
+    ) -> Result<Vec<AccountDelta>, StorageError> {
+        let state = self.state.read().await;
+        let start_version_ts = match start_version {
+            Some(version) => state.block_ts(version)?,
+            None => Utc::now().naive_utc(),
+        };
+        let target_version_ts = state.block_ts(end_version)?;
+        state.account_deltas(chain, &start_version_ts, &target_version_ts)
+    }
+
+    async fn add_account_balances(
+        &self,
+        account_balances: &[AccountBalance],
+    ) -> Result<(), StorageError> {
+        let mut state = self.state.write().await;
+
+        let mut writes = Vec::with_capacity(account_balances.len());
+        for balance in account_balances.iter() {
+            if !state
+                .tokens
+                .iter()
+                .any(|t| t.address == balance.token)
+            {
+                return Err(StorageError::NotFound("Token".to_owned(), hex::encode(&balance.token)));
+            }
+            let tx = state
+                .tx(&balance.modify_tx)
+                .ok_or_else(|| {
+                    StorageError::NotFound(
+                        "Transaction".to_owned(),
+                        hex::encode(&balance.modify_tx),
+                    )
+                })?;
+            if state
+                .account(&tx.chain, &balance.account)
+                .is_none()
+            {
+                return Err(StorageError::NotFound(
+                    "Account".to_owned(),
+                    hex::encode(&balance.account),
+                ));
+            }
+            writes.push((tx, balance));
+        }
+
+        writes.sort_by_key(|(tx, _)| (tx.ts, tx.index));
+        for (tx, balance) in writes {
+            state
+                .account_mut(&tx.chain, &balance.account)
+                .expect("account existence was checked")
+                .balances
+                .entry(balance.token.clone())
+                .or_default()
+                .push(balance.balance.clone(), &tx);
+        }
+        Ok(())
+    }
+
+    async fn get_account_balances(
+        &self,
+        chain: &Chain,
+        accounts: Option<&[Address]>,
+        version: Option<&Version>,
+    ) -> Result<HashMap<Address, HashMap<Address, AccountBalance>>, StorageError> {
+        let state = self.state.read().await;
+        let version_ts = version
+            .map(|v| state.version_ts(v))
+            .transpose()?;
+        Ok(state.account_balances(chain, accounts, version_ts.as_ref(), false))
+    }
+}
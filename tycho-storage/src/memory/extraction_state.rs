@@ -0,0 +1,31 @@
+use async_trait::async_trait;
+use tycho_core::{
+    models::{Chain, ExtractionState},
+    storage::{BlockIdentifier, ExtractionStateGateway, StorageError},
+};
+
+use super::MemoryGateway;
+
+#[async_trait]
+impl ExtractionStateGateway for MemoryGateway {
+    async fn get_state(&self, name: &str, chain: &Chain) -> Result<ExtractionState, StorageError> {
+        let state = self.state.read().await;
+        state
+            .extraction_states
+            .get(&(name.to_owned(), *chain))
+            .cloned()
+            .ok_or_else(|| StorageError::NotFound("ExtractionState".to_owned(), name.to_owned()))
+    }
+
+    async fn save_state(&self, new: &ExtractionState) -> Result<(), StorageError> {
+        let mut state = self.state.write().await;
+        // the referenced block has to exist, same as for the postgres backend
+        state
+            .block(&BlockIdentifier::Hash(new.block_hash.clone()))
+            .map_err(|_| StorageError::NotFound("ExtractionState".to_owned(), new.name.clone()))?;
+        state
+            .extraction_states
+            .insert((new.name.clone(), new.chain), new.clone());
+        Ok(())
+    }
+}
@@ -0,0 +1,439 @@
+//! # In-memory storage backend
+//!
+//! This backend keeps all data in process memory and provides implementations for the traits
+//! defined in the storage module. It is intended for tests and short-lived tooling that should
+//! not depend on a running database.
+//!
+//! ## Semantics
+//!
+//! The in-memory gateway mirrors the behaviour of the postgres backend as closely as possible,
+//! this includes:
+//!
+//! * **Versioning**: Contract storage, contract code, account balances, component balances and
+//!   protocol state keep their full history. A version is valid at timestamp `ts` if `valid_from <=
+//!   ts < valid_to`. Currently active versions use [`MAX_TS`] as their end version. Within a block,
+//!   versions are ordered by the index of the transaction that modified them.
+//!
+//! * **Reverts**: Reverting to a block removes all later blocks of the same chain together with
+//!   their transactions and any entity that was created or modified by those transactions. Versions
+//!   that were invalidated after the target block become active again.
+//!
+//! * **Pagination**: Entities are returned in insertion order, which corresponds to the primary key
+//!   order used by the postgres backend. Totals are computed the same way, e.g. some protocol state
+//!   queries only report totals if pagination was requested.
+//!
+//! Writes are applied immediately, there is no write cache or transaction batching.
+//!
+//! ## Limitations
+//!
+//! The retention horizon is not supported, all historical versions are kept indefinitely.
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::Arc,
+};
+
+use chrono::NaiveDateTime;
+use tokio::sync::RwLock;
+use tycho_core::{
+    models::{
+        blockchain::{Block, Transaction},
+        protocol::ProtocolComponent,
+        token::{CurrencyToken, TokenAnalysis},
+        Address, AttrStoreKey, Balance, Chain, Code, CodeHash, ExtractionState, ProtocolType,
+        StoreKey, StoreVal, TxHash,
+    },
+    storage::{BlockIdentifier, BlockOrTimestamp, Gateway, StorageError, Version, VersionKind},
+};
+
+mod chain;
+mod contract;
+mod extraction_state;
+mod protocol;
+
+/// End version used for currently active versions.
+const MAX_TS: NaiveDateTime = NaiveDateTime::MAX;
+
+/// A single version of a versioned entity.
+#[derive(Debug, Clone, PartialEq)]
+struct Versioned<T> {
+    value: T,
+    /// The value of the version this version replaced, `None` if there was no active version.
+    previous_value: Option<T>,
+    modify_tx: TxHash,
+    tx_index: u64,
+    valid_from: NaiveDateTime,
+    valid_to: NaiveDateTime,
+}
+
+impl<T> Versioned<T> {
+    fn is_valid_at(&self, ts: &NaiveDateTime) -> bool {
+        &self.valid_from <= ts && &self.valid_to > ts
+    }
+
+    fn is_latest(&self) -> bool {
+        self.valid_to == MAX_TS
+    }
+}
+
+/// The full version history of a single entity, ordered by ascending execution order.
+#[derive(Debug, Clone, PartialEq)]
+struct History<T>(Vec<Versioned<T>>);
+
+impl<T> Default for History<T> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<T: Clone> History<T> {
+    /// Adds a new version, archiving the currently active one.
+    ///
+    /// New versions are expected to be pushed in ascending execution order.
+    fn push(&mut self, value: T, tx: &TxRef) {
+        let previous_value = match self.0.last_mut() {
+            Some(latest) if latest.is_latest() => {
+                latest.valid_to = tx.ts;
+                Some(latest.value.clone())
+            }
+            _ => None,
+        };
+        self.0.push(Versioned {
+            value,
+            previous_value,
+            modify_tx: tx.hash.clone(),
+            tx_index: tx.index,
+            valid_from: tx.ts,
+            valid_to: MAX_TS,
+        });
+    }
+
+    /// Ends the currently active version at `ts`.
+    ///
+    /// Returns false if there is no active version.
+    fn delete(&mut self, ts: NaiveDateTime) -> bool {
+        match self.0.last_mut() {
+            Some(latest) if latest.is_latest() => {
+                latest.valid_to = ts;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the version valid at `ts`, or the currently active version if `ts` is `None`.
+    fn at(&self, ts: Option<&NaiveDateTime>) -> Option<&Versioned<T>> {
+        match ts {
+            Some(ts) => self
+                .0
+                .iter()
+                .rev()
+                .find(|v| v.is_valid_at(ts)),
+            None => self.0.last().filter(|v| v.is_latest()),
+        }
+    }
+
+    /// Returns all versions that became valid within `(start, end]`.
+    fn changed_within<'a>(
+        &'a self,
+        start: &'a NaiveDateTime,
+        end: &'a NaiveDateTime,
+    ) -> impl Iterator<Item = &'a Versioned<T>> + 'a {
+        self.0
+            .iter()
+            .filter(move |v| start < &v.valid_from && &v.valid_from <= end)
+    }
+
+    /// Removes versions created by reverted transactions and reactivates versions that were
+    /// invalidated after `ts`.
+    fn revert(&mut self, ts: &NaiveDateTime, reverted_txs: &HashSet<TxHash>) {
+        self.0
+            .retain(|v| !reverted_txs.contains(&v.modify_tx));
+        for v in self.0.iter_mut() {
+            if &v.valid_to > ts {
+                v.valid_to = MAX_TS;
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// A stored transaction resolved to its position in the chain.
+#[derive(Debug, Clone)]
+struct TxRef {
+    hash: TxHash,
+    index: u64,
+    ts: NaiveDateTime,
+    chain: Chain,
+}
+
+#[derive(Debug, Clone)]
+struct AccountEntry {
+    chain: Chain,
+    address: Address,
+    title: String,
+    creation_tx: Option<TxHash>,
+    created_at: Option<NaiveDateTime>,
+    deletion_tx: Option<TxHash>,
+    deleted_at: Option<NaiveDateTime>,
+    code: History<(Code, CodeHash)>,
+    /// Balances by token address, the native balance is stored using the native token address.
+    balances: HashMap<Address, History<Balance>>,
+    slots: HashMap<StoreKey, History<Option<StoreVal>>>,
+}
+
+impl AccountEntry {
+    fn new(chain: Chain, address: Address, title: String) -> Self {
+        Self {
+            chain,
+            address,
+            title,
+            creation_tx: None,
+            created_at: None,
+            deletion_tx: None,
+            deleted_at: None,
+            code: History::default(),
+            balances: HashMap::new(),
+            slots: HashMap::new(),
+        }
+    }
+
+    fn exists_at(&self, ts: &NaiveDateTime) -> bool {
+        self.created_at
+            .map(|created| &created <= ts)
+            .unwrap_or(false) &&
+            self.deleted_at
+                .map(|deleted| &deleted > ts)
+                .unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ComponentEntry {
+    component: ProtocolComponent,
+    deleted_at: Option<NaiveDateTime>,
+    tvl: Option<f64>,
+    state: HashMap<AttrStoreKey, History<StoreVal>>,
+    /// Balances by token address, stores the balance and its float representation.
+    balances: HashMap<Address, History<(Balance, f64)>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    chains: HashSet<Chain>,
+    protocol_systems: BTreeSet<String>,
+    blocks: Vec<Block>,
+    transactions: Vec<Transaction>,
+    extraction_states: HashMap<(String, Chain), ExtractionState>,
+    protocol_types: HashMap<String, ProtocolType>,
+    accounts: Vec<AccountEntry>,
+    tokens: Vec<CurrencyToken>,
+    token_analyses: Vec<TokenAnalysis>,
+    token_prices: HashMap<(Chain, Address), f64>,
+    components: Vec<ComponentEntry>,
+}
+
+impl MemoryState {
+    fn block(&self, id: &BlockIdentifier) -> Result<&Block, StorageError> {
+        let block = match id {
+            BlockIdentifier::Number((chain, number)) => self
+                .blocks
+                .iter()
+                .find(|b| &b.chain == chain && b.number as i64 == *number),
+            BlockIdentifier::Hash(hash) => self
+                .blocks
+                .iter()
+                .find(|b| &b.hash == hash),
+            BlockIdentifier::Latest(chain) => self
+                .blocks
+                .iter()
+                .filter(|b| &b.chain == chain)
+                .max_by_key(|b| b.number),
+        };
+        block.ok_or_else(|| StorageError::NotFound("Block".to_owned(), id.to_string()))
+    }
+
+    fn tx(&self, hash: &TxHash) -> Option<TxRef> {
+        let tx = self
+            .transactions
+            .iter()
+            .find(|tx| &tx.hash == hash)?;
+        let block = self
+            .blocks
+            .iter()
+            .find(|b| b.hash == tx.block_hash)?;
+        Some(TxRef { hash: tx.hash.clone(), index: tx.index, ts: block.ts, chain: block.chain })
+    }
+
+    fn block_ts(&self, version: &BlockOrTimestamp) -> Result<NaiveDateTime, StorageError> {
+        match version {
+            BlockOrTimestamp::Block(id) => Ok(self.block(id)?.ts),
+            BlockOrTimestamp::Timestamp(ts) => Ok(*ts),
+        }
+    }
+
+    fn version_ts(&self, version: &Version) -> Result<NaiveDateTime, StorageError> {
+        if !matches!(version.1, VersionKind::Last) {
+            return Err(StorageError::Unsupported(format!(
+                "Unsupported version kind: {:?}",
+                version.1
+            )));
+        }
+        self.block_ts(&version.0)
+    }
+
+    fn account(&self, chain: &Chain, address: &Address) -> Option<&AccountEntry> {
+        self.accounts
+            .iter()
+            .find(|a| &a.chain == chain && &a.address == address)
+    }
+
+    fn account_mut(&mut self, chain: &Chain, address: &Address) -> Option<&mut AccountEntry> {
+        self.accounts
+            .iter_mut()
+            .find(|a| &a.chain == chain && &a.address == address)
+    }
+
+    fn component(&self, chain: &Chain, id: &str) -> Option<&ComponentEntry> {
+        self.components
+            .iter()
+            .find(|c| &c.component.chain == chain && c.component.id == id)
+    }
+
+    fn component_mut(&mut self, chain: &Chain, id: &str) -> Option<&mut ComponentEntry> {
+        self.components
+            .iter_mut()
+            .find(|c| &c.component.chain == chain && c.component.id == id)
+    }
+
+    fn token(&self, chain: &Chain, address: &Address) -> Option<&CurrencyToken> {
+        self.tokens
+            .iter()
+            .find(|t| &t.chain == chain && &t.address == address)
+    }
+}
+
+/// A [`Gateway`] implementation that keeps all data in memory.
+///
+/// Cloning the gateway is cheap, all clones share the same underlying state.
+#[derive(Clone, Default)]
+pub struct MemoryGateway {
+    state: Arc<RwLock<MemoryState>>,
+}
+
+impl MemoryGateway {
+    /// Creates a new, empty gateway.
+    ///
+    /// Similar to the postgres backend, chains and protocol systems are static and have to be
+    /// provided upfront. Components of unknown protocol systems can't be inserted. The native
+    /// token of each chain is inserted, the same way the postgres backend ensures it exists.
+    pub fn new(chains: &[Chain], protocol_systems: &[String]) -> Self {
+        let mut state = MemoryState {
+            chains: chains.iter().copied().collect(),
+            protocol_systems: protocol_systems
+                .iter()
+                .cloned()
+                .collect(),
+            ..Default::default()
+        };
+        for chain in chains {
+            let token = chain.native_token();
+            let title = format!("{}_{}", token.symbol, token.address);
+            state
+                .accounts
+                .push(AccountEntry::new(*chain, token.address.clone(), title));
+            state
+                .tokens
+                .push(CurrencyToken { gas: vec![], ..token });
+        }
+        Self { state: Arc::new(RwLock::new(state)) }
+    }
+
+    /// Sets token prices.
+    ///
+    /// Token prices are not written through the gateway traits, in production they are maintained
+    /// by a separate job. This method allows to seed them.
+    pub async fn set_token_prices(&self, chain: &Chain, prices: &HashMap<Address, f64>) {
+        let mut state = self.state.write().await;
+        for (address, price) in prices {
+            state
+                .token_prices
+                .insert((*chain, address.clone()), *price);
+        }
+    }
+}
+
+impl Gateway for MemoryGateway {}
+
+#[cfg(test)]
+mod test_conformance {
+    use std::future::Future;
+
+    use tycho_core::storage::conformance::{ConformanceBackend, CHAIN, PROTOCOL_SYSTEM};
+
+    use super::*;
+
+    impl ConformanceBackend for MemoryGateway {}
+
+    async fn run<F, Fut>(case: F)
+    where
+        F: FnOnce(MemoryGateway) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        case(MemoryGateway::new(&[CHAIN], &[PROTOCOL_SYSTEM.to_string()])).await
+    }
+
+    tycho_core::conformance_tests!(run);
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::DateTime;
+    use tycho_core::Bytes;
+
+    use super::*;
+
+    fn ts(secs: i64) -> NaiveDateTime {
+        DateTime::from_timestamp(secs, 0)
+            .map(|d| d.naive_utc())
+            .unwrap()
+    }
+
+    fn tx(index: u64, secs: i64) -> TxRef {
+        TxRef {
+            hash: Bytes::from(index.to_be_bytes()),
+            index,
+            ts: ts(secs),
+            chain: Chain::Ethereum,
+        }
+    }
+
+    #[test]
+    fn test_history_versioning() {
+        let mut history = History::default();
+        history.push(Bytes::from("0x01"), &tx(1, 10));
+        history.push(Bytes::from("0x02"), &tx(2, 20));
+
+        assert_eq!(history.at(Some(&ts(15))).unwrap().value, Bytes::from("0x01"));
+        assert!(history.at(Some(&ts(5))).is_none());
+        let latest = history.at(None).unwrap();
+        assert_eq!(latest.value, Bytes::from("0x02"));
+        assert_eq!(latest.previous_value, Some(Bytes::from("0x01")));
+    }
+
+    #[test]
+    fn test_history_revert() {
+        let mut history = History::default();
+        history.push(Bytes::from("0x01"), &tx(1, 10));
+        history.push(Bytes::from("0x02"), &tx(2, 20));
+        assert!(history.delete(ts(30)));
+        assert!(history.at(None).is_none());
+
+        history.revert(&ts(10), &[tx(2, 20).hash].into());
+
+        assert_eq!(history.0.len(), 1);
+        assert_eq!(history.at(None).unwrap().value, Bytes::from("0x01"));
+    }
+}
@@ -0,0 +1,1301 @@
+use std::collections::{HashMap, HashSet};
+
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use tracing::warn;
+use tycho_core::{
+    models::{
+        protocol::{
+            ComponentBalance, ProtocolComponent, ProtocolComponentState,
+            ProtocolComponentStateDelta, QualityRange,
+        },
+        token::{CurrencyToken, TokenAnalysis},
+        Address, AttrStoreKey, Balance, Chain, ChangeType, ComponentId, PaginationParams,
+        ProtocolType, StoreVal, TxHash,
+    },
+    storage::{
+        BlockOrTimestamp, ProtocolGateway, ProtocolSystemRemoval, StorageError, Version, WithTotal,
+    },
+    Bytes,
+};
+
+use super::{AccountEntry, ComponentEntry, History, MemoryGateway, MemoryState};
+
+fn paginate<T>(items: Vec<T>, pagination_params: Option<&PaginationParams>) -> Vec<T> {
+    match pagination_params {
+        Some(pagination) => items
+            .into_iter()
+            .skip(pagination.offset() as usize)
+            .take(pagination.page_size as usize)
+            .collect(),
+        None => items,
+    }
+}
+
+fn zero_tx_hash() -> TxHash {
+    TxHash::from("0x0000000000000000000000000000000000000000000000000000000000000000")
+}
+
+/// A pending write to the protocol state of a component.
+enum StateWrite {
+    Update(AttrStoreKey, StoreVal),
+    Deletion(AttrStoreKey),
+}
+
+impl MemoryState {
+    fn component_balances(
+        &self,
+        chain: &Chain,
+        ids: Option<&[&str]>,
+        version_ts: Option<&NaiveDateTime>,
+    ) -> HashMap<ComponentId, HashMap<Address, ComponentBalance>> {
+        self.components
+            .iter()
+            .filter(|c| &c.component.chain == chain)
+            .filter(|c| ids.is_none_or(|ids| ids.contains(&c.component.id.as_str())))
+            .filter_map(|c| {
+                let balances: HashMap<_, _> = c
+                    .balances
+                    .iter()
+                    .filter_map(|(token, history)| {
+                        let version = history.at(version_ts)?;
+                        Some((
+                            token.clone(),
+                            ComponentBalance::new(
+                                token.clone(),
+                                version.value.0.clone(),
+                                version.value.1,
+                                zero_tx_hash(),
+                                &c.component.id,
+                            ),
+                        ))
+                    })
+                    .collect();
+                (!balances.is_empty()).then(|| (c.component.id.clone(), balances))
+            })
+            .collect()
+    }
+
+    /// Decodes the attributes of the passed components into protocol states.
+    ///
+    /// Components without any valid attribute are skipped. Any remaining balances are added as
+    /// states with empty attributes, same as the postgres backend does.
+    fn decode_protocol_states(
+        mut balances: HashMap<ComponentId, HashMap<Address, ComponentBalance>>,
+        components: &[&ComponentEntry],
+        version_ts: Option<&NaiveDateTime>,
+    ) -> Vec<ProtocolComponentState> {
+        let mut protocol_states = Vec::new();
+        for c in components {
+            let attributes: HashMap<_, _> = c
+                .state
+                .iter()
+                .filter_map(|(attr, history)| {
+                    history
+                        .at(version_ts)
+                        .map(|v| (attr.clone(), v.value.clone()))
+                })
+                .collect();
+            if attributes.is_empty() {
+                continue;
+            }
+            let component_balances: HashMap<Address, Balance> = balances
+                .remove(&c.component.id)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(token, balance)| (token, balance.balance))
+                .collect();
+            protocol_states.push(ProtocolComponentState::new(
+                &c.component.id,
+                attributes,
+                component_balances,
+            ));
+        }
+
+        // add remaining balances as states with empty attributes
+        for (component_id, balances) in balances.into_iter() {
+            protocol_states.push(ProtocolComponentState::new(
+                component_id.as_str(),
+                HashMap::new(),
+                balances
+                    .into_iter()
+                    .map(|(token, balance)| (token, balance.balance))
+                    .collect(),
+            ))
+        }
+        protocol_states
+    }
+
+    fn has_state_at(component: &ComponentEntry, version_ts: Option<&NaiveDateTime>) -> bool {
+        component
+            .state
+            .values()
+            .any(|history| history.at(version_ts).is_some())
+    }
+
+    /// Returns the existing accounts referenced by the given components, either as contract or
+    /// as token.
+    fn referenced_accounts<'a>(
+        &self,
+        components: impl Iterator<Item = &'a ComponentEntry>,
+        as_contract: bool,
+    ) -> HashSet<(Chain, Address)> {
+        components
+            .flat_map(|c| {
+                let addresses =
+                    if as_contract { &c.component.contract_addresses } else { &c.component.tokens };
+                addresses
+                    .iter()
+                    .map(|address| (c.component.chain, address.clone()))
+            })
+            .filter(|(chain, address)| {
+                if as_contract {
+                    self.account(chain, address).is_some()
+                } else {
+                    self.token(chain, address).is_some()
+                }
+            })
+            .collect()
+    }
+
+    /// Removes a protocol system, mirroring the postgres backend: contracts and tokens that are
+    /// shared with other systems are kept, balances of exclusive contracts are always removed.
+    fn remove_protocol_system(&mut self, system: &str, dry_run: bool) -> ProtocolSystemRemoval {
+        let is_removed = |c: &&ComponentEntry| c.component.protocol_system == system;
+        let own_contracts = self.referenced_accounts(
+            self.components
+                .iter()
+                .filter(is_removed),
+            true,
+        );
+        let own_tokens = self.referenced_accounts(
+            self.components
+                .iter()
+                .filter(is_removed),
+            false,
+        );
+        let shared_contracts = self.referenced_accounts(
+            self.components
+                .iter()
+                .filter(|c| !is_removed(c)),
+            true,
+        );
+        let shared_tokens = self.referenced_accounts(
+            self.components
+                .iter()
+                .filter(|c| !is_removed(c)),
+            false,
+        );
+
+        let exclusive_contracts: HashSet<_> = own_contracts
+            .difference(&shared_contracts)
+            .cloned()
+            .collect();
+        let removed_contracts: HashSet<_> = exclusive_contracts
+            .difference(&shared_tokens)
+            .cloned()
+            .collect();
+        let removed_tokens: HashSet<_> = own_tokens
+            .iter()
+            .filter(|key| !shared_tokens.contains(key) && !shared_contracts.contains(key))
+            .cloned()
+            .collect();
+
+        let removed_components = self
+            .components
+            .iter()
+            .filter(is_removed)
+            .collect::<Vec<_>>();
+        let removal = ProtocolSystemRemoval {
+            protocol_components: removed_components.len() as i64,
+            protocol_states: removed_components
+                .iter()
+                .flat_map(|c| c.state.values())
+                .map(|history| history.0.len() as i64)
+                .sum(),
+            component_balances: removed_components
+                .iter()
+                .flat_map(|c| c.balances.values())
+                .map(|history| history.0.len() as i64)
+                .sum(),
+            contracts: removed_contracts
+                .difference(&removed_tokens)
+                .count() as i64,
+            tokens: removed_tokens.len() as i64,
+            extraction_states: self
+                .extraction_states
+                .keys()
+                .filter(|(name, _)| name == system)
+                .count() as i64,
+        };
+        if dry_run {
+            return removal;
+        }
+
+        self.accounts.retain(|a| {
+            let key = (a.chain, a.address.clone());
+            !removed_contracts.contains(&key) && !removed_tokens.contains(&key)
+        });
+        for account in self.accounts.iter_mut() {
+            if exclusive_contracts.contains(&(account.chain, account.address.clone())) {
+                account.balances.clear();
+            }
+        }
+        self.tokens
+            .retain(|t| !removed_tokens.contains(&(t.chain, t.address.clone())));
+        self.token_analyses
+            .retain(|a| !removed_tokens.contains(&(a.chain, a.token.clone())));
+        self.token_prices
+            .retain(|key, _| !removed_tokens.contains(key));
+        self.components
+            .retain(|c| c.component.protocol_system != system);
+        self.protocol_systems.remove(system);
+        self.extraction_states
+            .retain(|(name, _), _| name != system);
+        removal
+    }
+}
+
+fn forward_state_delta(
+    component: &ComponentEntry,
+    start_ts: &NaiveDateTime,
+    end_ts: &NaiveDateTime,
+) -> Option<ProtocolComponentStateDelta> {
+    let mut updated = HashMap::new();
+    let mut deleted = HashSet::new();
+    for (attr, history) in component.state.iter() {
+        // only consider attributes that were updated within the range and are still valid by
+        // end_ts
+        if let Some(v) = history
+            .changed_within(start_ts, end_ts)
+            .find(|v| &v.valid_to > end_ts)
+        {
+            updated.insert(attr.clone(), v.value.clone());
+        }
+        let validity_ended = history
+            .0
+            .iter()
+            .any(|v| start_ts < &v.valid_to && &v.valid_to <= end_ts);
+        if validity_ended && history.at(Some(end_ts)).is_none() {
+            deleted.insert(attr.clone());
+        }
+    }
+    if updated.is_empty() && deleted.is_empty() {
+        return None;
+    }
+    Some(ProtocolComponentStateDelta::new(&component.component.id, updated, deleted))
+}
+
+fn backward_state_delta(
+    component: &ComponentEntry,
+    start_ts: &NaiveDateTime,
+    target_ts: &NaiveDateTime,
+) -> Option<ProtocolComponentStateDelta> {
+    let mut updated = HashMap::new();
+    let mut deleted = HashSet::new();
+    for (attr, history) in component.state.iter() {
+        // revert the first update within the range
+        if let Some(v) = history
+            .changed_within(target_ts, start_ts)
+            .next()
+        {
+            match &v.previous_value {
+                // the attribute was updated and must be reverted via a reversed update
+                Some(previous) => {
+                    updated.insert(attr.clone(), previous.clone());
+                }
+                // the attribute was created and must be deleted on revert
+                None => {
+                    deleted.insert(attr.clone());
+                }
+            }
+        }
+        // reinstate attributes that were deleted within the range
+        if history.at(Some(start_ts)).is_none() {
+            if let Some(v) = history.0.iter().find(|v| {
+                &v.valid_from <= target_ts && target_ts < &v.valid_to && &v.valid_to <= start_ts
+            }) {
+                updated.insert(attr.clone(), v.value.clone());
+            }
+        }
+    }
+    if updated.is_empty() && deleted.is_empty() {
+        return None;
+    }
+    Some(ProtocolComponentStateDelta::new(&component.component.id, updated, deleted))
+}
+
+#[async_trait]
+impl ProtocolGateway for MemoryGateway {
+    async fn get_protocol_components(
+        &self,
+        chain: &Chain,
+        system: Option<String>,
+        ids: Option<&[&str]>,
+        min_tvl: Option<f64>,
+        pagination_params: Option<&PaginationParams>,
+    ) -> Result<WithTotal<Vec<ProtocolComponent>>, StorageError> {
+        let state = self.state.read().await;
+        let matching: Vec<_> = state
+            .components
+            .iter()
+            .filter(|c| &c.component.chain == chain)
+            .filter(|c| {
+                system
+                    .as_ref()
+                    .is_none_or(|s| &c.component.protocol_system == s)
+            })
+            .filter(|c| ids.is_none_or(|ids| ids.contains(&c.component.id.as_str())))
+            .filter(|c| min_tvl.is_none_or(|thr| c.tvl.is_some_and(|tvl| tvl > thr)))
+            .collect();
+        let total = matching.len() as i64;
+        let components = paginate(matching, pagination_params)
+            .into_iter()
+            .map(|c| ProtocolComponent { change: ChangeType::Creation, ..c.component.clone() })
+            .collect();
+        Ok(WithTotal { entity: components, total: Some(total) })
+    }
+
+    async fn get_protocol_components_at(
+        &self,
+        chain: &Chain,
+        system: &str,
+        version: &Version,
+        pagination_params: Option<&PaginationParams>,
+    ) -> Result<WithTotal<Vec<ProtocolComponent>>, StorageError> {
+        let state = self.state.read().await;
+        let ts = state.version_ts(version)?;
+        let matching: Vec<_> = state
+            .components
+            .iter()
+            .filter(|c| &c.component.chain == chain && c.component.protocol_system == system)
+            .filter(|c| c.component.created_at <= ts)
+            .filter(|c| {
+                c.deleted_at
+                    .is_none_or(|deleted| deleted > ts)
+            })
+            .collect();
+        let total = matching.len() as i64;
+        let components = paginate(matching, pagination_params)
+            .into_iter()
+            .map(|c| ProtocolComponent { change: ChangeType::Creation, ..c.component.clone() })
+            .collect();
+        Ok(WithTotal { entity: components, total: Some(total) })
+    }
+
+    async fn get_token_owners(
+        &self,
+        chain: &Chain,
+        tokens: &[Address],
+        min_balance: Option<f64>,
+    ) -> Result<HashMap<Address, (ComponentId, Bytes)>, StorageError> {
+        let state = self.state.read().await;
+        let min_balance = min_balance.unwrap_or(0f64);
+        let mut res: HashMap<Address, (ComponentId, Bytes)> = HashMap::new();
+        for c in state
+            .components
+            .iter()
+            .filter(|c| &c.component.chain == chain)
+        {
+            for token in tokens {
+                if state.token(chain, token).is_none() {
+                    continue;
+                }
+                let Some((balance, balance_float)) = c
+                    .balances
+                    .get(token)
+                    .and_then(|h| h.at(None))
+                    .map(|v| v.value.clone())
+                else {
+                    continue;
+                };
+                if balance_float < min_balance {
+                    continue;
+                }
+                res.entry(token.clone())
+                    .and_modify(|v| {
+                        // Bytes uses lexicographical order which in case lengths
+                        // are equal, is equivalent to big-endian numerical order.
+                        if v.1.len() == balance.len() && v.1 < balance {
+                            *v = (c.component.id.clone(), balance.clone());
+                        }
+                    })
+                    .or_insert_with(|| (c.component.id.clone(), balance));
+            }
+        }
+        Ok(res)
+    }
+
+    async fn add_protocol_components(&self, new: &[ProtocolComponent]) -> Result<(), StorageError> {
+        let mut state = self.state.write().await;
+        let mut to_insert = Vec::with_capacity(new.len());
+        for pc in new {
+            if !state
+                .protocol_types
+                .contains_key(&pc.protocol_type_name)
+            {
+                return Err(StorageError::NotFound(
+                    "ProtocolType".to_owned(),
+                    pc.protocol_type_name.clone(),
+                ));
+            }
+            if !state
+                .protocol_systems
+                .contains(&pc.protocol_system)
+            {
+                return Err(StorageError::NotFound(
+                    "ProtocolSystem".to_owned(),
+                    pc.protocol_system.clone(),
+                ));
+            }
+            if state.tx(&pc.creation_tx).is_none() {
+                return Err(StorageError::DecodeError("TxHash not found".to_string()));
+            }
+            // conflicting components are ignored
+            if state
+                .component(&pc.chain, &pc.id)
+                .is_some() ||
+                to_insert
+                    .iter()
+                    .any(|c: &ProtocolComponent| c.chain == pc.chain && c.id == pc.id)
+            {
+                continue;
+            }
+            if let Some(t) = pc
+                .tokens
+                .iter()
+                .find(|t| state.token(&pc.chain, t).is_none())
+            {
+                return Err(StorageError::NotFound("Token".to_string(), t.to_string()));
+            }
+            if let Some(address) = pc
+                .contract_addresses
+                .iter()
+                .find(|address| {
+                    state
+                        .account(&pc.chain, address)
+                        .is_none_or(|a: &AccountEntry| a.code.is_empty())
+                })
+            {
+                return Err(StorageError::NotFound("Account".to_string(), address.to_string()));
+            }
+            to_insert.push(pc.clone());
+        }
+
+        state.components.extend(
+            to_insert
+                .into_iter()
+                .map(|pc| ComponentEntry {
+                    component: ProtocolComponent { change: ChangeType::Creation, ..pc },
+                    deleted_at: None,
+                    tvl: None,
+                    state: HashMap::new(),
+                    balances: HashMap::new(),
+                }),
+        );
+        Ok(())
+    }
+
+    async fn delete_protocol_components(
+        &self,
+        to_delete: &[ProtocolComponent],
+        block_ts: NaiveDateTime,
+    ) -> Result<(), StorageError> {
+        let mut state = self.state.write().await;
+        let ids: HashSet<_> = to_delete
+            .iter()
+            .map(|c| c.id.as_str())
+            .collect();
+        for c in state.components.iter_mut() {
+            if ids.contains(c.component.id.as_str()) {
+                c.deleted_at = Some(block_ts);
+            }
+        }
+        Ok(())
+    }
+
+    async fn add_protocol_types(
+        &self,
+        new_protocol_types: &[ProtocolType],
+    ) -> Result<(), StorageError> {
+        let mut state = self.state.write().await;
+        for protocol_type in new_protocol_types {
+            state
+                .protocol_types
+                .entry(protocol_type.name.clone())
+                .or_insert_with(|| protocol_type.clone());
+        }
+        Ok(())
+    }
+
+    async fn get_protocol_types(
+        &self,
+        names: Option<&[&str]>,
+    ) -> Result<Vec<ProtocolType>, StorageError> {
+        let state = self.state.read().await;
+        let mut protocol_types: Vec<_> = state
+            .protocol_types
+            .values()
+            .filter(|t| names.is_none_or(|names| names.contains(&t.name.as_str())))
+            .cloned()
+            .collect();
+        protocol_types.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(protocol_types)
+    }
+
+    async fn get_protocol_states(
+        &self,
+        chain: &Chain,
+        at: Option<Version>,
+        system: Option<String>,
+        ids: Option<&[&str]>,
+        retrieve_balances: bool,
+        pagination_params: Option<&PaginationParams>,
+    ) -> Result<WithTotal<Vec<ProtocolComponentState>>, StorageError> {
+        let state = self.state.read().await;
+        let version_ts = at
+            .as_ref()
+            .map(|v| state.version_ts(v))
+            .transpose()?;
+        let version_ts = version_ts.as_ref();
+
+        let balances = if retrieve_balances {
+            state.component_balances(chain, ids, version_ts)
+        } else {
+            HashMap::new()
+        };
+
+        let on_chain = state
+            .components
+            .iter()
+            .filter(|c| &c.component.chain == chain);
+        let matches_ids =
+            |c: &&ComponentEntry| ids.is_none_or(|ids| ids.contains(&c.component.id.as_str()));
+        let (components, total) = match (ids, system) {
+            (_, Some(system)) => {
+                let in_system: Vec<_> = on_chain
+                    .filter(|c| c.component.protocol_system == system)
+                    .collect();
+                // the total ignores the id filter, same as the postgres backend
+                let total = pagination_params.map(|_| in_system.len() as i64);
+                let selected = in_system
+                    .into_iter()
+                    .filter(matches_ids)
+                    .collect();
+                (paginate(selected, pagination_params), total)
+            }
+            (Some(_), None) => {
+                let selected: Vec<_> = on_chain.filter(matches_ids).collect();
+                let total = pagination_params.map(|_| selected.len() as i64);
+                let mut page = paginate(selected, pagination_params);
+                page.sort_by(|a, b| a.component.id.cmp(&b.component.id));
+                (page, total)
+            }
+            (None, None) => {
+                let selected: Vec<_> = on_chain
+                    .filter(|c| MemoryState::has_state_at(c, version_ts))
+                    .collect();
+                let total = pagination_params.map(|_| selected.len() as i64);
+                let page = paginate(selected, pagination_params);
+                // If no components found, return empty result
+                let total = if page.is_empty() { Some(0) } else { total };
+                (page, total)
+            }
+        };
+
+        let protocol_states =
+            MemoryState::decode_protocol_states(balances, &components, version_ts);
+        Ok(WithTotal { entity: protocol_states, total })
+    }
+
+    async fn update_protocol_states(
+        &self,
+        new: &[(TxHash, ProtocolComponentStateDelta)],
+    ) -> Result<(), StorageError> {
+        let mut state = self.state.write().await;
+
+        let mut writes = Vec::new();
+        for (tx_hash, delta) in new.iter() {
+            let tx = state
+                .tx(tx_hash)
+                .ok_or(StorageError::NotFound("Tx id".to_string(), tx_hash.to_string()))?;
+            let component_idx = state
+                .components
+                .iter()
+                .position(|c| c.component.chain == tx.chain && c.component.id == delta.component_id)
+                .ok_or(StorageError::NotFound(
+                    "Component id".to_string(),
+                    delta.component_id.to_string(),
+                ))?;
+            writes.extend(
+                delta
+                    .updated_attributes
+                    .iter()
+                    .map(|(attr, value)| {
+                        (tx.clone(), component_idx, StateWrite::Update(attr.clone(), value.clone()))
+                    }),
+            );
+            writes.extend(
+                delta
+                    .deleted_attributes
+                    .iter()
+                    .map(|attr| (tx.clone(), component_idx, StateWrite::Deletion(attr.clone()))),
+            );
+        }
+        writes.sort_by_key(|(tx, _, _)| (tx.ts, tx.index));
+
+        // Apply writes to copies first, so a failed deletion leaves the state untouched.
+        let mut updated: HashMap<usize, HashMap<AttrStoreKey, History<StoreVal>>> = HashMap::new();
+        for (tx, idx, write) in writes {
+            let component_state = updated
+                .entry(idx)
+                .or_insert_with(|| state.components[idx].state.clone());
+            match write {
+                StateWrite::Update(attr, value) => component_state
+                    .entry(attr)
+                    .or_default()
+                    .push(value, &tx),
+                StateWrite::Deletion(attr) => {
+                    let deleted = component_state
+                        .get_mut(&attr)
+                        .is_some_and(|history| history.delete(tx.ts));
+                    if !deleted {
+                        return Err(StorageError::Unexpected(format!(
+                            "Missing deleted row with id {:?}",
+                            (&state.components[idx].component.id, attr)
+                        )));
+                    }
+                }
+            }
+        }
+        for (idx, component_state) in updated {
+            state.components[idx].state = component_state;
+        }
+        Ok(())
+    }
+
+    async fn get_tokens(
+        &self,
+        chain: Chain,
+        address: Option<&[&Address]>,
+        quality: QualityRange,
+        traded_n_days_ago: Option<NaiveDateTime>,
+        pagination_params: Option<&PaginationParams>,
+    ) -> Result<WithTotal<Vec<CurrencyToken>>, StorageError> {
+        let state = self.state.read().await;
+        let matching: Vec<_> = state
+            .tokens
+            .iter()
+            .filter(|t| t.chain == chain)
+            .filter(|t| address.is_none_or(|addresses| addresses.contains(&&t.address)))
+            .filter(|t| {
+                quality
+                    .min
+                    .is_none_or(|min| t.quality as i64 >= min as i64)
+            })
+            .filter(|t| {
+                quality
+                    .max
+                    .is_none_or(|max| t.quality as i64 <= max as i64)
+            })
+            .filter(|t| {
+                traded_n_days_ago.is_none_or(|threshold| {
+                    state
+                        .components
+                        .iter()
+                        .filter(|c| c.component.chain == chain)
+                        .filter_map(|c| c.balances.get(&t.address)?.at(None))
+                        .any(|v| v.valid_from > threshold)
+                })
+            })
+            .cloned()
+            .collect();
+        let total = matching.len() as i64;
+        Ok(WithTotal { entity: paginate(matching, pagination_params), total: Some(total) })
+    }
+
+    async fn add_component_balances(
+        &self,
+        component_balances: &[ComponentBalance],
+    ) -> Result<(), StorageError> {
+        let mut state = self.state.write().await;
+
+        let mut writes = Vec::with_capacity(component_balances.len());
+        for component_balance in component_balances.iter() {
+            if !state
+                .tokens
+                .iter()
+                .any(|t| t.address == component_balance.token)
+            {
+                return Err(StorageError::NotFound(
+                    "Token".to_string(),
+                    component_balance.token.to_string(),
+                ));
+            }
+            let tx = state
+                .tx(&component_balance.modify_tx)
+                .ok_or_else(|| {
+                    StorageError::NotFound(
+                        "Transaction".to_string(),
+                        component_balance.modify_tx.to_string(),
+                    )
+                })?;
+            let component_idx = state
+                .components
+                .iter()
+                .position(|c| {
+                    c.component.chain == tx.chain &&
+                        c.component.id == component_balance.component_id
+                })
+                .ok_or_else(|| {
+                    StorageError::NotFound(
+                        "ProtocolComponent".to_string(),
+                        component_balance.component_id.clone(),
+                    )
+                })?;
+            writes.push((tx, component_idx, component_balance));
+        }
+
+        writes.sort_by_key(|(tx, _, _)| (tx.ts, tx.index));
+        for (tx, idx, component_balance) in writes {
+            state.components[idx]
+                .balances
+                .entry(component_balance.token.clone())
+                .or_default()
+                .push((component_balance.balance.clone(), component_balance.balance_float), &tx);
+        }
+        Ok(())
+    }
+
+    async fn add_tokens(&self, tokens: &[CurrencyToken]) -> Result<(), StorageError> {
+        let mut state = self.state.write().await;
+        for token in tokens {
+            if state
+                .account(&token.chain, &token.address)
+                .is_none()
+            {
+                let title = format!("{:?}_{}", token.chain, token.symbol);
+                state
+                    .accounts
+                    .push(AccountEntry::new(token.chain, token.address.clone(), title));
+            }
+            // existing tokens are not updated
+            if state
+                .token(&token.chain, &token.address)
+                .is_none()
+            {
+                state.tokens.push(token.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn add_token_analyses(&self, analyses: &[TokenAnalysis]) -> Result<(), StorageError> {
+        let mut state = self.state.write().await;
+        for analysis in analyses {
+            if state
+                .token(&analysis.chain, &analysis.token)
+                .is_some()
+            {
+                state
+                    .token_analyses
+                    .push(analysis.clone());
+            } else {
+                warn!(address=?&analysis.token, "Tried to add analysis of non existing token! Consider inserting it first!");
+            }
+        }
+        Ok(())
+    }
+
+    async fn update_tokens(
+        &self,
+        tokens: &[CurrencyToken],
+        analyses: &[TokenAnalysis],
+    ) -> Result<(), StorageError> {
+        {
+            let mut state = self.state.write().await;
+            for t in tokens.iter() {
+                if let Some(existing) = state
+                    .tokens
+                    .iter_mut()
+                    .find(|existing| existing.chain == t.chain && existing.address == t.address)
+                {
+                    *existing = t.clone();
+                } else {
+                    warn!(address=?&t.address, "Tried to update non existing token! Consider inserting it first!");
+                }
+            }
+        }
+        self.add_token_analyses(analyses).await
+    }
+
+    async fn get_token_analyses(
+        &self,
+        chain: &Chain,
+        token: &Address,
+        limit: i64,
+    ) -> Result<Vec<TokenAnalysis>, StorageError> {
+        let state = self.state.read().await;
+        if state.token(chain, token).is_none() {
+            return Err(StorageError::NotFound("Token".to_string(), token.to_string()));
+        }
+        let mut analyses: Vec<_> = state
+            .token_analyses
+            .iter()
+            .rev()
+            .filter(|a| &a.chain == chain && &a.token == token)
+            .cloned()
+            .collect();
+        // stable sort keeps the most recently inserted first among equal timestamps
+        analyses.sort_by_key(|a| std::cmp::Reverse(a.analyzed_at));
+        analyses.truncate(limit.max(0) as usize);
+        Ok(analyses)
+    }
+
+    async fn get_protocol_states_delta(
+        &self,
+        chain: &Chain,
+        start_version: Option<&BlockOrTimestamp>,
+        end_version: &BlockOrTimestamp,
+    ) -> Result<Vec<ProtocolComponentStateDelta>, StorageError> {
+        let state = self.state.read().await;
+        let start_ts = match start_version {
+            Some(version) => state.block_ts(version)?,
+            None => Utc::now().naive_utc(),
+        };
+        let end_ts = state.block_ts(end_version)?;
+
+        let components = state
+            .components
+            .iter()
+            .filter(|c| &c.component.chain == chain);
+        let deltas = if start_ts <= end_ts {
+            components
+                .filter_map(|c| forward_state_delta(c, &start_ts, &end_ts))
+                .collect()
+        } else {
+            components
+                .filter_map(|c| backward_state_delta(c, &start_ts, &end_ts))
+                .collect()
+        };
+        Ok(deltas)
+    }
+
+    async fn get_balance_deltas(
+        &self,
+        chain: &Chain,
+        start_version: Option<&BlockOrTimestamp>,
+        target_version: &BlockOrTimestamp,
+    ) -> Result<Vec<ComponentBalance>, StorageError> {
+        let state = self.state.read().await;
+        let start_ts = match start_version {
+            Some(version) => state.block_ts(version)?,
+            None => Utc::now().naive_utc(),
+        };
+        let target_ts = state.block_ts(target_version)?;
+
+        let mut res = Vec::new();
+        for c in state
+            .components
+            .iter()
+            .filter(|c| &c.component.chain == chain)
+        {
+            let mut tokens: Vec<_> = c.balances.iter().collect();
+            tokens.sort_by(|a, b| a.0.cmp(b.0));
+            for (token, history) in tokens {
+                if start_ts <= target_ts {
+                    // Going forward: the latest balance update between start and target version.
+                    if let Some(v) = history
+                        .changed_within(&start_ts, &target_ts)
+                        .filter(|v| v.valid_to > target_ts)
+                        .last()
+                    {
+                        res.push(ComponentBalance::new(
+                            token.clone(),
+                            v.value.0.clone(),
+                            v.value.1,
+                            v.modify_tx.clone(),
+                            &c.component.id,
+                        ));
+                    }
+                } else {
+                    // Going backwards: the previous value of the first update between target and
+                    // start version.
+                    if let Some(v) = history.0.iter().find(|v| {
+                        target_ts <= v.valid_from &&
+                            v.valid_from < start_ts &&
+                            v.valid_to > target_ts
+                    }) {
+                        let previous = v
+                            .previous_value
+                            .as_ref()
+                            .map(|(balance, _)| balance.clone())
+                            .unwrap_or_else(|| Bytes::from("0x00"));
+                        res.push(ComponentBalance::new(
+                            token.clone(),
+                            previous,
+                            f64::NAN,
+                            v.modify_tx.clone(),
+                            &c.component.id,
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    async fn get_component_balances(
+        &self,
+        chain: &Chain,
+        ids: Option<&[&str]>,
+        version: Option<&Version>,
+    ) -> Result<HashMap<String, HashMap<Bytes, ComponentBalance>>, StorageError> {
+        let state = self.state.read().await;
+        let version_ts = version
+            .map(|v| state.version_ts(v))
+            .transpose()?;
+        Ok(state.component_balances(chain, ids, version_ts.as_ref()))
+    }
+
+    async fn get_token_prices(&self, chain: &Chain) -> Result<HashMap<Bytes, f64>, StorageError> {
+        let state = self.state.read().await;
+        Ok(state
+            .token_prices
+            .iter()
+            .filter(|((price_chain, _), _)| price_chain == chain)
+            .map(|((_, address), price)| (address.clone(), *price))
+            .collect())
+    }
+
+    async fn upsert_component_tvl(
+        &self,
+        chain: &Chain,
+        tvl_values: &HashMap<String, f64>,
+    ) -> Result<(), StorageError> {
+        let mut state = self.state.write().await;
+        for (component_id, tvl) in tvl_values {
+            if let Some(component) = state.component_mut(chain, component_id) {
+                component.tvl = Some(*tvl);
+            } else {
+                warn!(?component_id, "Tried to upsert tvl for unknown component!");
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_component_tvls(
+        &self,
+        chain: &Chain,
+        ids: Option<&[&str]>,
+    ) -> Result<HashMap<String, f64>, StorageError> {
+        let state = self.state.read().await;
+        Ok(state
+            .components
+            .iter()
+            .filter(|c| &c.component.chain == chain && c.deleted_at.is_none())
+            .filter(|c| ids.is_none_or(|ids| ids.contains(&c.component.id.as_str())))
+            .filter_map(|c| {
+                c.tvl
+                    .map(|tvl| (c.component.id.clone(), tvl))
+            })
+            .collect())
+    }
+
+    async fn get_protocol_systems(
+        &self,
+        chain: &Chain,
+        pagination_params: Option<&PaginationParams>,
+    ) -> Result<WithTotal<Vec<String>>, StorageError> {
+        let state = self.state.read().await;
+        if !state.chains.contains(chain) {
+            return Err(StorageError::NotFound("Chain".to_string(), chain.to_string()));
+        }
+        let all_protocol_systems: Vec<String> = state
+            .protocol_systems
+            .iter()
+            .cloned()
+            .collect();
+        let total = all_protocol_systems.len() as i64;
+        Ok(WithTotal {
+            total: Some(total),
+            entity: paginate(all_protocol_systems, pagination_params),
+        })
+    }
+
+    async fn remove_protocol_system(
+        &self,
+        system: &str,
+        dry_run: bool,
+    ) -> Result<ProtocolSystemRemoval, StorageError> {
+        let mut state = self.state.write().await;
+        Ok(state.remove_protocol_system(system, dry_run))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::DateTime;
+    use tycho_core::{
+        models::{
+            blockchain::{Block, Transaction},
+            contract::Account,
+            FinancialType, ImplementationType,
+        },
+        storage::{BlockIdentifier, ChainGateway, ContractStateGateway},
+    };
+
+    use super::*;
+
+    fn block(number: u64) -> Block {
+        Block::new(
+            number,
+            Chain::Ethereum,
+            Bytes::from(number.to_be_bytes()).lpad(32, 0),
+            Bytes::from((number - 1).to_be_bytes()).lpad(32, 0),
+            DateTime::from_timestamp(number as i64 * 12, 0)
+                .map(|d| d.naive_utc())
+                .unwrap(),
+        )
+    }
+
+    fn tx_hash(number: u64) -> TxHash {
+        Bytes::from((number + 100).to_be_bytes()).lpad(32, 0)
+    }
+
+    /// Sets up three blocks with a single transaction each and a component created in block 1.
+    async fn setup() -> MemoryGateway {
+        let gw = MemoryGateway::new(&[Chain::Ethereum], &["ambient".to_string()]);
+        gw.upsert_block(&[block(1), block(2), block(3)])
+            .await
+            .unwrap();
+        let txs: Vec<_> = (1..=3)
+            .map(|n| Transaction::new(tx_hash(n), block(n).hash, Bytes::from("0x01"), None, 0))
+            .collect();
+        gw.upsert_tx(&txs).await.unwrap();
+        gw.add_protocol_types(&[ProtocolType::new(
+            "pool".to_string(),
+            FinancialType::Swap,
+            None,
+            ImplementationType::Custom,
+        )])
+        .await
+        .unwrap();
+        gw.add_protocol_components(&[ProtocolComponent::new(
+            "pc_0",
+            "ambient",
+            "pool",
+            Chain::Ethereum,
+            vec![],
+            vec![],
+            HashMap::new(),
+            ChangeType::Creation,
+            tx_hash(1),
+            block(1).ts,
+        )])
+        .await
+        .unwrap();
+        gw
+    }
+
+    fn delta(updated: &[(&str, &str)], deleted: &[&str]) -> ProtocolComponentStateDelta {
+        ProtocolComponentStateDelta::new(
+            "pc_0",
+            updated
+                .iter()
+                .map(|(k, v)| (k.to_string(), Bytes::from(*v)))
+                .collect(),
+            deleted
+                .iter()
+                .map(|k| k.to_string())
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_get_protocol_states_versioned() {
+        let gw = setup().await;
+        gw.update_protocol_states(&[
+            (tx_hash(1), delta(&[("reserve", "0x01")], &[])),
+            (tx_hash(2), delta(&[("reserve", "0x02")], &[])),
+        ])
+        .await
+        .unwrap();
+
+        let at_block_1 = gw
+            .get_protocol_states(
+                &Chain::Ethereum,
+                Some(Version::from_block_number(Chain::Ethereum, 1)),
+                None,
+                None,
+                false,
+                None,
+            )
+            .await
+            .unwrap();
+        let latest = gw
+            .get_protocol_states(&Chain::Ethereum, None, None, None, false, None)
+            .await
+            .unwrap();
+
+        assert_eq!(at_block_1.entity[0].attributes["reserve"], Bytes::from("0x01"));
+        assert_eq!(latest.entity[0].attributes["reserve"], Bytes::from("0x02"));
+    }
+
+    #[tokio::test]
+    async fn test_get_protocol_states_delta_backward() {
+        let gw = setup().await;
+        gw.update_protocol_states(&[
+            (tx_hash(1), delta(&[("reserve", "0x01"), ("fee", "0x05")], &[])),
+            (tx_hash(2), delta(&[("reserve", "0x02"), ("tick", "0x03")], &["fee"])),
+        ])
+        .await
+        .unwrap();
+
+        let res = gw
+            .get_protocol_states_delta(
+                &Chain::Ethereum,
+                Some(&BlockOrTimestamp::Block(BlockIdentifier::Number((Chain::Ethereum, 3)))),
+                &BlockOrTimestamp::Block(BlockIdentifier::Number((Chain::Ethereum, 1))),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(res, vec![delta(&[("reserve", "0x01"), ("fee", "0x05")], &["tick"])]);
+    }
+
+    #[tokio::test]
+    async fn test_update_protocol_states_missing_deletion_is_atomic() {
+        let gw = setup().await;
+
+        let res = gw
+            .update_protocol_states(&[
+                (tx_hash(1), delta(&[("reserve", "0x01")], &[])),
+                (tx_hash(2), delta(&[], &["fee"])),
+            ])
+            .await;
+
+        assert!(matches!(res, Err(StorageError::Unexpected(_))));
+        let states = gw
+            .get_protocol_states(&Chain::Ethereum, None, None, None, false, None)
+            .await
+            .unwrap();
+        assert!(states.entity.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_remove_protocol_system_keeps_shared_accounts() {
+        let gw =
+            MemoryGateway::new(&[Chain::Ethereum], &["ambient".to_string(), "other".to_string()]);
+        gw.upsert_block(&[block(1)])
+            .await
+            .unwrap();
+        gw.upsert_tx(&[Transaction::new(tx_hash(1), block(1).hash, Bytes::from("0x01"), None, 0)])
+            .await
+            .unwrap();
+        let address = |id: u8| Bytes::from(vec![id; 20]);
+        let (shared, token_of_other, exclusive) = (address(1), address(2), address(3));
+        let (exclusive_token, shared_token) = (address(4), address(5));
+        for contract in [&shared, &token_of_other, &exclusive] {
+            gw.upsert_contract(&Account::new(
+                Chain::Ethereum,
+                contract.clone(),
+                "contract".to_string(),
+                HashMap::new(),
+                Bytes::from("0x01"),
+                HashMap::new(),
+                Bytes::from("0x6080"),
+                Bytes::from("0x01"),
+                tx_hash(1),
+                tx_hash(1),
+                Some(tx_hash(1)),
+            ))
+            .await
+            .unwrap();
+        }
+        gw.add_tokens(&[
+            CurrencyToken::new(&exclusive_token, "EXC", 18, 0, &[], Chain::Ethereum, 100),
+            CurrencyToken::new(&shared_token, "SHR", 18, 0, &[], Chain::Ethereum, 100),
+            CurrencyToken::new(&token_of_other, "OTH", 18, 0, &[], Chain::Ethereum, 100),
+        ])
+        .await
+        .unwrap();
+        gw.add_protocol_types(&[ProtocolType::new(
+            "pool".to_string(),
+            FinancialType::Swap,
+            None,
+            ImplementationType::Custom,
+        )])
+        .await
+        .unwrap();
+        let component = |id: &str, system: &str, tokens: Vec<Address>, contracts: Vec<Address>| {
+            ProtocolComponent::new(
+                id,
+                system,
+                "pool",
+                Chain::Ethereum,
+                tokens,
+                contracts,
+                HashMap::new(),
+                ChangeType::Creation,
+                tx_hash(1),
+                block(1).ts,
+            )
+        };
+        gw.add_protocol_components(&[
+            component(
+                "pc_0",
+                "ambient",
+                vec![exclusive_token.clone(), shared_token.clone()],
+                vec![shared.clone(), token_of_other.clone(), exclusive.clone()],
+            ),
+            component(
+                "pc_1",
+                "other",
+                vec![shared_token.clone(), token_of_other.clone()],
+                vec![shared.clone()],
+            ),
+        ])
+        .await
+        .unwrap();
+
+        let removal = gw
+            .remove_protocol_system("ambient", false)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            removal,
+            ProtocolSystemRemoval {
+                protocol_components: 1,
+                contracts: 1,
+                tokens: 1,
+                ..Default::default()
+            }
+        );
+        let state = gw.state.read().await;
+        let remaining = |address: &Address| {
+            state
+                .account(&Chain::Ethereum, address)
+                .is_some()
+        };
+        assert!(remaining(&shared));
+        assert!(remaining(&token_of_other));
+        assert!(!remaining(&exclusive));
+        assert!(!remaining(&exclusive_token));
+        assert!(remaining(&shared_token));
+        // the exclusive contract's balances are removed even though the account is kept
+        assert!(state
+            .account(&Chain::Ethereum, &token_of_other)
+            .unwrap()
+            .balances
+            .is_empty());
+        assert_eq!(
+            state
+                .protocol_systems
+                .iter()
+                .collect::<Vec<_>>(),
+            vec!["other"]
+        );
+    }
+}
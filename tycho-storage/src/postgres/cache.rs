@@ -21,13 +21,14 @@ use tycho_core::{
             ComponentBalance, ProtocolComponent, ProtocolComponentState,
             ProtocolComponentStateDelta, QualityRange,
         },
-        token::CurrencyToken,
+        token::{CurrencyToken, TokenAnalysis},
         Address, Chain, ComponentId, ContractId, ExtractionState, PaginationParams, ProtocolType,
-        TxHash,
+        StoreKey, StoreVal, TxHash,
     },
     storage::{
         BlockIdentifier, BlockOrTimestamp, ChainGateway, ContractStateGateway,
-        ExtractionStateGateway, Gateway, ProtocolGateway, StorageError, Version, WithTotal,
+        ExtractionStateGateway, Gateway, ProtocolGateway, ProtocolSystemRemoval, StorageError,
+        Version, WithTotal,
     },
     Bytes,
 };
@@ -53,6 +54,8 @@ pub(crate) enum WriteOp {
     InsertProtocolComponents(Vec<models::protocol::ProtocolComponent>),
     // Simply merge
     InsertTokens(Vec<models::token::CurrencyToken>),
+    // Simply merge
+    InsertTokenAnalyses(Vec<models::token::TokenAnalysis>),
     // Currently unused but supported, please see `CacheGateway.update_tokens` docs.
     #[allow(dead_code)]
     UpdateTokens(Vec<models::token::CurrencyToken>),
@@ -73,6 +76,7 @@ impl WriteOp {
             WriteOp::InsertAccountBalances(_) => "InsertAccountBalances",
             WriteOp::InsertProtocolComponents(_) => "InsertProtocolComponents",
             WriteOp::InsertTokens(_) => "InsertTokens",
+            WriteOp::InsertTokenAnalyses(_) => "InsertTokenAnalyses",
             WriteOp::UpdateTokens(_) => "UpdateTokens",
             WriteOp::InsertComponentBalances(_) => "InsertComponentBalances",
             WriteOp::UpsertProtocolState(_) => "UpsertProtocolState",
@@ -86,12 +90,13 @@ impl WriteOp {
             WriteOp::UpsertContract(_) => 2,
             WriteOp::UpdateContracts(_) => 3,
             WriteOp::InsertTokens(_) => 4,
-            WriteOp::UpdateTokens(_) => 5,
-            WriteOp::InsertAccountBalances(_) => 6,
-            WriteOp::InsertProtocolComponents(_) => 7,
-            WriteOp::InsertComponentBalances(_) => 8,
-            WriteOp::UpsertProtocolState(_) => 9,
-            WriteOp::SaveExtractionState(_) => 10,
+            WriteOp::InsertTokenAnalyses(_) => 5,
+            WriteOp::UpdateTokens(_) => 6,
+            WriteOp::InsertAccountBalances(_) => 7,
+            WriteOp::InsertProtocolComponents(_) => 8,
+            WriteOp::InsertComponentBalances(_) => 9,
+            WriteOp::UpsertProtocolState(_) => 10,
+            WriteOp::SaveExtractionState(_) => 11,
         }
     }
 }
@@ -178,6 +183,11 @@ impl DBTransaction {
                     l.extend(r.iter().cloned());
                     return Ok(());
                 }
+                (WriteOp::InsertTokenAnalyses(l), WriteOp::InsertTokenAnalyses(r)) => {
+                    self.size += r.len();
+                    l.extend(r.iter().cloned());
+                    return Ok(());
+                }
                 (WriteOp::UpdateTokens(l), WriteOp::InsertTokens(r)) => {
                     self.size += r.len();
                     l.extend(r.iter().cloned());
@@ -414,6 +424,11 @@ impl DBCacheWriteExecutor {
                     .add_tokens(tokens.as_slice(), conn)
                     .await?
             }
+            WriteOp::InsertTokenAnalyses(analyses) => {
+                self.state_gateway
+                    .add_token_analyses(analyses.as_slice(), conn)
+                    .await?
+            }
             WriteOp::UpdateTokens(tokens) => {
                 self.state_gateway
                     .update_tokens(tokens.as_slice(), conn)
@@ -630,6 +645,18 @@ impl CachedGateway {
 
         Ok((accounts_delta, protocol_delta, balance_deltas))
     }
+
+    /// Makes sure the protocol system exists, so extractors for new protocol systems can be started
+    /// without recreating the gateway.
+    pub async fn ensure_protocol_system(&self, protocol_system: &str) -> Result<(), StorageError> {
+        let mut conn =
+            self.pool.get().await.map_err(|e| {
+                StorageError::Unexpected(format!("Failed to retrieve connection: {e}"))
+            })?;
+        self.state_gateway
+            .ensure_protocol_system(protocol_system, &mut conn)
+            .await
+    }
 }
 
 #[async_trait]
@@ -672,6 +699,21 @@ impl ChainGateway for CachedGateway {
             .await
     }
 
+    #[instrument(skip_all)]
+    async fn get_block_at_timestamp(
+        &self,
+        chain: &Chain,
+        ts: &NaiveDateTime,
+    ) -> Result<Block, StorageError> {
+        let mut conn =
+            self.pool.get().await.map_err(|e| {
+                StorageError::Unexpected(format!("Failed to retrieve connection: {e}"))
+            })?;
+        self.state_gateway
+            .get_block_at_timestamp(chain, ts, &mut conn)
+            .await
+    }
+
     async fn upsert_tx(&self, new: &[Transaction]) -> Result<(), StorageError> {
         self.add_op(WriteOp::UpsertTx(new.to_vec()))
             .await?;
@@ -737,6 +779,23 @@ impl ContractStateGateway for CachedGateway {
             .await
     }
 
+    #[instrument(skip_all)]
+    async fn sample_contract_slots(
+        &self,
+        chain: &Chain,
+        address: &Address,
+        version: Option<&Version>,
+        limit: usize,
+    ) -> Result<HashMap<StoreKey, StoreVal>, StorageError> {
+        let mut conn =
+            self.pool.get().await.map_err(|e| {
+                StorageError::Unexpected(format!("Failed to retrieve connection: {e}"))
+            })?;
+        self.state_gateway
+            .sample_contract_slots(chain, address, version, limit, &mut conn)
+            .await
+    }
+
     #[instrument(skip_all)]
     async fn upsert_contract(&self, new: &Account) -> Result<(), StorageError> {
         self.add_op(WriteOp::UpsertContract(vec![new.clone()]))
@@ -825,6 +884,23 @@ impl ProtocolGateway for CachedGateway {
             .await
     }
 
+    #[instrument(skip_all)]
+    async fn get_protocol_components_at(
+        &self,
+        chain: &Chain,
+        system: &str,
+        version: &Version,
+        pagination_params: Option<&PaginationParams>,
+    ) -> Result<WithTotal<Vec<ProtocolComponent>>, StorageError> {
+        let mut conn =
+            self.pool.get().await.map_err(|e| {
+                StorageError::Unexpected(format!("Failed to retrieve connection: {e}"))
+            })?;
+        self.state_gateway
+            .get_protocol_components_at(chain, system, version, pagination_params, &mut conn)
+            .await
+    }
+
     #[instrument(skip_all)]
     async fn get_token_owners(
         &self,
@@ -877,6 +953,20 @@ impl ProtocolGateway for CachedGateway {
             .await
     }
 
+    #[instrument(skip_all)]
+    async fn get_protocol_types(
+        &self,
+        names: Option<&[&str]>,
+    ) -> Result<Vec<ProtocolType>, StorageError> {
+        let mut conn =
+            self.pool.get().await.map_err(|e| {
+                StorageError::Unexpected(format!("Failed to retrieve connection: {e}"))
+            })?;
+        self.state_gateway
+            .get_protocol_types(names, &mut conn)
+            .await
+    }
+
     #[instrument(skip_all)]
     async fn get_protocol_states(
         &self,
@@ -949,6 +1039,13 @@ impl ProtocolGateway for CachedGateway {
         Ok(())
     }
 
+    #[instrument(skip_all)]
+    async fn add_token_analyses(&self, analyses: &[TokenAnalysis]) -> Result<(), StorageError> {
+        self.add_op(WriteOp::InsertTokenAnalyses(analyses.to_vec()))
+            .await?;
+        Ok(())
+    }
+
     /// Updates tokens without using the write cache.
     ///
     /// This method is currently only used by the tycho-ethereum job and therefore does
@@ -959,7 +1056,11 @@ impl ProtocolGateway for CachedGateway {
     /// This is a short term solution. Ideally we should have a simple gateway version
     /// for these use cases that creates a single transactions and emits them immediately.
     #[instrument(skip_all)]
-    async fn update_tokens(&self, tokens: &[CurrencyToken]) -> Result<(), StorageError> {
+    async fn update_tokens(
+        &self,
+        tokens: &[CurrencyToken],
+        analyses: &[TokenAnalysis],
+    ) -> Result<(), StorageError> {
         let mut conn =
             self.pool.get().await.map_err(|e| {
                 StorageError::Unexpected(format!("Failed to retrieve connection: {e}"))
@@ -970,6 +1071,9 @@ impl ProtocolGateway for CachedGateway {
                 self.state_gateway
                     .update_tokens(tokens, conn)
                     .await?;
+                self.state_gateway
+                    .add_token_analyses(analyses, conn)
+                    .await?;
                 Result::<(), PostgresError>::Ok(())
             }
             .scope_boxed()
@@ -978,6 +1082,22 @@ impl ProtocolGateway for CachedGateway {
         .map_err(|e| StorageError::Unexpected(format!("Failed to update tokens: {}", e.0)))
     }
 
+    #[instrument(skip_all)]
+    async fn get_token_analyses(
+        &self,
+        chain: &Chain,
+        token: &Address,
+        limit: i64,
+    ) -> Result<Vec<TokenAnalysis>, StorageError> {
+        let mut conn =
+            self.pool.get().await.map_err(|e| {
+                StorageError::Unexpected(format!("Failed to retrieve connection: {e}"))
+            })?;
+        self.state_gateway
+            .get_token_analyses(chain, token, limit, &mut conn)
+            .await
+    }
+
     #[instrument(skip_all)]
     async fn get_protocol_states_delta(
         &self,
@@ -1053,6 +1173,21 @@ impl ProtocolGateway for CachedGateway {
             .await
     }
 
+    #[instrument(skip_all)]
+    async fn get_component_tvls(
+        &self,
+        chain: &Chain,
+        ids: Option<&[&str]>,
+    ) -> Result<HashMap<String, f64>, StorageError> {
+        let mut conn =
+            self.pool.get().await.map_err(|e| {
+                StorageError::Unexpected(format!("Failed to retrieve connection: {e}"))
+            })?;
+        self.state_gateway
+            .get_component_tvls(chain, ids, &mut conn)
+            .await
+    }
+
     #[instrument(skip_all)]
     async fn get_protocol_systems(
         &self,
@@ -1063,6 +1198,36 @@ impl ProtocolGateway for CachedGateway {
             .get_protocol_systems(chain, pagination_params)
             .await
     }
+
+    /// Removes a protocol system without using the write cache.
+    ///
+    /// Counts and deletions happen within a single transaction. Extractors of the removed
+    /// system must not be running, and the gateway should be recreated afterwards as its id
+    /// caches still contain the removed system.
+    #[instrument(skip(self))]
+    async fn remove_protocol_system(
+        &self,
+        system: &str,
+        dry_run: bool,
+    ) -> Result<ProtocolSystemRemoval, StorageError> {
+        let mut conn =
+            self.pool.get().await.map_err(|e| {
+                StorageError::Unexpected(format!("Failed to retrieve connection: {e}"))
+            })?;
+
+        conn.transaction(|conn| {
+            async {
+                let removal = self
+                    .state_gateway
+                    .remove_protocol_system(system, dry_run, conn)
+                    .await?;
+                Result::<_, PostgresError>::Ok(removal)
+            }
+            .scope_boxed()
+        })
+        .await
+        .map_err(|e| e.0)
+    }
 }
 
 impl Gateway for CachedGateway {}
@@ -1661,3 +1826,48 @@ mod test_serial_db {
         .await;
     }
 }
+
+#[cfg(test)]
+mod test_serial_db_conformance {
+    use std::future::Future;
+
+    use tycho_core::storage::conformance::{ConformanceBackend, CHAIN, PROTOCOL_SYSTEM};
+
+    use super::*;
+    use crate::postgres::{builder::GatewayBuilder, testing::run_against_db};
+
+    #[async_trait]
+    impl ConformanceBackend for CachedGateway {
+        async fn start_block(&self, block: &Block) {
+            self.start_transaction(block, Some("conformance"))
+                .await;
+        }
+
+        async fn commit_block(&self) {
+            self.commit_transaction(0)
+                .await
+                .expect("Failed to commit transaction");
+        }
+    }
+
+    async fn run<F, Fut>(case: F)
+    where
+        F: FnOnce(CachedGateway) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        run_against_db(|_| async move {
+            let db_url = std::env::var("DATABASE_URL").expect("Database URL must be set");
+            let (gw, handle) = GatewayBuilder::new(&db_url)
+                .set_chains(&[CHAIN])
+                .set_protocol_systems(&[PROTOCOL_SYSTEM.to_string()])
+                .build()
+                .await
+                .expect("Failed to build gateway");
+            case(gw).await;
+            handle.abort();
+        })
+        .await;
+    }
+
+    tycho_core::conformance_tests!(run);
+}
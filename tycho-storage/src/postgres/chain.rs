@@ -1,11 +1,12 @@
 use std::collections::HashMap;
 
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
 use diesel_async::{AsyncPgConnection, RunQueryDsl};
 use itertools::Itertools;
 use tracing::{instrument, warn};
 use tycho_core::{
-    models::{blockchain::*, BlockHash, TxHash},
+    models::{blockchain::*, BlockHash, Chain, TxHash},
     storage::{BlockIdentifier, StorageError},
     Bytes,
 };
@@ -82,6 +83,25 @@ impl PostgresGateway {
         ))
     }
 
+    #[instrument(skip_all)]
+    pub async fn get_block_at_timestamp(
+        &self,
+        chain: &Chain,
+        ts: &NaiveDateTime,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Block, StorageError> {
+        let mut orm_block = orm::Block::at_timestamp(*chain, *ts, conn)
+            .await
+            .map_err(|err| storage_error_from_diesel(err, "Block", &ts.to_string(), None))?;
+        Ok(Block::new(
+            orm_block.number as u64,
+            *chain,
+            std::mem::take(&mut orm_block.hash),
+            std::mem::take(&mut orm_block.parent_hash),
+            orm_block.ts,
+        ))
+    }
+
     #[instrument(skip_all)]
     pub async fn upsert_tx(
         &self,
@@ -797,6 +797,45 @@ impl PostgresGateway {
         Ok(account)
     }
 
+    #[instrument(level = Level::DEBUG, skip(self, conn))]
+    pub async fn sample_contract_slots(
+        &self,
+        chain: &Chain,
+        address: &Address,
+        version: Option<&Version>,
+        limit: usize,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<HashMap<StoreKey, StoreVal>, StorageError> {
+        let version_ts = match &version {
+            Some(version) => maybe_lookup_version_ts(version, conn).await?,
+            None => Utc::now().naive_utc(),
+        };
+
+        use schema::{account, contract_storage::dsl::*};
+        // Only a single row per slot is valid at any given timestamp, so no deduplication is
+        // needed before sampling.
+        let slots = contract_storage
+            .inner_join(account::table)
+            .filter(account::chain_id.eq(self.get_chain_id(chain)))
+            .filter(account::address.eq(address))
+            .filter(
+                valid_from
+                    .le(version_ts)
+                    .and(valid_to.gt(version_ts)),
+            )
+            .order_by(diesel::dsl::sql::<diesel::sql_types::Double>("random()"))
+            .limit(limit as i64)
+            .select((slot, value))
+            .get_results::<(Bytes, Option<Bytes>)>(conn)
+            .await
+            .map_err(PostgresError::from)?;
+
+        Ok(slots
+            .into_iter()
+            .map(|(k, v)| (k, v.unwrap_or_default()))
+            .collect())
+    }
+
     #[instrument(level = Level::DEBUG, skip(self, ids, conn))]
     pub async fn get_contracts(
         &self,
@@ -128,7 +128,14 @@
 //! into a single transaction. This guarantees preservation of valid state
 //! throughout the application lifetime, even if the process panics during
 //! database operations.
-use std::{collections::HashMap, hash::Hash, ops::Deref, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    ops::Deref,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
 use chrono::NaiveDateTime;
 use diesel::prelude::*;
@@ -443,7 +450,8 @@ async fn maybe_lookup_version_ts(
 
 #[derive(Clone)]
 pub(crate) struct PostgresGateway {
-    protocol_system_id_cache: Arc<ProtocolSystemEnumCache>,
+    /// Extended by `ensure_protocol_system` when extractors are added at runtime.
+    protocol_system_id_cache: Arc<RwLock<ProtocolSystemEnumCache>>,
     chain_id_cache: Arc<ChainEnumCache>,
     native_token_id_cache: Arc<NativeTokenEnumCache>,
     /// Any versions dated before this date, as per their `valid_to` column, will be
@@ -458,11 +466,11 @@ impl PostgresGateway {
     pub fn with_cache(
         chain_cache: Arc<ChainEnumCache>,
         native_token_cache: Arc<NativeTokenEnumCache>,
-        protocol_system_cache: Arc<ProtocolSystemEnumCache>,
+        protocol_system_cache: ProtocolSystemEnumCache,
         retention_horizon: NaiveDateTime,
     ) -> Self {
         Self {
-            protocol_system_id_cache: protocol_system_cache,
+            protocol_system_id_cache: Arc::new(RwLock::new(protocol_system_cache)),
             chain_id_cache: chain_cache,
             native_token_id_cache: native_token_cache,
             retention_horizon,
@@ -484,7 +492,7 @@ impl PostgresGateway {
         Self::with_cache(
             Arc::new(chain_cache),
             Arc::new(native_token_cache),
-            Arc::new(protocol_system_cache),
+            protocol_system_cache,
             NaiveDateTime::default(),
         )
     }
@@ -503,14 +511,49 @@ impl PostgresGateway {
 
     fn get_protocol_system_id(&self, protocol_system: &String) -> i64 {
         self.protocol_system_id_cache
+            .read()
+            .expect("protocol system cache lock poisoned")
             .get_id(protocol_system)
     }
 
     fn get_protocol_system(&self, id: &i64) -> String {
         self.protocol_system_id_cache
+            .read()
+            .expect("protocol system cache lock poisoned")
             .get_value(id)
     }
 
+    /// Inserts the protocol system if it doesn't exist yet and reloads the protocol system cache.
+    ///
+    /// Allows to add extractors for new protocol systems without restarting the application.
+    pub async fn ensure_protocol_system(
+        &self,
+        protocol_system: &str,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<(), StorageError> {
+        if self
+            .protocol_system_id_cache
+            .read()
+            .expect("protocol system cache lock poisoned")
+            .value_exists(&protocol_system.to_string())
+        {
+            return Ok(());
+        }
+        diesel::insert_into(schema::protocol_system::table)
+            .values(schema::protocol_system::name.eq(protocol_system))
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .await
+            .map_err(PostgresError::from)?;
+        let cache = ProtocolSystemEnumCache::from_connection(conn).await?;
+        *self
+            .protocol_system_id_cache
+            .write()
+            .expect("protocol system cache lock poisoned") = cache;
+        debug!(protocol_system, "Ensured protocol system presence");
+        Ok(())
+    }
+
     pub async fn new(
         pool: Pool<AsyncPgConnection>,
         retention_horizon: NaiveDateTime,
@@ -522,7 +565,7 @@ impl PostgresGateway {
         let gw = PostgresGateway::with_cache(
             Arc::new(chain_cache),
             Arc::new(native_token_cache),
-            Arc::new(protocol_system_cache),
+            protocol_system_cache,
             retention_horizon,
         );
 
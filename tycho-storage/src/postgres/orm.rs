@@ -27,7 +27,8 @@ use super::{
         account, account_balance, block, chain, component_balance, component_balance_default,
         component_tvl, contract_code, contract_storage, contract_storage_default, extraction_state,
         protocol_component, protocol_component_holds_contract, protocol_component_holds_token,
-        protocol_state, protocol_state_default, protocol_system, protocol_type, token, transaction,
+        protocol_state, protocol_state_default, protocol_system, protocol_type, token,
+        token_analysis, transaction,
     },
     versioning::{StoredVersionedRow, VersionedRow},
     PostgresError, MAX_TS, MAX_VERSION_TS,
@@ -192,6 +193,21 @@ impl Block {
             .await
     }
 
+    pub async fn at_timestamp(
+        chain: models::Chain,
+        ts: NaiveDateTime,
+        conn: &mut AsyncPgConnection,
+    ) -> QueryResult<Block> {
+        block::table
+            .inner_join(chain::table)
+            .filter(chain::name.eq(chain.to_string()))
+            .filter(block::ts.le(ts))
+            .order(block::number.desc())
+            .select(Block::as_select())
+            .first::<Block>(conn)
+            .await
+    }
+
     pub async fn by_id(id: &BlockIdentifier, conn: &mut AsyncPgConnection) -> QueryResult<Block> {
         match id {
             BlockIdentifier::Hash(hash) => Self::by_hash(hash, conn).await,
@@ -1249,6 +1265,8 @@ pub struct Token {
     pub inserted_ts: NaiveDateTime,
     pub modified_ts: NaiveDateTime,
     pub quality: i32,
+    pub metadata_fallbacks: Vec<String>,
+    pub flags: Vec<String>,
 }
 
 #[derive(AsChangeset, Insertable, Debug)]
@@ -1261,6 +1279,8 @@ pub struct NewToken {
     pub tax: i64,
     pub gas: Vec<Option<i64>>,
     pub quality: i32,
+    pub metadata_fallbacks: Vec<String>,
+    pub flags: Vec<String>,
 }
 
 impl NewToken {
@@ -1276,6 +1296,87 @@ impl NewToken {
                 .map(|g| g.map(|u| u as i64))
                 .collect(),
             quality: token.quality as i32,
+            metadata_fallbacks: token
+                .metadata_fallbacks
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            flags: token
+                .flags
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Identifiable, Queryable, Associations, Selectable, Debug)]
+#[diesel(belongs_to(Token))]
+#[diesel(table_name = token_analysis)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct TokenAnalysis {
+    pub id: i64,
+    pub token_id: i64,
+    pub analyzed_at: NaiveDateTime,
+    pub block_number: Option<i64>,
+    pub outcome: String,
+    pub reason: Option<String>,
+    pub quality: i32,
+    pub gas: Option<i64>,
+    pub tax: Option<i64>,
+    pub inserted_ts: NaiveDateTime,
+}
+
+impl TokenAnalysis {
+    pub fn to_analysis(
+        &self,
+        address: Address,
+        chain: models::Chain,
+    ) -> Result<models::token::TokenAnalysis, StorageError> {
+        Ok(models::token::TokenAnalysis {
+            token: address,
+            chain,
+            analyzed_at: self.analyzed_at,
+            block_number: self.block_number.map(|n| n as u64),
+            outcome: self.outcome.parse().map_err(|_| {
+                StorageError::DecodeError(format!(
+                    "Invalid token analysis outcome: {}",
+                    self.outcome
+                ))
+            })?,
+            reason: self.reason.clone(),
+            quality: self.quality as u32,
+            gas: self.gas.map(|g| g as u64),
+            tax: self.tax.map(|t| t as u64),
+        })
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = token_analysis)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct NewTokenAnalysis {
+    pub token_id: i64,
+    pub analyzed_at: NaiveDateTime,
+    pub block_number: Option<i64>,
+    pub outcome: String,
+    pub reason: Option<String>,
+    pub quality: i32,
+    pub gas: Option<i64>,
+    pub tax: Option<i64>,
+}
+
+impl NewTokenAnalysis {
+    pub fn from_analysis(token_id: i64, analysis: &models::token::TokenAnalysis) -> Self {
+        Self {
+            token_id,
+            analyzed_at: analysis.analyzed_at,
+            block_number: analysis.block_number.map(|n| n as i64),
+            outcome: analysis.outcome.to_string(),
+            reason: analysis.reason.clone(),
+            quality: analysis.quality as i32,
+            gas: analysis.gas.map(|g| g as i64),
+            tax: analysis.tax.map(|t| t as i64),
         }
     }
 }
@@ -14,11 +14,11 @@ use tycho_core::{
             ComponentBalance, ProtocolComponent, ProtocolComponentState,
             ProtocolComponentStateDelta, QualityRange,
         },
-        token::CurrencyToken,
+        token::{CurrencyToken, TokenAnalysis},
         Address, Balance, Chain, ChangeType, ComponentId, FinancialType, ImplementationType,
         PaginationParams, ProtocolType, StoreVal, TxHash,
     },
-    storage::{BlockOrTimestamp, StorageError, Version, WithTotal},
+    storage::{BlockOrTimestamp, ProtocolSystemRemoval, StorageError, Version, WithTotal},
     Bytes,
 };
 
@@ -240,6 +240,67 @@ impl PostgresGateway {
         Ok(WithTotal { entity: res, total: Some(count) })
     }
 
+    pub async fn get_protocol_components_at(
+        &self,
+        chain: &Chain,
+        system: &str,
+        version: &Version,
+        pagination_params: Option<&PaginationParams>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<WithTotal<Vec<ProtocolComponent>>, StorageError> {
+        use super::schema::{protocol_component::dsl::*, transaction::dsl::*};
+        let chain_id_value = self.get_chain_id(chain);
+        let protocol_system = self.get_protocol_system_id(&system.to_string());
+        let version_ts = maybe_lookup_version_ts(version, conn).await?;
+
+        let count = protocol_component
+            .filter(chain_id.eq(chain_id_value))
+            .filter(protocol_system_id.eq(protocol_system))
+            .filter(created_at.le(version_ts))
+            .filter(
+                deleted_at
+                    .is_null()
+                    .or(deleted_at.gt(version_ts)),
+            )
+            .count()
+            .get_result::<i64>(conn)
+            .await
+            .map_err(PostgresError::from)?;
+
+        let mut query = protocol_component
+            .inner_join(transaction.on(creation_tx.eq(schema::transaction::id)))
+            .filter(chain_id.eq(chain_id_value))
+            .filter(protocol_system_id.eq(protocol_system))
+            .filter(created_at.le(version_ts))
+            .filter(
+                deleted_at
+                    .is_null()
+                    .or(deleted_at.gt(version_ts)),
+            )
+            .order_by(schema::protocol_component::id)
+            .select((orm::ProtocolComponent::as_select(), hash))
+            .into_boxed();
+        if let Some(pagination) = pagination_params {
+            query = query
+                .limit(pagination.page_size)
+                .offset(pagination.offset());
+        }
+
+        let orm_protocol_components = query
+            .load::<(orm::ProtocolComponent, TxHash)>(conn)
+            .await
+            .map_err(PostgresError::from)?
+            .into_iter()
+            .map(|(pc, txh)| (pc, Some(txh)))
+            .collect();
+
+        let res = self
+            .build_protocol_components(orm_protocol_components, chain, conn)
+            .await?;
+
+        Ok(WithTotal { entity: res, total: Some(count) })
+    }
+
     #[instrument(level = Level::DEBUG, skip(self, orm_protocol_components, conn))]
     async fn build_protocol_components(
         &self,
@@ -979,7 +1040,7 @@ impl PostgresGateway {
                     .iter()
                     .map(|u| u.map(|g| g as u64))
                     .collect();
-                CurrencyToken::new(
+                let mut currency_token = CurrencyToken::new(
                     &address_,
                     orm_token.symbol.as_str(),
                     orm_token.decimals as u32,
@@ -987,7 +1048,18 @@ impl PostgresGateway {
                     gas_usage.as_slice(),
                     chain,
                     orm_token.quality as u32,
-                )
+                );
+                currency_token.metadata_fallbacks = orm_token
+                    .metadata_fallbacks
+                    .iter()
+                    .filter_map(|fallback| fallback.parse().ok())
+                    .collect();
+                currency_token.flags = orm_token
+                    .flags
+                    .iter()
+                    .filter_map(|flag| flag.parse().ok())
+                    .collect();
+                currency_token
             })
             .collect();
 
@@ -1113,6 +1185,16 @@ impl PostgresGateway {
                         tax.eq(t.tax as i64),
                         quality.eq(t.quality as i32),
                         gas.eq(gas_val),
+                        metadata_fallbacks.eq(t
+                            .metadata_fallbacks
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<_>>()),
+                        flags.eq(t
+                            .flags
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<_>>()),
                     ))
                     .filter(id.eq(db_id))
                     .execute(conn)
@@ -1126,6 +1208,80 @@ impl PostgresGateway {
         Ok(())
     }
 
+    pub async fn add_token_analyses(
+        &self,
+        analyses: &[TokenAnalysis],
+        conn: &mut AsyncPgConnection,
+    ) -> Result<(), StorageError> {
+        if analyses.is_empty() {
+            return Ok(());
+        }
+        let token_addresses: Vec<&Address> = analyses
+            .iter()
+            .map(|a| &a.token)
+            .collect();
+        let token_ids = schema::account::table
+            .inner_join(schema::token::table)
+            .select((schema::account::address, schema::account::chain_id, schema::token::id))
+            .filter(schema::account::address.eq_any(token_addresses))
+            .get_results::<(Bytes, i64, i64)>(conn)
+            .await
+            .map_err(PostgresError::from)?
+            .into_iter()
+            .map(|(address, chain_id, token_id)| ((address, chain_id), token_id))
+            .collect::<HashMap<_, _>>();
+
+        let new_analyses: Vec<orm::NewTokenAnalysis> = analyses
+            .iter()
+            .filter_map(|analysis| {
+                let key = (analysis.token.clone(), self.get_chain_id(&analysis.chain));
+                match token_ids.get(&key) {
+                    Some(token_id) => Some(orm::NewTokenAnalysis::from_analysis(*token_id, analysis)),
+                    None => {
+                        warn!(address=?&analysis.token, "Tried to add analysis of non existing token! Consider inserting it first!");
+                        None
+                    }
+                }
+            })
+            .collect();
+        diesel::insert_into(schema::token_analysis::table)
+            .values(&new_analyses)
+            .execute(conn)
+            .await
+            .map_err(|err| storage_error_from_diesel(err, "TokenAnalysis", "batch", None))?;
+        Ok(())
+    }
+
+    pub async fn get_token_analyses(
+        &self,
+        chain: &Chain,
+        token: &Address,
+        limit: i64,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<TokenAnalysis>, StorageError> {
+        let chain_db_id = self.get_chain_id(chain);
+        let token_id = schema::token::table
+            .inner_join(schema::account::table)
+            .filter(schema::account::address.eq(token))
+            .filter(schema::account::chain_id.eq(chain_db_id))
+            .select(schema::token::id)
+            .first::<i64>(conn)
+            .await
+            .map_err(|err| storage_error_from_diesel(err, "Token", &token.to_string(), None))?;
+
+        schema::token_analysis::table
+            .filter(schema::token_analysis::token_id.eq(token_id))
+            .order((schema::token_analysis::analyzed_at.desc(), schema::token_analysis::id.desc()))
+            .limit(limit)
+            .select(orm::TokenAnalysis::as_select())
+            .get_results::<orm::TokenAnalysis>(conn)
+            .await
+            .map_err(PostgresError::from)?
+            .iter()
+            .map(|analysis| analysis.to_analysis(token.clone(), *chain))
+            .collect()
+    }
+
     pub async fn add_component_balances(
         &self,
         component_balances: &[ComponentBalance],
@@ -1712,6 +1868,67 @@ impl PostgresGateway {
         Ok(())
     }
 
+    pub async fn get_component_tvls(
+        &self,
+        chain: &Chain,
+        ids: Option<&[&str]>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<HashMap<String, f64>, StorageError> {
+        use schema::{component_tvl, protocol_component};
+        let chain_id = self.get_chain_id(chain);
+        let mut query = component_tvl::table
+            .inner_join(protocol_component::table)
+            .filter(protocol_component::chain_id.eq(chain_id))
+            .filter(protocol_component::deleted_at.is_null())
+            .select((protocol_component::external_id, component_tvl::tvl))
+            .into_boxed();
+        if let Some(ids) = ids {
+            query = query.filter(protocol_component::external_id.eq_any(ids));
+        }
+        Ok(query
+            .get_results::<(String, f64)>(conn)
+            .await
+            .map_err(|err| {
+                storage_error_from_diesel(err, "ComponentTVL", &chain.to_string(), None)
+            })?
+            .into_iter()
+            .collect())
+    }
+
+    pub async fn get_protocol_types(
+        &self,
+        names: Option<&[&str]>,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<ProtocolType>, StorageError> {
+        use schema::protocol_type;
+        let mut query = protocol_type::table
+            .select(orm::ProtocolType::as_select())
+            .order_by(protocol_type::name)
+            .into_boxed();
+        if let Some(names) = names {
+            query = query.filter(protocol_type::name.eq_any(names));
+        }
+        Ok(query
+            .get_results::<orm::ProtocolType>(conn)
+            .await
+            .map_err(PostgresError::from)?
+            .into_iter()
+            .map(|pt| {
+                let financial_type = match pt.financial_type {
+                    orm::FinancialType::Swap => FinancialType::Swap,
+                    orm::FinancialType::Psm => FinancialType::Psm,
+                    orm::FinancialType::Debt => FinancialType::Debt,
+                    orm::FinancialType::Leverage => FinancialType::Leverage,
+                };
+                let implementation = match pt.implementation {
+                    orm::ImplementationType::Custom => ImplementationType::Custom,
+                    orm::ImplementationType::Vm => ImplementationType::Vm,
+                };
+                ProtocolType::new(pt.name, financial_type, pt.attribute_schema, implementation)
+            })
+            .collect())
+    }
+
     pub async fn get_protocol_systems(
         &self,
         chain: &Chain,
@@ -1722,6 +1939,8 @@ impl PostgresGateway {
         }
         let all_protocol_systems: Vec<String> = self
             .protocol_system_id_cache
+            .read()
+            .expect("protocol system cache lock poisoned")
             .map_enum
             .values()
             .cloned()
@@ -1741,6 +1960,183 @@ impl PostgresGateway {
 
         Ok(WithTotal { total: Some(total), entity: paginated_protocol_systems })
     }
+
+    /// Removes a protocol system and all data exclusively linked to it.
+    ///
+    /// Relies on cascading deletes for component related tables and for contract code and storage
+    /// of deleted accounts. The caller is responsible for running this within a transaction.
+    pub async fn remove_protocol_system(
+        &self,
+        system: &str,
+        dry_run: bool,
+        conn: &mut AsyncPgConnection,
+    ) -> Result<ProtocolSystemRemoval, StorageError> {
+        let system_id = schema::protocol_system::table
+            .filter(schema::protocol_system::name.eq(system))
+            .select(schema::protocol_system::id)
+            .first::<i64>(conn)
+            .await
+            .optional()
+            .map_err(PostgresError::from)?;
+
+        let extraction_states = schema::extraction_state::table
+            .filter(schema::extraction_state::name.eq(system))
+            .count()
+            .get_result::<i64>(conn)
+            .await
+            .map_err(PostgresError::from)?;
+
+        let mut removal = ProtocolSystemRemoval { extraction_states, ..Default::default() };
+        let mut token_accounts = HashSet::new();
+        let mut exclusive_contract_accounts = HashSet::new();
+        let mut removed_contract_accounts = HashSet::new();
+
+        if let Some(system_id) = system_id {
+            let component_ids = schema::protocol_component::table
+                .filter(schema::protocol_component::protocol_system_id.eq(system_id))
+                .select(schema::protocol_component::id)
+                .load::<i64>(conn)
+                .await
+                .map_err(PostgresError::from)?;
+            removal.protocol_components = component_ids.len() as i64;
+            removal.protocol_states = schema::protocol_state::table
+                .filter(schema::protocol_state::protocol_component_id.eq_any(&component_ids))
+                .count()
+                .get_result::<i64>(conn)
+                .await
+                .map_err(PostgresError::from)?;
+            removal.component_balances = schema::component_balance::table
+                .filter(schema::component_balance::protocol_component_id.eq_any(&component_ids))
+                .count()
+                .get_result::<i64>(conn)
+                .await
+                .map_err(PostgresError::from)?;
+
+            // Only rows of accounts linked to the removed system are loaded, other systems'
+            // links are looked up for these accounts only.
+            let system_contract_accounts: HashSet<i64> =
+                schema::protocol_component_holds_contract::table
+                    .inner_join(schema::contract_code::table)
+                    .inner_join(schema::protocol_component::table)
+                    .filter(schema::protocol_component::protocol_system_id.eq(system_id))
+                    .select(schema::contract_code::account_id)
+                    .distinct()
+                    .load::<i64>(conn)
+                    .await
+                    .map_err(PostgresError::from)?
+                    .into_iter()
+                    .collect();
+            let system_token_accounts: HashSet<i64> = schema::protocol_component_holds_token::table
+                .inner_join(schema::token::table)
+                .inner_join(schema::protocol_component::table)
+                .filter(schema::protocol_component::protocol_system_id.eq(system_id))
+                .select(schema::token::account_id)
+                .distinct()
+                .load::<i64>(conn)
+                .await
+                .map_err(PostgresError::from)?
+                .into_iter()
+                .collect();
+            let candidates: Vec<i64> = system_contract_accounts
+                .union(&system_token_accounts)
+                .copied()
+                .collect();
+
+            // candidates used as contract or token by components of other systems
+            let shared_contract_accounts: HashSet<i64> =
+                schema::protocol_component_holds_contract::table
+                    .inner_join(schema::contract_code::table)
+                    .inner_join(schema::protocol_component::table)
+                    .filter(schema::protocol_component::protocol_system_id.ne(system_id))
+                    .filter(schema::contract_code::account_id.eq_any(&candidates))
+                    .select(schema::contract_code::account_id)
+                    .distinct()
+                    .load::<i64>(conn)
+                    .await
+                    .map_err(PostgresError::from)?
+                    .into_iter()
+                    .collect();
+            let shared_token_accounts: HashSet<i64> = schema::protocol_component_holds_token::table
+                .inner_join(schema::token::table)
+                .inner_join(schema::protocol_component::table)
+                .filter(schema::protocol_component::protocol_system_id.ne(system_id))
+                .filter(schema::token::account_id.eq_any(&candidates))
+                .select(schema::token::account_id)
+                .distinct()
+                .load::<i64>(conn)
+                .await
+                .map_err(PostgresError::from)?
+                .into_iter()
+                .collect();
+
+            exclusive_contract_accounts = system_contract_accounts
+                .difference(&shared_contract_accounts)
+                .copied()
+                .collect();
+            // accounts still used as tokens by other systems are kept
+            removed_contract_accounts = exclusive_contract_accounts
+                .difference(&shared_token_accounts)
+                .copied()
+                .collect();
+            // tokens whose account is used as contract by another system are kept
+            token_accounts = system_token_accounts
+                .iter()
+                .filter(|account_id| {
+                    !shared_token_accounts.contains(account_id) &&
+                        !shared_contract_accounts.contains(account_id)
+                })
+                .copied()
+                .collect();
+            removal.tokens = token_accounts.len() as i64;
+            removal.contracts = removed_contract_accounts
+                .difference(&token_accounts)
+                .count() as i64;
+        }
+
+        if dry_run {
+            return Ok(removal);
+        }
+
+        // Deleting accounts cascades to tokens, contract code and storage.
+        let removed_accounts: Vec<i64> = token_accounts
+            .union(&removed_contract_accounts)
+            .copied()
+            .collect();
+        diesel::delete(
+            schema::account::table.filter(schema::account::id.eq_any(&removed_accounts)),
+        )
+        .execute(conn)
+        .await
+        .map_err(PostgresError::from)?;
+        // Balances of contracts that are kept as tokens of other systems are removed as well.
+        let exclusive_contract_accounts: Vec<i64> = exclusive_contract_accounts
+            .into_iter()
+            .collect();
+        diesel::delete(
+            schema::account_balance::table
+                .filter(schema::account_balance::account_id.eq_any(&exclusive_contract_accounts)),
+        )
+        .execute(conn)
+        .await
+        .map_err(PostgresError::from)?;
+        if let Some(system_id) = system_id {
+            // Cascades to components, their states, balances, tvl and junction tables.
+            diesel::delete(
+                schema::protocol_system::table.filter(schema::protocol_system::id.eq(system_id)),
+            )
+            .execute(conn)
+            .await
+            .map_err(PostgresError::from)?;
+        }
+        diesel::delete(
+            schema::extraction_state::table.filter(schema::extraction_state::name.eq(system)),
+        )
+        .execute(conn)
+        .await
+        .map_err(PostgresError::from)?;
+
+        Ok(removal)
+    }
 }
 
 #[cfg(test)]
@@ -265,6 +265,24 @@ diesel::table! {
         inserted_ts -> Timestamptz,
         modified_ts -> Timestamptz,
         quality -> Int4,
+        metadata_fallbacks -> Array<Text>,
+        flags -> Array<Text>,
+    }
+}
+
+diesel::table! {
+    token_analysis (id) {
+        id -> Int8,
+        token_id -> Int8,
+        analyzed_at -> Timestamp,
+        block_number -> Nullable<Int8>,
+        #[max_length = 16]
+        outcome -> Varchar,
+        reason -> Nullable<Text>,
+        quality -> Int4,
+        gas -> Nullable<Int8>,
+        tax -> Nullable<Int8>,
+        inserted_ts -> Timestamptz,
     }
 }
 
@@ -323,6 +341,7 @@ diesel::joinable!(protocol_state -> transaction (modify_tx));
 diesel::joinable!(protocol_state_default -> protocol_component (protocol_component_id));
 diesel::joinable!(protocol_state_default -> transaction (modify_tx));
 diesel::joinable!(token -> account (account_id));
+diesel::joinable!(token_analysis -> token (token_id));
 diesel::joinable!(token_price -> token (token_id));
 diesel::joinable!(transaction -> block (block_id));
 
@@ -346,6 +365,7 @@ diesel::allow_tables_to_appear_in_same_query!(
     protocol_system,
     protocol_type,
     token,
+    token_analysis,
     token_price,
     transaction,
 );